@@ -0,0 +1,174 @@
+//! Handshakes per second and relay throughput of the server, over loopback.
+//!
+//! Run with `cargo bench`, criterion compares with the previous run.
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use fast_socks5::client::{Config, Socks5Datagram, Socks5Stream};
+use fast_socks5::server::{
+    run_tcp_proxy_with_options, run_udp_proxy, ConnectOptions, Socks5ServerProtocol,
+};
+use fast_socks5::Socks5Command;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::runtime::Runtime;
+
+const LOCALHOST: IpAddr = IpAddr::V4(std::net::Ipv4Addr::LOCALHOST);
+const CHUNK: usize = 64 * 1024;
+const DATAGRAM: usize = 1024;
+
+fn runtime() -> Runtime {
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+}
+
+/// A no-auth server relaying CONNECT and UDP ASSOCIATE, or only replying to CONNECT
+/// without dialing the target if `reply_only`.
+async fn spawn_server(reply_only: bool) -> SocketAddr {
+    let listener = TcpListener::bind((LOCALHOST, 0)).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (socket, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let (proto, cmd, target) = Socks5ServerProtocol::accept_no_auth(socket)
+                    .await?
+                    .read_command()
+                    .await?;
+                match cmd {
+                    _ if reply_only => {
+                        proto.reply_success(addr).await?;
+                    }
+                    Socks5Command::UDPAssociate => {
+                        run_udp_proxy(proto, &target, Some(LOCALHOST), LOCALHOST, None).await?;
+                    }
+                    _ => {
+                        let mut opts = ConnectOptions::new();
+                        opts.set_nodelay(true);
+                        run_tcp_proxy_with_options(proto, &target, &opts).await?;
+                    }
+                }
+                Ok::<_, fast_socks5::server::SocksServerError>(())
+            });
+        }
+    });
+    addr
+}
+
+async fn spawn_tcp_echo() -> SocketAddr {
+    let listener = TcpListener::bind((LOCALHOST, 0)).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let (mut read, mut write) = stream.split();
+                let _ = tokio::io::copy(&mut read, &mut write).await;
+            });
+        }
+    });
+    addr
+}
+
+async fn spawn_udp_echo() -> SocketAddr {
+    let socket = UdpSocket::bind((LOCALHOST, 0)).await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; 65536];
+        loop {
+            let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+            let _ = socket.send_to(&buf[..len], from).await;
+        }
+    });
+    addr
+}
+
+async fn connect(proxy: SocketAddr, target: SocketAddr) -> Socks5Stream<TcpStream> {
+    let mut config = Config::default();
+    config.set_connect_timeout(10);
+    Socks5Stream::connect(proxy, target.ip().to_string(), target.port(), config)
+        .await
+        .unwrap()
+}
+
+fn handshake(c: &mut Criterion) {
+    let rt = runtime();
+    let proxy = rt.block_on(spawn_server(true));
+    let mut group = c.benchmark_group("handshake");
+    group.throughput(Throughput::Elements(1));
+    group.bench_function("connect_no_auth", |b| {
+        b.to_async(&rt).iter(|| connect(proxy, proxy))
+    });
+    group.finish();
+}
+
+fn tcp_relay(c: &mut Criterion) {
+    let rt = runtime();
+    let (proxy, echo) = rt.block_on(async { (spawn_server(false).await, spawn_tcp_echo().await) });
+    let mut group = c.benchmark_group("tcp_relay");
+    group.throughput(Throughput::Bytes(CHUNK as u64));
+    group.bench_function("echo_64k", |b| {
+        b.iter_custom(|iters| {
+            rt.block_on(async {
+                let stream = connect(proxy, echo).await;
+                let (mut read, mut write) = tokio::io::split(stream);
+                let chunk = vec![7u8; CHUNK];
+                let start = Instant::now();
+                let writer = async {
+                    for _ in 0..iters {
+                        write.write_all(&chunk).await.unwrap();
+                    }
+                };
+                let reader = async {
+                    let mut buf = vec![0u8; CHUNK];
+                    for _ in 0..iters {
+                        read.read_exact(&mut buf).await.unwrap();
+                    }
+                };
+                tokio::join!(writer, reader);
+                start.elapsed()
+            })
+        })
+    });
+    group.finish();
+}
+
+fn udp_relay(c: &mut Criterion) {
+    let rt = runtime();
+    let (proxy, echo) = rt.block_on(async { (spawn_server(false).await, spawn_udp_echo().await) });
+    let mut group = c.benchmark_group("udp_relay");
+    group.throughput(Throughput::Bytes(DATAGRAM as u64));
+    group.bench_function("echo_1k", |b| {
+        b.iter_custom(|iters| {
+            rt.block_on(async {
+                let socket = Socks5Datagram::connect_udp_bind(
+                    proxy,
+                    (LOCALHOST, 0),
+                    None,
+                    Config::default(),
+                )
+                .await
+                .unwrap();
+                let datagram = vec![7u8; DATAGRAM];
+                let mut buf = vec![0u8; 2 * DATAGRAM];
+                let start = Instant::now();
+                // one datagram in flight at a time, so that none is dropped
+                for _ in 0..iters {
+                    socket.send_to(&datagram, echo).await.unwrap();
+                    tokio::time::timeout(Duration::from_secs(1), socket.recv_from(&mut buf))
+                        .await
+                        .expect("datagram lost")
+                        .unwrap();
+                }
+                start.elapsed()
+            })
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, handshake, tcp_relay, udp_relay);
+criterion_main!(benches);
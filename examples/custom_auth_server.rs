@@ -5,14 +5,15 @@ extern crate log;
 use fast_socks5::{
     auth_method_enums,
     server::{
-        run_tcp_proxy, AuthMethod, AuthMethodSuccessState, DnsResolveHelper as _,
-        PasswordAuthentication, PasswordAuthenticationStarted, Socks5ServerProtocol,
+        run_tcp_proxy_with_options, AuthMethod, AuthMethodSuccessState, ConnectOptions,
+        DnsResolveHelper as _, PasswordAuthentication, PasswordAuthenticationStarted, SessionTasks,
+        Socks5ServerProtocol,
     },
     ReplyError, Result, Socks5Command, SocksError,
 };
-use std::{future::Future, time::Duration};
+use std::time::Duration;
 use structopt::StructOpt;
-use tokio::task;
+use tokio::task::JoinError;
 use tokio::{
     io::{AsyncRead, AsyncReadExt},
     net::TcpListener,
@@ -54,11 +55,14 @@ async fn spawn_socks_server() -> Result<()> {
 
     info!("Listen for socks connections @ {}", &opt.listen_addr);
 
+    let mut tasks = SessionTasks::new();
+    tasks.set_result_handler(log_session_result);
+
     // Standard TCP loop
     loop {
         match listener.accept().await {
             Ok((socket, _client_addr)) => {
-                spawn_and_log_error(serve_socks5(socket));
+                tasks.spawn(serve_socks5(socket)).await;
             }
             Err(err) => {
                 error!("accept error = {:?}", err);
@@ -151,12 +155,18 @@ async fn serve_socks5(socket: tokio::net::TcpStream) -> Result<(), SocksError> {
         AuthStarted::BackdoorAuthentication(auth) => auth.verify_timing().await?.finish_auth(),
     };
 
-    let (proto, cmd, target_addr) = proto.read_command().await?.resolve_dns().await?;
-
     const REQUEST_TIMEOUT: u64 = 10;
+    let mut opts = ConnectOptions::new();
+    opts.set_request_timeout(REQUEST_TIMEOUT);
+    let (proto, cmd, target_addr) = proto
+        .read_command()
+        .await?
+        .resolve_dns_with_options(&opts)
+        .await?;
+
     match cmd {
         Socks5Command::TCPConnect => {
-            run_tcp_proxy(proto, &target_addr, REQUEST_TIMEOUT, false).await?;
+            run_tcp_proxy_with_options(proto, &target_addr, &opts).await?;
         }
         _ => {
             proto.reply_error(&ReplyError::CommandNotSupported).await?;
@@ -166,14 +176,10 @@ async fn serve_socks5(socket: tokio::net::TcpStream) -> Result<(), SocksError> {
     Ok(())
 }
 
-fn spawn_and_log_error<F>(fut: F) -> task::JoinHandle<()>
-where
-    F: Future<Output = Result<()>> + Send + 'static,
-{
-    task::spawn(async move {
-        match fut.await {
-            Ok(()) => {}
-            Err(err) => error!("{:#}", &err),
-        }
-    })
+fn log_session_result(res: Result<Result<()>, JoinError>) {
+    match res {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => error!("{:#}", &err),
+        Err(err) => error!("session task failed: {}", err),
+    }
 }
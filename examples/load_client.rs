@@ -0,0 +1,206 @@
+#[forbid(unsafe_code)]
+#[macro_use]
+extern crate log;
+
+use anyhow::Context;
+use fast_socks5::client::{Config, Socks5Stream};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use structopt::StructOpt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinSet;
+
+/// # How to use it:
+///
+/// Load a server with 100 sessions echoing 16 KiB payloads 1000 times, through a local
+/// echo server:
+///   `$ cargo run --release --example load_client -- --socks-server 127.0.0.1:1337 -n 100 -b 16384 -r 1000`
+///
+/// Or through an echo server reachable by the SOCKS server:
+///   `$ cargo run --release --example load_client -- --socks-server 127.0.0.1:1337 -a 10.0.0.2 -p 7`
+///
+/// Each session opens a connection through the SOCKS server, then writes a payload and
+/// reads it back, in rounds. The latencies of the handshakes and of the rounds are
+/// reported in percentiles.
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "socks5-load-client",
+    about = "Generates load on a socks5 server and reports latencies."
+)]
+struct Opt {
+    /// Socks5 server address + port. eg. `127.0.0.1:1080`
+    #[structopt(short, long)]
+    pub socks_server: String,
+
+    /// Address of an echo server, reached through the socks server. An echo server is
+    /// started on 127.0.0.1 if missing
+    #[structopt(short = "a", long)]
+    pub target_addr: Option<String>,
+
+    /// Port of the echo server
+    #[structopt(short = "p", long, default_value = "7")]
+    pub target_port: u16,
+
+    /// Concurrent sessions
+    #[structopt(short = "n", long, default_value = "10")]
+    pub sessions: usize,
+
+    /// Bytes sent and read back at each round
+    #[structopt(short = "b", long, default_value = "16384")]
+    pub payload: usize,
+
+    /// Rounds per session
+    #[structopt(short = "r", long, default_value = "100")]
+    pub rounds: usize,
+
+    #[structopt(short, long)]
+    pub username: Option<String>,
+
+    #[structopt(long)]
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Default)]
+struct Latencies {
+    handshakes: Vec<Duration>,
+    rounds: Vec<Duration>,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    env_logger::init();
+    let opt: Opt = Opt::from_args();
+
+    let (target_addr, target_port) = match opt.target_addr.clone() {
+        Some(addr) => (addr, opt.target_port),
+        None => {
+            let echo = spawn_echo_server().await?;
+            info!("Echo server listening on {}", echo);
+            (echo.ip().to_string(), echo.port())
+        }
+    };
+
+    let started = Instant::now();
+    let mut sessions = JoinSet::new();
+    for _ in 0..opt.sessions {
+        let (socks_server, target_addr) = (opt.socks_server.clone(), target_addr.clone());
+        let credentials = opt.username.clone().zip(opt.password.clone());
+        let (payload, rounds) = (opt.payload, opt.rounds);
+        sessions.spawn(async move {
+            run_session(
+                socks_server,
+                target_addr,
+                target_port,
+                credentials,
+                payload,
+                rounds,
+            )
+            .await
+        });
+    }
+
+    let mut latencies = Latencies::default();
+    let mut failures = 0;
+    while let Some(session) = sessions.join_next().await {
+        match session? {
+            Ok(session) => {
+                latencies.handshakes.extend(session.handshakes);
+                latencies.rounds.extend(session.rounds);
+            }
+            Err(err) => {
+                warn!("session failed: {:#}", err);
+                failures += 1;
+            }
+        }
+    }
+    let elapsed = started.elapsed();
+
+    let bytes = latencies.rounds.len() * opt.payload * 2;
+    println!(
+        "{} sessions ({} failed) in {:.2?}, {:.1} MiB/s",
+        opt.sessions,
+        failures,
+        elapsed,
+        bytes as f64 / elapsed.as_secs_f64() / (1024.0 * 1024.0),
+    );
+    report("handshake", &mut latencies.handshakes);
+    report("round", &mut latencies.rounds);
+    Ok(())
+}
+
+async fn run_session(
+    socks_server: String,
+    target_addr: String,
+    target_port: u16,
+    credentials: Option<(String, String)>,
+    payload: usize,
+    rounds: usize,
+) -> anyhow::Result<Latencies> {
+    let mut latencies = Latencies::default();
+
+    let started = Instant::now();
+    let config = Config::default();
+    let mut socks = match credentials {
+        Some((username, password)) => {
+            Socks5Stream::connect_with_password(
+                socks_server,
+                target_addr,
+                target_port,
+                username,
+                password,
+                config,
+            )
+            .await?
+        }
+        None => Socks5Stream::connect(socks_server, target_addr, target_port, config).await?,
+    };
+    latencies.handshakes.push(started.elapsed());
+
+    let sent = vec![0x5a; payload];
+    let mut received = vec![0; payload];
+    for _ in 0..rounds {
+        let started = Instant::now();
+        // the echo server sends back while we write, large payloads don't deadlock
+        let (mut read, mut write) = tokio::io::split(&mut socks);
+        let (written, read) = tokio::join!(write.write_all(&sent), read.read_exact(&mut received));
+        written.context("Can't write the payload")?;
+        read.context("Can't read the payload back")?;
+        latencies.rounds.push(started.elapsed());
+    }
+    Ok(latencies)
+}
+
+/// Print the percentiles of `latencies`.
+fn report(name: &str, latencies: &mut [Duration]) {
+    if latencies.is_empty() {
+        return;
+    }
+    latencies.sort_unstable();
+    let percentile = |p: usize| latencies[(latencies.len() - 1) * p / 100];
+    println!(
+        "{:<10} n={:<8} p50={:<10.2?} p90={:<10.2?} p99={:<10.2?} max={:.2?}",
+        name,
+        latencies.len(),
+        percentile(50),
+        percentile(90),
+        percentile(99),
+        percentile(100),
+    );
+}
+
+async fn spawn_echo_server() -> std::io::Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(echo(stream));
+        }
+    });
+    Ok(addr)
+}
+
+async fn echo(mut stream: TcpStream) {
+    let (mut read, mut write) = stream.split();
+    let _ = tokio::io::copy(&mut read, &mut write).await;
+}
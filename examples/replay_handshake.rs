@@ -0,0 +1,83 @@
+#![forbid(unsafe_code)]
+use fast_socks5::server::{replay_handshake, ReplayAuth};
+use fast_socks5::ReplyError;
+use structopt::StructOpt;
+
+/// # How to use it:
+///
+/// Replay a captured client handshake, given as hex bytes:
+///   `$ RUST_LOG=debug cargo run --example replay_handshake -- --password "05 02 00 02 01 05 61 6c 69 63 65 03 70 77 64 05 01 00 01 c0 00 02 01 00 50"`
+///
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "socks5-replay-handshake",
+    about = "Replay a recorded client handshake against the server, offline."
+)]
+struct Opt {
+    /// The bytes sent by the client, in hex, whitespace is ignored
+    pub input: String,
+
+    /// Only accept username/password authentication
+    #[structopt(long)]
+    pub password: bool,
+
+    /// Also accept the no-authentication method
+    #[structopt(long)]
+    pub allow_no_auth: bool,
+
+    /// Only accept these credentials, e.g. `admin:password`
+    #[structopt(long)]
+    pub credentials: Option<String>,
+
+    /// Skip the authentication, the input starts with the command request
+    #[structopt(long)]
+    pub skip_auth: bool,
+}
+
+fn parse_hex(input: &str) -> Result<Vec<u8>, String> {
+    let digits: String = input.chars().filter(|c| !c.is_whitespace()).collect();
+    if !digits.len().is_multiple_of(2) {
+        return Err("odd number of hex digits".to_owned());
+    }
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).map_err(|e| e.to_string()))
+        .collect()
+}
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+    let opt: Opt = Opt::from_args();
+
+    let input = match parse_hex(&opt.input) {
+        Ok(input) => input,
+        Err(err) => {
+            eprintln!("Invalid input: {}", err);
+            std::process::exit(2);
+        }
+    };
+
+    let auth = if opt.skip_auth {
+        ReplayAuth::SkipAuth
+    } else if let Some((username, password)) =
+        opt.credentials.as_deref().and_then(|c| c.split_once(':'))
+    {
+        ReplayAuth::Password {
+            username: username.to_owned(),
+            password: password.to_owned().into(),
+        }
+    } else if opt.password && opt.allow_no_auth {
+        ReplayAuth::PasswordOrNoAuth
+    } else if opt.password {
+        ReplayAuth::AnyPassword
+    } else {
+        ReplayAuth::NoAuth
+    };
+
+    let report = replay_handshake(&input, auth, ReplyError::Succeeded).await;
+    println!("{}", report);
+    if !report.is_success() {
+        std::process::exit(1);
+    }
+}
@@ -4,13 +4,17 @@ extern crate log;
 
 use anyhow::Context;
 use fast_socks5::{
-    server::{run_tcp_proxy, run_udp_proxy, DnsResolveHelper as _, Socks5ServerProtocol},
-    ReplyError, Result, Socks5Command, SocksError,
+    server::{
+        run_tcp_proxy_with_options, run_udp_proxy_with_options, verify_credentials, ConnectOptions,
+        DnsResolveHelper as _, HandshakeLimits, SessionTasks, Socks5ServerProtocol,
+        UdpProxyOptions,
+    },
+    ConfigError, ReplyError, Result, Socks5Command, SocksError,
 };
-use std::future::Future;
+use std::time::Duration;
 use structopt::StructOpt;
 use tokio::net::TcpListener;
-use tokio::task;
+use tokio::task::JoinError;
 
 /// # How to use it:
 ///
@@ -51,6 +55,10 @@ struct Opt {
     /// Allow UDP proxying, requires public-addr to be set
     #[structopt(short = "U", long)]
     pub allow_udp: bool,
+
+    /// Log the bytes of the handshakes which can't be parsed (including credentials)
+    #[structopt(long)]
+    pub debug_handshakes: bool,
 }
 
 /// Choose the authentication type
@@ -70,9 +78,6 @@ enum AuthMode {
 /// Useful read 2. https://blog.yoshuawuyts.com/futures-concurrency/
 /// Useful read 3. https://blog.yoshuawuyts.com/streams-concurrency/
 /// error-libs benchmark: https://blog.yoshuawuyts.com/error-handling-survey/
-///
-/// TODO: Write functional tests: https://github.com/ark0f/async-socks5/blob/master/src/lib.rs#L762
-/// TODO: Write functional tests with cURL?
 #[tokio::main]
 async fn main() -> Result<()> {
     env_logger::init();
@@ -82,26 +87,30 @@ async fn main() -> Result<()> {
 
 async fn spawn_socks_server() -> Result<()> {
     let opt: &'static Opt = Box::leak(Box::new(Opt::from_args()));
-    if opt.allow_udp && opt.public_addr.is_none() {
-        return Err(SocksError::ArgumentInputError(
-            "Can't allow UDP if public-addr is not set",
-        ));
-    }
-    if opt.skip_auth && opt.auth != AuthMode::NoAuth {
-        return Err(SocksError::ArgumentInputError(
-            "Can't use skip-auth flag and authentication altogether.",
-        ));
-    }
+    let mut config_err = ConfigError::new();
+    config_err
+        .check(
+            !opt.allow_udp || opt.public_addr.is_some(),
+            "--allow-udp requires --public-addr",
+        )
+        .check(
+            !opt.skip_auth || opt.auth == AuthMode::NoAuth,
+            "--skip-auth can only be used with no-auth",
+        );
+    config_err.into_result()?;
 
     let listener = TcpListener::bind(&opt.listen_addr).await?;
 
     info!("Listen for socks connections @ {}", &opt.listen_addr);
 
+    let mut tasks = SessionTasks::new();
+    tasks.set_result_handler(log_session_result);
+
     // Standard TCP loop
     loop {
         match listener.accept().await {
             Ok((socket, _client_addr)) => {
-                spawn_and_log_error(serve_socks5(opt, socket));
+                tasks.spawn(serve_socks5(opt, socket)).await;
             }
             Err(err) => {
                 error!("accept error = {:?}", err);
@@ -110,105 +119,42 @@ async fn spawn_socks_server() -> Result<()> {
     }
 }
 
-async fn negotiate_auth_method(
-    socket: &mut tokio::net::TcpStream,
-    client_ip: IpAddr,
-    whitelist: &Arc<RwLock<HashSet<IpAddr>>>,
-    auth_mode: &AuthMode
-) -> Result<u8, SocksError> {
-    use tokio::io::{AsyncReadExt, AsyncWriteExt};
-    
-    // Read method selection request
-    let mut buf = [0u8; 257];
-    let n = socket.read(&mut buf).await?;
-    
-    if n < 2 || buf[0] != 5 {
-        return Err(SocksError::InvalidData);
-    }
-    
-    let n_methods = buf[1] as usize;
-    if n < 2 + n_methods {
-        return Err(SocksError::InvalidData);
-    }
-    
-    let methods = &buf[2..2+n_methods];
-    
-    // Check available methods like MicroSocks
-    let selected_method = match auth_mode {
-        AuthMode::NoAuth => {
-            if methods.contains(&0) { 0 } else { 0xFF }
-        },
-        AuthMode::Password { .. } => {
-            // Check if IP is whitelisted (auth_once logic)
-            if whitelist.read().await.contains(&client_ip) {
-                debug!("IP {} whitelisted, selecting NO_AUTH", client_ip);
-                if methods.contains(&0) { 0 } else { 0xFF }
-            } else {
-                debug!("IP {} requires PASSWORD auth", client_ip);
-                if methods.contains(&2) { 2 } else { 0xFF }
+async fn serve_socks5(opt: &Opt, socket: tokio::net::TcpStream) -> Result<(), SocksError> {
+    let mut limits = HandshakeLimits::new();
+    limits
+        .set_timeout(Duration::from_secs(opt.request_timeout))
+        .set_diagnostics(opt.debug_handshakes);
+    let mut opts = ConnectOptions::new();
+    opts.set_request_timeout(opt.request_timeout);
+    let (proto, cmd, target_addr) = limits
+        .run(socket, |socket| async {
+            match &opt.auth {
+                AuthMode::NoAuth if opt.skip_auth => {
+                    Socks5ServerProtocol::skip_auth_this_is_not_rfc_compliant(socket)
+                }
+                AuthMode::NoAuth => Socks5ServerProtocol::accept_no_auth(socket).await?,
+                AuthMode::Password { username, password } => {
+                    Socks5ServerProtocol::accept_password_auth(socket, |user, pass| {
+                        verify_credentials(&user, &pass, username, password)
+                    })
+                    .await?
+                    .0
+                }
             }
-        }
-    };
-    
-    // Send method selection response
-    let response = [5u8, selected_method];
-    socket.write_all(&response).await?;
-    
-    Ok(selected_method)
-}
-
-// Modify serve_socks5 function
-async fn serve_socks5(
-    opt: &Opt, 
-    mut socket: tokio::net::TcpStream, 
-    client_ip: IpAddr,
-    whitelist: Arc<RwLock<HashSet<IpAddr>>>
-) -> Result<(), SocksError> {
-    
-    let (proto, cmd, target_addr) = match &opt.auth {
-        AuthMode::NoAuth if opt.skip_auth => {
-            Socks5ServerProtocol::skip_auth_this_is_not_rfc_compliant(socket)
-        }
-        AuthMode::NoAuth => Socks5ServerProtocol::accept_no_auth(socket).await?,
-        AuthMode::Password { username, password } => {
-            // Use proper method negotiation
-            let selected_method = negotiate_auth_method(&mut socket, client_ip, &whitelist, &opt.auth).await?;
-            
-            match selected_method {
-                0 => {
-                    // NO_AUTH selected (whitelisted IP)
-                    Socks5ServerProtocol::accept_no_auth(socket).await?
-                },
-                2 => {
-                    // PASSWORD auth selected
-                    let (proto, ..) = Socks5ServerProtocol::accept_password_auth(socket, |user, pass| {
-                        user == *username && pass == *password
-                    }).await?;
-                    
-                    // Add to whitelist after successful auth
-                    if opt.auth_once {
-                        whitelist.write().await.insert(client_ip);
-                        info!("IP {} authenticated and whitelisted", client_ip);
-                    }
-                    
-                    proto
-                },
-                _ => return Err(SocksError::NoAcceptableAuthMethods)
-            }
-        }
-    }
-    .read_command()
-    .await?
-    .resolve_dns()
-    .await?;
+            .read_command()
+            .await
+        })
+        .await?
+        .resolve_dns_with_options(&opts)
+        .await?;
 
     match cmd {
         Socks5Command::TCPConnect => {
-            run_tcp_proxy(proto, &target_addr, opt.request_timeout, false).await?;
+            run_tcp_proxy_with_options(proto, &target_addr, &opts).await?;
         }
         Socks5Command::UDPAssociate if opt.allow_udp => {
             let reply_ip = opt.public_addr.context("invalid reply ip")?;
-            run_udp_proxy(proto, &target_addr, None, reply_ip, None).await?;
+            run_udp_proxy_with_options(proto, &target_addr, UdpProxyOptions::new(reply_ip)).await?;
         }
         _ => {
             proto.reply_error(&ReplyError::CommandNotSupported).await?;
@@ -218,14 +164,10 @@ async fn serve_socks5(
     Ok(())
 }
 
-fn spawn_and_log_error<F>(fut: F) -> task::JoinHandle<()>
-where
-    F: Future<Output = Result<()>> + Send + 'static,
-{
-    task::spawn(async move {
-        match fut.await {
-            Ok(()) => {}
-            Err(err) => error!("{:#}", &err),
-        }
-    })
+fn log_session_result(res: Result<Result<()>, JoinError>) {
+    match res {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => error!("{:#}", &err),
+        Err(err) => error!("session task failed: {}", err),
+    }
 }
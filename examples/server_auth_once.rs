@@ -4,22 +4,21 @@ extern crate log;
 
 use anyhow::Context;
 use fast_socks5::{
-    server::{run_tcp_proxy, run_udp_proxy, DnsResolveHelper as _, Socks5ServerProtocol},
-    ReplyError, Result, Socks5Command, SocksError,
-};
-use std::{
-    collections::HashSet,
-    future::Future,
-    net::{IpAddr, SocketAddr},
-    sync::Arc,
+    server::{
+        run_tcp_proxy_with_options, run_udp_proxy_with_options, verify_password, AuthFailure,
+        AuthOnceAcceptor, ConnectOptions, DnsResolveHelper as _, SessionLogger, Socks5Listener,
+        Socks5ServerProtocol, UdpProxyOptions, WithReason,
+    },
+    ConfigError, ReplyError, Result, Socks5Command, SocksError,
 };
+use std::{future::Future, net::IpAddr, sync::Arc};
 use structopt::StructOpt;
-use tokio::{
-    net::TcpListener,
-    sync::RwLock,
-    task,
-    io::{AsyncReadExt, AsyncWriteExt},
-};
+
+/// # How to use it:
+///
+/// Listen with one-time authentication, the IPs which authenticated once can then
+/// connect without password:
+///     `$ RUST_LOG=debug cargo run --example server_auth_once -- --listen-addr 127.0.0.1:1337 --auth-once password --username admin --password password`
 
 #[derive(Debug, StructOpt)]
 #[structopt(
@@ -27,8 +26,12 @@ use tokio::{
     about = "A simple implementation of a socks5-server."
 )]
 struct Opt {
-    #[structopt(short, long)]
-    pub listen_addr: String,
+    /// Bind on address, can be repeated. eg. `-l 0.0.0.0:1080 -l [::]:1080`
+    ///
+    /// Not needed when the listeners are passed by systemd socket activation
+    /// (built with the `socket-activation` feature).
+    #[structopt(short, long, number_of_values = 1)]
+    pub listen_addr: Vec<String>,
 
     #[structopt(long)]
     pub public_addr: Option<IpAddr>,
@@ -39,6 +42,8 @@ struct Opt {
     #[structopt(subcommand, name = "auth")]
     pub auth: AuthMode,
 
+    /// Remember the IPs which authenticated with a password, and let them connect
+    /// without authentication afterwards
     #[structopt(long)]
     pub auth_once: bool,
 
@@ -61,148 +66,112 @@ enum AuthMode {
     },
 }
 
-struct ServerState {
-    auth_once_ips: RwLock<HashSet<IpAddr>>,
-}
-
 #[tokio::main]
 async fn main() -> Result<()> {
-    env_logger::init();
+    // prefix the logs of each session with its id
+    let logger = env_logger::Builder::from_default_env().build();
+    log::set_max_level(logger.filter());
+    SessionLogger::new(logger)
+        .install()
+        .expect("no logger installed yet");
     spawn_socks_server().await
 }
 
 async fn spawn_socks_server() -> Result<()> {
     let opt: &'static Opt = Box::leak(Box::new(Opt::from_args()));
 
-    if opt.allow_udp && opt.public_addr.is_none() {
-        return Err(SocksError::ArgumentInputError(
-            "Can't allow UDP if public-addr is not set",
-        ));
-    }
-
-    if opt.skip_auth && opt.auth != AuthMode::NoAuth {
-        return Err(SocksError::ArgumentInputError(
-            "Can't use skip-auth flag and authentication altogether.",
-        ));
-    }
-
-    if opt.auth_once && matches!(opt.auth, AuthMode::NoAuth) {
-        return Err(SocksError::ArgumentInputError(
-            "Can't use auth-once with no-auth mode",
-        ));
-    }
-
-    let state = Arc::new(ServerState {
-        auth_once_ips: RwLock::new(HashSet::new()),
-    });
-
-    let listener = TcpListener::bind(&opt.listen_addr).await?;
-    info!("Listening @ {}", &opt.listen_addr);
+    let mut config_err = ConfigError::new();
+    config_err
+        .check(
+            !opt.allow_udp || opt.public_addr.is_some(),
+            "--allow-udp requires --public-addr",
+        )
+        .check(
+            !opt.skip_auth || opt.auth == AuthMode::NoAuth,
+            "--skip-auth can only be used with no-auth",
+        )
+        .check(
+            !opt.auth_once || opt.auth != AuthMode::NoAuth,
+            "--auth-once requires password authentication",
+        );
+    config_err.into_result()?;
+
+    let auth_once = Arc::new(AuthOnceAcceptor::new());
+
+    #[cfg(feature = "socket-activation")]
+    let inherited = Socks5Listener::from_env()?;
+    #[cfg(not(feature = "socket-activation"))]
+    let inherited = None;
+    let listener = match inherited {
+        Some(listener) => listener,
+        None => Socks5Listener::bind(&opt.listen_addr).await?,
+    };
 
-    loop {
-        match listener.accept().await {
-            Ok((socket, client_addr)) => {
-                let state = state.clone();
-                spawn_and_log_error(serve_socks5(opt, socket, client_addr.ip(), state));
-            }
-            Err(err) => {
-                error!("accept error = {:?}", err);
-            }
-        }
-    }
-}
+    let handler = |socket, client_addr: std::net::SocketAddr| {
+        let auth_once = auth_once.clone();
+        log_error(serve_socks5(opt, socket, client_addr.ip(), auth_once))
+    };
 
-fn select_auth_method(client_methods: &[u8], opt: &Opt, client_ip: IpAddr, ip_whitelisted: bool) -> Option<u8> {
-    let auth_user = !matches!(opt.auth, AuthMode::NoAuth);
-    for &method in client_methods {
-        match method {
-            0x00 => {
-                if !auth_user || (opt.auth_once && ip_whitelisted) {
-                    return Some(0x00);
-                }
-            }
-            0x02 => {
-                if auth_user {
-                    return Some(0x02);
-                }
-            }
-            _ => continue,
-        }
-    }
-    None
+    // SIGHUP forgets the IPs authenticated once, SIGTERM drains the sessions
+    #[cfg(all(unix, feature = "signal"))]
+    fast_socks5::server::run_with_signals(
+        &listener,
+        handler,
+        || async { auth_once.clear() },
+        std::time::Duration::from_secs(30),
+    )
+    .await?;
+    #[cfg(not(all(unix, feature = "signal")))]
+    listener.serve(handler).await;
+    Ok(())
 }
 
 async fn serve_socks5(
     opt: &Opt,
-    mut socket: tokio::net::TcpStream,
+    socket: tokio::net::TcpStream,
     client_ip: IpAddr,
-    state: Arc<ServerState>,
+    auth_once: Arc<AuthOnceAcceptor>,
 ) -> Result<(), SocksError> {
-    let mut buf = [0u8; 2];
-    socket.read_exact(&mut buf).await.map_err(|_| SocksError::ArgumentInputError("Failed to read SOCKS version and methods length"))?;
-    if buf[0] != 0x05 {
-        return Err(SocksError::ArgumentInputError("Invalid SOCKS version"));
-    }
-
-    let nmethods = buf[1] as usize;
-    let mut methods = vec![0u8; nmethods];
-    socket.read_exact(&mut methods).await.map_err(|_| SocksError::ArgumentInputError("Failed to read methods"))?;
-
-    let ip_whitelisted = opt.auth_once && state.auth_once_ips.read().await.contains(&client_ip);
-    let selected = select_auth_method(&methods, opt, client_ip, ip_whitelisted);
-
-    let auth_method = match selected {
-        Some(m) => m,
-        None => {
-            socket.write_all(&[0x05, 0xFF]).await.ok();
-            return Err(SocksError::ArgumentInputError("No acceptable authentication method"));
+    let mut opts = ConnectOptions::new();
+    opts.set_request_timeout(opt.request_timeout);
+    let (proto, cmd, target_addr) = match &opt.auth {
+        AuthMode::NoAuth if opt.skip_auth => {
+            Socks5ServerProtocol::skip_auth_this_is_not_rfc_compliant(socket)
         }
-    };
-
-    socket.write_all(&[0x05, auth_method]).await.map_err(|_| SocksError::ArgumentInputError("Failed to write selected auth method"))?;
-
-    let (proto, cmd, target_addr) = match auth_method {
-        0x00 => {
-            if opt.skip_auth {
-                Socks5ServerProtocol::skip_auth_this_is_not_rfc_compliant(socket)
+        AuthMode::NoAuth => Socks5ServerProtocol::accept_no_auth(socket).await?,
+        AuthMode::Password { username, password } => {
+            let check = |user: String, pass: String| {
+                let res = match (
+                    verify_password(&user, username),
+                    verify_password(&pass, password),
+                ) {
+                    (false, _) => Err(AuthFailure::UnknownUser),
+                    (true, false) => Err(AuthFailure::BadPassword),
+                    (true, true) => Ok(()),
+                };
+                WithReason(res)
+            };
+            if opt.auth_once {
+                auth_once.accept(socket, client_ip, check).await?.0
             } else {
-                Socks5ServerProtocol::accept_no_auth(socket).await?
+                Socks5ServerProtocol::accept_password_auth(socket, check)
+                    .await?
+                    .0
             }
         }
-        0x02 => {
-            if let AuthMode::Password { username, password } = &opt.auth {
-                let (proto, _) = Socks5ServerProtocol::accept_password_auth(socket, {
-                    let username = username.clone();
-                    let password = password.clone();
-                    move |user, pass| user == username && pass == password
-                }).await?;
-
-                if opt.auth_once {
-                    let state = state.clone();
-                    task::spawn(async move {
-                        state.auth_once_ips.write().await.insert(client_ip);
-                    });
-                }
-
-                proto
-            } else {
-                return Err(SocksError::ArgumentInputError("Password auth selected but no credentials provided"));
-            }
-        }
-        _ => return Err(SocksError::ArgumentInputError("Unsupported authentication method")),
     }
     .read_command()
     .await?
-    .resolve_dns()
+    .resolve_dns_with_options(&opts)
     .await?;
 
     match cmd {
         Socks5Command::TCPConnect => {
-            run_tcp_proxy(proto, &target_addr, opt.request_timeout, false).await?;
+            run_tcp_proxy_with_options(proto, &target_addr, &opts).await?;
         }
         Socks5Command::UDPAssociate if opt.allow_udp => {
             let reply_ip = opt.public_addr.context("invalid reply ip")?;
-            run_udp_proxy(proto, &target_addr, None, reply_ip, None).await?;
+            run_udp_proxy_with_options(proto, &target_addr, UdpProxyOptions::new(reply_ip)).await?;
         }
         _ => {
             proto.reply_error(&ReplyError::CommandNotSupported).await?;
@@ -213,13 +182,11 @@ async fn serve_socks5(
     Ok(())
 }
 
-fn spawn_and_log_error<F>(fut: F) -> task::JoinHandle<()>
+async fn log_error<F>(fut: F)
 where
-    F: Future<Output = Result<()>> + Send + 'static,
+    F: Future<Output = Result<()>>,
 {
-    task::spawn(async move {
-        if let Err(e) = fut.await {
-            error!("{:#}", e);
-        }
-    })
+    if let Err(e) = fut.await {
+        error!("{:#}", e);
+    }
 }
@@ -1,28 +1,77 @@
 use crate::read_exact;
+use crate::ready;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::util::proxy_url::ProxyUrl;
+#[cfg(not(target_arch = "wasm32"))]
 use crate::util::stream::{tcp_connect, tcp_connect_with_timeout};
 use crate::util::target_addr::{read_address, TargetAddr, ToTargetAddr};
 use crate::{
-    consts, new_udp_header, parse_udp_request, AuthenticationMethod, ReplyError, Result,
-    Socks5Command, SocksError,
+    consts, AuthenticationMethod, ConfigError, ReplyError, Result, Socks5Command, SocksError,
 };
+#[cfg(not(target_arch = "wasm32"))]
+use crate::{new_udp_header, parse_udp_request};
 use anyhow::Context;
+#[cfg(not(target_arch = "wasm32"))]
+use socket2::{SockRef, TcpKeepalive};
+#[cfg(not(target_arch = "wasm32"))]
+use std::any::Any;
 use std::io;
+#[cfg(not(target_arch = "wasm32"))]
+use std::net::IpAddr;
 use std::net::SocketAddr;
+#[cfg(not(target_arch = "wasm32"))]
 use std::net::ToSocketAddrs;
 use std::pin::Pin;
+#[cfg(not(target_arch = "wasm32"))]
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(not(target_arch = "wasm32"))]
+use std::sync::Arc;
 use std::task::Poll;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use std::time::Duration;
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf,
+    WriteHalf,
+};
+#[cfg(not(target_arch = "wasm32"))]
 use tokio::net::{TcpStream, UdpSocket};
+#[cfg(not(target_arch = "wasm32"))]
+use tokio::task::JoinHandle;
+
+mod credentials;
+#[cfg(not(target_arch = "wasm32"))]
+mod env_proxy;
+#[cfg(feature = "pac")]
+mod pac;
+#[cfg(not(target_arch = "wasm32"))]
+mod proxy_selector;
+#[cfg(feature = "futures-io")]
+mod futures_io;
+
+pub use credentials::{CredentialsProvider, EnvCredentials, StaticCredentials};
+#[cfg(not(target_arch = "wasm32"))]
+pub use env_proxy::EnvProxy;
+#[cfg(feature = "pac")]
+pub use pac::{parse_pac_result, PacDirective, PacEvaluator};
+#[cfg(not(target_arch = "wasm32"))]
+pub use proxy_selector::{MaybeProxied, ProxySelector};
+#[cfg(feature = "futures-io")]
+pub use futures_io::FuturesIo;
 
 const MAX_ADDR_LEN: usize = 260;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Config {
     /// Timeout of the socket connect
     connect_timeout: Option<u64>,
     /// Avoid useless roundtrips if we don't need the Authentication layer
     /// make sure to also activate it on the server side.
     skip_auth: bool,
+    /// Use the IP of the proxy when it replies to UDP ASSOCIATE with an unspecified address.
+    substitute_unspecified_relay: bool,
+    /// Idle time before TCP keepalive probes are sent on the connection to the proxy.
+    tcp_keepalive: Option<Duration>,
+    /// Send the targets given as IP literals as domains.
+    ip_literals_as_domain: bool,
 }
 
 impl Default for Config {
@@ -30,6 +79,9 @@ impl Default for Config {
         Config {
             connect_timeout: None,
             skip_auth: false,
+            substitute_unspecified_relay: true,
+            tcp_keepalive: None,
+            ip_literals_as_domain: false,
         }
     }
 }
@@ -41,21 +93,101 @@ impl Config {
         self
     }
 
+    /// Don't negotiate an auth method, send the request right away, for servers speaking
+    /// this non-RFC dialect, such as `Socks5ServerProtocol::skip_auth_this_is_not_rfc_compliant`
+    /// or minimal embedded endpoints. Credentials can't be used then.
     pub fn set_skip_auth(&mut self, value: bool) -> &mut Self {
         self.skip_auth = value;
         self
     }
+
+    /// Many servers reply to UDP ASSOCIATE with `0.0.0.0` (or `::`) as BND.ADDR, meaning
+    /// "the address you reached me on". When enabled, which is the default, the IP of
+    /// the TCP connection to the proxy is used instead, if it is known.
+    pub fn set_substitute_unspecified_relay(&mut self, value: bool) -> &mut Self {
+        self.substitute_unspecified_relay = value;
+        self
+    }
+
+    /// Enable TCP keepalive on the connection to the proxy, probing after `idle` without traffic.
+    ///
+    /// The control connection of a UDP association carries no data, so NATs and
+    /// firewalls tend to silently drop it, and the association with it.
+    pub fn set_tcp_keepalive(&mut self, idle: Duration) -> &mut Self {
+        self.tcp_keepalive = Some(idle);
+        self
+    }
+
+    /// Send the target hostnames that are IP literals, e.g. `10.0.0.1` or `[::1]`, as
+    /// DOMAIN addresses instead of IPv4 and IPv6 ones, to test how servers handle them.
+    ///
+    /// Off by default: IP literals are always sent as IPs, never resolved nor sent as
+    /// domains.
+    pub fn set_ip_literals_as_domain(&mut self, value: bool) -> &mut Self {
+        self.ip_literals_as_domain = value;
+        self
+    }
+
+    /// Check the settings, reporting every issue found.
+    pub fn validate(&self) -> std::result::Result<(), ConfigError> {
+        let mut err = ConfigError::new();
+        err.check(
+            self.connect_timeout != Some(0),
+            "connect timeout must be at least 1s",
+        )
+        .check(
+            self.tcp_keepalive != Some(Duration::ZERO),
+            "TCP keepalive idle time must not be zero",
+        );
+        err.into_result()
+    }
+
+    /// Like `validate`, also checking that `auth` can be used with these settings.
+    pub fn validate_with_auth(
+        &self,
+        auth: Option<&AuthenticationMethod>,
+    ) -> std::result::Result<(), ConfigError> {
+        let mut err = ConfigError::new();
+        err.merge(self.validate()).check(
+            !(self.skip_auth && auth.is_some()),
+            "credentials can't be sent when skipping the auth negotiation",
+        );
+        err.into_result()
+    }
 }
 
 /// A SOCKS5 client.
 /// `Socks5Stream` implements [`AsyncRead`] and [`AsyncWrite`].
+///
+/// Shutting it down (`AsyncWriteExt::shutdown`) only closes the write side: the proxy
+/// forwards the FIN to the target, and the responses can still be read until EOF.
 #[derive(Debug)]
 pub struct Socks5Stream<S: AsyncRead + AsyncWrite + Unpin> {
     socket: S,
     target_addr: Option<TargetAddr>,
     config: Config,
+    /// The auth method chosen by the server, `None` when skipped.
+    auth_method: Option<u8>,
+    /// BND.ADDR of the last reply.
+    bind_addr: Option<TargetAddr>,
+    /// A request was cancelled or failed midway, the stream is out of sync with the proxy.
+    desynced: bool,
+    /// Read ahead by `peek` or `AsyncBufRead`, `read_buf[read_pos..read_end]` isn't consumed yet.
+    read_buf: Vec<u8>,
+    read_pos: usize,
+    read_end: usize,
+}
+
+fn check_synced(desynced: bool) -> io::Result<()> {
+    match desynced {
+        true => Err(io::Error::other(SocksError::Desynced)),
+        false => Ok(()),
+    }
 }
 
+/// The read-ahead buffer of `Socks5Stream`, allocated on the first `peek` or `fill_buf`.
+const READ_BUF_LEN: usize = 8 * 1024;
+
 impl<S> Socks5Stream<S>
 where
     S: AsyncRead + AsyncWrite + Unpin,
@@ -67,10 +199,17 @@ where
         auth: Option<AuthenticationMethod>,
         config: Config,
     ) -> Result<Self> {
+        config.validate_with_auth(auth.as_ref())?;
         let mut stream = Socks5Stream {
             socket,
             config,
             target_addr: None,
+            auth_method: None,
+            bind_addr: None,
+            desynced: false,
+            read_buf: Vec::new(),
+            read_pos: 0,
+            read_end: 0,
         };
 
         // Auth none is always used by default.
@@ -92,21 +231,59 @@ where
         Ok(stream)
     }
 
+    /// Send a request, returning the BND.ADDR of the reply.
+    ///
+    /// Servers hiding their topology reply with an unspecified or dummy address, which is
+    /// accepted like any other.
+    ///
+    /// # Cancel safety
+    ///
+    /// This method isn't cancel safe: if its future is dropped before completion, or it
+    /// fails, the request may be half sent or the reply half read, and the stream can't be
+    /// used anymore. Later requests, reads and writes fail instead of going out of sync.
     pub async fn request(
         &mut self,
         cmd: Socks5Command,
         target_addr: TargetAddr,
     ) -> Result<TargetAddr> {
-        self.target_addr = Some(target_addr);
+        if self.desynced {
+            return Err(SocksError::Desynced);
+        }
+        self.target_addr = Some(match target_addr {
+            // an IP literal given as a domain goes out as an IP
+            TargetAddr::Domain(domain, port) if !self.config.ip_literals_as_domain => {
+                (domain.as_str(), port).to_target_addr()?
+            }
+            target_addr => target_addr,
+        });
 
         // Request Lifecycle
         info!("Requesting headers `{:?}`...", &self.target_addr);
+        self.desynced = true;
         self.request_header(cmd).await?;
         let bind_addr = self.read_request_reply().await?;
+        self.bind_addr = Some(bind_addr.clone());
+        self.desynced = false;
 
         Ok(bind_addr)
     }
 
+    /// The id of the auth method the server chose, e.g. `consts::SOCKS5_AUTH_METHOD_PASSWORD`,
+    /// or `None` if the negotiation was skipped.
+    pub fn auth_method(&self) -> Option<u8> {
+        self.auth_method
+    }
+
+    /// The BND.ADDR the server replied with to the last request.
+    pub fn bind_addr(&self) -> Option<&TargetAddr> {
+        self.bind_addr.as_ref()
+    }
+
+    /// The target of the last request.
+    pub fn target_addr(&self) -> Option<&TargetAddr> {
+        self.target_addr.as_ref()
+    }
+
     /// Decide to whether or not, accept the authentication method
     /// A client send a list of methods that he supports, he could send
     ///
@@ -180,6 +357,9 @@ where
             return Err(SocksError::UnsupportedSocksVersion(version));
         }
 
+        if method != consts::SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE {
+            self.auth_method = Some(method);
+        }
         match method {
             consts::SOCKS5_AUTH_METHOD_NONE => info!("No auth will be used"),
             consts::SOCKS5_AUTH_METHOD_PASSWORD => self.use_password_auth(methods).await?,
@@ -214,9 +394,9 @@ where
         }?;
 
         let user_bytes = username.as_bytes();
-        let pass_bytes = password.as_bytes();
+        let pass_bytes = password.expose().as_bytes();
 
-        let mut packet: Vec<u8> = vec![1, user_bytes.len() as u8];
+        let mut packet: Vec<u8> = vec![consts::SOCKS5_PASSWORD_AUTH_VERSION, user_bytes.len() as u8];
         packet.extend(user_bytes);
         packet.push(pass_bytes.len() as u8);
         packet.extend(pass_bytes);
@@ -236,10 +416,9 @@ where
         );
 
         if is_success != consts::SOCKS5_REPLY_SUCCEEDED {
-            return Err(SocksError::AuthenticationRejected(format!(
-                "Authentication with username `{}`, rejected.",
-                username
-            )));
+            return Err(SocksError::AuthenticationRejected(
+                "Authentication with the username and password rejected.".to_owned(),
+            ));
         }
 
         Ok(())
@@ -269,7 +448,7 @@ where
         let mut packet = [0u8; MAX_ADDR_LEN + 3];
         let padding; // maximum len of the headers sent
                      // build our request packet with (socks version, Command, reserved)
-        packet[..3].copy_from_slice(&[consts::SOCKS5_VERSION, cmd.as_u8(), 0x00]);
+        packet[..3].copy_from_slice(&[consts::SOCKS5_VERSION, cmd.as_u8(), consts::SOCKS5_RESERVED]);
 
         match self.target_addr.as_ref() {
             None => {
@@ -277,7 +456,7 @@ where
                     debug!("UDPAssociate without target_addr, fallback to zeros.");
                     padding = 10;
 
-                    packet[3] = 0x01;
+                    packet[3] = consts::SOCKS5_ADDR_TYPE_IPV4;
                     packet[4..8].copy_from_slice(&[0, 0, 0, 0]); // ip
                     packet[8..padding].copy_from_slice(&[0, 0]); // port
                 } else {
@@ -289,7 +468,7 @@ where
                     debug!("TargetAddr::IpV4");
                     padding = 10;
 
-                    packet[3] = 0x01;
+                    packet[3] = consts::SOCKS5_ADDR_TYPE_IPV4;
                     debug!("addr ip {:?}", (*addr.ip()).octets());
                     packet[4..8].copy_from_slice(&(addr.ip()).octets()); // ip
                     packet[8..padding].copy_from_slice(&addr.port().to_be_bytes());
@@ -299,7 +478,7 @@ where
                     debug!("TargetAddr::IpV6");
                     padding = 22;
 
-                    packet[3] = 0x04;
+                    packet[3] = consts::SOCKS5_ADDR_TYPE_IPV6;
                     debug!("addr ip {:?}", (*addr.ip()).octets());
                     packet[4..20].copy_from_slice(&(addr.ip()).octets()); // ip
                     packet[20..padding].copy_from_slice(&addr.port().to_be_bytes());
@@ -368,29 +547,87 @@ where
         Ok(address)
     }
 
+    /// Read the next bytes from the proxy without consuming them, like
+    /// `TcpStream::peek`, e.g. to detect the protocol spoken by the target. Returns 0 at
+    /// the end of the stream.
+    ///
+    /// Returns the bytes already read ahead if any, without waiting for more.
+    pub async fn peek(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf().await?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        Ok(n)
+    }
+
+    /// The bytes read ahead by `peek` or `AsyncBufRead` and not consumed yet, which
+    /// `into_inner` leaves behind.
+    pub fn buffer(&self) -> &[u8] {
+        &self.read_buf[self.read_pos..self.read_end]
+    }
+
+    /// Take the stream to the proxy, e.g. to wrap it with TLS after the handshake.
+    ///
+    /// The bytes read ahead, if any, must be taken with `buffer` before.
+    pub fn into_inner(self) -> S {
+        self.socket
+    }
+
+    /// Borrow the stream to the proxy, e.g. to set socket options.
+    pub fn get_ref(&self) -> &S {
+        &self.socket
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.socket
+    }
+
+    /// Split into owned read and write halves, which can be moved to different tasks.
+    ///
+    /// The halves share the stream behind a lock, through `tokio::io::split`. A
+    /// `TcpStream` can be split without it, with `into_inner().into_split()`.
+    pub fn into_split(self) -> (ReadHalf<Self>, WriteHalf<Self>) {
+        tokio::io::split(self)
+    }
+
+    /// Same as [`Socks5Stream::into_inner`].
     pub fn get_socket(self) -> S {
         self.socket
     }
 
+    /// Same as [`Socks5Stream::get_ref`].
     pub fn get_socket_ref(&self) -> &S {
         &self.socket
     }
 
+    /// Same as [`Socks5Stream::get_mut`].
     pub fn get_socket_mut(&mut self) -> &mut S {
         &mut self.socket
     }
 }
 
 /// A SOCKS5 UDP client.
+#[cfg(not(target_arch = "wasm32"))]
 #[derive(Debug)]
 pub struct Socks5Datagram<S: AsyncRead + AsyncWrite + Unpin> {
     socket: UdpSocket,
-    // keeps the session alive
-    #[allow(dead_code)]
-    stream: Socks5Stream<S>,
+    // keeps the session alive, until it's moved to the watcher task by `watch_control`
+    stream: Option<Socks5Stream<S>>,
+    control_watcher: Option<JoinHandle<()>>,
+    control_alive: Arc<AtomicBool>,
     proxy_addr: Option<TargetAddr>,
+    relay_addr: SocketAddr,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl<S: AsyncRead + AsyncWrite + Unpin> Drop for Socks5Datagram<S> {
+    fn drop(&mut self) {
+        if let Some(watcher) = self.control_watcher.take() {
+            watcher.abort();
+        }
+    }
 }
 
+#[cfg(not(target_arch = "wasm32"))]
 impl<S: AsyncRead + AsyncWrite + Unpin> Socks5Datagram<S> {
     /// Creates a UDP socket bound to the specified address which will have its
     /// traffic routed through the specified proxy.
@@ -413,9 +650,11 @@ impl<S: AsyncRead + AsyncWrite + Unpin> Socks5Datagram<S> {
     /// ```
     pub async fn bind<U>(backing_socket: S, client_bind_addr: U) -> Result<Socks5Datagram<S>>
     where
+        S: 'static,
         U: ToSocketAddrs,
     {
-        Self::bind_internal(backing_socket, Self::create_out_sock(client_bind_addr).await?, None).await
+        let proxy_ip = proxy_ip(&backing_socket);
+        Self::bind_internal(backing_socket, Self::create_out_sock(client_bind_addr).await?, None, proxy_ip, Config::default()).await
     }
     /// Creates a UDP socket bound to the specified address which will have its
     /// traffic routed through the specified proxy. The given username and password
@@ -427,20 +666,26 @@ impl<S: AsyncRead + AsyncWrite + Unpin> Socks5Datagram<S> {
         password: &str,
     ) -> Result<Socks5Datagram<S>>
     where
+        S: 'static,
         U: ToSocketAddrs,
     {
         let auth = AuthenticationMethod::Password {
             username: username.to_owned(),
-            password: password.to_owned(),
+            password: password.to_owned().into(),
         };
-        Self::bind_internal(backing_socket, Self::create_out_sock(client_bind_addr).await?, Some(auth)).await
+        let proxy_ip = proxy_ip(&backing_socket);
+        Self::bind_internal(backing_socket, Self::create_out_sock(client_bind_addr).await?, Some(auth), proxy_ip, Config::default()).await
     }
     /// Use a UdpSocket already created rather than creating a whole new `UdpSocket::bind`.
     pub async fn use_socket(
         backing_socket: S,
         out_sock: UdpSocket,
-    ) -> Result<Socks5Datagram<S>> {
-        Self::bind_internal(backing_socket, out_sock, None).await
+    ) -> Result<Socks5Datagram<S>>
+    where
+        S: 'static,
+    {
+        let proxy_ip = proxy_ip(&backing_socket);
+        Self::bind_internal(backing_socket, out_sock, None, proxy_ip, Config::default()).await
     }
     /// Same as `use_socket` but with credentials.
     pub async fn use_socket_with_password(
@@ -448,12 +693,16 @@ impl<S: AsyncRead + AsyncWrite + Unpin> Socks5Datagram<S> {
         out_sock: UdpSocket,
         username: &str,
         password: &str,
-    ) -> Result<Socks5Datagram<S>> {
+    ) -> Result<Socks5Datagram<S>>
+    where
+        S: 'static,
+    {
         let auth = AuthenticationMethod::Password {
             username: username.to_owned(),
-            password: password.to_owned(),
+            password: password.to_owned().into(),
         };
-        Self::bind_internal(backing_socket, out_sock, Some(auth)).await
+        let proxy_ip = proxy_ip(&backing_socket);
+        Self::bind_internal(backing_socket, out_sock, Some(auth), proxy_ip, Config::default()).await
     }
 
     async fn create_out_sock<U: ToSocketAddrs>(client_bind_addr: U) -> Result<UdpSocket> {
@@ -470,11 +719,14 @@ impl<S: AsyncRead + AsyncWrite + Unpin> Socks5Datagram<S> {
         backing_socket: S,
         out_sock: UdpSocket,
         auth: Option<AuthenticationMethod>,
+        proxy_ip: Option<IpAddr>,
+        config: Config,
     ) -> Result<Socks5Datagram<S>>
     {
+        let substitute_unspecified = config.substitute_unspecified_relay;
         // Init socks5 stream.
         let mut proxy_stream =
-            Socks5Stream::use_stream(backing_socket, auth, Config::default()).await?;
+            Socks5Stream::use_stream(backing_socket, auth, config).await?;
 
         // we don't know what our IP is from the perspective of the proxy, so
         // don't try to pass `addr` in here.
@@ -483,18 +735,30 @@ impl<S: AsyncRead + AsyncWrite + Unpin> Socks5Datagram<S> {
             .request(Socks5Command::UDPAssociate, client_src)
             .await?;
 
-        let proxy_addr_resolved = proxy_addr
+        let mut relay_addr = proxy_addr
             .to_socket_addrs()?
             .next()
             .context("unreachable")?;
-        info!("UdpSocket client connecting to {}", proxy_addr_resolved);
-        out_sock.connect(proxy_addr_resolved).await?;
+        if relay_addr.ip().is_unspecified() && substitute_unspecified {
+            match proxy_ip {
+                Some(proxy_ip) => {
+                    debug!("Proxy replied with {}, using its IP {} instead", relay_addr, proxy_ip);
+                    relay_addr.set_ip(proxy_ip);
+                }
+                None => warn!("Proxy replied with {}, but its IP is unknown", relay_addr),
+            }
+        }
+        info!("UdpSocket client connecting to {}", relay_addr);
+        out_sock.connect(relay_addr).await?;
         info!("UdpSocket client connected");
 
         Ok(Socks5Datagram {
             socket: out_sock,
-            stream: proxy_stream,
+            stream: Some(proxy_stream),
+            control_watcher: None,
+            control_alive: Arc::new(AtomicBool::new(true)),
             proxy_addr: Some(proxy_addr),
+            relay_addr,
         })
     }
 
@@ -509,6 +773,11 @@ impl<S: AsyncRead + AsyncWrite + Unpin> Socks5Datagram<S> {
     where
         A: ToTargetAddr,
     {
+        if !self.is_control_alive() {
+            return Err(SocksError::Other(anyhow::anyhow!(
+                "The control connection of the UDP association is closed."
+            )));
+        }
         let mut buf = new_udp_header(addr)?;
         let buf_len = buf.len();
         buf.extend_from_slice(data);
@@ -542,6 +811,51 @@ impl<S: AsyncRead + AsyncWrite + Unpin> Socks5Datagram<S> {
             .context("proxy addr is not ready")?)
     }
 
+    /// Watch the control connection of the association in a background task, calling
+    /// `on_drop` once the proxy closes it, since the proxy then ends the association.
+    ///
+    /// The watcher task is stopped when this `Socks5Datagram` is dropped.
+    pub fn watch_control<F>(&mut self, on_drop: F)
+    where
+        S: Send + 'static,
+        F: FnOnce() + Send + 'static,
+    {
+        let Some(mut stream) = self.stream.take() else {
+            warn!("The control connection is already watched");
+            return;
+        };
+        let alive = self.control_alive.clone();
+        self.control_watcher = Some(tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            loop {
+                match stream.read(&mut buf).await {
+                    Ok(0) => break,
+                    Ok(n) => debug!("Ignoring {} unexpected bytes on the control connection", n),
+                    Err(err) => {
+                        debug!("Control connection error: {}", err);
+                        break;
+                    }
+                }
+            }
+            info!("The control connection of the UDP association was closed");
+            alive.store(false, Ordering::Relaxed);
+            on_drop();
+        }));
+    }
+
+    /// Whether the control connection of the association is still open, as far as known.
+    ///
+    /// This can only turn to `false` once `watch_control` has been called.
+    pub fn is_control_alive(&self) -> bool {
+        self.control_alive.load(Ordering::Relaxed)
+    }
+
+    /// Returns the address datagrams are actually sent to, which is `proxy_addr` resolved,
+    /// with the IP of the proxy substituted for an unspecified one.
+    pub fn relay_addr(&self) -> SocketAddr {
+        self.relay_addr
+    }
+
     /// Returns a shared reference to the inner socket.
     pub fn get_ref(&self) -> &UdpSocket {
         &self.socket
@@ -553,7 +867,72 @@ impl<S: AsyncRead + AsyncWrite + Unpin> Socks5Datagram<S> {
     }
 }
 
+/// The IP of the proxy `backing_socket` is connected to, known for a `TcpStream`.
+#[cfg(not(target_arch = "wasm32"))]
+fn proxy_ip<S: 'static>(backing_socket: &S) -> Option<IpAddr> {
+    let socket = (backing_socket as &dyn Any).downcast_ref::<TcpStream>()?;
+    socket.peer_addr().ok().map(|addr| addr.ip())
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn set_tcp_keepalive(socket: &TcpStream, config: &Config) -> io::Result<()> {
+    if let Some(idle) = config.tcp_keepalive {
+        SockRef::from(socket).set_tcp_keepalive(&TcpKeepalive::new().with_time(idle))?;
+    }
+    Ok(())
+}
+
+/// Api if you want to use TcpStream to create a new UDP association with the SOCKS5 server.
+#[cfg(not(target_arch = "wasm32"))]
+impl Socks5Datagram<TcpStream> {
+    /// Connects to the SOCKS5 proxy and associates a UDP socket bound to `client_bind_addr`,
+    /// e.g. to use a specific source port allowed by a firewall.
+    pub async fn connect_udp_bind<T, U>(
+        socks_server: T,
+        client_bind_addr: U,
+        auth: Option<AuthenticationMethod>,
+        config: Config,
+    ) -> Result<Self>
+    where
+        T: ToSocketAddrs,
+        U: ToSocketAddrs,
+    {
+        let out_sock = Self::create_out_sock(client_bind_addr).await?;
+        Self::connect_udp(socks_server, out_sock, auth, config).await
+    }
+
+    /// Connects to the SOCKS5 proxy and associates the given, already bound, UDP socket.
+    ///
+    /// The socket is connected to the relay address of the proxy, so it should not be
+    /// connected to anything beforehand.
+    pub async fn connect_udp<T>(
+        socks_server: T,
+        out_sock: UdpSocket,
+        auth: Option<AuthenticationMethod>,
+        config: Config,
+    ) -> Result<Self>
+    where
+        T: ToSocketAddrs,
+    {
+        config.validate_with_auth(auth.as_ref())?;
+        let addr = socks_server
+            .to_socket_addrs()?
+            .next()
+            .context("unreachable")?;
+        let socket = match config.connect_timeout {
+            None => tcp_connect(addr).await?,
+            Some(connect_timeout) => tcp_connect_with_timeout(addr, connect_timeout).await?,
+        };
+        info!("Connected @ {}", &socket.peer_addr()?);
+        set_tcp_keepalive(&socket, &config)?;
+
+        let proxy_ip = socket.peer_addr()?.ip();
+        Self::bind_internal(socket, out_sock, auth, Some(proxy_ip), config).await
+    }
+}
+
 /// Api if you want to use TcpStream to create a new connection to the SOCKS5 server.
+#[cfg(not(target_arch = "wasm32"))]
 impl Socks5Stream<TcpStream> {
     /// Connects to a target server through a SOCKS5 proxy.
     pub async fn connect<T>(
@@ -588,7 +967,10 @@ impl Socks5Stream<TcpStream> {
     where
         T: ToSocketAddrs,
     {
-        let auth = AuthenticationMethod::Password { username, password };
+        let auth = AuthenticationMethod::Password {
+            username,
+            password: password.into(),
+        };
 
         Self::connect_raw(
             Socks5Command::TCPConnect,
@@ -601,6 +983,73 @@ impl Socks5Stream<TcpStream> {
         .await
     }
 
+    /// Connect with the credentials currently supplied by `provider`, which is asked again
+    /// for every connection.
+    pub async fn connect_with_credentials<T, P>(
+        socks_server: T,
+        target_addr: String,
+        target_port: u16,
+        provider: &P,
+        config: Config,
+    ) -> Result<Self>
+    where
+        T: ToSocketAddrs,
+        P: CredentialsProvider + ?Sized,
+    {
+        let auth = provider.authentication().await?;
+
+        Self::connect_raw(
+            Socks5Command::TCPConnect,
+            socks_server,
+            target_addr,
+            target_port,
+            auth,
+            config,
+        )
+        .await
+    }
+
+    /// Connects to a target server through the proxy at `proxy`, resolving the target
+    /// locally first unless [`ProxyUrl::remote_dns`].
+    pub async fn connect_with_url(
+        proxy: &ProxyUrl,
+        target_addr: String,
+        target_port: u16,
+        config: Config,
+    ) -> Result<Self> {
+        let (target_addr, target_port) = match proxy.remote_dns() {
+            true => (target_addr, target_port),
+            false => TargetAddr::Domain(target_addr, target_port)
+                .resolve_dns()
+                .await?
+                .into_string_and_port(),
+        };
+        Self::connect_raw(
+            Socks5Command::TCPConnect,
+            (proxy.host(), proxy.port()),
+            target_addr,
+            target_port,
+            proxy.authentication(),
+            config,
+        )
+        .await
+    }
+
+    /// Connects to a target server through the proxy set in the environment, see
+    /// [`EnvProxy`] for the variables.
+    ///
+    /// Returns `None` when no proxy is set or the target is excluded by `NO_PROXY`, the
+    /// target should then be reached directly.
+    pub async fn connect_from_env(
+        target_addr: String,
+        target_port: u16,
+        config: Config,
+    ) -> Result<Option<Self>> {
+        EnvProxy::from_env()?
+            .connect(target_addr, target_port, config)
+            .await
+    }
+
     /// Process clients SOCKS requests
     /// This is the entry point where a whole request is processed.
     pub async fn connect_raw<T>(
@@ -614,6 +1063,7 @@ impl Socks5Stream<TcpStream> {
     where
         T: ToSocketAddrs,
     {
+        config.validate_with_auth(auth.as_ref())?;
         let addr = socks_server
             .to_socket_addrs()?
             .next()
@@ -623,11 +1073,15 @@ impl Socks5Stream<TcpStream> {
             Some(connect_timeout) => tcp_connect_with_timeout(addr, connect_timeout).await?,
         };
         info!("Connected @ {}", &socket.peer_addr()?);
+        set_tcp_keepalive(&socket, &config)?;
 
         // Specify the target, here domain name, dns will be resolved on the server side
-        let target_addr = (target_addr.as_str(), target_port)
-            .to_target_addr()
-            .context("Can't convert address to TargetAddr format")?;
+        let target_addr = match config.ip_literals_as_domain {
+            true => TargetAddr::Domain(target_addr, target_port),
+            false => (target_addr.as_str(), target_port)
+                .to_target_addr()
+                .context("Can't convert address to TargetAddr format")?,
+        };
 
         // upgrade the TcpStream to Socks5Stream
         let mut socks_stream = Self::use_stream(socket, auth, config).await?;
@@ -647,10 +1101,42 @@ where
         context: &mut std::task::Context,
         buf: &mut tokio::io::ReadBuf<'_>,
     ) -> Poll<std::io::Result<()>> {
+        check_synced(self.desynced)?;
+        if self.read_pos < self.read_end {
+            let n = buf.remaining().min(self.read_end - self.read_pos);
+            buf.put_slice(&self.read_buf[self.read_pos..self.read_pos + n]);
+            self.read_pos += n;
+            return Poll::Ready(Ok(()));
+        }
         Pin::new(&mut self.socket).poll_read(context, buf)
     }
 }
 
+impl<S> AsyncBufRead for Socks5Stream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_fill_buf(
+        self: Pin<&mut Self>,
+        context: &mut std::task::Context,
+    ) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        check_synced(this.desynced)?;
+        if this.read_pos >= this.read_end {
+            this.read_buf.resize(READ_BUF_LEN, 0);
+            let mut buf = tokio::io::ReadBuf::new(&mut this.read_buf);
+            ready!(Pin::new(&mut this.socket).poll_read(context, &mut buf))?;
+            this.read_pos = 0;
+            this.read_end = buf.filled().len();
+        }
+        Poll::Ready(Ok(&this.read_buf[this.read_pos..this.read_end]))
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amt: usize) {
+        self.read_pos = (self.read_pos + amt).min(self.read_end);
+    }
+}
+
 /// Allow us to write directly into the struct
 impl<S> AsyncWrite for Socks5Stream<S>
 where
@@ -661,6 +1147,7 @@ where
         context: &mut std::task::Context,
         buf: &[u8],
     ) -> Poll<io::Result<usize>> {
+        check_synced(self.desynced)?;
         Pin::new(&mut self.socket).poll_write(context, buf)
     }
 
@@ -0,0 +1,122 @@
+use crate::util::secret::Secret;
+use crate::AuthenticationMethod;
+use std::future::Future;
+use std::io;
+
+/// Supplies the username and password for each new connection to the proxy, so that
+/// long-running clients pick up rotated credentials without any change on their side.
+///
+/// Implemented by [`StaticCredentials`], [`EnvCredentials`], and by async closures
+/// returning `io::Result<Option<(String, String)>>`, e.g. to fetch them from a vault.
+#[async_trait::async_trait]
+pub trait CredentialsProvider: Send + Sync {
+    /// The current credentials, or `None` to connect without authentication.
+    async fn credentials(&self) -> io::Result<Option<(String, String)>>;
+
+    /// The authentication method to offer to the proxy.
+    async fn authentication(&self) -> io::Result<Option<AuthenticationMethod>> {
+        Ok(self
+            .credentials()
+            .await?
+            .map(|(username, password)| AuthenticationMethod::Password {
+                username,
+                password: password.into(),
+            }))
+    }
+}
+
+#[async_trait::async_trait]
+impl<F, R> CredentialsProvider for F
+where
+    F: Fn() -> R + Send + Sync,
+    R: Future<Output = io::Result<Option<(String, String)>>> + Send,
+{
+    async fn credentials(&self) -> io::Result<Option<(String, String)>> {
+        self().await
+    }
+}
+
+/// Credentials which never change.
+#[derive(Debug, Clone)]
+pub struct StaticCredentials {
+    pub username: String,
+    pub password: Secret<String>,
+}
+
+#[async_trait::async_trait]
+impl CredentialsProvider for StaticCredentials {
+    async fn credentials(&self) -> io::Result<Option<(String, String)>> {
+        Ok(Some((
+            self.username.clone(),
+            self.password.expose().clone(),
+        )))
+    }
+}
+
+/// Credentials read from environment variables on each connection, `SOCKS5_USERNAME`
+/// and `SOCKS5_PASSWORD` by default.
+///
+/// Connects without authentication if the username variable isn't set.
+#[derive(Debug, Clone)]
+pub struct EnvCredentials {
+    username_var: String,
+    password_var: String,
+}
+
+impl Default for EnvCredentials {
+    fn default() -> Self {
+        EnvCredentials::new("SOCKS5_USERNAME", "SOCKS5_PASSWORD")
+    }
+}
+
+impl EnvCredentials {
+    pub fn new(username_var: impl Into<String>, password_var: impl Into<String>) -> Self {
+        EnvCredentials {
+            username_var: username_var.into(),
+            password_var: password_var.into(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl CredentialsProvider for EnvCredentials {
+    async fn credentials(&self) -> io::Result<Option<(String, String)>> {
+        let Ok(username) = std::env::var(&self.username_var) else {
+            return Ok(None);
+        };
+        let password = std::env::var(&self.password_var).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} is set but not {}", self.username_var, self.password_var),
+            )
+        })?;
+        Ok(Some((username, password)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CredentialsProvider, EnvCredentials};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn env_and_callback_credentials() {
+        let env = EnvCredentials::new("FAST_SOCKS5_TEST_USER", "FAST_SOCKS5_TEST_PASS");
+        assert!(env.credentials().await.unwrap().is_none());
+        std::env::set_var("FAST_SOCKS5_TEST_USER", "alice");
+        assert!(env.credentials().await.is_err());
+        std::env::set_var("FAST_SOCKS5_TEST_PASS", "secret");
+        assert_eq!(
+            env.credentials().await.unwrap(),
+            Some(("alice".to_owned(), "secret".to_owned()))
+        );
+
+        let rotation = AtomicUsize::new(0);
+        let vault = || async {
+            let n = rotation.fetch_add(1, Ordering::Relaxed);
+            Ok(Some(("bob".to_owned(), format!("token-{}", n))))
+        };
+        assert_eq!(vault.credentials().await.unwrap().unwrap().1, "token-0");
+        assert_eq!(vault.credentials().await.unwrap().unwrap().1, "token-1");
+    }
+}
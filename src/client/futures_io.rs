@@ -0,0 +1,166 @@
+use crate::ready;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Adapts a stream implementing the `futures-io` traits, e.g. from a WebSocket or a WASI
+/// runtime, so that [`Socks5Stream::use_stream`] can run the handshake on it without the
+/// crate creating any socket.
+///
+/// The adapter also implements the `futures-io` traits, to keep using the stream the same
+/// way after the handshake.
+///
+/// On `wasm32`, the crate builds without the server, the socket options and the
+/// constructors opening sockets, such as `Socks5Stream::connect`.
+///
+/// [`Socks5Stream::use_stream`]: super::Socks5Stream::use_stream
+#[derive(Debug)]
+pub struct FuturesIo<S> {
+    inner: S,
+}
+
+impl<S> FuturesIo<S> {
+    pub fn new(inner: S) -> Self {
+        FuturesIo { inner }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: futures_io::AsyncRead + Unpin> AsyncRead for FuturesIo<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let n = ready!(Pin::new(&mut self.inner).poll_read(cx, buf.initialize_unfilled()))?;
+        buf.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: futures_io::AsyncWrite + Unpin> AsyncWrite for FuturesIo<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+impl<S: futures_io::AsyncRead + Unpin> futures_io::AsyncRead for FuturesIo<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: futures_io::AsyncWrite + Unpin> futures_io::AsyncWrite for FuturesIo<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::FuturesIo;
+    use crate::client::{Config, Socks5Stream};
+    use crate::util::target_addr::TargetAddr;
+    use crate::Socks5Command;
+    use std::io;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    /// A futures-io stream replaying canned server bytes, recording what is written.
+    struct Scripted {
+        replies: io::Cursor<Vec<u8>>,
+        written: Vec<u8>,
+    }
+
+    impl futures_io::AsyncRead for Scripted {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Ready(io::Read::read(&mut self.replies, buf))
+        }
+    }
+
+    impl futures_io::AsyncWrite for Scripted {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.written.extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn handshake_on_futures_io_stream() {
+        let stream = FuturesIo::new(Scripted {
+            replies: io::Cursor::new(vec![5, 0, 5, 0, 0, 1, 192, 0, 2, 9, 0, 80]),
+            written: vec![],
+        });
+        let mut stream = Socks5Stream::use_stream(stream, None, Config::default())
+            .await
+            .unwrap();
+        let bind = stream
+            .request(
+                Socks5Command::TCPConnect,
+                TargetAddr::Domain("example.com".to_owned(), 80),
+            )
+            .await
+            .unwrap();
+        assert_eq!(bind.to_string(), "192.0.2.9:80");
+
+        let written = stream.into_inner().into_inner().written;
+        assert_eq!(&written[..3], [5, 1, 0]);
+        assert_eq!(&written[3..8], [5, 1, 0, 3, 11]);
+    }
+}
@@ -0,0 +1,126 @@
+use crate::util::proxy_url::ProxyUrl;
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+
+/// Evaluates `FindProxyForURL(url, host)` of a proxy auto-config (PAC) file, returning
+/// its result, such as `"SOCKS5 proxy:1080; DIRECT"`.
+///
+/// This crate doesn't embed a JavaScript engine: implement it on top of one, or with a
+/// closure taking `(url, host)` for the policies simple enough to be written in Rust.
+/// Set it with `ProxySelector::set_pac`.
+pub trait PacEvaluator: Send + Sync {
+    fn find_proxy(&self, url: &str, host: &str) -> io::Result<String>;
+}
+
+impl<F> PacEvaluator for F
+where
+    F: Fn(&str, &str) -> io::Result<String> + Send + Sync,
+{
+    fn find_proxy(&self, url: &str, host: &str) -> io::Result<String> {
+        self(url, host)
+    }
+}
+
+/// An entry of a PAC result, see [`parse_pac_result`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PacDirective {
+    Direct,
+    /// A `SOCKS5 host:port` entry, the proxy resolving the domains.
+    Socks5(ProxyUrl),
+    /// `PROXY`, `HTTPS` or `SOCKS` (SOCKS4) entries, which this client can't use.
+    Unsupported(String),
+}
+
+/// Parse the result of `FindProxyForURL`, the entries separated by `;` to be tried in
+/// order. An empty result means `DIRECT`.
+pub fn parse_pac_result(result: &str) -> Vec<PacDirective> {
+    let directives: Vec<_> = result
+        .split(';')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (kind, addr) = entry.split_once(char::is_whitespace).unwrap_or((entry, ""));
+            match kind.to_uppercase().as_str() {
+                "DIRECT" => PacDirective::Direct,
+                "SOCKS5" => match format!("socks5h://{}", addr.trim()).parse() {
+                    Ok(proxy) => PacDirective::Socks5(proxy),
+                    Err(_) => PacDirective::Unsupported(entry.to_owned()),
+                },
+                _ => PacDirective::Unsupported(entry.to_owned()),
+            }
+        })
+        .collect();
+    match directives.is_empty() {
+        true => vec![PacDirective::Direct],
+        false => directives,
+    }
+}
+
+/// The URL passed to `FindProxyForURL` for a TCP target, which has none: `https://` for
+/// port 443, `http://` otherwise.
+pub(super) fn target_url(host: &str, port: u16) -> String {
+    let host = match host.contains(':') {
+        true => format!("[{}]", host),
+        false => host.to_owned(),
+    };
+    match port {
+        443 => format!("https://{}/", host),
+        80 => format!("http://{}/", host),
+        port => format!("http://{}:{}/", host, port),
+    }
+}
+
+#[derive(Clone)]
+pub(super) struct Pac(pub(super) Arc<dyn PacEvaluator>);
+
+impl fmt::Debug for Pac {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PacEvaluator")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_pac_result, target_url, PacDirective};
+    use crate::client::{Config, ProxySelector};
+    use crate::util::proxy_url::ProxyUrl;
+    use crate::SocksError;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn pac_results() {
+        assert_eq!(
+            parse_pac_result("PROXY web:3128; SOCKS5 socks:1080;DIRECT"),
+            [
+                PacDirective::Unsupported("PROXY web:3128".to_owned()),
+                PacDirective::Socks5(ProxyUrl::new("socks", 1080)),
+                PacDirective::Direct,
+            ]
+        );
+        assert_eq!(parse_pac_result(" "), [PacDirective::Direct]);
+        assert_eq!(target_url("::1", 443), "https://[::1]/");
+        assert_eq!(target_url("example.com", 8080), "http://example.com:8080/");
+
+        // the PAC result is tried in order, falling back to DIRECT
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut selector = ProxySelector::new(None);
+        selector.set_pac(|url: &str, host: &str| {
+            assert_eq!(host, "127.0.0.1");
+            assert!(url.starts_with("http://127.0.0.1:"));
+            Ok("SOCKS host.invalid:1080; SOCKS5 127.0.0.1:1; DIRECT".to_owned())
+        });
+        let mut config = Config::default();
+        config.set_connect_timeout(1);
+        let stream = selector
+            .connect("127.0.0.1".to_owned(), port, config.clone())
+            .await
+            .unwrap();
+        assert!(!stream.is_proxied());
+
+        selector.set_pac(|_: &str, _: &str| Ok("PROXY proxy:3128".to_owned()));
+        let res = selector.connect("127.0.0.1".to_owned(), port, config).await;
+        assert!(matches!(res, Err(SocksError::NoUsablePacEntry(_))));
+    }
+}
@@ -0,0 +1,260 @@
+use super::{Config, Socks5Stream};
+use crate::util::proxy_url::ProxyUrl;
+use crate::util::target_pattern::{normalize_host, HostPattern};
+use crate::Result;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+
+/// Decides for each target whether to connect directly or through which proxy, so that
+/// all the outbound connections of an application follow one policy.
+///
+/// The rules are checked in the order they were added, the first matching the target
+/// wins, otherwise the default proxy is used, or none. A pattern is a domain, matching
+/// its subdomains too (`example.com`, `.example.com` or `*.example.com`), an IP, a CIDR
+/// block (`10.0.0.0/8`), or `*` for every target.
+///
+/// With the `pac` feature, a PAC evaluator can take the place of the default proxy.
+#[derive(Debug, Clone, Default)]
+pub struct ProxySelector {
+    rules: Vec<(HostPattern, Option<ProxyUrl>)>,
+    default: Option<ProxyUrl>,
+    #[cfg(feature = "pac")]
+    pac: Option<super::pac::Pac>,
+}
+
+impl ProxySelector {
+    /// Send the targets matching no rule through `default`, or directly if `None`.
+    pub fn new(default: Option<ProxyUrl>) -> Self {
+        ProxySelector {
+            rules: vec![],
+            default,
+            #[cfg(feature = "pac")]
+            pac: None,
+        }
+    }
+
+    /// Ask `pac` for the targets matching no rule, instead of using the default proxy.
+    ///
+    /// The entries of its result are tried in order until a connection succeeds, see
+    /// [`parse_pac_result`](super::parse_pac_result). Only [`ProxySelector::connect`]
+    /// evaluates it, `select` still returns the default proxy.
+    #[cfg(feature = "pac")]
+    pub fn set_pac<P: super::PacEvaluator + 'static>(&mut self, pac: P) -> &mut Self {
+        self.pac = Some(super::pac::Pac(std::sync::Arc::new(pac)));
+        self
+    }
+
+    /// Connect directly to the targets matching `pattern`, as with `NO_PROXY`.
+    pub fn add_bypass(&mut self, pattern: &str) -> &mut Self {
+        if let Some(pattern) = HostPattern::parse(pattern) {
+            self.rules.push((pattern, None));
+        }
+        self
+    }
+
+    /// Connect to the targets matching `pattern` through `proxy`.
+    pub fn add_route(&mut self, pattern: &str, proxy: ProxyUrl) -> &mut Self {
+        if let Some(pattern) = HostPattern::parse(pattern) {
+            self.rules.push((pattern, Some(proxy)));
+        }
+        self
+    }
+
+    /// The proxy of the targets matching no rule.
+    pub fn default_proxy(&self) -> Option<&ProxyUrl> {
+        self.default.as_ref()
+    }
+
+    /// The proxy to reach `host`, a domain or an IP, through, `None` to connect directly.
+    pub fn select(&self, host: &str) -> Option<&ProxyUrl> {
+        self.rule(host).unwrap_or(self.default.as_ref())
+    }
+
+    /// The choice of the first rule matching `host`, if any.
+    fn rule(&self, host: &str) -> Option<Option<&ProxyUrl>> {
+        let (host, ip) = normalize_host(host);
+        self.rules
+            .iter()
+            .find(|(pattern, _)| pattern.matches(&host, ip))
+            .map(|(_, proxy)| proxy.as_ref())
+    }
+
+    /// Connect to a target as selected, honoring the connect timeout of `config` for
+    /// direct connections too.
+    pub async fn connect(
+        &self,
+        target_addr: String,
+        target_port: u16,
+        config: Config,
+    ) -> Result<MaybeProxied> {
+        #[cfg(feature = "pac")]
+        if let (None, Some(pac)) = (self.rule(&target_addr), &self.pac) {
+            return connect_with_pac(&pac.0, target_addr, target_port, config).await;
+        }
+        connect_to(self.select(&target_addr), target_addr, target_port, config).await
+    }
+}
+
+async fn connect_to(
+    proxy: Option<&ProxyUrl>,
+    target_addr: String,
+    target_port: u16,
+    config: Config,
+) -> Result<MaybeProxied> {
+    if let Some(proxy) = proxy {
+        debug!("Connecting to {} through {}", target_addr, proxy);
+        let stream =
+            Socks5Stream::connect_with_url(proxy, target_addr, target_port, config).await?;
+        return Ok(MaybeProxied::Proxied(stream));
+    }
+    debug!("Connecting to {} directly", target_addr);
+    let connect = TcpStream::connect((target_addr.as_str(), target_port));
+    let stream = match config.connect_timeout {
+        None => connect.await?,
+        Some(secs) => tokio::time::timeout(Duration::from_secs(secs), connect)
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??,
+    };
+    Ok(MaybeProxied::Direct(stream))
+}
+
+#[cfg(feature = "pac")]
+async fn connect_with_pac(
+    pac: &std::sync::Arc<dyn super::PacEvaluator>,
+    target_addr: String,
+    target_port: u16,
+    config: Config,
+) -> Result<MaybeProxied> {
+    use super::PacDirective;
+
+    let url = super::pac::target_url(&target_addr, target_port);
+    let result = pac.find_proxy(&url, &target_addr)?;
+    debug!("PAC result for {}: {}", url, result);
+    let mut last_err = None;
+    for directive in super::parse_pac_result(&result) {
+        let proxy = match directive {
+            PacDirective::Direct => None,
+            PacDirective::Socks5(proxy) => Some(proxy),
+            PacDirective::Unsupported(entry) => {
+                debug!("Skipping the PAC entry `{}`", entry);
+                continue;
+            }
+        };
+        let res = connect_to(
+            proxy.as_ref(),
+            target_addr.clone(),
+            target_port,
+            config.clone(),
+        )
+        .await;
+        match res {
+            Ok(stream) => return Ok(stream),
+            Err(err) => {
+                debug!("PAC entry failed for {}: {}", target_addr, err);
+                last_err = Some(err);
+            }
+        }
+    }
+    Err(last_err.unwrap_or(crate::SocksError::NoUsablePacEntry(result)))
+}
+
+/// A connection made by [`ProxySelector::connect`].
+#[derive(Debug)]
+pub enum MaybeProxied {
+    Direct(TcpStream),
+    Proxied(Socks5Stream<TcpStream>),
+}
+
+impl MaybeProxied {
+    pub fn is_proxied(&self) -> bool {
+        matches!(self, MaybeProxied::Proxied(_))
+    }
+}
+
+impl AsyncRead for MaybeProxied {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeProxied::Direct(stream) => Pin::new(stream).poll_read(cx, buf),
+            MaybeProxied::Proxied(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for MaybeProxied {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            MaybeProxied::Direct(stream) => Pin::new(stream).poll_write(cx, buf),
+            MaybeProxied::Proxied(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeProxied::Direct(stream) => Pin::new(stream).poll_flush(cx),
+            MaybeProxied::Proxied(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeProxied::Direct(stream) => Pin::new(stream).poll_shutdown(cx),
+            MaybeProxied::Proxied(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ProxySelector;
+    use crate::client::Config;
+    use crate::util::proxy_url::ProxyUrl;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn select_per_target() {
+        let corp = ProxyUrl::new("corp-proxy", 1080);
+        let default = ProxyUrl::new("proxy", 1080);
+        let mut selector = ProxySelector::new(Some(default.clone()));
+        selector
+            .add_bypass("localhost")
+            .add_bypass("10.0.0.0/8")
+            .add_route(".corp.example", corp.clone())
+            .add_bypass("public.corp.example")
+            .add_bypass("::1");
+
+        for (host, expected) in [
+            ("localhost", None),
+            ("10.1.2.3", None),
+            ("::ffff:10.1.2.3", None),
+            ("[::1]", None),
+            ("git.corp.example", Some(&corp)),
+            // the route comes first
+            ("public.corp.example", Some(&corp)),
+            ("notcorp.example", Some(&default)),
+            ("11.1.2.3", Some(&default)),
+        ] {
+            assert_eq!(selector.select(host), expected, "{}", host);
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        selector.add_bypass("127.0.0.1");
+        let stream = selector
+            .connect("127.0.0.1".to_owned(), port, Config::default())
+            .await
+            .unwrap();
+        assert!(!stream.is_proxied());
+    }
+}
@@ -7,7 +7,7 @@
 //! - An `async`/`.await` [SOCKS5](https://tools.ietf.org/html/rfc1928) implementation.
 //! - An `async`/`.await` [SOCKS4 Client](https://www.openssh.com/txt/socks4.protocol) implementation.
 //! - An `async`/`.await` [SOCKS4a Client](https://www.openssh.com/txt/socks4a.protocol) implementation.
-//! - No **unsafe** code
+//! - No **unsafe** code (but for the opt-in `fast-open` and `icmp-errors` features, using the socket options and error queue)
 //! - Built on top of the [Tokio](https://tokio.rs/) runtime
 //! - Ultra lightweight and scalable
 //! - No system dependencies
@@ -16,7 +16,7 @@
 //!   - You control the request handling, the library only ensures you follow the proper protocol flow
 //!   - Can skip DNS resolution
 //!   - Can skip the authentication/handshake process (not RFC-compliant, for private use, to save on useless round-trips)
-//!   - Instead of proxying in-process, swap out `run_tcp_proxy` for custom handling to build a router or to use a custom accelerated proxying method
+//!   - Instead of proxying in-process, swap out `run_tcp_proxy_with_options` for custom handling to build a router or to use a custom accelerated proxying method
 //! - Authentication methods:
 //!   - No-Auth method (`0x00`)
 //!   - Username/Password auth method (`0x02`)
@@ -35,20 +35,32 @@
 //!
 //! Please check [`examples`](https://github.com/dizda/fast-socks5/tree/master/examples) directory.
 
-#![forbid(unsafe_code)]
+#![cfg_attr(
+    not(any(feature = "fast-open", feature = "icmp-errors")),
+    forbid(unsafe_code)
+)]
+#![cfg_attr(any(feature = "fast-open", feature = "icmp-errors"), deny(unsafe_code))]
 #[macro_use]
 extern crate log;
 
 pub mod client;
+#[cfg(not(target_arch = "wasm32"))]
 pub mod server;
 pub mod util;
 
 #[cfg(feature = "socks4")]
 pub mod socks4;
 
+#[cfg(feature = "mux")]
+pub mod mux;
+
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
+
 use std::fmt;
 use std::io;
 use thiserror::Error;
+use util::secret::Secret;
 use util::stream::ConnectError;
 use util::target_addr::read_address;
 use util::target_addr::AddrError;
@@ -60,12 +72,20 @@ use tokio::io::AsyncReadExt;
 #[rustfmt::skip]
 pub mod consts {
     pub const SOCKS5_VERSION:                          u8 = 0x05;
+    pub const SOCKS5_RESERVED:                         u8 = 0x00;
 
     pub const SOCKS5_AUTH_METHOD_NONE:                 u8 = 0x00;
     pub const SOCKS5_AUTH_METHOD_GSSAPI:               u8 = 0x01;
     pub const SOCKS5_AUTH_METHOD_PASSWORD:             u8 = 0x02;
+    /// First and last of the method ids reserved for private methods (RFC 1928).
+    pub const SOCKS5_AUTH_METHOD_PRIVATE_FIRST:        u8 = 0x80;
+    pub const SOCKS5_AUTH_METHOD_PRIVATE_LAST:         u8 = 0xfe;
     pub const SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE:       u8 = 0xff;
 
+    /// Username/password subnegotiation (RFC 1929).
+    pub const SOCKS5_PASSWORD_AUTH_VERSION:            u8 = 0x01;
+    pub const SOCKS5_PASSWORD_AUTH_SUCCEEDED:          u8 = 0x00;
+
     pub const SOCKS5_CMD_TCP_CONNECT:                  u8 = 0x01;
     pub const SOCKS5_CMD_TCP_BIND:                     u8 = 0x02;
     pub const SOCKS5_CMD_UDP_ASSOCIATE:                u8 = 0x03;
@@ -85,18 +105,18 @@ pub mod consts {
     pub const SOCKS5_REPLY_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Socks5Command {
     TCPConnect,
     TCPBind,
     UDPAssociate,
 }
 
-#[allow(dead_code)]
 impl Socks5Command {
     #[inline]
     #[rustfmt::skip]
-    fn as_u8(&self) -> u8 {
+    pub fn as_u8(&self) -> u8 {
         match self {
             Socks5Command::TCPConnect   => consts::SOCKS5_CMD_TCP_CONNECT,
             Socks5Command::TCPBind      => consts::SOCKS5_CMD_TCP_BIND,
@@ -106,7 +126,7 @@ impl Socks5Command {
 
     #[inline]
     #[rustfmt::skip]
-    fn from_u8(code: u8) -> Option<Socks5Command> {
+    pub fn from_u8(code: u8) -> Option<Socks5Command> {
         match code {
             consts::SOCKS5_CMD_TCP_CONNECT      => Some(Socks5Command::TCPConnect),
             consts::SOCKS5_CMD_TCP_BIND         => Some(Socks5Command::TCPBind),
@@ -116,10 +136,28 @@ impl Socks5Command {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum AuthenticationMethod {
     None,
-    Password { username: String, password: String },
+    Password {
+        username: String,
+        password: Secret<String>,
+    },
+}
+
+/// Doesn't show the credentials, like `Display`.
+impl fmt::Debug for AuthenticationMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthenticationMethod::None => f.write_str("None"),
+            AuthenticationMethod::Password { .. } => f
+                .debug_struct("Password")
+                .field("username", &"<redacted>")
+                .field("password", &"<redacted>")
+                .finish(),
+        }
+    }
 }
 
 impl AuthenticationMethod {
@@ -138,7 +176,7 @@ impl AuthenticationMethod {
     fn from_u8(code: u8) -> Option<AuthenticationMethod> {
         match code {
             consts::SOCKS5_AUTH_METHOD_NONE     => Some(AuthenticationMethod::None),
-            consts::SOCKS5_AUTH_METHOD_PASSWORD => Some(AuthenticationMethod::Password { username: "test".to_string(), password: "test".to_string()}),
+            consts::SOCKS5_AUTH_METHOD_PASSWORD => Some(AuthenticationMethod::Password { username: "test".to_string(), password: Secret::new("test".to_string())}),
             _                                   => None,
         }
     }
@@ -183,9 +221,18 @@ pub enum SocksError {
     #[error("Authentication rejected `{0}`")]
     AuthenticationRejected(String),
 
+    #[cfg(not(target_arch = "wasm32"))]
     #[error(transparent)]
     ServerError(#[from] server::SocksServerError),
 
+    /// An error of the session with this id, from the legacy `Socks5Socket`.
+    #[cfg(not(target_arch = "wasm32"))]
+    #[error("session {session}: {source}")]
+    Session {
+        session: server::SessionId,
+        source: Box<SocksError>,
+    },
+
     #[error(transparent)]
     UdpHeaderError(#[from] UdpHeaderError),
 
@@ -202,18 +249,41 @@ pub enum SocksError {
     #[error("Error with reply: {0}.")]
     ReplySocks4Error(#[from] socks4::ReplyError),
 
+    /// No longer raised by this crate, see `InvalidConfig` and the other variants.
     #[error("Argument input error: `{0}`.")]
     ArgumentInputError(&'static str),
 
+    #[cfg(feature = "pac")]
+    #[error("No usable entry in the PAC result `{0}`")]
+    NoUsablePacEntry(String),
+
+    #[error("A request was cancelled or failed midway, the stream is out of sync")]
+    Desynced,
+
+    #[error(transparent)]
+    InvalidConfig(#[from] ConfigError),
+
     //    #[error("Other: `{0}`.")]
     #[error(transparent)]
     Other(#[from] anyhow::Error),
 }
 
+impl SocksError {
+    /// The id of the session which failed, if known.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn session_id(&self) -> Option<server::SessionId> {
+        match self {
+            SocksError::Session { session, .. } => Some(*session),
+            _ => None,
+        }
+    }
+}
+
 pub type Result<T, E = SocksError> = core::result::Result<T, E>;
 
 /// SOCKS5 reply code
 #[derive(Error, Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ReplyError {
     #[error("Succeeded")]
     Succeeded,
@@ -276,6 +346,62 @@ impl ReplyError {
     }
 }
 
+/// Every misconfiguration found while validating settings, so that they can all be
+/// fixed in one go.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    issues: Vec<String>,
+}
+
+impl ConfigError {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record `issue` unless `ok` holds.
+    pub fn check(&mut self, ok: bool, issue: impl Into<String>) -> &mut Self {
+        if !ok {
+            self.issues.push(issue.into());
+        }
+        self
+    }
+
+    /// Add the issues found by another validation.
+    pub fn merge(&mut self, other: Result<(), ConfigError>) -> &mut Self {
+        if let Err(other) = other {
+            self.issues.extend(other.issues);
+        }
+        self
+    }
+
+    pub fn issues(&self) -> &[String] {
+        &self.issues
+    }
+
+    /// `Ok` if no issue was recorded.
+    pub fn into_result(self) -> Result<(), ConfigError> {
+        if self.issues.is_empty() {
+            Ok(())
+        } else {
+            Err(self)
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid configuration")?;
+        match self.issues.as_slice() {
+            [issue] => write!(f, ": {}", issue),
+            issues => issues
+                .iter()
+                .try_for_each(|issue| write!(f, "\n  - {}", issue)),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
 #[derive(thiserror::Error, Debug)]
 pub enum UdpHeaderError {
     #[error(transparent)]
@@ -350,10 +476,11 @@ mod test {
         sync::oneshot::Sender,
     };
 
-    use crate::{client, server, ReplyError, Socks5Command};
+    use crate::{client, server, ConfigError, ReplyError, Socks5Command};
     use std::{
         net::{SocketAddr, ToSocketAddrs},
         num::ParseIntError,
+        time::Duration,
     };
     use tokio::io::{AsyncReadExt, AsyncWriteExt};
     use tokio::sync::oneshot;
@@ -376,10 +503,12 @@ mod test {
             target_addr = target_addr.resolve_dns().await?;
             match cmd {
                 Socks5Command::TCPConnect => {
-                    server::run_tcp_proxy(proto, &target_addr, 10, false).await?;
+                    let opts = server::ConnectOptions::new();
+                    server::run_tcp_proxy_with_options(proto, &target_addr, &opts).await?;
                 }
                 Socks5Command::UDPAssociate => {
-                    server::run_udp_proxy(proto, &target_addr, None, reply_ip, None).await?;
+                    let opts = server::UdpProxyOptions::new(reply_ip);
+                    server::run_udp_proxy_with_options(proto, &target_addr, opts).await?;
                 }
                 Socks5Command::TCPBind => {
                     proto.reply_error(&ReplyError::CommandNotSupported).await?;
@@ -511,6 +640,29 @@ mod test {
         });
     }
 
+    #[test]
+    fn config_error_reports_every_issue() {
+        let mut config = client::Config::default();
+        assert!(config.validate().is_ok());
+
+        config.set_connect_timeout(0).set_tcp_keepalive(Duration::ZERO);
+        let err = config.validate().unwrap_err();
+        assert_eq!(err.issues().len(), 2);
+        assert_eq!(
+            err.to_string(),
+            "invalid configuration\n  - connect timeout must be at least 1s\n  - TCP keepalive idle time must not be zero"
+        );
+
+        let mut udp = server::UdpAssociation::default();
+        udp.set_max_datagram_size(4);
+        let mut err = ConfigError::new();
+        err.merge(udp.validate()).check(true, "never reported");
+        assert_eq!(
+            err.into_result().unwrap_err().to_string(),
+            "invalid configuration: max datagram size of 4 can't fit a SOCKS5 UDP header and data"
+        );
+    }
+
     fn decode_hex(s: &str) -> Result<Vec<u8>, ParseIntError> {
         (0..s.len())
             .step_by(2)
@@ -0,0 +1,699 @@
+//! Many sessions over a single connection, e.g. between a client gateway and a server, to
+//! save the connection setup of each session in chained deployments.
+//!
+//! Both ends wrap their connection (TCP, TLS...) in a [`MuxConnection`], which speaks
+//! [yamux](https://github.com/hashicorp/yamux/blob/master/spec.md) so that the other end
+//! may be any yamux implementation. Each logical stream is a [`MuxStream`] on which a
+//! SOCKS5 handshake runs as on any connection: the gateway opens them with
+//! [`MuxConnection::open`] and the server takes them with [`MuxConnection::accept`].
+//!
+//! Each stream has its own flow control window, 256 KiB at first: a stream which isn't
+//! read stalls its sender only, never the other streams. The window the peer grants a
+//! stream is capped at 1 GiB, the stream is reset beyond.
+
+use std::collections::HashMap;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::{mpsc, oneshot};
+use tokio_util::compat::{Compat, FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt};
+
+// streams open at once by default, both ends together
+const DEFAULT_MAX_STREAMS: usize = 1024;
+// the most yamux allows with its default receive window of 1 GiB
+const MAX_STREAMS: usize = 4096;
+// streams opened by the peer and not accepted yet
+const ACCEPT_QUEUE: usize = 64;
+
+// the frames of yamux: version, type, flags (u16), stream id (u32) and length (u32),
+// big-endian, followed by `length` bytes of data for a data frame
+const HEADER_LEN: usize = 12;
+const TYPE_DATA: u8 = 0;
+const TYPE_WINDOW_UPDATE: u8 = 1;
+const FLAG_SYN: u16 = 1;
+const FLAG_FIN: u16 = 4;
+const FLAG_RST: u16 = 8;
+// the window of a new stream
+const DEFAULT_WINDOW: u64 = yamux::DEFAULT_CREDIT as u64;
+// the most a yamux peer grants a stream, its default connection receive window
+const MAX_STREAM_WINDOW: u64 = 1024 * 1024 * 1024;
+
+/// Which end of the connection, the two ends allocate stream ids apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MuxRole {
+    Client,
+    Server,
+}
+
+/// A logical stream of a [`MuxConnection`].
+///
+/// Shutting it down half-closes the stream, the data sent by the peer can still be read.
+/// Dropping it without shutting it down resets the stream.
+#[derive(Debug)]
+pub struct MuxStream {
+    inner: Compat<yamux::Stream>,
+    open: Arc<AtomicUsize>,
+}
+
+impl MuxStream {
+    fn new(inner: yamux::Stream, open: &Arc<AtomicUsize>) -> Self {
+        open.fetch_add(1, Ordering::Relaxed);
+        MuxStream {
+            inner: inner.compat(),
+            open: open.clone(),
+        }
+    }
+}
+
+impl Drop for MuxStream {
+    fn drop(&mut self) {
+        self.open.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl AsyncRead for MuxStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for MuxStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+type OpenReply = oneshot::Sender<io::Result<MuxStream>>;
+
+/// One end of a multiplexed connection, see the [module docs](self).
+///
+/// The connection is served until the peer closes it or a transport error, dropping this
+/// doesn't close the streams already open.
+#[derive(Debug)]
+pub struct MuxConnection {
+    open: Arc<AtomicUsize>,
+    max_streams: Arc<AtomicUsize>,
+    opens: mpsc::Sender<OpenReply>,
+    incoming: mpsc::Receiver<MuxStream>,
+}
+
+impl MuxConnection {
+    /// Run the multiplexing over `transport`, in a task spawned on the current runtime.
+    pub fn new<T>(transport: T, role: MuxRole) -> Self
+    where
+        T: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let mode = match role {
+            MuxRole::Client => yamux::Mode::Client,
+            MuxRole::Server => yamux::Mode::Server,
+        };
+        let mut config = yamux::Config::default();
+        config.set_max_num_streams(MAX_STREAMS);
+        let transport = FrameGuard::new(Box::pin(transport), role);
+        let connection = yamux::Connection::new(transport.compat(), config, mode);
+
+        let open = Arc::new(AtomicUsize::new(0));
+        let max_streams = Arc::new(AtomicUsize::new(DEFAULT_MAX_STREAMS));
+        let (opens, opens_rx) = mpsc::channel(1);
+        let (incoming_tx, incoming) = mpsc::channel(ACCEPT_QUEUE);
+        tokio::spawn(drive(
+            connection,
+            open.clone(),
+            max_streams.clone(),
+            opens_rx,
+            incoming_tx,
+        ));
+        MuxConnection {
+            open,
+            max_streams,
+            opens,
+            incoming,
+        }
+    }
+
+    /// How many streams may be open at once, 1024 by default and 4096 at most. Beyond,
+    /// the peer's new streams are reset and [`open`](Self::open) fails.
+    pub fn set_max_streams(&mut self, max: usize) -> &mut Self {
+        self.max_streams
+            .store(max.min(MAX_STREAMS), Ordering::Relaxed);
+        self
+    }
+
+    /// Open a new stream to the peer, which sees it on the first write.
+    pub async fn open(&self) -> io::Result<MuxStream> {
+        if self.streams() >= self.max_streams.load(Ordering::Relaxed) {
+            return Err(io::Error::other("too many mux streams"));
+        }
+        let (reply, stream) = oneshot::channel();
+        self.opens
+            .send(reply)
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        stream
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?
+    }
+
+    /// The next stream opened by the peer, `None` once the connection is closed.
+    pub async fn accept(&mut self) -> Option<MuxStream> {
+        self.incoming.recv().await
+    }
+
+    /// How many streams are open.
+    pub fn streams(&self) -> usize {
+        self.open.load(Ordering::Relaxed)
+    }
+}
+
+/// Serve the yamux `connection`: open the streams asked for on `opens`, queue the peer's
+/// on `incoming`.
+async fn drive<T: AsyncRead + AsyncWrite>(
+    mut connection: yamux::Connection<Compat<FrameGuard<T>>>,
+    open: Arc<AtomicUsize>,
+    max_streams: Arc<AtomicUsize>,
+    mut opens: mpsc::Receiver<OpenReply>,
+    incoming: mpsc::Sender<MuxStream>,
+) {
+    let mut opening: Option<OpenReply> = None;
+    let res = std::future::poll_fn(|cx| loop {
+        if opening.is_none() {
+            if let Poll::Ready(Some(reply)) = opens.poll_recv(cx) {
+                opening = Some(reply);
+            }
+        }
+        if let Some(reply) = opening.take() {
+            match connection.poll_new_outbound(cx) {
+                Poll::Ready(stream) => {
+                    let stream = stream
+                        .map(|stream| MuxStream::new(stream, &open))
+                        .map_err(io::Error::other);
+                    let _ = reply.send(stream);
+                    continue;
+                }
+                Poll::Pending => opening = Some(reply),
+            }
+        }
+        match connection.poll_next_inbound(cx) {
+            Poll::Ready(Some(Ok(stream))) => {
+                // dropping it resets it
+                if open.load(Ordering::Relaxed) >= max_streams.load(Ordering::Relaxed) {
+                    debug!("too many mux streams, resetting {}", stream.id());
+                    continue;
+                }
+                if let Err(err) = incoming.try_send(MuxStream::new(stream, &open)) {
+                    debug!("mux accept queue full, resetting a stream: {}", err);
+                }
+            }
+            Poll::Ready(Some(Err(err))) => return Poll::Ready(Err(err)),
+            Poll::Ready(None) => return Poll::Ready(Ok(())),
+            Poll::Pending => return Poll::Pending,
+        }
+    })
+    .await;
+    if let Err(err) = res {
+        debug!("mux connection closed: {}", err);
+    }
+}
+
+/// A frame being read or written.
+#[derive(Debug, Default)]
+struct FrameCursor {
+    header: [u8; HEADER_LEN],
+    filled: usize,
+    body: u64,
+    // dropped, with its data
+    skip: bool,
+}
+
+impl FrameCursor {
+    fn at_boundary(&self) -> bool {
+        self.filled == 0 && self.body == 0
+    }
+}
+
+/// The window the peer granted a stream and which ends have finished it.
+#[derive(Debug)]
+struct Window {
+    credit: u64,
+    fin_sent: bool,
+    fin_received: bool,
+}
+
+/// The transport of a yamux connection, checking the frames of the peer before yamux
+/// does: a stream the peer grants more than [`MAX_STREAM_WINDOW`], or a stream it opens
+/// with one of our ids, is reset.
+struct FrameGuard<T> {
+    inner: Pin<Box<T>>,
+    role: MuxRole,
+    windows: HashMap<u32, Window>,
+    read: FrameCursor,
+    // the frames read and checked, for yamux
+    checked: Vec<u8>,
+    scratch: Box<[u8]>,
+    written: FrameCursor,
+    // the reset frames to send the peer, between two frames of yamux
+    resets: Vec<u8>,
+}
+
+impl<T: AsyncRead + AsyncWrite> FrameGuard<T> {
+    fn new(inner: Pin<Box<T>>, role: MuxRole) -> Self {
+        FrameGuard {
+            inner,
+            role,
+            windows: HashMap::new(),
+            read: FrameCursor::default(),
+            checked: vec![],
+            scratch: vec![0u8; 16 * 1024].into_boxed_slice(),
+            written: FrameCursor::default(),
+            resets: vec![],
+        }
+    }
+
+    fn is_local(&self, id: u32) -> bool {
+        match self.role {
+            MuxRole::Client => !id.is_multiple_of(2),
+            MuxRole::Server => id.is_multiple_of(2),
+        }
+    }
+
+    fn reset(&mut self, id: u32) {
+        debug!("resetting mux stream {}", id);
+        self.windows.remove(&id);
+        let mut frame = [0u8; HEADER_LEN];
+        frame[1] = TYPE_DATA;
+        frame[2..4].copy_from_slice(&FLAG_RST.to_be_bytes());
+        frame[4..8].copy_from_slice(&id.to_be_bytes());
+        self.resets.extend_from_slice(&frame);
+    }
+
+    /// Check the bytes read from the peer, queuing for yamux what passes.
+    fn check(&mut self, mut bytes: &[u8]) {
+        while !bytes.is_empty() {
+            if self.read.filled < HEADER_LEN {
+                let n = bytes.len().min(HEADER_LEN - self.read.filled);
+                self.read.header[self.read.filled..self.read.filled + n]
+                    .copy_from_slice(&bytes[..n]);
+                self.read.filled += n;
+                bytes = &bytes[n..];
+                if self.read.filled == HEADER_LEN {
+                    let mut header = self.read.header;
+                    let pass = self.check_header(&mut header);
+                    self.read.body = match header[1] {
+                        TYPE_DATA => u32::from_be_bytes(header[8..12].try_into().unwrap()) as u64,
+                        _ => 0,
+                    };
+                    self.read.skip = !pass;
+                    if pass {
+                        self.checked.extend_from_slice(&header);
+                    }
+                }
+            } else {
+                let n = bytes.len().min(self.read.body as usize);
+                if !self.read.skip {
+                    self.checked.extend_from_slice(&bytes[..n]);
+                }
+                self.read.body -= n as u64;
+                bytes = &bytes[n..];
+            }
+            if self.read.filled == HEADER_LEN && self.read.body == 0 {
+                self.read = FrameCursor::default();
+            }
+        }
+    }
+
+    /// Whether to pass a frame of the peer to yamux, `header` may be rewritten.
+    fn check_header(&mut self, header: &mut [u8; HEADER_LEN]) -> bool {
+        let kind = header[1];
+        let flags = u16::from_be_bytes([header[2], header[3]]);
+        let id = u32::from_be_bytes(header[4..8].try_into().unwrap());
+        let length = u32::from_be_bytes(header[8..12].try_into().unwrap()) as u64;
+        // pings and go aways
+        if id == 0 || !matches!(kind, TYPE_DATA | TYPE_WINDOW_UPDATE) {
+            return true;
+        }
+        if flags & FLAG_RST != 0 {
+            self.windows.remove(&id);
+            return true;
+        }
+        if flags & FLAG_SYN != 0 {
+            let credit = match kind {
+                TYPE_WINDOW_UPDATE => DEFAULT_WINDOW + length,
+                _ => DEFAULT_WINDOW,
+            };
+            // ours to allocate, it would collide with a stream we open later
+            if self.is_local(id) || credit > MAX_STREAM_WINDOW {
+                self.reset(id);
+                return false;
+            }
+            self.windows.insert(
+                id,
+                Window {
+                    credit,
+                    fin_sent: false,
+                    fin_received: false,
+                },
+            );
+        }
+        let Some(window) = self.windows.get_mut(&id) else {
+            // late frames of a closed stream, yamux may still hold it: no more window, which
+            // could overflow it after a reset
+            return kind == TYPE_DATA;
+        };
+        if kind == TYPE_WINDOW_UPDATE && flags & FLAG_SYN == 0 {
+            window.credit += length;
+            if window.credit > MAX_STREAM_WINDOW {
+                self.reset(id);
+                // and for yamux, which would overflow the window
+                header[2..4].copy_from_slice(&FLAG_RST.to_be_bytes());
+                header[8..12].copy_from_slice(&0u32.to_be_bytes());
+                return true;
+            }
+        }
+        if flags & FLAG_FIN != 0 {
+            window.fin_received = true;
+            if window.fin_sent {
+                self.windows.remove(&id);
+            }
+        }
+        true
+    }
+
+    /// Account for the bytes of yamux written to the peer.
+    fn sent(&mut self, mut bytes: &[u8]) {
+        while !bytes.is_empty() {
+            if self.written.filled < HEADER_LEN {
+                let n = bytes.len().min(HEADER_LEN - self.written.filled);
+                self.written.header[self.written.filled..self.written.filled + n]
+                    .copy_from_slice(&bytes[..n]);
+                self.written.filled += n;
+                bytes = &bytes[n..];
+                if self.written.filled == HEADER_LEN {
+                    let header = self.written.header;
+                    self.written.body = self.sent_header(&header);
+                }
+            } else {
+                let n = bytes.len().min(self.written.body as usize);
+                self.written.body -= n as u64;
+                bytes = &bytes[n..];
+            }
+            if self.written.filled == HEADER_LEN && self.written.body == 0 {
+                self.written = FrameCursor::default();
+            }
+        }
+    }
+
+    /// Account for a frame sent to the peer, returning the length of its data.
+    fn sent_header(&mut self, header: &[u8; HEADER_LEN]) -> u64 {
+        let kind = header[1];
+        let flags = u16::from_be_bytes([header[2], header[3]]);
+        let id = u32::from_be_bytes(header[4..8].try_into().unwrap());
+        let length = u32::from_be_bytes(header[8..12].try_into().unwrap()) as u64;
+        let body = match kind {
+            TYPE_DATA => length,
+            _ => 0,
+        };
+        if id == 0 || !matches!(kind, TYPE_DATA | TYPE_WINDOW_UPDATE) {
+            return body;
+        }
+        if flags & FLAG_RST != 0 {
+            self.windows.remove(&id);
+            return body;
+        }
+        if flags & FLAG_SYN != 0 {
+            self.windows.insert(
+                id,
+                Window {
+                    credit: DEFAULT_WINDOW,
+                    fin_sent: false,
+                    fin_received: false,
+                },
+            );
+        }
+        if let Some(window) = self.windows.get_mut(&id) {
+            window.credit = window.credit.saturating_sub(body);
+            if flags & FLAG_FIN != 0 {
+                window.fin_sent = true;
+                if window.fin_received {
+                    self.windows.remove(&id);
+                }
+            }
+        }
+        body
+    }
+
+    /// Send the queued resets, if not in the middle of a frame of yamux.
+    fn poll_resets(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if !self.written.at_boundary() {
+            return Poll::Ready(Ok(()));
+        }
+        while !self.resets.is_empty() {
+            let n = ready!(self.inner.as_mut().poll_write(cx, &self.resets))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.resets.drain(..n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite> AsyncRead for FrameGuard<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        while this.checked.is_empty() {
+            let mut scratch = ReadBuf::new(&mut this.scratch);
+            ready!(this.inner.as_mut().poll_read(cx, &mut scratch))?;
+            let n = scratch.filled().len();
+            if n == 0 {
+                return Poll::Ready(Ok(()));
+            }
+            let scratch = std::mem::take(&mut this.scratch);
+            this.check(&scratch[..n]);
+            this.scratch = scratch;
+            // the resets are sent along, yamux may have nothing to write
+            if this.poll_resets(cx).is_ready() {
+                let _ = this.inner.as_mut().poll_flush(cx);
+            }
+        }
+        let n = buf.remaining().min(this.checked.len());
+        buf.put_slice(&this.checked[..n]);
+        this.checked.drain(..n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite> AsyncWrite for FrameGuard<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        ready!(this.poll_resets(cx))?;
+        let n = ready!(this.inner.as_mut().poll_write(cx, buf))?;
+        this.sent(&buf[..n]);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_resets(cx))?;
+        self.inner.as_mut().poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_resets(cx))?;
+        self.inner.as_mut().poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{MuxConnection, MuxRole};
+    use crate::client::{Config, Socks5Stream};
+    use crate::server::Socks5ServerProtocol;
+    use crate::util::target_addr::TargetAddr;
+    use crate::Socks5Command;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    /// A yamux frame of no data.
+    fn frame(kind: u8, flags: u16, id: u32, length: u32) -> Vec<u8> {
+        let mut frame = vec![0, kind];
+        frame.extend_from_slice(&flags.to_be_bytes());
+        frame.extend_from_slice(&id.to_be_bytes());
+        frame.extend_from_slice(&length.to_be_bytes());
+        frame
+    }
+
+    /// Read the frames of `peer` until a reset of stream `id`.
+    async fn read_reset(peer: &mut DuplexStream, id: u32) {
+        let reset = async {
+            loop {
+                let mut header = [0u8; 12];
+                peer.read_exact(&mut header).await.unwrap();
+                let flags = u16::from_be_bytes([header[2], header[3]]);
+                let length = u32::from_be_bytes(header[8..12].try_into().unwrap());
+                if header[1] == 0 {
+                    let mut data = vec![0u8; length as usize];
+                    peer.read_exact(&mut data).await.unwrap();
+                }
+                if flags & 8 != 0 && header[4..8] == id.to_be_bytes() {
+                    return;
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), reset)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn sessions_over_one_connection() {
+        let (client_end, server_end) = duplex(1024);
+        let gateway = MuxConnection::new(client_end, MuxRole::Client);
+        let mut server = MuxConnection::new(server_end, MuxRole::Server);
+        tokio::spawn(async move {
+            while let Some(stream) = server.accept().await {
+                tokio::spawn(async move {
+                    let (proto, _, target) = Socks5ServerProtocol::accept_no_auth(stream)
+                        .await
+                        .unwrap()
+                        .read_command()
+                        .await
+                        .unwrap();
+                    let bind: SocketAddr = "192.0.2.1:1".parse().unwrap();
+                    let mut inner = proto.reply_success(bind).await.unwrap();
+                    // echo the target back, then whatever the client sends
+                    inner
+                        .write_all(target.to_string().as_bytes())
+                        .await
+                        .unwrap();
+                    let mut rest = vec![];
+                    inner.read_to_end(&mut rest).await.unwrap();
+                    inner.write_all(&rest).await.unwrap();
+                    inner.shutdown().await.unwrap();
+                });
+            }
+        });
+
+        let mut sessions = vec![];
+        for port in [80, 443] {
+            let stream = gateway.open().await.unwrap();
+            let mut client = Socks5Stream::use_stream(stream, None, Config::default())
+                .await
+                .unwrap();
+            let target = TargetAddr::Domain("example.com".to_owned(), port);
+            client
+                .request(Socks5Command::TCPConnect, target)
+                .await
+                .unwrap();
+            sessions.push((port, client));
+        }
+        assert_eq!(gateway.streams(), 2);
+
+        for (port, mut client) in sessions {
+            client.write_all(b"bye").await.unwrap();
+            client.shutdown().await.unwrap();
+            let mut echoed = String::new();
+            client.read_to_string(&mut echoed).await.unwrap();
+            assert_eq!(echoed, format!("example.com:{}bye", port));
+        }
+    }
+
+    #[tokio::test]
+    async fn unread_stream_stalls_only_itself() {
+        let (client_end, server_end) = duplex(1024);
+        let gateway = MuxConnection::new(client_end, MuxRole::Client);
+        let mut server = MuxConnection::new(server_end, MuxRole::Server);
+
+        let mut stalled = gateway.open().await.unwrap();
+        let writer = tokio::spawn(async move {
+            // far more than the window, never read by the server
+            let _ = stalled.write_all(&vec![0u8; 1024 * 1024]).await;
+        });
+        let _unread = server.accept().await.unwrap();
+
+        let mut client = gateway.open().await.unwrap();
+        client.write_all(b"ping").await.unwrap();
+        let mut echo = server.accept().await.unwrap();
+        let mut buf = [0u8; 4];
+        echo.read_exact(&mut buf).await.unwrap();
+        echo.write_all(&buf).await.unwrap();
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        assert!(!writer.is_finished());
+    }
+
+    #[tokio::test]
+    async fn streams_over_the_limit_reset() {
+        let (client_end, server_end) = duplex(1024);
+        let gateway = MuxConnection::new(client_end, MuxRole::Client);
+        let mut server = MuxConnection::new(server_end, MuxRole::Server);
+        server.set_max_streams(1);
+
+        let mut first = gateway.open().await.unwrap();
+        first.write_all(b"a").await.unwrap();
+        let _accepted = server.accept().await.unwrap();
+        let mut second = gateway.open().await.unwrap();
+        second.write_all(b"b").await.unwrap();
+        let mut buf = [0u8; 1];
+        assert_eq!(second.read(&mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn window_overflow_resets_stream() {
+        let (mut peer, server_end) = duplex(1024);
+        let mut server = MuxConnection::new(server_end, MuxRole::Server);
+
+        peer.write_all(&frame(1, 1, 1, 0)).await.unwrap();
+        let mut stream = server.accept().await.unwrap();
+        // 3 GiB more in all, past the cap and a u32
+        for _ in 0..3 {
+            peer.write_all(&frame(1, 0, 1, 1 << 30)).await.unwrap();
+        }
+        read_reset(&mut peer, 1).await;
+        assert!(stream.write_all(b"late").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn remote_open_with_local_id_resets() {
+        let (mut peer, server_end) = duplex(1024);
+        let mut server = MuxConnection::new(server_end, MuxRole::Server);
+
+        // even ids are the server's
+        peer.write_all(&frame(1, 1, 2, 0)).await.unwrap();
+        read_reset(&mut peer, 2).await;
+        // the connection is still up
+        peer.write_all(&frame(1, 1, 1, 0)).await.unwrap();
+        let mut stream = server.accept().await.unwrap();
+        stream.write_all(b"hi").await.unwrap();
+        let mut reply = [0u8; 14];
+        peer.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply[4..8], &1u32.to_be_bytes());
+        assert_eq!(&reply[12..], b"hi");
+    }
+}
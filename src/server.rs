@@ -1,25 +1,160 @@
-use crate::util::stream::{tcp_connect_with_timeout, ConnectError};
-use crate::util::target_addr::{read_address, AddrError, TargetAddr};
+use crate::util::secret::Secret;
+use crate::util::socket_options::SocketOptions;
+use crate::util::stream::{tcp_connect_with_options, ConnectError};
+use crate::util::target_addr::{read_address, AddrError, ResolutionPreference, TargetAddr};
 use crate::{
-    consts, new_udp_header, parse_udp_request, read_exact, ready, AuthenticationMethod, ReplyError,
-    Socks5Command, SocksError, UdpHeaderError,
+    consts, read_exact, ready, AuthenticationMethod, ReplyError, Socks5Command, SocksError,
+    UdpHeaderError,
 };
 use anyhow::Context;
-use socket2::{Domain, Socket, Type};
+use resolution_audit::{resolve_reported, ResolutionHook};
+use std::fmt;
 use std::future::Future;
 use std::io;
 use std::marker::PhantomData;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs as StdToSocketAddrs};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::ops::Deref;
 use std::pin::Pin;
 use std::string::FromUtf8Error;
 use std::sync::Arc;
 use std::task::{Context as AsyncContext, Poll};
+use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream, ToSocketAddrs as AsyncToSocketAddrs, UdpSocket};
-use tokio::try_join;
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs as AsyncToSocketAddrs};
 use tokio_stream::Stream;
 
+mod acl;
+mod adaptive_buffer;
+mod auth;
+mod auth_once;
+mod close;
+mod debug_targets;
+mod dialer;
+mod dns_prefetch;
+mod dscp;
+mod early_close;
+mod early_data;
+mod egress;
+mod fair_share;
+#[cfg(feature = "flow-export")]
+mod flow_export;
+mod flows;
+mod geo_routing;
+mod geoip;
+mod handshake_limits;
+mod health;
+mod listener;
+mod log_sink;
+mod memory;
+#[cfg(windows)]
+mod named_pipe;
+mod panic_guard;
+#[cfg(all(unix, feature = "per-core"))]
+mod per_core;
+mod port_policy;
+mod quota;
+mod rate_limit;
+#[cfg(feature = "redis")]
+mod redis_store;
+mod replay;
+mod resolution_audit;
+mod reverse;
+mod schedule;
+mod session_addrs;
+mod session_id;
+mod sharded;
+#[cfg(all(unix, feature = "signal"))]
+mod signals;
+mod sniffer;
+mod state_store;
+mod static_hosts;
+mod tap;
+mod tasks;
+mod teardown;
+#[cfg(feature = "totp")]
+mod totp;
+#[cfg(all(target_os = "linux", feature = "transparent"))]
+mod transparent;
+mod udp;
+mod udp_shared;
+mod user_routes;
+#[cfg(feature = "webhook")]
+mod webhook;
+
+pub use acl::{AccessControl, AclAction, AclRequest, AclRule};
+#[cfg(feature = "password-hash")]
+pub use auth::PasswordHash;
+pub use auth::{verify_credentials, verify_password, NoAuthPolicy};
+pub use auth_once::AuthOnceAcceptor;
+pub use close::{transfer_until_closed, CloseReason, SessionCloser};
+pub use debug_targets::{DebugTarget, DebugTargets, TargetedLogger};
+pub use dialer::{
+    run_tcp_proxy_with_dialer, DialStream, DialedStream, Dialer, DialerRoutes, DirectDialer, Route,
+    UpstreamDialer,
+};
+pub use dns_prefetch::{system_lookup, DnsPrefetcher, DomainIpSet};
+pub use dscp::DscpPolicy;
+pub use early_close::EarlyCloseDetector;
+pub use early_data::EarlyDataStream;
+pub use egress::{EgressPool, EgressStrategy};
+pub use fair_share::{BandwidthShare, FairShare, FairShareStream};
+#[cfg(feature = "flow-export")]
+pub use flow_export::{FlowExporter, FlowRecord, FlowTap};
+pub use flows::{connect_udp_flow, relay_tcp_flow};
+pub use geo_routing::{
+    split_country_tag, CountryTagParser, GeoFallback, GeoRouteError, GeoRouter,
+};
+#[cfg(feature = "geoip")]
+pub use geoip::GeoIpDatabase;
+pub use geoip::{CountryLookup, CountryRule};
+pub use handshake_limits::{HandshakeLimits, HandshakeStream};
+#[cfg(feature = "admin")]
+pub use health::serve_health_http;
+pub use health::{HealthSnapshot, ListenerHealth};
+pub use listener::{ShedMode, Socks5Listener};
+pub use log_sink::AsyncLogSink;
+pub use memory::{MemoryBudget, MemoryReservation};
+#[cfg(windows)]
+pub use named_pipe::NamedPipeListener;
+pub use panic_guard::{catch_panic, CatchPanic, SessionPanic};
+#[cfg(all(unix, feature = "per-core"))]
+pub use per_core::PerCoreServer;
+pub use port_policy::{PortRule, ProtocolPolicy, ProtocolTap, SniffedProtocol, SMTP_PORTS};
+pub use quota::{
+    FileQuotaStore, QuotaKey, QuotaLimits, QuotaManager, QuotaSession, QuotaStore, QuotaUsage,
+};
+pub use rate_limit::ConnectionRateLimiter;
+#[cfg(feature = "redis")]
+pub use redis_store::RedisStateStore;
+pub use replay::{replay_handshake, ReplayAuth, ReplayReport, ReplayStep};
+pub use resolution_audit::{ResolutionRecord, ResolverKind};
+pub use reverse::ReverseListener;
+pub use schedule::{Schedule, SchedulePolicy};
+pub use session_addrs::SessionAddrs;
+pub use session_id::{SessionId, SessionLogger};
+#[cfg(all(unix, feature = "signal"))]
+pub use signals::run_with_signals;
+pub use sniffer::HandshakeSniffer;
+pub use state_store::{BanList, MemoryStateStore, StateStore};
+pub use static_hosts::StaticHosts;
+pub use tap::{transfer_tapped, StreamTap, TapAction, TapDirection};
+pub use tasks::SessionTasks;
+pub use teardown::TeardownMode;
+#[cfg(feature = "totp")]
+pub use totp::{split_second_factor, SecondFactor, Totp, TotpUsers, TwoFactorAuth};
+#[cfg(all(target_os = "linux", feature = "transparent"))]
+pub use transparent::{bind_transparent, TransparentMode, TransparentProxy};
+pub use udp::{
+    run_udp_proxy, run_udp_proxy_custom, run_udp_proxy_with_binding, run_udp_proxy_with_options,
+    transfer_udp, transfer_udp_association, transfer_udp_with_binding, wait_on_tcp,
+    UdpAddressFamily, UdpAssociation, UdpNatFilter, UdpNatTable, UdpOversizePolicy, UdpPeerBinding,
+    UdpProxyOptions, UdpRelayStats, UdpRelayStatsSnapshot, DEFAULT_MAX_DATAGRAM_SIZE,
+};
+pub use udp_shared::UdpSharedRelay;
+pub use user_routes::{UserRoute, UserRoutes};
+#[cfg(feature = "webhook")]
+pub use webhook::{WebhookConfig, WebhookEvent, WebhookNotifier};
+
 #[derive(thiserror::Error, Debug)]
 pub enum SocksServerError {
     #[error("i/o error when {context}: {source}")]
@@ -48,12 +183,56 @@ pub enum SocksServerError {
     UnknownCommand(u8),
     #[error("Unexpected garbage received on TCP stream used for UDP proxy keep-alive: `{0}`")]
     UnexpectedUdpControlGarbage(u8),
+    #[error("UDP datagram of {size} bytes exceeds the {max} bytes limit of the association")]
+    UdpDatagramTooLarge { size: usize, max: usize },
+    /// See `UdpAddressFamily`.
+    #[error("UDP target {0} is of an address family the relay can't reach")]
+    UdpFamilyUnreachable(SocketAddr),
+    #[error("Unsupported username/password subnegotiation version `{0}`.")]
+    UnsupportedPasswordAuthVersion(u8),
     #[error("Empty username received")]
     EmptyUsername,
     #[error("Empty password received")]
     EmptyPassword,
     #[error("Authentication rejected")]
     AuthenticationRejected,
+    /// The username is kept for the embedder, but never shown.
+    #[error("Authentication rejected: {reason}")]
+    AuthenticationFailed {
+        username: Secret<String>,
+        reason: String,
+    },
+    #[error("Client disconnected while {0}")]
+    ClientDisconnected(&'static str),
+    #[error("Handshake not completed within {0:?}")]
+    HandshakeTimeout(Duration),
+    #[error("Handshake cancelled")]
+    HandshakeCancelled,
+    /// See `HandshakeSniffer`, `None` for an unknown protocol.
+    #[error("Not a SOCKS client, but {0:?}")]
+    NotSocks(Option<SniffedProtocol>),
+    #[error("Handshake exceeds {0} bytes")]
+    HandshakeTooLarge(usize),
+    /// See `HandshakeLimits::set_diagnostics`.
+    #[error("Malformed handshake at byte {position} [{bytes}]: {source}")]
+    MalformedHandshake {
+        /// The first bytes read, in hexadecimal.
+        bytes: String,
+        /// How many bytes were read when the parsing failed.
+        position: usize,
+        source: Box<SocksServerError>,
+    },
+    /// See `DialerRoutes`.
+    #[error("Target blocked by the routing table")]
+    RouteBlocked,
+    /// See `QuotaManager::begin`.
+    #[error("Quota exceeded")]
+    QuotaExceeded,
+    #[error("Upstream proxy failed: {0}")]
+    Upstream(Box<crate::SocksError>),
+    /// The settings of the command are invalid, e.g. `UdpAssociation::validate` failed.
+    #[error(transparent)]
+    InvalidConfig(#[from] crate::ConfigError),
     #[error("End of stream")]
     EOF,
 }
@@ -63,9 +242,42 @@ impl SocksServerError {
         match self {
             SocksServerError::UnknownCommand(_) => ReplyError::CommandNotSupported,
             SocksServerError::AddrError(err) => err.to_reply_error(),
+            SocksServerError::ConnectError(err) => err.to_reply_error(),
+            SocksServerError::MalformedHandshake { source, .. } => source.to_reply_error(),
+            SocksServerError::RouteBlocked | SocksServerError::QuotaExceeded => {
+                ReplyError::ConnectionNotAllowed
+            }
+            SocksServerError::Upstream(err) => match err.as_ref() {
+                crate::SocksError::ReplyError(err) => *err,
+                crate::SocksError::ConnectError(err) => err.to_reply_error(),
+                _ => ReplyError::GeneralFailure,
+            },
             _ => ReplyError::GeneralFailure,
         }
     }
+
+    /// Whether the client sent bytes which aren't a valid handshake, as opposed to
+    /// disconnecting or an I/O error.
+    pub fn is_malformed(&self) -> bool {
+        match self {
+            SocksServerError::AddrError(err) => {
+                matches!(
+                    err,
+                    AddrError::IncorrectAddressType
+                        | AddrError::Utf8(_)
+                        | AddrError::InvalidDomain(_)
+                )
+            }
+            SocksServerError::FromUtf8 { .. }
+            | SocksServerError::UnsupportedSocksVersion(_)
+            | SocksServerError::UnknownCommand(_)
+            | SocksServerError::UnsupportedPasswordAuthVersion(_)
+            | SocksServerError::EmptyUsername
+            | SocksServerError::EmptyPassword
+            | SocksServerError::MalformedHandshake { .. } => true,
+            _ => false,
+        }
+    }
 }
 
 pub trait ErrorContext<T> {
@@ -103,6 +315,18 @@ pub struct Config<A: Authentication = DenyAuthentication> {
     auth: Option<Arc<A>>,
     /// Disables Nagle's algorithm for TCP
     nodelay: bool,
+    /// Address family preference when resolving domains
+    resolution: ResolutionPreference,
+    /// Addresses of domains taking precedence over DNS
+    static_hosts: Option<Arc<StaticHosts>>,
+    /// Called with each resolution of a domain
+    resolution_hook: Option<ResolutionHook>,
+    /// VER bytes accepted in the username/password subnegotiation, any if `None`
+    password_auth_versions: Option<Vec<u8>>,
+    /// Bounds on the handshake, none if `None`
+    handshake_limits: Option<HandshakeLimits>,
+    /// Relay the half-closes rather than closing both sides
+    half_close: bool,
 }
 
 impl<A: Authentication> Default for Config<A> {
@@ -116,6 +340,12 @@ impl<A: Authentication> Default for Config<A> {
             allow_no_auth: false,
             auth: None,
             nodelay: false,
+            resolution: ResolutionPreference::System,
+            static_hosts: None,
+            resolution_hook: None,
+            password_auth_versions: None,
+            handshake_limits: Some(HandshakeLimits::default()),
+            half_close: true,
         }
     }
 }
@@ -128,23 +358,85 @@ pub trait Authentication: Send + Sync {
     async fn authenticate(&self, credentials: Option<(String, String)>) -> Option<Self::Item>;
 }
 
+/// The handshake of `Socks5Socket::upgrade_to_socks5`, up to reading the command, along
+/// with the auth method and credentials if `config` has an authentication.
+async fn legacy_handshake<T: AsyncRead + AsyncWrite + Unpin, A: Authentication>(
+    config: &Config<A>,
+    inner: T,
+) -> Result<
+    (
+        Socks5ServerProtocol<T, states::CommandRead>,
+        Socks5Command,
+        TargetAddr,
+        Option<(AuthenticationMethod, A::Item)>,
+    ),
+    SocksServerError,
+> {
+    let (proto, auth) = match config.auth.as_ref() {
+        _ if config.skip_auth => {
+            debug!("skipping auth");
+            (
+                Socks5ServerProtocol::skip_auth_this_is_not_rfc_compliant(inner),
+                None,
+            )
+        }
+        None => {
+            let proto = Socks5ServerProtocol::start(inner)
+                .negotiate_auth(&[NoAuthentication])
+                .await?
+                .finish_auth();
+            (proto, None)
+        }
+        Some(auth_callback) => {
+            let methods = StandardAuthentication::allow_no_auth(config.allow_no_auth);
+            let auth = Socks5ServerProtocol::start(inner)
+                .negotiate_auth(methods)
+                .await?;
+            let (proto, method, creds) = authenticate_callback(
+                auth_callback.as_ref(),
+                auth,
+                config.password_auth_versions.as_deref(),
+            )
+            .await?;
+            (proto, Some((method, creds)))
+        }
+    };
+    let (proto, cmd, target_addr) = proto.read_command().await?;
+    Ok((proto, cmd, target_addr, auth))
+}
+
 async fn authenticate_callback<T: AsyncRead + AsyncWrite + Unpin, A: Authentication>(
     auth_callback: &A,
     auth: StandardAuthenticationStarted<T>,
-) -> Result<(Socks5ServerProtocol<T, states::Authenticated>, A::Item), SocksServerError> {
+    versions: Option<&[u8]>,
+) -> Result<
+    (
+        Socks5ServerProtocol<T, states::Authenticated>,
+        AuthenticationMethod,
+        A::Item,
+    ),
+    SocksServerError,
+> {
     match auth {
         StandardAuthenticationStarted::NoAuthentication(auth) => {
             if let Some(credentials) = auth_callback.authenticate(None).await {
-                Ok((auth.finish_auth(), credentials))
+                Ok((auth.finish_auth(), AuthenticationMethod::None, credentials))
             } else {
                 Err(SocksServerError::AuthenticationRejected)
             }
         }
-        StandardAuthenticationStarted::PasswordAuthentication(auth) => {
+        StandardAuthenticationStarted::PasswordAuthentication(mut auth) => {
+            if let Some(versions) = versions {
+                auth.set_accepted_versions(versions);
+            }
             let (username, password, auth) = auth.read_username_password().await?;
+            let method = AuthenticationMethod::Password {
+                username: username.clone(),
+                password: password.clone().into(),
+            };
             if let Some(credentials) = auth_callback.authenticate(Some((username, password))).await
             {
-                Ok((auth.accept().await?.finish_auth(), credentials))
+                Ok((auth.accept().await?.finish_auth(), method, credentials))
             } else {
                 auth.reject().await?;
                 Err(SocksServerError::AuthenticationRejected)
@@ -173,7 +465,7 @@ impl Authentication for SimpleUserPassword {
     async fn authenticate(&self, credentials: Option<(String, String)>) -> Option<Self::Item> {
         if let Some((username, password)) = credentials {
             // Client has supplied credentials
-            if username == self.username && password == self.password {
+            if verify_credentials(&username, &password, &self.username, &self.password) {
                 // Some() will allow the authentication and the credentials
                 // will be forwarded to the socket
                 Some(AuthSucceeded { username })
@@ -243,6 +535,12 @@ impl<A: Authentication> Config<A> {
             allow_no_auth: self.allow_no_auth,
             auth: Some(Arc::new(authentication)),
             nodelay: self.nodelay,
+            resolution: self.resolution,
+            static_hosts: self.static_hosts,
+            resolution_hook: self.resolution_hook,
+            password_auth_versions: self.password_auth_versions,
+            handshake_limits: self.handshake_limits,
+            half_close: self.half_close,
         }
     }
 
@@ -270,6 +568,74 @@ impl<A: Authentication> Config<A> {
         self.allow_udp = value;
         self
     }
+
+    /// Disable Nagle's algorithm on the outbound TCP connections
+    pub fn set_nodelay(&mut self, value: bool) -> &mut Self {
+        self.nodelay = value;
+        self
+    }
+
+    /// Which address of a domain to connect to when it has both IPv4 and IPv6 ones
+    pub fn set_resolution_preference(&mut self, value: ResolutionPreference) -> &mut Self {
+        self.resolution = value;
+        self
+    }
+
+    /// Resolve the domains found in `hosts` to their static addresses, without DNS
+    pub fn set_static_hosts(&mut self, hosts: Arc<StaticHosts>) -> &mut Self {
+        self.static_hosts = Some(hosts);
+        self
+    }
+
+    /// Report each resolution of a domain to `hook`, e.g. to audit DNS leaks
+    pub fn set_resolution_hook<F>(&mut self, hook: F) -> &mut Self
+    where
+        F: Fn(&ResolutionRecord) + Send + Sync + 'static,
+    {
+        self.resolution_hook = Some(ResolutionHook(Arc::new(hook)));
+        self
+    }
+
+    /// Only accept these VER bytes in the username/password subnegotiation, see
+    /// `PasswordAuthenticationImpl::set_accepted_versions`
+    pub fn set_password_auth_versions(&mut self, versions: &[u8]) -> &mut Self {
+        self.password_auth_versions = Some(versions.to_vec());
+        self
+    }
+
+    /// Bound the time and the bytes of the handshake, `HandshakeLimits::default()` by
+    /// default, `None` to not bound it.
+    pub fn set_handshake_limits(&mut self, limits: Option<HandshakeLimits>) -> &mut Self {
+        self.handshake_limits = limits;
+        self
+    }
+
+    /// Relay the half-closes of the TCP sessions, enabled by default, see
+    /// `TransferOptions::set_half_close`.
+    pub fn set_half_close(&mut self, value: bool) -> &mut Self {
+        self.half_close = value;
+        self
+    }
+
+    async fn resolve(&self, addr: TargetAddr) -> Result<TargetAddr, AddrError> {
+        if addr.is_ip() {
+            return Ok(addr);
+        }
+        let addrs = resolve_reported(
+            &addr,
+            self.resolution,
+            self.static_hosts.as_deref(),
+            self.resolution_hook.as_ref(),
+        )
+        .await?;
+        Ok(TargetAddr::Ip(addrs[0]))
+    }
+
+    /// Former name of `set_execute_command`
+    #[deprecated(since = "0.9.0", note = "Use `set_execute_command` instead")]
+    pub fn set_transfer_data(&mut self, value: bool) -> &mut Self {
+        self.set_execute_command(value)
+    }
 }
 
 /// Wrapper of TcpListener
@@ -339,13 +705,15 @@ impl<'a, A: Authentication> Stream for Incoming<'a, A> {
                 self.1 = None;
 
                 let local_addr = socket.local_addr()?;
-                debug!(
-                    "incoming connection from peer {} @ {}",
-                    &peer_addr, &local_addr
-                );
-
                 // Wrap the TcpStream into Socks5Socket
                 let socket = Socks5Socket::new(socket, self.0.config.clone());
+                socket.closer().set_client_addrs(peer_addr, local_addr);
+                debug!(
+                    "session {} incoming from peer {} @ {}",
+                    socket.session_id(),
+                    &peer_addr,
+                    &local_addr
+                );
 
                 return Poll::Ready(Some(Ok(socket)));
             }
@@ -369,6 +737,10 @@ pub struct Socks5Socket<T: AsyncRead + AsyncWrite + Unpin, A: Authentication> {
     /// If the client has been authenticated, that's where we store his credentials
     /// to be accessed from the socket
     credentials: Option<A::Item>,
+    /// Id of the session, assigned on accept
+    session: SessionId,
+    /// Handle of the session, see `SessionCloser::scope`
+    closer: SessionCloser,
 }
 
 pub mod states {
@@ -377,29 +749,173 @@ pub mod states {
     pub struct CommandRead;
 }
 
+/// The server side of the SOCKS5 handshake over a client stream `T`, in the state `S`.
+///
+/// # Cancel safety
+///
+/// The handshake steps take the protocol by value: when one of their futures is dropped
+/// (a timeout, a `select!` branch...), the client stream is dropped with it, so a
+/// handshake cut midway can't be resumed on a desynced stream. To get the stream back
+/// instead, e.g. to reply an error or close it gracefully:
+///
+/// - run the handshake over `&mut stream`, the caller keeping the stream,
+/// - read the request with `read_command_until`, which hands the protocol back when
+///   cancelled,
+/// - end the relays with a `SessionCloser` rather than by dropping them, the streams
+///   relayed by `transfer_until_closed` over `&mut` being left to the caller once it
+///   returns.
+///
+/// `while_client_connected` is cancel safe, the data read from the client is kept.
 pub struct Socks5ServerProtocol<T, S> {
     inner: T,
+    /// Bytes sent by the client before the reply, read while watching it for a disconnection.
+    early_data: Vec<u8>,
+    /// Delay before answering a failed authentication, see `set_auth_failure_delay`.
+    auth_failure_delay: Duration,
+    delay_unacceptable_method: bool,
+    silent_unacceptable_method: bool,
+    reply_privacy: ReplyAddrPrivacy,
+    method_preference: MethodPreference,
     _state: PhantomData<S>,
 }
 
+/// Whose order decides the auth method when the client offers several acceptable ones,
+/// see `Socks5ServerProtocol::set_method_preference`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MethodPreference {
+    /// The first of `server_methods` offered by the client, e.g. password authentication
+    /// over no authentication when both are listed in that order.
+    #[default]
+    Server,
+    /// The first method offered by the client which is in `server_methods`.
+    Client,
+}
+
+/// What BND.ADDR the server replies with on success, see
+/// `Socks5ServerProtocol::set_reply_addr_privacy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReplyAddrPrivacy {
+    /// The address passed to `reply_success`.
+    #[default]
+    Off,
+    /// The unspecified IP of the same family, `0.0.0.0` or `::`, keeping the port which
+    /// UDP clients need. Clients use the IP of the proxy instead.
+    Unspecified,
+    /// Always this address, hiding the port too, for CONNECT only.
+    Fixed(SocketAddr),
+}
+
+impl ReplyAddrPrivacy {
+    fn apply(self, addr: SocketAddr) -> SocketAddr {
+        match self {
+            ReplyAddrPrivacy::Off => addr,
+            ReplyAddrPrivacy::Unspecified => {
+                let ip = match addr {
+                    SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                    SocketAddr::V6(_) => IpAddr::V6(std::net::Ipv6Addr::UNSPECIFIED),
+                };
+                SocketAddr::new(ip, addr.port())
+            }
+            ReplyAddrPrivacy::Fixed(fixed) => fixed,
+        }
+    }
+}
+
 impl<T, S> Socks5ServerProtocol<T, S> {
     fn new(inner: T) -> Self {
         Socks5ServerProtocol {
             inner,
+            early_data: Vec::new(),
+            auth_failure_delay: Duration::ZERO,
+            delay_unacceptable_method: false,
+            silent_unacceptable_method: false,
+            reply_privacy: ReplyAddrPrivacy::Off,
+            method_preference: MethodPreference::Server,
             _state: PhantomData,
         }
     }
+
+    /// Borrow the client stream, e.g. to set socket options.
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Mutably borrow the client stream. Reading or writing it during the handshake
+    /// breaks the protocol.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Take the client stream, to relay it or run the rest of the protocol yourself.
+    ///
+    /// The data the client sent early, if any, is lost, see `take_early_data`.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
 }
 
+/// The most bytes buffered from a client sending data before the reply, see
+/// `Socks5ServerProtocol::while_client_connected`.
+const MAX_EARLY_DATA: usize = 16 * 1024;
+
 impl<T> Socks5ServerProtocol<T, states::Opened> {
     /// Start handling the SOCKS5 protocol flow, wrapping a client socket.
     pub fn start(inner: T) -> Self {
         Self::new(inner)
     }
+
+    /// Wait `delay` before answering a failed authentication attempt, to slow down online
+    /// brute force. Legitimate clients, which succeed, aren't delayed.
+    ///
+    /// The delay is passed to the negotiated method, see `AuthMethod::new_with_failure_delay`.
+    pub fn set_auth_failure_delay(&mut self, delay: Duration) -> &mut Self {
+        self.auth_failure_delay = delay;
+        self
+    }
+
+    /// Also wait the auth failure delay before refusing a client offering no acceptable
+    /// method, e.g. a scanner probing for open proxies.
+    pub fn set_delay_unacceptable_method(&mut self, value: bool) -> &mut Self {
+        self.delay_unacceptable_method = value;
+        self
+    }
+
+    /// Close the connection of a client offering no acceptable method without replying,
+    /// instead of the RFC's "no acceptable methods", so that scanners can't tell a SOCKS
+    /// server is listening.
+    pub fn set_silent_unacceptable_method(&mut self, value: bool) -> &mut Self {
+        self.silent_unacceptable_method = value;
+        self
+    }
+
+    /// Pick the auth method in the order of `server_methods` (the default) or in the
+    /// order the client offered them.
+    pub fn set_method_preference(&mut self, preference: MethodPreference) -> &mut Self {
+        self.method_preference = preference;
+        self
+    }
 }
 
 pub trait CheckResult {
     fn is_good(&self) -> bool;
+
+    /// Why the check failed, logged and returned to the embedder but never sent to the
+    /// client, which only gets the generic failure byte.
+    fn rejection_reason(&self) -> Option<String> {
+        None
+    }
+}
+
+/// Typical reasons for a password check to fail, for checks returning
+/// `WithReason<_, AuthFailure>`.
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthFailure {
+    #[error("unknown user")]
+    UnknownUser,
+    #[error("wrong password")]
+    BadPassword,
+    #[error("too many attempts")]
+    Throttled,
 }
 
 impl CheckResult for bool {
@@ -420,6 +936,27 @@ impl<T, E> CheckResult for Result<T, E> {
     }
 }
 
+/// The result of a check reporting why it failed: its error, e.g. an [`AuthFailure`], is
+/// the reason of [`SocksServerError::AuthenticationFailed`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WithReason<T, E>(pub Result<T, E>);
+
+impl<T, E> From<Result<T, E>> for WithReason<T, E> {
+    fn from(res: Result<T, E>) -> Self {
+        WithReason(res)
+    }
+}
+
+impl<T, E: fmt::Display> CheckResult for WithReason<T, E> {
+    fn is_good(&self) -> bool {
+        self.0.is_ok()
+    }
+
+    fn rejection_reason(&self) -> Option<String> {
+        self.0.as_ref().err().map(|err| err.to_string())
+    }
+}
+
 impl<T> Socks5ServerProtocol<T, states::Authenticated> {
     /// Finish handling the authentication method-specific part of the protocol,
     /// returning back to the overall SOCKS5 flow.
@@ -450,27 +987,41 @@ impl<T> Socks5ServerProtocol<T, states::Authenticated> {
     /// and verify the provided username and password using the provided closure.
     ///
     /// The closure can mutate state variables and/or return a result as `Option`/`Result`.
+    /// Wrapped in a [`WithReason`], the error of the `Result`, e.g. an [`AuthFailure`], is
+    /// the reason of the rejection reported by [`SocksServerError::AuthenticationFailed`].
     pub async fn accept_password_auth<F, R>(
         inner: T,
-        mut check: F,
+        check: F,
     ) -> Result<(Self, R), SocksServerError>
     where
         T: AsyncWrite + AsyncRead + Unpin,
         F: FnMut(String, String) -> R,
         R: CheckResult,
     {
-        let (user, pass, auth) = Socks5ServerProtocol::start(inner)
+        Socks5ServerProtocol::start(inner)
             .negotiate_auth(&[PasswordAuthentication])
             .await?
-            .read_username_password()
+            .check_username_password(check)
+            .await
+    }
+
+    /// Like `accept_password_auth`, only accepting the subnegotiation `versions`, see
+    /// `PasswordAuthenticationImpl::set_accepted_versions`.
+    pub async fn accept_password_auth_with_versions<F, R>(
+        inner: T,
+        versions: &[u8],
+        check: F,
+    ) -> Result<(Self, R), SocksServerError>
+    where
+        T: AsyncWrite + AsyncRead + Unpin,
+        F: FnMut(String, String) -> R,
+        R: CheckResult,
+    {
+        let mut auth = Socks5ServerProtocol::start(inner)
+            .negotiate_auth(&[PasswordAuthentication])
             .await?;
-        let check_result = check(user, pass);
-        if check_result.is_good() {
-            Ok((auth.accept().await?.finish_auth(), check_result))
-        } else {
-            auth.reject().await?;
-            Err(SocksServerError::AuthenticationRejected)
-        }
+        auth.set_accepted_versions(versions);
+        auth.check_username_password(check).await
     }
 }
 
@@ -499,6 +1050,16 @@ pub trait AuthMethod<T>: Copy {
     type StartingState;
     fn method_id(self) -> u8;
     fn new(self, inner: T) -> Self::StartingState;
+
+    /// Like `new`, for a method which should wait `delay` before answering a failed
+    /// authentication attempt. Methods without failure ignore it.
+    fn new_with_failure_delay(self, inner: T, delay: Duration) -> Self::StartingState
+    where
+        Self: Sized,
+    {
+        let _ = delay;
+        self.new(inner)
+    }
 }
 
 pub struct NoAuthenticationImpl<T>(T);
@@ -524,7 +1085,7 @@ impl<T> AuthMethod<T> for NoAuthentication {
     type StartingState = NoAuthenticationImpl<T>;
 
     fn method_id(self) -> u8 {
-        0x00
+        consts::SOCKS5_AUTH_METHOD_NONE
     }
 
     fn new(self, inner: T) -> Self::StartingState {
@@ -540,6 +1101,8 @@ mod password_states {
 
 pub struct PasswordAuthenticationImpl<T, S> {
     inner: T,
+    failure_delay: Duration,
+    accepted_versions: Option<Vec<u8>>,
     _state: PhantomData<S>,
 }
 
@@ -549,15 +1112,40 @@ impl<T, S> PasswordAuthenticationImpl<T, S> {
     fn new(inner: T) -> Self {
         PasswordAuthenticationImpl {
             inner,
+            failure_delay: Duration::ZERO,
+            accepted_versions: None,
             _state: PhantomData,
         }
     }
+
+    fn into_state<S2>(self) -> PasswordAuthenticationImpl<T, S2> {
+        PasswordAuthenticationImpl {
+            inner: self.inner,
+            failure_delay: self.failure_delay,
+            accepted_versions: self.accepted_versions,
+            _state: PhantomData,
+        }
+    }
+
+    /// Wait `delay` before rejecting wrong credentials, to slow down online brute force.
+    pub fn set_failure_delay(&mut self, delay: Duration) -> &mut Self {
+        self.failure_delay = delay;
+        self
+    }
+
+    /// Only accept these VER bytes in the username/password subnegotiation, any by
+    /// default. RFC 1929 sends `0x01`, some buggy clients the SOCKS version `0x05`
+    /// instead, `&[0x01, 0x05]` is strict but tolerates them.
+    pub fn set_accepted_versions(&mut self, versions: &[u8]) -> &mut Self {
+        self.accepted_versions = Some(versions.to_vec());
+        self
+    }
 }
 
 impl<T: AsyncRead + Unpin> PasswordAuthenticationImpl<T, password_states::Started> {
     /// Handle the username and password sent by the client.
     pub async fn read_username_password(
-        self,
+        mut self,
     ) -> Result<
         (
             String,
@@ -566,24 +1154,26 @@ impl<T: AsyncRead + Unpin> PasswordAuthenticationImpl<T, password_states::Starte
         ),
         SocksServerError,
     > {
-        let mut socket = self.inner;
         trace!("PasswordAuthenticationStarted: read_username_password()");
-        let [version, user_len] = read_exact!(socket, [0u8; 2]).err_when("reading user len")?;
+        let [version, user_len] = read_exact!(self.inner, [0u8; 2]).err_when("reading user len")?;
         debug!(
             "Auth: [version: {version}, user len: {len}]",
             version = version,
             len = user_len,
         );
 
+        if matches!(&self.accepted_versions, Some(versions) if !versions.contains(&version)) {
+            return Err(SocksServerError::UnsupportedPasswordAuthVersion(version));
+        }
+
         if user_len < 1 {
             return Err(SocksServerError::EmptyUsername);
         }
 
         let username =
-            read_exact!(socket, vec![0u8; user_len as usize]).err_when("reading username")?;
-        debug!("username bytes: {:?}", &username);
+            read_exact!(self.inner, vec![0u8; user_len as usize]).err_when("reading username")?;
 
-        let [pass_len] = read_exact!(socket, [0u8; 1]).err_when("reading password len")?;
+        let [pass_len] = read_exact!(self.inner, [0u8; 1]).err_when("reading password len")?;
         debug!("Auth: [pass len: {len}]", len = pass_len,);
 
         if pass_len < 1 {
@@ -591,36 +1181,79 @@ impl<T: AsyncRead + Unpin> PasswordAuthenticationImpl<T, password_states::Starte
         }
 
         let password =
-            read_exact!(socket, vec![0u8; pass_len as usize]).err_when("reading password")?;
-        debug!("password bytes: {:?}", &password);
+            read_exact!(self.inner, vec![0u8; pass_len as usize]).err_when("reading password")?;
 
         let username = String::from_utf8(username).err_when("converting username")?;
         let password = String::from_utf8(password).err_when("converting password")?;
 
-        Ok((username, password, PasswordAuthenticationImpl::new(socket)))
+        Ok((username, password, self.into_state()))
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> PasswordAuthenticationImpl<T, password_states::Started> {
+    /// Read the username and password sent by the client and accept or reject them
+    /// according to `check`, see `Socks5ServerProtocol::accept_password_auth`.
+    pub async fn check_username_password<F, R>(
+        self,
+        check: F,
+    ) -> Result<(Socks5ServerProtocol<T, states::Authenticated>, R), SocksServerError>
+    where
+        F: FnOnce(String, String) -> R,
+        R: CheckResult,
+    {
+        let (user, pass, auth) = self.read_username_password().await?;
+        let username = Secret::new(user.clone());
+        let check_result = check(user, pass);
+        if check_result.is_good() {
+            return Ok((auth.accept().await?.finish_auth(), check_result));
+        }
+        auth.reject().await?;
+        match check_result.rejection_reason() {
+            Some(reason) => {
+                info!("Password authentication failed: {}", reason);
+                Err(SocksServerError::AuthenticationFailed { username, reason })
+            }
+            None => Err(SocksServerError::AuthenticationRejected),
+        }
     }
 }
 
-impl<T: AsyncWrite + Unpin> PasswordAuthenticationImpl<T, password_states::Received> {
+impl<T: AsyncRead + AsyncWrite + Unpin> PasswordAuthenticationImpl<T, password_states::Received> {
     /// Notify the client with a "SUCCEEDED" reply and proceed to finish the authentication.
     pub async fn accept(
         mut self,
     ) -> Result<PasswordAuthenticationImpl<T, password_states::Finished>, SocksServerError> {
         self.inner
-            .write_all(&[1, consts::SOCKS5_REPLY_SUCCEEDED])
+            .write_all(&[
+                consts::SOCKS5_PASSWORD_AUTH_VERSION,
+                consts::SOCKS5_PASSWORD_AUTH_SUCCEEDED,
+            ])
             .await
             .err_when("replying auth success")?;
 
         info!("Password authentication accepted.");
-        Ok(PasswordAuthenticationImpl::new(self.inner))
+        Ok(self.into_state())
     }
 
-    /// Notify the client with a "NOT_ACCEPTABLE" reply and drop the socket.
+    /// Notify the client with a "NOT_ACCEPTABLE" reply, after the failure delay if any,
+    /// and close the socket, see `reply_and_close`.
     pub async fn reject(mut self) -> Result<(), SocksServerError> {
-        self.inner
-            .write_all(&[1, consts::SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE])
-            .await
-            .err_when("replying with auth method not acceptable")?;
+        if !self.failure_delay.is_zero() {
+            debug!(
+                "Delaying the password rejection by {:?}",
+                self.failure_delay
+            );
+            tokio::time::sleep(self.failure_delay).await;
+        }
+        reply_and_close(
+            &mut self.inner,
+            &[
+                consts::SOCKS5_PASSWORD_AUTH_VERSION,
+                consts::SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE,
+            ],
+            "replying with auth method not acceptable",
+        )
+        .await?;
 
         info!("Password authentication rejected.");
         Ok(())
@@ -641,12 +1274,18 @@ impl<T> AuthMethod<T> for PasswordAuthentication {
     type StartingState = PasswordAuthenticationImpl<T, password_states::Started>;
 
     fn method_id(self) -> u8 {
-        0x02
+        consts::SOCKS5_AUTH_METHOD_PASSWORD
     }
 
     fn new(self, inner: T) -> Self::StartingState {
         PasswordAuthenticationImpl::new(inner)
     }
+
+    fn new_with_failure_delay(self, inner: T, delay: Duration) -> Self::StartingState {
+        let mut auth = PasswordAuthenticationImpl::new(inner);
+        auth.set_failure_delay(delay);
+        auth
+    }
 }
 
 #[macro_export]
@@ -682,6 +1321,18 @@ macro_rules! auth_method_enums {
                     $($enum::$method(auth) => $state_enum::$method(auth.new(inner))),+
                 }
             }
+
+            fn new_with_failure_delay(
+                self,
+                inner: T,
+                delay: std::time::Duration,
+            ) -> Self::StartingState {
+                match self {
+                    $($enum::$method(auth) => {
+                        $state_enum::$method(auth.new_with_failure_delay(inner, delay))
+                    }),+
+                }
+            }
         }
     };
 }
@@ -726,9 +1377,22 @@ impl<T: AsyncRead + AsyncWrite + Unpin, A: Authentication> Socks5Socket<T, A> {
             cmd: None,
             reply_ip: None,
             credentials: None,
+            session: SessionId::next(),
+            closer: SessionCloser::new(),
         }
     }
 
+    /// The handle of the session, to end its relay from elsewhere or to list its
+    /// addresses, see [`SessionCloser::scope`].
+    pub fn closer(&self) -> &SessionCloser {
+        &self.closer
+    }
+
+    /// The id the session runs under, see [`SessionId::current`].
+    pub fn session_id(&self) -> SessionId {
+        self.session
+    }
+
     /// Set the bind IP address in Socks5Reply.
     ///
     /// Only the inner socket owner knows the correct reply bind addr, so leave this field to be
@@ -746,43 +1410,51 @@ impl<T: AsyncRead + AsyncWrite + Unpin, A: Authentication> Socks5Socket<T, A> {
 
     /// Process clients SOCKS requests
     /// This is the entry point where a whole request is processed.
-    pub async fn upgrade_to_socks5(mut self) -> Result<Socks5Socket<T, A>, SocksError> {
+    ///
+    /// Runs under the id and the closer of the session, and an error is returned in a
+    /// `SocksError::Session` with the id.
+    pub async fn upgrade_to_socks5(self) -> Result<Socks5Socket<T, A>, SocksError> {
+        let session = self.session;
+        let closer = self.closer.clone();
+        session
+            .scope(closer.scope(self.upgrade()))
+            .await
+            .map_err(|source| SocksError::Session {
+                session,
+                source: Box::new(source),
+            })
+    }
+
+    async fn upgrade(mut self) -> Result<Socks5Socket<T, A>, SocksError> {
         trace!("upgrading to socks5...");
 
         // NOTE: this cannot be split in two without making self.inner an Option
 
         // Handshake
-        let proto = match self.config.auth.as_ref() {
-            _ if self.config.skip_auth => {
-                debug!("skipping auth");
-                Socks5ServerProtocol::skip_auth_this_is_not_rfc_compliant(self.inner)
-            }
-            None => Socks5ServerProtocol::start(self.inner)
-                .negotiate_auth(&[NoAuthentication])
-                .await?
-                .finish_auth(),
-            Some(auth_callback) => {
-                let methods = StandardAuthentication::allow_no_auth(self.config.allow_no_auth);
-                let auth = Socks5ServerProtocol::start(self.inner)
-                    .negotiate_auth(methods)
+        let config = self.config.clone();
+        let (mut proto, cmd, target_addr, auth) = match &config.handshake_limits {
+            Some(limits) => {
+                let (proto, cmd, target_addr, auth) = limits
+                    .run(self.inner, |s| legacy_handshake(&config, s))
                     .await?;
-                let (proto, creds) = authenticate_callback(auth_callback.as_ref(), auth).await?;
-                self.credentials = Some(creds);
-                proto
+                let proto = Socks5ServerProtocol::new(proto.inner.into_inner());
+                (proto, cmd, target_addr, auth)
             }
+            None => legacy_handshake(&config, self.inner).await?,
         };
+        if let Some((method, creds)) = auth {
+            self.auth = method;
+            self.credentials = Some(creds);
+        }
 
-        let (proto, cmd, target_addr) = {
-            let triple = proto.read_command().await?;
-
-            if self.config.dns_resolve {
-                triple.resolve_dns().await?
-            } else {
-                debug!(
-                    "Domain won't be resolved because `dns_resolve`'s config has been turned off."
-                );
-                triple
-            }
+        let target_addr = if self.config.dns_resolve {
+            let resolved_addr = proto
+                .while_client_connected(self.config.resolve(target_addr))
+                .await?;
+            try_notify!(proto, resolved_addr)
+        } else {
+            debug!("Domain won't be resolved because `dns_resolve`'s config has been turned off.");
+            target_addr
         };
 
         match cmd {
@@ -791,23 +1463,19 @@ impl<T: AsyncRead + AsyncWrite + Unpin, A: Authentication> Socks5Socket<T, A> {
                 self.inner = proto.inner;
             }
             Socks5Command::TCPConnect => {
-                self.inner = run_tcp_proxy(
-                    proto,
-                    &target_addr,
-                    self.config.request_timeout,
-                    self.config.nodelay,
-                )
-                .await?;
+                let mut opts = ConnectOptions::new();
+                opts.set_request_timeout(self.config.request_timeout)
+                    .set_nodelay(self.config.nodelay)
+                    .set_resolution_preference(self.config.resolution)
+                    .set_half_close(self.config.half_close);
+                // the domains left unresolved by `dns_resolve == false`
+                opts.static_hosts = self.config.static_hosts.clone();
+                opts.resolution_hook = self.config.resolution_hook.clone();
+                self.inner = run_tcp_proxy_with_options(proto, &target_addr, &opts).await?;
             }
             Socks5Command::UDPAssociate if self.config.allow_udp => {
-                self.inner = run_udp_proxy(
-                    proto,
-                    &target_addr,
-                    None,
-                    self.reply_ip.context("invalid reply ip")?,
-                    None,
-                )
-                .await?;
+                let opts = UdpProxyOptions::new(self.reply_ip.context("invalid reply ip")?);
+                self.inner = run_udp_proxy_with_options(proto, &target_addr, opts).await?;
             }
             _ => {
                 proto.reply_error(&ReplyError::CommandNotSupported).await?;
@@ -831,7 +1499,7 @@ impl<T: AsyncRead + AsyncWrite + Unpin, A: Authentication> Socks5Socket<T, A> {
         if let Some(target_addr) = self.target_addr.take() {
             // decide whether we have to resolve DNS or not
             self.target_addr = match target_addr {
-                TargetAddr::Domain(_, _) => Some(target_addr.resolve_dns().await?),
+                TargetAddr::Domain(_, _) => Some(self.config.resolve(target_addr).await?),
                 TargetAddr::Ip(_) => Some(target_addr),
             };
         }
@@ -869,7 +1537,9 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Socks5ServerProtocol<T, states::Opened>
     /// Negotiate an authentication method from a list of supported ones and initialize it.
     ///
     /// Internally, this reads the list of authentication methods provided by the client, and
-    /// picks the first one for which there exists an implementation in `server_methods`.
+    /// picks the first method of `server_methods` which the client offered, so list the
+    /// preferred methods first. See `set_method_preference` to follow the client's order
+    /// instead.
     ///
     /// If none of the auth methods requested by the client are in `server_methods`,
     /// returns a `SocksServerError::AuthMethodUnacceptable`.
@@ -893,41 +1563,84 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Socks5ServerProtocol<T, states::Opened>
         // {METHODS available from the client}
         // eg. (non-auth) {0, 1}
         // eg. (auth)     {0, 1, 2}
-        let methods =
-            read_exact!(self.inner, vec![0u8; methods_len as usize]).err_when("reading methods")?;
+        let mut methods = [0u8; 255];
+        let methods = &mut methods[..methods_len as usize];
+        self.inner
+            .read_exact(methods)
+            .await
+            .err_when("reading methods")?;
         debug!("methods supported sent by the client: {:?}", &methods);
 
-        // server_methods order matter!
-        // the server could choose to prioritize methods
-        for server_method in server_methods {
-            for client_method_id in methods.iter() {
-                if server_method.method_id() == *client_method_id {
-                    debug!("Reply with method {}", *client_method_id);
-                    self.inner
-                        .write_all(&[consts::SOCKS5_VERSION, *client_method_id])
-                        .await
-                        .err_when("replying with auth method")?;
-                    return Ok(server_method.new(self.inner));
-                }
-            }
+        let chosen = match self.method_preference {
+            MethodPreference::Server => server_methods
+                .iter()
+                .find(|server_method| methods.contains(&server_method.method_id())),
+            MethodPreference::Client => methods.iter().find_map(|client_method_id| {
+                server_methods
+                    .iter()
+                    .find(|server_method| server_method.method_id() == *client_method_id)
+            }),
+        };
+        if let Some(server_method) = chosen {
+            let method_id = server_method.method_id();
+            debug!("Reply with method {}", method_id);
+            self.inner
+                .write_all(&[consts::SOCKS5_VERSION, method_id])
+                .await
+                .err_when("replying with auth method")?;
+            return Ok(server_method.new_with_failure_delay(self.inner, self.auth_failure_delay));
         }
 
-        debug!("No auth method supported by both client and server, reply with (0xff)");
-        self.inner
-            .write_all(&[
-                consts::SOCKS5_VERSION,
-                consts::SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE,
-            ])
-            .await
-            .err_when("replying with method not acceptable")?;
-        Err(SocksServerError::AuthMethodUnacceptable(methods))
+        if self.delay_unacceptable_method && !self.auth_failure_delay.is_zero() {
+            tokio::time::sleep(self.auth_failure_delay).await;
+        }
+        if self.silent_unacceptable_method {
+            debug!("No auth method supported by both client and server, closing silently");
+        } else {
+            debug!("No auth method supported by both client and server, reply with (0xff)");
+            reply_and_close(
+                &mut self.inner,
+                &[
+                    consts::SOCKS5_VERSION,
+                    consts::SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE,
+                ],
+                "replying with method not acceptable",
+            )
+            .await?;
+        }
+        Err(SocksServerError::AuthMethodUnacceptable(methods.to_vec()))
     }
 }
 
 impl<T: AsyncRead + AsyncWrite + Unpin> Socks5ServerProtocol<T, states::CommandRead> {
     /// Reply success to the client according to the RFC.
     /// This consumes the wrapper as after this message actual proxying should begin.
+    ///
+    /// Any data the client sent early must be taken with `take_early_data` before, and
+    /// forwarded, or use `reply_success_buffered`.
     pub async fn reply_success(mut self, sock_addr: SocketAddr) -> Result<T, SocksServerError> {
+        if !self.early_data.is_empty() {
+            warn!(
+                "Dropping {} bytes the client sent before the reply",
+                self.early_data.len()
+            );
+        }
+        self.write_success(sock_addr).await?;
+        Ok(self.inner)
+    }
+
+    /// Like `reply_success`, the returned stream reading the data the client sent early
+    /// first, if any.
+    pub async fn reply_success_buffered(
+        mut self,
+        sock_addr: SocketAddr,
+    ) -> Result<EarlyDataStream<T>, SocksServerError> {
+        self.write_success(sock_addr).await?;
+        Ok(EarlyDataStream::new(self.inner, self.early_data))
+    }
+
+    async fn write_success(&mut self, sock_addr: SocketAddr) -> Result<(), SocksServerError> {
+        let sock_addr = self.reply_privacy.apply(sock_addr);
         self.inner
             .write(&new_reply(&ReplyError::Succeeded, sock_addr))
             .await
@@ -936,7 +1649,7 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Socks5ServerProtocol<T, states::CommandR
         self.inner.flush().await.err_when("flushing auth reply")?;
 
         debug!("Wrote success");
-        Ok(self.inner)
+        Ok(())
     }
 
     /// Reply error to the client with the reply code according to the RFC.
@@ -944,14 +1657,64 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Socks5ServerProtocol<T, states::CommandR
         let reply = new_reply(error, "0.0.0.0:0".parse().unwrap());
         debug!("reply error to be written: {:?}", &reply);
 
-        self.inner
-            .write(&reply)
-            .await
-            .err_when("writing unsuccessful reply")?;
+        reply_and_close(&mut self.inner, &reply, "writing unsuccessful reply").await
+    }
 
-        self.inner.flush().await.err_when("flushing auth reply")?;
+    /// Run `dial` (resolving, connecting to the target or to an upstream proxy...) while
+    /// watching the client, and cancel it as soon as the client disconnects, so orphaned
+    /// dials don't keep occupying sockets.
+    ///
+    /// Returns `SocksServerError::ClientDisconnected` if the client reset the connection
+    /// first. A client which only shut down its write side still waits for the reply, the
+    /// dial goes on. Clients sending data optimistically before the reply are fine, the
+    /// data is kept and must be forwarded to the target, see `take_early_data`.
+    ///
+    /// # Cancel safety
+    ///
+    /// This method is cancel safe if reading `T` is, as for `TcpStream`: the early data
+    /// read before is kept, and `dial` is dropped.
+    pub async fn while_client_connected<F: Future>(
+        &mut self,
+        dial: F,
+    ) -> Result<F::Output, SocksServerError> {
+        tokio::pin!(dial);
+        let mut buf = [0u8; 1024];
+        while self.early_data.len() < MAX_EARLY_DATA {
+            let max = buf.len().min(MAX_EARLY_DATA - self.early_data.len());
+            tokio::select! {
+                res = &mut dial => return Ok(res),
+                read = self.inner.read(&mut buf[..max]) => match read {
+                    Ok(0) => {
+                        debug!("Client half-closed the connection, waiting for the dial");
+                        break;
+                    }
+                    Ok(n) => self.early_data.extend_from_slice(&buf[..n]),
+                    Err(err) if matches!(
+                        err.kind(),
+                        io::ErrorKind::ConnectionReset
+                            | io::ErrorKind::ConnectionAborted
+                            | io::ErrorKind::BrokenPipe
+                    ) => {
+                        debug!("Client disconnected ({}), cancelling the dial", err);
+                        return Err(SocksServerError::ClientDisconnected("dialing"));
+                    }
+                    Err(err) => return Err(err).err_when("watching the client while dialing"),
+                },
+            }
+        }
+        Ok(dial.await)
+    }
 
-        Ok(())
+    /// Take the data the client sent before the reply, if any, see `while_client_connected`.
+    pub fn take_early_data(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.early_data)
+    }
+
+    /// Hide the address passed to `reply_success` from the client, so that replies don't
+    /// leak the internal topology, such as the private IPs of the server.
+    pub fn set_reply_addr_privacy(&mut self, privacy: ReplyAddrPrivacy) -> &mut Self {
+        self.reply_privacy = privacy;
+        self
     }
 }
 
@@ -972,6 +1735,8 @@ macro_rules! try_notify {
     };
 }
 
+pub(crate) use try_notify;
+
 impl<T: AsyncRead + AsyncWrite + Unpin> Socks5ServerProtocol<T, states::Authenticated> {
     /// Decide to whether or not, accept the authentication method.
     /// Don't forget that the methods list sent by the client, contains one or more methods.
@@ -988,7 +1753,7 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Socks5ServerProtocol<T, states::Authenti
     /// It the request is correct, it should returns a ['SocketAddr'].
     ///
     pub async fn read_command(
-        mut self,
+        self,
     ) -> Result<
         (
             Socks5ServerProtocol<T, states::CommandRead>,
@@ -997,33 +1762,168 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Socks5ServerProtocol<T, states::Authenti
         ),
         SocksServerError,
     > {
-        let [version, cmd, rsv, address_type] =
-            read_exact!(self.inner, [0u8; 4]).err_when("reading command")?;
-        debug!(
-            "Request: [version: {version}, command: {cmd}, rev: {rsv}, address_type: {address_type}]",
-            version = version,
-            cmd = cmd,
-            rsv = rsv,
-            address_type = address_type,
-        );
-
-        if version != consts::SOCKS5_VERSION {
-            return Err(SocksServerError::UnsupportedSocksVersion(version));
-        }
-
         let mut proto = Socks5ServerProtocol::new(self.inner);
+        let request = read_request(&mut proto.inner).await;
+        proto.parse_request(request).await
+    }
+
+    /// Like `read_command`, giving up when `cancel` completes, e.g. on shutdown.
+    ///
+    /// When cancelled, the protocol is handed back in `TimeoutError::Cancelled` so that
+    /// the client can still be told, e.g. with `ReplyError::GeneralFailure`. The request
+    /// may have been partially read by then.
+    pub async fn read_command_until<C: Future>(
+        self,
+        cancel: C,
+    ) -> Result<
+        (
+            Socks5ServerProtocol<T, states::CommandRead>,
+            Socks5Command,
+            TargetAddr,
+        ),
+        TimeoutError<Socks5ServerProtocol<T, states::CommandRead>>,
+    > {
+        let mut proto = Socks5ServerProtocol::new(self.inner);
+        let request = tokio::select! {
+            request = read_request(&mut proto.inner) => Some(request),
+            _ = cancel => None,
+        };
+        let Some(request) = request else {
+            return Err(TimeoutError::Cancelled { proto });
+        };
+        proto
+            .parse_request(request)
+            .await
+            .map_err(TimeoutError::Failed)
+    }
+
+    /// Like `read_command`, giving up after `timeout`.
+    ///
+    /// On timeout, the protocol is handed back in `TimeoutError::TimedOut` so that the
+    /// client can still be told, e.g. with `ReplyError::TtlExpired`, rather than just
+    /// dropped. The request may have been partially read by then.
+    pub async fn read_command_timeout(
+        self,
+        timeout: Duration,
+    ) -> Result<
+        (
+            Socks5ServerProtocol<T, states::CommandRead>,
+            Socks5Command,
+            TargetAddr,
+        ),
+        TimeoutError<Socks5ServerProtocol<T, states::CommandRead>>,
+    > {
+        let mut proto = Socks5ServerProtocol::new(self.inner);
+        let request = match tokio::time::timeout(timeout, read_request(&mut proto.inner)).await {
+            Ok(request) => request,
+            Err(_) => {
+                return Err(TimeoutError::TimedOut {
+                    after: timeout,
+                    proto,
+                })
+            }
+        };
+        proto
+            .parse_request(request)
+            .await
+            .map_err(TimeoutError::Failed)
+    }
+}
+
+/// Send the last `reply` of a failed handshake and close after it: flush, send a FIN, then
+/// drain what the client already sent, without waiting for more. Dropping a socket with
+/// unread data resets the connection, and the client may lose the reply to the reset.
+async fn reply_and_close<T: AsyncRead + AsyncWrite + Unpin>(
+    inner: &mut T,
+    reply: &[u8],
+    context: &'static str,
+) -> Result<(), SocksServerError> {
+    inner.write_all(reply).await.err_when(context)?;
+    inner.flush().await.err_when(context)?;
+    inner.shutdown().await.err_when(context)?;
+    let mut buf = [0u8; 1024];
+    // bounded, a client could send forever
+    for _ in 0..4 {
+        let drained = std::future::poll_fn(|cx| {
+            let mut buf = tokio::io::ReadBuf::new(&mut buf);
+            let res = Pin::new(&mut *inner).poll_read(cx, &mut buf);
+            Poll::Ready(matches!(res, Poll::Ready(Ok(()))) && !buf.filled().is_empty())
+        })
+        .await;
+        if !drained {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Read a request up to the target address, returning the command byte.
+async fn read_request<T: AsyncRead + Unpin>(
+    inner: &mut T,
+) -> Result<(u8, Result<TargetAddr, AddrError>), SocksServerError> {
+    let [version, cmd, rsv, address_type] =
+        read_exact!(inner, [0u8; 4]).err_when("reading command")?;
+    debug!(
+        "Request: [version: {version}, command: {cmd}, rev: {rsv}, address_type: {address_type}]",
+        version = version,
+        cmd = cmd,
+        rsv = rsv,
+        address_type = address_type,
+    );
+
+    if version != consts::SOCKS5_VERSION {
+        return Err(SocksServerError::UnsupportedSocksVersion(version));
+    }
+
+    // Guess address type
+    Ok((cmd, read_address(inner, address_type).await))
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> Socks5ServerProtocol<T, states::CommandRead> {
+    /// Check the request read by `read_request`, replying the errors to the client.
+    async fn parse_request(
+        self,
+        request: Result<(u8, Result<TargetAddr, AddrError>), SocksServerError>,
+    ) -> Result<(Self, Socks5Command, TargetAddr), SocksServerError> {
+        let (cmd, target_addr) = request?;
+        handshake_limits::handshake_done();
+        // the domains reach the ACLs, routes, logs and resolvers only in canonical form
+        let target_addr = try_notify!(self, target_addr.and_then(TargetAddr::canonicalize));
 
-        // Guess address type
-        let target_addr = try_notify!(proto, read_address(&mut proto.inner, address_type).await);
-
         debug!("Request target is {}", target_addr);
 
         let cmd = try_notify!(
-            proto,
+            self,
             Socks5Command::from_u8(cmd).ok_or(SocksServerError::UnknownCommand(cmd))
         );
 
-        Ok((proto, cmd, target_addr))
+        Ok((self, cmd, target_addr))
+    }
+}
+
+/// The error of a handshake step run with a timeout or until cancelled, such as
+/// `Socks5ServerProtocol::read_command_timeout`.
+#[derive(Debug)]
+pub enum TimeoutError<P> {
+    /// The step didn't complete within `after`, `proto` can still reply to the client.
+    TimedOut {
+        after: Duration,
+        proto: P,
+    },
+    /// The step was cancelled, `proto` can still reply to the client.
+    Cancelled {
+        proto: P,
+    },
+    Failed(SocksServerError),
+}
+
+impl<P> From<TimeoutError<P>> for SocksServerError {
+    fn from(err: TimeoutError<P>) -> Self {
+        match err {
+            TimeoutError::TimedOut { after, .. } => SocksServerError::HandshakeTimeout(after),
+            TimeoutError::Cancelled { .. } => SocksServerError::HandshakeCancelled,
+            TimeoutError::Failed(err) => err,
+        }
     }
 }
 
@@ -1033,6 +1933,22 @@ where
     Self: Sized,
 {
     async fn resolve_dns(self) -> Result<Self, SocksServerError>;
+
+    /// Resolve to the first address following `preference`.
+    ///
+    /// This asks the system resolver, bypassing [`StaticHosts`] and the resolution hook,
+    /// see [`DnsResolveHelper::resolve_dns_with_options`].
+    async fn resolve_dns_with(
+        self,
+        preference: ResolutionPreference,
+    ) -> Result<Self, SocksServerError>;
+
+    /// Resolve to the first address the way [`connect_to_target`] would with `opts`,
+    /// with its static hosts and reporting to its resolution hook.
+    async fn resolve_dns_with_options(
+        self,
+        opts: &ConnectOptions,
+    ) -> Result<Self, SocksServerError>;
 }
 
 impl<T> DnsResolveHelper
@@ -1045,164 +1961,265 @@ where
     T: AsyncRead + AsyncWrite + Unpin,
 {
     async fn resolve_dns(self) -> Result<Self, SocksServerError> {
-        let (proto, cmd, target_addr) = self;
-        let resolved_addr = try_notify!(proto, target_addr.resolve_dns().await);
+        self.resolve_dns_with(ResolutionPreference::System).await
+    }
+
+    async fn resolve_dns_with(
+        self,
+        preference: ResolutionPreference,
+    ) -> Result<Self, SocksServerError> {
+        let (mut proto, cmd, target_addr) = self;
+        let resolved_addr = proto
+            .while_client_connected(target_addr.resolve_dns_with(preference))
+            .await?;
+        let resolved_addr = try_notify!(proto, resolved_addr);
         Ok((proto, cmd, resolved_addr))
     }
+
+    async fn resolve_dns_with_options(
+        self,
+        opts: &ConnectOptions,
+    ) -> Result<Self, SocksServerError> {
+        let (mut proto, cmd, target_addr) = self;
+        let addrs = proto
+            .while_client_connected(resolve_reported(
+                &target_addr,
+                opts.resolution,
+                opts.static_hosts.as_deref(),
+                opts.resolution_hook.as_ref(),
+            ))
+            .await?;
+        let addrs = try_notify!(proto, addrs);
+        let addr = addrs
+            .into_iter()
+            .next()
+            .ok_or(SocksServerError::Bug("no socket addrs"))?;
+        Ok((proto, cmd, TargetAddr::Ip(addr)))
+    }
 }
 
-/// Handle the connect command by running a TCP proxy until the connection is done.
-pub async fn run_tcp_proxy<T: AsyncRead + AsyncWrite + Unpin>(
-    proto: Socks5ServerProtocol<T, states::CommandRead>,
-    addr: &TargetAddr,
+/// How [`connect_to_target`] connects to the target of a CONNECT command.
+#[derive(Debug, Clone)]
+pub struct ConnectOptions {
     request_timeout_s: u64,
     nodelay: bool,
-) -> Result<T, SocksServerError> {
-    let addr = try_notify!(
-        proto,
-        addr.to_socket_addrs()
-            .err_when("converting to socket addr")
-            .and_then(|mut addrs| addrs.next().ok_or(SocksServerError::Bug("no socket addrs")))
-    );
+    resolution: ResolutionPreference,
+    static_hosts: Option<Arc<StaticHosts>>,
+    resolution_hook: Option<ResolutionHook>,
+    socket: SocketOptions,
+    egress: Option<Arc<EgressPool>>,
+    egress_user: Option<String>,
+    transfer: TransferOptions,
+}
 
-    // TCP connect with timeout, to avoid memory leak for connection that takes forever
-    let outbound = match tcp_connect_with_timeout(addr, request_timeout_s).await {
-        Ok(stream) => stream,
-        Err(err) => {
-            proto.reply_error(&err.to_reply_error()).await?;
-            return Err(err.into());
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        ConnectOptions {
+            request_timeout_s: 10,
+            nodelay: false,
+            resolution: ResolutionPreference::System,
+            static_hosts: None,
+            resolution_hook: None,
+            socket: SocketOptions::default(),
+            egress: None,
+            egress_user: None,
+            transfer: TransferOptions::default(),
         }
-    };
+    }
+}
 
-    // Disable Nagle's algorithm if config specifies to do so.
-    try_notify!(
-        proto,
-        outbound.set_nodelay(nodelay).err_when("setting nodelay")
-    );
+impl ConnectOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
 
-    debug!("Connected to remote destination");
+    /// Give up connecting after this many seconds, 10 by default.
+    pub fn set_request_timeout(&mut self, n: u64) -> &mut Self {
+        self.request_timeout_s = n;
+        self
+    }
 
-    let mut inner = proto
-        .reply_success(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0))
-        .await?;
+    /// Disable Nagle's algorithm on the outbound connection.
+    pub fn set_nodelay(&mut self, value: bool) -> &mut Self {
+        self.nodelay = value;
+        self
+    }
 
-    transfer(&mut inner, outbound).await;
-    Ok(inner)
-}
+    /// The order in which the addresses of a domain target are tried.
+    pub fn set_resolution_preference(&mut self, value: ResolutionPreference) -> &mut Self {
+        self.resolution = value;
+        self
+    }
 
-fn udp_bind_random_port(addr: Option<IpAddr>) -> io::Result<Socket> {
-    if let Some(addr) = addr {
-        let sock_addr = SocketAddr::new(addr, 0);
-        let socket = Socket::new(Domain::for_address(sock_addr), Type::DGRAM, None)?;
-        socket.bind(&sock_addr.into())?;
-        Ok(socket)
-    } else {
-        const V4_UNSPEC: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
-        const V6_UNSPEC: SocketAddr = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0);
-        Socket::new(Domain::IPV6, Type::DGRAM, None)
-            .and_then(|socket| socket.set_only_v6(false).map(|_| socket))
-            .and_then(|socket| socket.bind(&V6_UNSPEC.into()).map(|_| socket))
-            .or_else(|_| {
-                Socket::new(Domain::IPV4, Type::DGRAM, None)
-                    .and_then(|socket| socket.bind(&V4_UNSPEC.into()).map(|_| socket))
-            })
+    /// Connect to the static addresses of the domains found in `hosts`, without DNS.
+    pub fn set_static_hosts(&mut self, hosts: Arc<StaticHosts>) -> &mut Self {
+        self.static_hosts = Some(hosts);
+        self
+    }
+
+    /// Set up the outbound sockets with `options`, e.g. DSCP marking or multipath TCP.
+    ///
+    /// Clone the options per session to set them per user or per policy, see
+    /// [`DscpPolicy`].
+    pub fn set_socket_options(&mut self, options: SocketOptions) -> &mut Self {
+        self.socket = options;
+        self
+    }
+
+    /// Report each resolution of a domain target to `hook`, e.g. to audit DNS leaks.
+    pub fn set_resolution_hook<F>(&mut self, hook: F) -> &mut Self
+    where
+        F: Fn(&ResolutionRecord) + Send + Sync + 'static,
+    {
+        self.resolution_hook = Some(ResolutionHook(Arc::new(hook)));
+        self
+    }
+
+    /// Bind the outbound connections to a source IP picked from `pool`, overriding the
+    /// bind IP of the socket options.
+    pub fn set_egress_pool(&mut self, pool: Arc<EgressPool>) -> &mut Self {
+        self.egress = Some(pool);
+        self
+    }
+
+    /// The user of the session, for [`EgressStrategy::PerUser`].
+    pub fn set_egress_user(&mut self, user: Option<String>) -> &mut Self {
+        self.egress_user = user;
+        self
+    }
+
+    /// Relay the half-closes in [`run_tcp_proxy_with_options`], enabled by default, see
+    /// [`TransferOptions::set_half_close`].
+    pub fn set_half_close(&mut self, value: bool) -> &mut Self {
+        self.transfer.set_half_close(value);
+        self
+    }
+
+    /// End the relay of [`run_tcp_proxy_with_options`] after `timeout` without traffic,
+    /// see [`TransferOptions::set_idle_timeout`].
+    pub fn set_idle_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.transfer.set_idle_timeout(timeout);
+        self
     }
-    .and_then(|socket| socket.set_nonblocking(true).map(|_| socket))
 }
 
-/// Handle the associate command by running a UDP proxy until the connection is done.
-pub async fn run_udp_proxy<T: AsyncRead + AsyncWrite + Unpin>(
-    proto: Socks5ServerProtocol<T, states::CommandRead>,
+/// Connect to the target of a CONNECT command.
+///
+/// A domain is resolved following the [`ResolutionPreference`] of `opts`, or to its
+/// [`StaticHosts`] entry, its addresses being tried in turn until one accepts the
+/// connection, each within the timeout.
+///
+/// This is the first stage of [`run_tcp_proxy_with_options`], for embedders which need to wrap or
+/// inspect the outbound stream before relaying: connect with this (ideally inside
+/// [`Socks5ServerProtocol::while_client_connected`]), answer with
+/// [`Socks5ServerProtocol::reply_success`], or with `reply_error(&err.to_reply_error())`
+/// on failure, forward [`Socks5ServerProtocol::take_early_data`] and finally [`transfer`].
+pub async fn connect_to_target(
     addr: &TargetAddr,
-    peer_bind_ip: Option<IpAddr>,
-    reply_ip: IpAddr,
-    outbound_bind_ip: Option<IpAddr>,
-) -> Result<T, SocksServerError> {
-    run_udp_proxy_custom(
-        proto,
+    opts: &ConnectOptions,
+) -> Result<TcpStream, SocksServerError> {
+    let addrs = resolve_reported(
         addr,
-        peer_bind_ip,
-        reply_ip,
-        move |inbound| async move {
-            let outbound =
-                udp_bind_random_port(outbound_bind_ip).err_when("binding outbound udp socket")?;
-
-            transfer_udp(inbound, outbound).await
-        },
+        opts.resolution,
+        opts.static_hosts.as_deref(),
+        opts.resolution_hook.as_ref(),
     )
-    .await
+    .await?;
+    let mut addrs = addrs.into_iter();
+    let mut peer = addrs
+        .next()
+        .ok_or(SocksServerError::Bug("no socket addrs"))?;
+
+    // TCP connect with timeout, to avoid memory leak for connection that takes forever
+    let outbound = loop {
+        let source = opts
+            .egress
+            .as_ref()
+            .and_then(|pool| pool.select(opts.egress_user.as_deref(), addr, peer));
+        let connected = match source {
+            Some(ip) => {
+                debug!("Connecting to {} from {}", peer, ip);
+                let mut socket = opts.socket.clone();
+                socket.set_bind_ip(Some(ip));
+                tcp_connect_with_options(peer, &socket, opts.request_timeout_s).await
+            }
+            None => tcp_connect_with_options(peer, &opts.socket, opts.request_timeout_s).await,
+        };
+        match connected {
+            Ok(outbound) => break outbound,
+            Err(err) => match addrs.next() {
+                Some(next) => {
+                    debug!("Can't connect to {}: {}, trying {}", peer, err, next);
+                    peer = next;
+                }
+                None => return Err(err.into()),
+            },
+        }
+    };
+
+    // Disable Nagle's algorithm if config specifies to do so.
+    outbound
+        .set_nodelay(opts.nodelay)
+        .err_when("setting nodelay")?;
+
+    debug!("Connected to remote destination");
+    Ok(outbound)
 }
 
-/// Handle the associate command by running a UDP proxy until the connection is done.
-///
-/// This version allows passing in a custom transfer function while reusing the initialization code.
-pub async fn run_udp_proxy_custom<T, F, R>(
+/// Former signature of [`run_tcp_proxy_with_options`], whose last arguments are
+/// `ConnectOptions::set_request_timeout` and `ConnectOptions::set_nodelay`.
+#[deprecated(
+    since = "1.0.0",
+    note = "Use `run_tcp_proxy_with_options`, with the timeout and nodelay set on `ConnectOptions`"
+)]
+pub async fn run_tcp_proxy<T: AsyncRead + AsyncWrite + Unpin>(
     proto: Socks5ServerProtocol<T, states::CommandRead>,
-    _addr: &TargetAddr,
-    peer_bind_ip: Option<IpAddr>,
-    reply_ip: IpAddr,
-    transfer: F,
-) -> Result<T, SocksServerError>
-where
-    T: AsyncRead + AsyncWrite + Unpin,
-    F: FnOnce(Socket) -> R,
-    R: Future<Output = Result<(), SocksServerError>>,
-{
-    // The DST.ADDR and DST.PORT fields contain the address and port that
-    // the client expects to use to send UDP datagrams on for the
-    // association. The server MAY use this information to limit access
-    // to the association.
-    // @see Page 6, https://datatracker.ietf.org/doc/html/rfc1928.
-    //
-    // We do NOT limit the access from the client currently in this implementation.
-
-    // By default, listen on a UDP6 socket, so that the client can connect
-    // to it with either IPv4 or IPv6.
-    let peer_sock = try_notify!(
-        proto,
-        udp_bind_random_port(peer_bind_ip).err_when("binding client udp socket")
-    );
+    addr: &TargetAddr,
+    request_timeout_s: u64,
+    nodelay: bool,
+) -> Result<T, SocksServerError> {
+    let mut opts = ConnectOptions::new();
+    opts.set_request_timeout(request_timeout_s)
+        .set_nodelay(nodelay);
+    run_tcp_proxy_with_options(proto, addr, &opts).await
+}
 
-    let peer_addr = try_notify!(
-        proto,
-        peer_sock.local_addr().err_when("getting peer's local addr")
-    );
+/// Handle the connect command by running a TCP proxy until the connection is done,
+/// connecting to `addr` with `opts`.
+pub async fn run_tcp_proxy_with_options<T: AsyncRead + AsyncWrite + Unpin>(
+    mut proto: Socks5ServerProtocol<T, states::CommandRead>,
+    addr: &TargetAddr,
+    opts: &ConnectOptions,
+) -> Result<T, SocksServerError> {
+    let outbound = proto
+        .while_client_connected(connect_to_target(addr, opts))
+        .await?;
+    let mut outbound = try_notify!(proto, outbound);
+    let closer = SessionCloser::current();
+    if let Some(closer) = &closer {
+        closer.set_outbound_addrs(outbound.local_addr().ok(), outbound.peer_addr().ok(), addr);
+    }
 
-    let reply_port = peer_addr
-        .as_socket()
-        .ok_or(SocksServerError::Bug("addr not IP"))?
-        .port();
+    let early_data = proto.take_early_data();
+    if !early_data.is_empty() {
+        try_notify!(
+            proto,
+            outbound
+                .write_all(&early_data)
+                .await
+                .err_when("forwarding early data")
+        );
+    }
 
-    // Respect the pre-populated reply IP address.
     let mut inner = proto
-        .reply_success(SocketAddr::new(reply_ip, reply_port))
+        .reply_success(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0))
         .await?;
 
-    let udp_fut = transfer(peer_sock);
-    let tcp_fut = wait_on_tcp(&mut inner);
-    match try_join!(udp_fut, tcp_fut) {
-        Ok(_) => warn!("unreachable"),
-        Err(SocksServerError::EOF) => debug!("EOF on controlling TCP stream, closed UDP proxy"),
-        Err(err) => warn!("while UDP proxying: {err}"),
-    }
+    let closer = closer.unwrap_or_default();
+    transfer_until_closed(&mut inner, outbound, &opts.transfer, &closer).await;
     Ok(inner)
 }
 
-/// Wait until a TCP stream (that's not supposed to receive anything) closes.
-///
-/// This is intended for cancelling the `transfer_udp` task.
-pub async fn wait_on_tcp<I>(stream: &mut I) -> Result<(), SocksServerError>
-where
-    I: AsyncRead + Unpin,
-{
-    let mut buf = [0; 1];
-    match stream.read(&mut buf).await {
-        Ok(0) => Err(SocksServerError::EOF),
-        Ok(_) => Err(SocksServerError::UnexpectedUdpControlGarbage(buf[0])),
-        Err(err) => Err(err).err_when("waiting on UDP control stream"),
-    }
-}
-
 /// Run a bidirectional proxy between two streams.
 /// Using 2 different generators, because they could be different structs with same traits.
 pub async fn transfer<I, O>(mut inbound: I, mut outbound: O)
@@ -1216,113 +2233,76 @@ where
     };
 }
 
-async fn handle_udp_request(
-    inbound: &UdpSocket,
-    outbound: &UdpSocket,
-    outbound_v6: bool,
-    buf: &mut [u8],
-) -> Result<(), SocksServerError> {
-    let (size, client_addr) = inbound
-        .recv_from(buf)
-        .await
-        .err_when("udp receiving from")?;
-    debug!("Server recieve udp from {}", client_addr);
-    inbound
-        .connect(client_addr)
-        .await
-        .err_when("connecting udp inbound")?;
-
-    let (frag, target_addr, data) = parse_udp_request(&buf[..size]).await?;
-
-    if frag != 0 {
-        debug!("Discard UDP frag packets sliently.");
-        return Ok(());
-    }
-
-    debug!("Server forward to packet to {}", target_addr);
-    let mut target_addr = target_addr
-        .resolve_dns()
-        .await?
-        .to_socket_addrs()
-        .err_when("udp target to socket addrs")?
-        .next()
-        .ok_or(SocksServerError::Bug("no socket addrs"))?;
-
-    if outbound_v6 {
-        target_addr.set_ip(match target_addr.ip() {
-            std::net::IpAddr::V4(v4) => std::net::IpAddr::V6(v4.to_ipv6_mapped()),
-            v6 @ std::net::IpAddr::V6(_) => v6,
-        });
-    }
-    outbound
-        .send_to(data, target_addr)
-        .await
-        .err_when("udp sending to")?;
-    Ok(())
+/// How [`transfer_with_options`] relays between the client and the target.
+#[derive(Debug, Clone)]
+pub struct TransferOptions {
+    half_close: bool,
+    idle_timeout: Option<Duration>,
+    adaptive_buffers: Option<(usize, usize)>,
+    memory: Option<Arc<MemoryReservation>>,
 }
 
-async fn handle_udp_requests(
-    inbound: &UdpSocket,
-    outbound: &UdpSocket,
-) -> Result<(), SocksServerError> {
-    let mut buf = vec![0u8; 8192];
-    let outbound_v6 = outbound
-        .local_addr()
-        .err_when("udp outbound local addr")?
-        .is_ipv6();
-    loop {
-        match handle_udp_request(inbound, outbound, outbound_v6, &mut buf).await {
-            Ok(_) => trace!("handled udp response"),
-            Err(err) => debug!("error in handling udp response: {err}"),
+impl Default for TransferOptions {
+    fn default() -> Self {
+        TransferOptions {
+            half_close: true,
+            idle_timeout: None,
+            adaptive_buffers: None,
+            memory: None,
         }
     }
 }
 
-async fn handle_udp_response(
-    inbound: &UdpSocket,
-    outbound: &UdpSocket,
-    buf: &mut [u8],
-) -> Result<(), SocksServerError> {
-    let (size, mut remote_addr) = outbound
-        .recv_from(buf)
-        .await
-        .err_when("udp receiving from")?;
-    debug!("Recieve packet from {}", remote_addr);
-
-    // Clients don't tend to expect v6-mapped addresses when they connect to v4 ones
-    if let std::net::IpAddr::V6(v6) = remote_addr.ip() {
-        if let Some(v4) = v6.to_ipv4_mapped() {
-            remote_addr.set_ip(std::net::IpAddr::V4(v4));
-        }
+impl TransferOptions {
+    pub fn new() -> Self {
+        Self::default()
     }
 
-    let mut data = new_udp_header(remote_addr)?;
-    data.extend_from_slice(&buf[..size]);
-    inbound.send(&data).await.err_when("udp sending")?;
+    /// When one side stops sending, forward its FIN to the other side and keep relaying
+    /// the other direction until it ends too, as [`transfer`] does. Enabled by default,
+    /// protocols such as HTTP/1.0 or some database wire protocols rely on it.
+    ///
+    /// When disabled, both connections are closed as soon as one direction ends.
+    pub fn set_half_close(&mut self, value: bool) -> &mut Self {
+        self.half_close = value;
+        self
+    }
 
-    Ok(())
-}
+    /// End the session with [`CloseReason::IdleTimeout`] when nothing was received from
+    /// either side for `timeout`. Disabled by default.
+    pub fn set_idle_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.idle_timeout = timeout;
+        self
+    }
 
-async fn handle_udp_responses(
-    inbound: &UdpSocket,
-    outbound: &UdpSocket,
-) -> Result<(), SocksServerError> {
-    let mut buf = vec![0u8; 8192];
-    loop {
-        match handle_udp_response(inbound, outbound, &mut buf).await {
-            Ok(_) => trace!("handled udp response"),
-            Err(err) => debug!("error in handling udp response: {err}"),
-        }
+    /// Relay each direction with a buffer of `min` bytes, doubling up to `max` while the
+    /// reads keep filling it, and back to `min` after a couple of idle seconds, so that
+    /// many mostly idle sessions keep little memory. Fixed 8 KiB buffers by default.
+    pub fn set_adaptive_buffers(&mut self, min: usize, max: usize) -> &mut Self {
+        self.adaptive_buffers = Some((min, max));
+        self
+    }
+
+    /// Reserve the relay buffers from `reservation` as they are allocated, the session
+    /// ending with an `OutOfMemory` error when they don't fit. Defaults to
+    /// [`MemoryReservation::current`].
+    pub fn set_memory_reservation(&mut self, reservation: Arc<MemoryReservation>) -> &mut Self {
+        self.memory = Some(reservation);
+        self
     }
 }
 
-/// Run a bidirectional UDP SOCKS proxy for a given pair of inbound (SOCKS client) and outbound sockets.
-pub async fn transfer_udp(inbound: Socket, outbound: Socket) -> Result<(), SocksServerError> {
-    let inbound = UdpSocket::from_std(inbound.into()).err_when("wrapping inbound socket")?;
-    let outbound = UdpSocket::from_std(outbound.into()).err_when("wrapping outbound socket")?;
-    let req_fut = handle_udp_requests(&inbound, &outbound);
-    let res_fut = handle_udp_responses(&inbound, &outbound);
-    try_join!(req_fut, res_fut).map(|_| ())
+/// Like [`transfer`], with options, returning why the session ended.
+pub async fn transfer_with_options<I, O>(
+    inbound: I,
+    outbound: O,
+    opts: &TransferOptions,
+) -> CloseReason
+where
+    I: AsyncRead + AsyncWrite + Unpin,
+    O: AsyncRead + AsyncWrite + Unpin,
+{
+    transfer_until_closed(inbound, outbound, opts, &SessionCloser::new()).await
 }
 
 // Fixes the issue "cannot borrow data in dereference of `Pin<&mut >` as mutable"
@@ -1405,11 +2385,616 @@ fn new_reply(error: &ReplyError, sock_addr: SocketAddr) -> Vec<u8> {
 #[cfg(test)]
 #[allow(deprecated)]
 mod test {
-    use crate::server::Socks5Server;
+    use crate::server::{
+        AuthFailure, MethodPreference, PasswordAuthentication, Socks5Server, Socks5ServerProtocol,
+        SocksServerError, StandardAuthentication, WithReason,
+    };
+    use std::time::{Duration, Instant};
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
     use tokio_test::block_on;
 
     use super::AcceptAuthentication;
 
+    const CONNECT_REQUEST: [u8; 10] = [5, 1, 0, 1, 192, 0, 2, 1, 0, 80];
+
+    /// A TCP connection, the client end first.
+    pub(crate) async fn tcp_pair() -> (tokio::net::TcpStream, tokio::net::TcpStream) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = tokio::net::TcpStream::connect(listener.local_addr().unwrap());
+        let (client, accepted) = tokio::join!(client, listener.accept());
+        (client.unwrap(), accepted.unwrap().0)
+    }
+
+    /// Close `stream` with a RST.
+    pub(crate) fn reset(stream: tokio::net::TcpStream) {
+        socket2::SockRef::from(&stream)
+            .set_linger(Some(Duration::ZERO))
+            .unwrap();
+        drop(stream);
+    }
+
+    #[tokio::test]
+    async fn dial_cancelled_on_client_disconnect() {
+        const UDP_ASSOCIATE_REQUEST: [u8; 10] = [5, 3, 0, 1, 0, 0, 0, 0, 0, 0];
+
+        for request in [CONNECT_REQUEST, UDP_ASSOCIATE_REQUEST] {
+            let (mut client, server) = tcp_pair().await;
+            client.write_all(&request).await.unwrap();
+            let (mut proto, _, _) =
+                Socks5ServerProtocol::skip_auth_this_is_not_rfc_compliant(server)
+                    .read_command()
+                    .await
+                    .unwrap();
+            reset(client);
+
+            let dial = proto.while_client_connected(tokio::time::sleep(Duration::from_secs(60)));
+            let res = tokio::time::timeout(Duration::from_secs(1), dial).await;
+            assert!(matches!(
+                res.unwrap(),
+                Err(SocksServerError::ClientDisconnected(_))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn dial_survives_half_close() {
+        let (mut client, server) = tcp_pair().await;
+        client.write_all(&CONNECT_REQUEST).await.unwrap();
+        client.write_all(b"GET /").await.unwrap();
+        client.shutdown().await.unwrap();
+        let (mut proto, _, _) = Socks5ServerProtocol::skip_auth_this_is_not_rfc_compliant(server)
+            .read_command()
+            .await
+            .unwrap();
+
+        let dial = proto.while_client_connected(async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            42
+        });
+        assert_eq!(dial.await.unwrap(), 42);
+
+        // the early data is read first from the buffered stream
+        let mut stream = proto
+            .reply_success_buffered("192.0.2.1:80".parse().unwrap())
+            .await
+            .unwrap();
+        let mut data = vec![];
+        stream.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, b"GET /");
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[..2], [5, 0]);
+    }
+
+    #[tokio::test]
+    async fn dial_keeps_early_data() {
+        let (mut client, server) = duplex(64);
+        client.write_all(&CONNECT_REQUEST).await.unwrap();
+        client.write_all(b"GET /").await.unwrap();
+        let (mut proto, _, _) = Socks5ServerProtocol::skip_auth_this_is_not_rfc_compliant(server)
+            .read_command()
+            .await
+            .unwrap();
+
+        let dial = proto.while_client_connected(async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            42
+        });
+        assert_eq!(dial.await.unwrap(), 42);
+        assert_eq!(proto.take_early_data(), b"GET /");
+    }
+
+    #[tokio::test]
+    async fn password_failure_reason() {
+        let (mut client, server) = duplex(64);
+        client.write_all(&[5, 1, 2]).await.unwrap();
+        client
+            .write_all(&[1, 5, b'a', b'l', b'i', b'c', b'e', 1, b'x'])
+            .await
+            .unwrap();
+
+        let res = Socks5ServerProtocol::accept_password_auth(server, |_, _| {
+            WithReason(Err::<(), _>(AuthFailure::BadPassword))
+        })
+        .await;
+        match res {
+            Err(SocksServerError::AuthenticationFailed { username, reason }) => {
+                assert_eq!(username.expose(), "alice");
+                assert_eq!(reason, "wrong password");
+            }
+            _ => panic!("unexpected result"),
+        }
+
+        let mut reply = vec![];
+        client.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, [5, 2, 1, 0xff]);
+
+        // a plain `Result`, whatever its error, has no reason
+        struct Opaque;
+        let (mut client, server) = duplex(64);
+        client.write_all(&[5, 1, 2]).await.unwrap();
+        client
+            .write_all(&[1, 5, b'a', b'l', b'i', b'c', b'e', 1, b'x'])
+            .await
+            .unwrap();
+        let res =
+            Socks5ServerProtocol::accept_password_auth(server, |_, _| Err::<(), _>(Opaque)).await;
+        assert!(matches!(res, Err(SocksServerError::AuthenticationRejected)));
+    }
+
+    #[tokio::test]
+    async fn password_auth_version() {
+        for (versions, ver, accepted) in [
+            (None, 5, true),
+            (Some(&[1][..]), 1, true),
+            (Some(&[1][..]), 5, false),
+            (Some(&[1, 5][..]), 5, true),
+        ] {
+            let (mut client, server) = duplex(64);
+            client.write_all(&[5, 1, 2]).await.unwrap();
+            client.write_all(&[ver, 1, b'a', 1, b'x']).await.unwrap();
+            let mut auth = Socks5ServerProtocol::start(server)
+                .negotiate_auth(&[PasswordAuthentication])
+                .await
+                .unwrap();
+            if let Some(versions) = versions {
+                auth.set_accepted_versions(versions);
+            }
+            let res = auth.read_username_password().await;
+            match res {
+                Ok((user, _, _)) => assert!(accepted && user == "a"),
+                Err(err) => assert!(
+                    !accepted && matches!(err, SocksServerError::UnsupportedPasswordAuthVersion(5))
+                ),
+            }
+        }
+
+        let (mut client, server) = duplex(64);
+        client.write_all(&[5, 1, 2]).await.unwrap();
+        client.write_all(&[5, 1, b'a', 1, b'x']).await.unwrap();
+        let res =
+            Socks5ServerProtocol::accept_password_auth_with_versions(server, &[1], |_, _| true)
+                .await;
+        assert!(matches!(
+            res,
+            Err(SocksServerError::UnsupportedPasswordAuthVersion(5))
+        ));
+    }
+
+    #[tokio::test]
+    async fn read_command_timeout() {
+        use crate::server::TimeoutError;
+        use crate::ReplyError;
+
+        let (mut client, server) = duplex(64);
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        // a request stalled after the header
+        client.write_all(&[5, 1, 0, 1]).await.unwrap();
+        let proto = Socks5ServerProtocol::accept_no_auth(server).await.unwrap();
+        let res = proto.read_command_timeout(Duration::from_millis(50)).await;
+        let Err(TimeoutError::TimedOut { proto, .. }) = res else {
+            panic!("the request didn't time out");
+        };
+        proto.reply_error(&ReplyError::TtlExpired).await.unwrap();
+        let mut reply = [0u8; 4];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [5, 0, 5, 6]);
+    }
+
+    #[tokio::test]
+    async fn read_command_cancelled() {
+        use crate::server::TimeoutError;
+        use crate::ReplyError;
+
+        let (mut client, mut server) = duplex(64);
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        // over a borrowed stream, kept by the caller whatever happens
+        let proto = Socks5ServerProtocol::accept_no_auth(&mut server).await.unwrap();
+        let (cancel, cancelled) = tokio::sync::oneshot::channel::<()>();
+        drop(cancel);
+        let res = proto.read_command_until(cancelled).await;
+        let Err(TimeoutError::Cancelled { proto }) = res else {
+            panic!("the request wasn't cancelled");
+        };
+        proto.reply_error(&ReplyError::GeneralFailure).await.unwrap();
+        let mut reply = [0u8; 4];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [5, 0, 5, 1]);
+        drop(server);
+    }
+
+    #[tokio::test]
+    async fn read_command_canonical_domain() {
+        use crate::util::target_addr::TargetAddr;
+
+        let (mut client, server) = duplex(64);
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        client.write_all(&[5, 1, 0, 3, 12]).await.unwrap();
+        client.write_all(b"Example.COM.\x01\xbb").await.unwrap();
+        let proto = Socks5ServerProtocol::accept_no_auth(server).await.unwrap();
+        let (_, _, target) = proto.read_command().await.unwrap();
+        assert_eq!(target, TargetAddr::Domain("example.com".to_owned(), 443));
+
+        let (mut client, server) = duplex(64);
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        client.write_all(&[5, 1, 0, 3, 8]).await.unwrap();
+        client.write_all(b"exa\nmple\x01\xbb").await.unwrap();
+        let proto = Socks5ServerProtocol::accept_no_auth(server).await.unwrap();
+        assert!(proto.read_command().await.is_err());
+        let mut reply = [0u8; 4];
+        client.read_exact(&mut reply).await.unwrap();
+        // general failure
+        assert_eq!(reply, [5, 0, 5, 1]);
+    }
+
+    #[tokio::test]
+    async fn resolve_dns_with_options() {
+        use super::{ConnectOptions, DnsResolveHelper, StaticHosts};
+        use crate::util::target_addr::TargetAddr;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let mut hosts = StaticHosts::new();
+        hosts.insert("db.internal", ["10.0.0.1".parse().unwrap()]);
+        let reported = Arc::new(AtomicUsize::new(0));
+        let mut opts = ConnectOptions::new();
+        opts.set_static_hosts(Arc::new(hosts))
+            .set_resolution_hook({
+                let reported = reported.clone();
+                move |_| {
+                    reported.fetch_add(1, Ordering::Relaxed);
+                }
+            });
+
+        let (mut client, server) = duplex(64);
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        client.write_all(&[5, 1, 0, 3, 11]).await.unwrap();
+        client.write_all(b"db.internal\x01\xbb").await.unwrap();
+        let proto = Socks5ServerProtocol::accept_no_auth(server).await.unwrap();
+        let (_, _, target) = proto
+            .read_command()
+            .await
+            .unwrap()
+            .resolve_dns_with_options(&opts)
+            .await
+            .unwrap();
+        assert_eq!(target, TargetAddr::Ip("10.0.0.1:443".parse().unwrap()));
+        assert_eq!(reported.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn unacceptable_method_close() {
+        for silent in [false, true] {
+            let (mut client, server) = duplex(64);
+            // the request is sent optimistically, and must not reset the connection
+            client.write_all(&[5, 1, 2, 5, 1, 0, 1]).await.unwrap();
+            let mut proto = Socks5ServerProtocol::start(server);
+            proto.set_silent_unacceptable_method(silent);
+            let res = proto.negotiate_auth(&[super::NoAuthentication]).await;
+            assert!(matches!(
+                res,
+                Err(SocksServerError::AuthMethodUnacceptable(_))
+            ));
+            let mut reply = vec![];
+            client.read_to_end(&mut reply).await.unwrap();
+            let expected: &[u8] = if silent { &[] } else { &[5, 0xff] };
+            assert_eq!(reply, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn inner_stream() {
+        let (mut client, server) = duplex(64);
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        let mut proto = Socks5ServerProtocol::accept_no_auth(server).await.unwrap();
+        proto.get_mut().write_all(b"hi").await.unwrap();
+        let mut server = proto.into_inner();
+        server.write_all(b"!").await.unwrap();
+        let mut buf = [0u8; 5];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, &[5, 0, b'h', b'i', b'!']);
+    }
+
+    #[tokio::test]
+    async fn method_preference() {
+        for (preference, expected) in [(MethodPreference::Server, 2), (MethodPreference::Client, 0)]
+        {
+            let (mut client, server) = duplex(64);
+            client.write_all(&[5, 2, 0, 2]).await.unwrap();
+            let mut proto = Socks5ServerProtocol::start(server);
+            proto.set_method_preference(preference);
+            proto
+                .negotiate_auth(StandardAuthentication::allow_no_auth(true))
+                .await
+                .unwrap();
+            let mut reply = [0u8; 2];
+            client.read_exact(&mut reply).await.unwrap();
+            assert_eq!(reply, [5, expected]);
+        }
+    }
+
+    #[tokio::test]
+    async fn auth_failure_delay() {
+        let delay = Duration::from_millis(200);
+        let (mut client, server) = duplex(64);
+        client.write_all(&[5, 1, 2]).await.unwrap();
+        client.write_all(&[1, 1, b'a', 1, b'x']).await.unwrap();
+
+        let mut proto = Socks5ServerProtocol::start(server);
+        proto.set_auth_failure_delay(delay);
+        let (_, _, auth) = proto
+            .negotiate_auth(&[PasswordAuthentication])
+            .await
+            .unwrap()
+            .read_username_password()
+            .await
+            .unwrap();
+        let started = Instant::now();
+        auth.reject().await.unwrap();
+        assert!(started.elapsed() >= delay);
+
+        let (mut client, server) = duplex(64);
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        let mut proto = Socks5ServerProtocol::start(server);
+        proto
+            .set_auth_failure_delay(delay)
+            .set_delay_unacceptable_method(true);
+        let started = Instant::now();
+        let res = proto.negotiate_auth(&[PasswordAuthentication]).await;
+        assert!(matches!(
+            res,
+            Err(SocksServerError::AuthMethodUnacceptable(_))
+        ));
+        assert!(started.elapsed() >= delay);
+    }
+
+    #[tokio::test]
+    #[allow(deprecated)]
+    async fn legacy_socket_upgrade() {
+        use super::{Config, SimpleUserPassword, Socks5Socket};
+        use crate::{AuthenticationMethod, Socks5Command};
+        use std::sync::Arc;
+
+        let mut config: Config = Config::default();
+        config.set_execute_command(false);
+        let config = config.with_authentication(SimpleUserPassword {
+            username: "alice".to_owned(),
+            password: "pass".to_owned(),
+        });
+
+        let (mut client, server) = duplex(64);
+        client.write_all(&[5, 1, 2]).await.unwrap();
+        client
+            .write_all(&[
+                1, 5, b'a', b'l', b'i', b'c', b'e', 4, b'p', b'a', b's', b's',
+            ])
+            .await
+            .unwrap();
+        client.write_all(&CONNECT_REQUEST).await.unwrap();
+
+        let mut socket = Socks5Socket::new(server, Arc::new(config))
+            .upgrade_to_socks5()
+            .await
+            .unwrap();
+        assert_eq!(socket.cmd(), &Some(Socks5Command::TCPConnect));
+        assert_eq!(
+            socket.target_addr().unwrap().to_string(),
+            "192.0.2.1:80".to_owned()
+        );
+        assert!(matches!(
+            socket.auth(),
+            AuthenticationMethod::Password { username, .. } if username == "alice"
+        ));
+        assert_eq!(socket.take_credentials().unwrap().username, "alice");
+
+        // the handshake is bounded by default
+        let mut config: Config = Config::default();
+        let mut limits = config.handshake_limits.clone().unwrap();
+        limits.set_timeout(Duration::from_millis(50));
+        config.set_handshake_limits(Some(limits));
+        let (mut client, server) = duplex(64);
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        let res = Socks5Socket::new(server, Arc::new(config))
+            .upgrade_to_socks5()
+            .await;
+        let Err(crate::SocksError::Session { source, .. }) = res else {
+            panic!("the error isn't tagged with the session");
+        };
+        assert!(matches!(
+            *source,
+            crate::SocksError::ServerError(super::SocksServerError::HandshakeTimeout(_))
+        ));
+    }
+
+    #[tokio::test]
+    #[allow(deprecated)]
+    async fn legacy_connect_static_hosts() {
+        use super::{Config, Socks5Socket, StaticHosts};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+        use tokio::net::TcpListener;
+
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = target.local_addr().unwrap().port();
+        let mut hosts = StaticHosts::new();
+        hosts.insert("db.internal", ["127.0.0.1".parse().unwrap()]);
+        let reported = Arc::new(AtomicUsize::new(0));
+        let mut config: Config = Config::default();
+        config
+            .set_dns_resolve(false)
+            .set_static_hosts(Arc::new(hosts))
+            .set_resolution_hook({
+                let reported = reported.clone();
+                move |_| {
+                    reported.fetch_add(1, Ordering::Relaxed);
+                }
+            });
+
+        // resolved when connecting, with the static hosts and the hook of the config
+        let (mut client, server) = duplex(64);
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        client.write_all(&[5, 1, 0, 3, 11]).await.unwrap();
+        client.write_all(b"db.internal").await.unwrap();
+        client.write_all(&port.to_be_bytes()).await.unwrap();
+        tokio::spawn(Socks5Socket::new(server, Arc::new(config)).upgrade_to_socks5());
+        let (mut accepted, _) = tokio::time::timeout(Duration::from_secs(5), target.accept())
+            .await
+            .unwrap()
+            .unwrap();
+        accepted.write_all(b"hi").await.unwrap();
+        let mut reply = [0u8; 14];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[..2], [5, 0]);
+        assert_eq!(&reply[12..], b"hi");
+        assert_eq!(reported.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn connect_to_target_stages() {
+        use super::{connect_to_target, ConnectOptions};
+        use crate::util::target_addr::TargetAddr;
+        use crate::ReplyError;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = TargetAddr::Ip(listener.local_addr().unwrap());
+        let mut opts = ConnectOptions::new();
+        opts.set_nodelay(true);
+        let outbound = connect_to_target(&target, &opts).await.unwrap();
+        assert!(outbound.nodelay().unwrap());
+        drop(listener);
+
+        let err = connect_to_target(&target, &opts).await.unwrap_err();
+        assert!(matches!(
+            err.to_reply_error(),
+            ReplyError::ConnectionRefused
+        ));
+    }
+
+    #[tokio::test]
+    async fn reply_addr_privacy() {
+        use super::ReplyAddrPrivacy;
+
+        let bound = "10.1.2.3:5000".parse().unwrap();
+        for (privacy, expected) in [
+            (ReplyAddrPrivacy::Off, [10, 1, 2, 3, 0x13, 0x88]),
+            (ReplyAddrPrivacy::Unspecified, [0, 0, 0, 0, 0x13, 0x88]),
+            (
+                ReplyAddrPrivacy::Fixed("192.0.2.1:1".parse().unwrap()),
+                [192, 0, 2, 1, 0, 1],
+            ),
+        ] {
+            let (mut client, server) = duplex(64);
+            client.write_all(&CONNECT_REQUEST).await.unwrap();
+            let (mut proto, _, _) =
+                Socks5ServerProtocol::skip_auth_this_is_not_rfc_compliant(server)
+                    .read_command()
+                    .await
+                    .unwrap();
+            proto.set_reply_addr_privacy(privacy);
+            proto.reply_success(bound).await.unwrap();
+            let mut reply = [0u8; 10];
+            client.read_exact(&mut reply).await.unwrap();
+            assert_eq!(reply[4..], expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn transfer_half_close() {
+        use super::{transfer_with_options, TransferOptions};
+
+        for half_close in [true, false] {
+            let (mut client, inbound) = duplex(64);
+            let (outbound, mut target) = duplex(64);
+            let mut opts = TransferOptions::new();
+            opts.set_half_close(half_close);
+            let relay =
+                tokio::spawn(async move { transfer_with_options(inbound, outbound, &opts).await });
+
+            client.write_all(b"request").await.unwrap();
+            client.shutdown().await.unwrap();
+            let mut request = vec![];
+            target.read_to_end(&mut request).await.unwrap();
+            assert_eq!(request, b"request");
+
+            // the response still flows back after the client FIN, unless disabled
+            let _ = target.write_all(b"response").await;
+            drop(target);
+            let mut response = vec![];
+            client.read_to_end(&mut response).await.unwrap();
+            let expected: &[u8] = if half_close { b"response" } else { b"" };
+            assert_eq!(response, expected);
+            relay.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn tcp_proxy_half_close() {
+        use super::{run_tcp_proxy_with_options, ConnectOptions};
+
+        for half_close in [true, false] {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port().to_be_bytes();
+            let (mut client, server) = duplex(64);
+            client
+                .write_all(&[5, 1, 0, 1, 127, 0, 0, 1, port[0], port[1]])
+                .await
+                .unwrap();
+            let relay = tokio::spawn(async move {
+                let (proto, _, target) =
+                    Socks5ServerProtocol::skip_auth_this_is_not_rfc_compliant(server)
+                        .read_command()
+                        .await
+                        .unwrap();
+                let mut opts = ConnectOptions::new();
+                opts.set_half_close(half_close);
+                run_tcp_proxy_with_options(proto, &target, &opts).await
+            });
+            let (mut target, _) = listener.accept().await.unwrap();
+            let mut reply = [0u8; 10];
+            client.read_exact(&mut reply).await.unwrap();
+
+            client.write_all(b"request").await.unwrap();
+            client.shutdown().await.unwrap();
+            let mut request = vec![];
+            target.read_to_end(&mut request).await.unwrap();
+            assert_eq!(request, b"request");
+
+            let _ = target.write_all(b"response").await;
+            drop(target);
+            let mut response = vec![];
+            client.read_to_end(&mut response).await.unwrap();
+            let expected: &[u8] = if half_close { b"response" } else { b"" };
+            assert_eq!(response, expected);
+            relay.await.unwrap().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn tcp_proxy_idle_timeout() {
+        use super::{run_tcp_proxy_with_options, ConnectOptions};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port().to_be_bytes();
+        let (mut client, server) = duplex(64);
+        client
+            .write_all(&[5, 1, 0, 1, 127, 0, 0, 1, port[0], port[1]])
+            .await
+            .unwrap();
+        let relay = tokio::spawn(async move {
+            let (proto, _, target) =
+                Socks5ServerProtocol::skip_auth_this_is_not_rfc_compliant(server)
+                    .read_command()
+                    .await
+                    .unwrap();
+            let mut opts = ConnectOptions::new();
+            opts.set_idle_timeout(Some(Duration::from_millis(100)));
+            run_tcp_proxy_with_options(proto, &target, &opts).await
+        });
+        let (_target, _) = listener.accept().await.unwrap();
+        let res = tokio::time::timeout(Duration::from_secs(2), relay).await;
+        assert!(res.unwrap().unwrap().is_ok());
+    }
+
     #[test]
     fn test_bind() {
         let f = async {
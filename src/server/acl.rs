@@ -0,0 +1,141 @@
+use crate::util::target_addr::TargetAddr;
+use crate::Socks5Command;
+use std::net::IpAddr;
+
+/// The decision of an access rule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AclAction {
+    Allow,
+    Deny,
+}
+
+/// A client request, as seen by the access rules once the command is read.
+#[derive(Debug, Clone, Copy)]
+pub struct AclRequest<'a> {
+    pub client_ip: IpAddr,
+    /// The authenticated username, if any.
+    pub username: Option<&'a str>,
+    pub command: Socks5Command,
+    /// The target, resolved or not depending on when the rules are checked.
+    pub target: &'a TargetAddr,
+}
+
+impl AclRequest<'_> {
+    /// The IP of the target, `None` for a domain not resolved yet.
+    pub fn target_ip(&self) -> Option<IpAddr> {
+        match self.target {
+            TargetAddr::Ip(addr) => Some(addr.ip()),
+            TargetAddr::Domain(..) => None,
+        }
+    }
+}
+
+/// One rule of an [`AccessControl`] list.
+///
+/// Returns `None` when the rule doesn't apply to the request, so the next rules are
+/// checked. Implemented by closures taking an [`AclRequest`].
+pub trait AclRule: Send + Sync {
+    fn evaluate(&self, request: &AclRequest<'_>) -> Option<AclAction>;
+}
+
+impl<F> AclRule for F
+where
+    F: Fn(&AclRequest<'_>) -> Option<AclAction> + Send + Sync,
+{
+    fn evaluate(&self, request: &AclRequest<'_>) -> Option<AclAction> {
+        self(request)
+    }
+}
+
+/// An ordered list of access rules: the first rule applying to a request decides, or
+/// the default action if none does.
+///
+/// Check it after reading the command, and reply `ReplyError::ConnectionNotAllowed` to
+/// the denied requests.
+pub struct AccessControl {
+    rules: Vec<Box<dyn AclRule>>,
+    default: AclAction,
+}
+
+impl std::fmt::Debug for AccessControl {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AccessControl")
+            .field("rules", &self.rules.len())
+            .field("default", &self.default)
+            .finish()
+    }
+}
+
+impl Default for AccessControl {
+    fn default() -> Self {
+        AccessControl::new(AclAction::Allow)
+    }
+}
+
+impl AccessControl {
+    /// An empty list, taking `default` for the requests no rule applies to.
+    pub fn new(default: AclAction) -> Self {
+        AccessControl {
+            rules: vec![],
+            default,
+        }
+    }
+
+    /// Add a rule, checked after the ones already added.
+    pub fn push<R: AclRule + 'static>(&mut self, rule: R) -> &mut Self {
+        self.rules.push(Box::new(rule));
+        self
+    }
+
+    pub fn check(&self, request: &AclRequest<'_>) -> AclAction {
+        let action = self
+            .rules
+            .iter()
+            .find_map(|rule| rule.evaluate(request))
+            .unwrap_or(self.default);
+        if action == AclAction::Deny {
+            debug!(
+                "{} denied access to {} ({:?})",
+                request.client_ip, request.target, request.command
+            );
+        }
+        action
+    }
+
+    pub fn is_allowed(&self, request: &AclRequest<'_>) -> bool {
+        self.check(request) == AclAction::Allow
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AccessControl, AclAction, AclRequest};
+    use crate::util::target_addr::TargetAddr;
+    use crate::Socks5Command;
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let mut acl = AccessControl::new(AclAction::Deny);
+        acl.push(|req: &AclRequest<'_>| {
+            (req.username == Some("admin")).then_some(AclAction::Allow)
+        })
+        .push(|req: &AclRequest<'_>| {
+            (req.command == Socks5Command::TCPConnect).then_some(AclAction::Allow)
+        });
+
+        let target = TargetAddr::Domain("example.com".to_owned(), 443);
+        let mut req = AclRequest {
+            client_ip: "192.0.2.1".parse().unwrap(),
+            username: None,
+            command: Socks5Command::UDPAssociate,
+            target: &target,
+        };
+        assert_eq!(acl.check(&req), AclAction::Deny);
+        req.username = Some("admin");
+        assert!(acl.is_allowed(&req));
+        req.username = None;
+        req.command = Socks5Command::TCPConnect;
+        assert!(acl.is_allowed(&req));
+        assert_eq!(req.target_ip(), None);
+    }
+}
@@ -0,0 +1,184 @@
+use super::memory::BufferReservation;
+use super::MemoryReservation;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+// reads filling the whole buffer in a row before it doubles
+const GROW_AFTER: u32 = 2;
+// nothing read for this long gives the buffer back
+const SHRINK_AFTER: Duration = Duration::from_secs(2);
+
+/// A relay buffer of `min` bytes, doubling up to `max` while the reads keep filling it
+/// and the memory budget allows, back to `min` once the direction idles.
+#[derive(Debug)]
+struct AdaptiveBuffer {
+    buf: Vec<u8>,
+    min: usize,
+    max: usize,
+    full_reads: u32,
+    memory: BufferReservation,
+}
+
+impl AdaptiveBuffer {
+    fn new(min: usize, max: usize, memory: Option<Arc<MemoryReservation>>) -> io::Result<Self> {
+        let min = min.max(1);
+        let mut memory = BufferReservation::new(memory);
+        if !memory.resize(min) {
+            return Err(io::Error::from(io::ErrorKind::OutOfMemory));
+        }
+        Ok(AdaptiveBuffer {
+            buf: vec![0; min],
+            min,
+            max: max.max(min),
+            full_reads: 0,
+            memory,
+        })
+    }
+
+    /// Account for a read of `n` bytes, the buffer being resized before the next one.
+    fn record(&mut self, n: usize) {
+        if n < self.buf.len() {
+            self.full_reads = 0;
+            return;
+        }
+        self.full_reads += 1;
+        if self.full_reads >= GROW_AFTER && self.buf.len() < self.max {
+            let len = (self.buf.len() * 2).min(self.max);
+            if self.memory.resize(len) {
+                trace!("growing relay buffer to {} bytes", len);
+                self.buf = vec![0; len];
+            }
+            self.full_reads = 0;
+        }
+    }
+
+    fn shrink(&mut self) {
+        if self.buf.len() > self.min {
+            trace!("shrinking idle relay buffer to {} bytes", self.min);
+            self.buf = vec![0; self.min];
+            self.memory.resize(self.min);
+        }
+        self.full_reads = 0;
+    }
+}
+
+/// Copy until EOF with an [`AdaptiveBuffer`], then shut `writer` down, returning the
+/// number of bytes copied.
+async fn copy_adaptive<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    min: usize,
+    max: usize,
+    memory: Option<Arc<MemoryReservation>>,
+) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buffer = AdaptiveBuffer::new(min, max, memory)?;
+    let mut copied = 0;
+    loop {
+        // reads are cancel safe, so an idle one can be restarted with a smaller buffer
+        let n = if buffer.buf.len() > buffer.min {
+            match tokio::time::timeout(SHRINK_AFTER, reader.read(&mut buffer.buf)).await {
+                Ok(res) => res?,
+                Err(_) => {
+                    buffer.shrink();
+                    continue;
+                }
+            }
+        } else {
+            reader.read(&mut buffer.buf).await?
+        };
+        if n == 0 {
+            writer.shutdown().await?;
+            return Ok(copied);
+        }
+        writer.write_all(&buffer.buf[..n]).await?;
+        copied += n as u64;
+        buffer.record(n);
+    }
+}
+
+/// Relay both directions with adaptive buffers, until both end if `half_close`, the
+/// first one otherwise.
+pub(crate) async fn relay_adaptive<I, O>(
+    inbound: &mut I,
+    outbound: &mut O,
+    (min, max): (usize, usize),
+    half_close: bool,
+    memory: Option<Arc<MemoryReservation>>,
+) -> io::Result<()>
+where
+    I: AsyncRead + AsyncWrite + Unpin,
+    O: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut inbound_read, mut inbound_write) = tokio::io::split(inbound);
+    let (mut outbound_read, mut outbound_write) = tokio::io::split(outbound);
+    let upload = copy_adaptive(
+        &mut inbound_read,
+        &mut outbound_write,
+        min,
+        max,
+        memory.clone(),
+    );
+    let download = copy_adaptive(&mut outbound_read, &mut inbound_write, min, max, memory);
+    if half_close {
+        return tokio::try_join!(upload, download).map(|_| ());
+    }
+    let res = tokio::select! {
+        res = upload => res,
+        res = download => res,
+    };
+    let _ = inbound_write.shutdown().await;
+    let _ = outbound_write.shutdown().await;
+    res.map(|_| ())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{copy_adaptive, AdaptiveBuffer};
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn adaptive_buffer() {
+        let mut buffer = AdaptiveBuffer::new(1024, 4096, None).unwrap();
+        buffer.record(1024);
+        assert_eq!(buffer.buf.len(), 1024);
+        buffer.record(1024);
+        assert_eq!(buffer.buf.len(), 2048);
+        // a short read breaks the streak
+        buffer.record(2048);
+        buffer.record(100);
+        buffer.record(2048);
+        assert_eq!(buffer.buf.len(), 2048);
+        for _ in 0..10 {
+            let len = buffer.buf.len();
+            buffer.record(len);
+        }
+        assert_eq!(buffer.buf.len(), 4096);
+        buffer.shrink();
+        assert_eq!(buffer.buf.len(), 1024);
+
+        let data: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+        let (mut client, mut inbound) = duplex(8192);
+        let (mut outbound, mut target) = duplex(8192);
+        let copy = tokio::spawn(async move {
+            copy_adaptive(&mut inbound, &mut outbound, 512, 16384, None).await
+        });
+        let write = tokio::spawn({
+            let data = data.clone();
+            async move {
+                client.write_all(&data).await.unwrap();
+                client.shutdown().await.unwrap();
+            }
+        });
+        let mut received = vec![];
+        target.read_to_end(&mut received).await.unwrap();
+        write.await.unwrap();
+        assert_eq!(copy.await.unwrap().unwrap(), data.len() as u64);
+        assert_eq!(received, data);
+    }
+}
@@ -0,0 +1,229 @@
+use super::StandardAuthentication;
+use std::hint::black_box;
+use std::net::IpAddr;
+
+/// Compare a password sent by a client with the expected one in constant time, so that
+/// the time taken doesn't tell an attacker how many leading bytes were right.
+///
+/// Only the length of the expected password may leak. Use it instead of `==` in the
+/// password checks, or [`verify_credentials`] to check the username too.
+pub fn verify_password(given: &str, expected: &str) -> bool {
+    constant_time_eq(given.as_bytes(), expected.as_bytes())
+}
+
+/// Compare both the username and the password sent by a client in constant time.
+///
+/// Both are always compared, so that the time taken doesn't tell whether the username
+/// was right, as `user == username && verify_password(..)` would.
+pub fn verify_credentials(
+    given_user: &str,
+    given_password: &str,
+    username: &str,
+    password: &str,
+) -> bool {
+    let user_ok = verify_password(given_user, username);
+    let password_ok = verify_password(given_password, password);
+    user_ok & password_ok
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let diff = a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y));
+    black_box(diff) == 0
+}
+
+/// Which clients may skip authentication, by source address, the others must send a
+/// username and password.
+///
+/// Pass [`NoAuthPolicy::methods`] to `Socks5ServerProtocol::negotiate_auth`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NoAuthPolicy {
+    /// Every client must authenticate.
+    #[default]
+    Never,
+    /// Clients on the same host, `127.0.0.0/8` and `::1`.
+    Loopback,
+    /// Clients on the same host or a private network: RFC 1918 and link-local IPv4,
+    /// unique local and link-local IPv6.
+    Lan,
+    /// No client has to authenticate.
+    Always,
+}
+
+impl NoAuthPolicy {
+    /// Whether a client connecting from `ip` may skip authentication.
+    pub fn allows(self, ip: IpAddr) -> bool {
+        // IPv4 clients of a dual-stack listener show up as mapped addresses
+        let ip = ip.to_canonical();
+        match self {
+            NoAuthPolicy::Never => false,
+            NoAuthPolicy::Loopback => ip.is_loopback(),
+            NoAuthPolicy::Lan => match ip {
+                IpAddr::V4(v4) => v4.is_loopback() || v4.is_private() || v4.is_link_local(),
+                IpAddr::V6(v6) => {
+                    let segment = v6.segments()[0];
+                    v6.is_loopback() || (segment & 0xfe00) == 0xfc00 || (segment & 0xffc0) == 0xfe80
+                }
+            },
+            NoAuthPolicy::Always => true,
+        }
+    }
+
+    /// The auth methods accepted from a client connecting from `ip`, password
+    /// authentication being preferred when the client offers both.
+    pub fn methods(self, ip: IpAddr) -> &'static [StandardAuthentication] {
+        StandardAuthentication::allow_no_auth(self.allows(ip))
+    }
+}
+
+/// A PBKDF2-HMAC-SHA256 hash of a password, so that the server doesn't store the
+/// passwords themselves.
+///
+/// The equality of two hashes is checked in constant time, like [`PasswordHash::verify`].
+#[cfg(feature = "password-hash")]
+#[derive(Clone)]
+pub struct PasswordHash {
+    salt: Vec<u8>,
+    rounds: u32,
+    hash: [u8; 32],
+}
+
+#[cfg(feature = "password-hash")]
+impl PartialEq for PasswordHash {
+    fn eq(&self, other: &Self) -> bool {
+        let hash_eq = constant_time_eq(&self.hash, &other.hash);
+        hash_eq & (self.rounds == other.rounds) & (self.salt == other.salt)
+    }
+}
+
+#[cfg(feature = "password-hash")]
+impl Eq for PasswordHash {}
+
+#[cfg(feature = "password-hash")]
+impl std::fmt::Debug for PasswordHash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("PasswordHash(<redacted>)")
+    }
+}
+
+#[cfg(feature = "password-hash")]
+impl PasswordHash {
+    /// The rounds of [`PasswordHash::new`], as recommended by OWASP for PBKDF2-HMAC-SHA256.
+    pub const DEFAULT_ROUNDS: u32 = 600_000;
+
+    /// Hash `password` with `salt`, which should be random and unique to each user.
+    pub fn new(password: &str, salt: &[u8]) -> Self {
+        Self::with_rounds(password, salt, Self::DEFAULT_ROUNDS)
+    }
+
+    pub fn with_rounds(password: &str, salt: &[u8], rounds: u32) -> Self {
+        PasswordHash {
+            salt: salt.to_vec(),
+            rounds,
+            hash: Self::digest(password, salt, rounds),
+        }
+    }
+
+    /// A hash computed before, by [`PasswordHash::new`] or [`PasswordHash::with_rounds`].
+    pub fn from_parts(salt: Vec<u8>, rounds: u32, hash: [u8; 32]) -> Self {
+        PasswordHash { salt, rounds, hash }
+    }
+
+    pub fn salt(&self) -> &[u8] {
+        &self.salt
+    }
+
+    pub fn rounds(&self) -> u32 {
+        self.rounds
+    }
+
+    pub fn hash(&self) -> &[u8; 32] {
+        &self.hash
+    }
+
+    /// Check a password sent by a client, in constant time.
+    pub fn verify(&self, password: &str) -> bool {
+        constant_time_eq(&Self::digest(password, &self.salt, self.rounds), &self.hash)
+    }
+
+    fn digest(password: &str, salt: &[u8], rounds: u32) -> [u8; 32] {
+        let mut hash = [0u8; 32];
+        pbkdf2::pbkdf2_hmac::<sha2::Sha256>(password.as_bytes(), salt, rounds, &mut hash);
+        hash
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{verify_credentials, verify_password, NoAuthPolicy};
+
+    #[test]
+    fn password_checks() {
+        assert!(verify_password("hunter2", "hunter2"));
+        assert!(!verify_password("hunter3", "hunter2"));
+        assert!(!verify_password("hunter", "hunter2"));
+        assert!(!verify_password("", "hunter2"));
+        assert!(verify_credentials("alice", "hunter2", "alice", "hunter2"));
+        assert!(!verify_credentials("bob", "hunter2", "alice", "hunter2"));
+        assert!(!verify_credentials("alice", "hunter3", "alice", "hunter2"));
+
+        #[cfg(feature = "password-hash")]
+        {
+            use super::PasswordHash;
+            // RFC 7914, section 11
+            let known = PasswordHash::with_rounds("passwd", b"salt", 1);
+            assert_eq!(
+                known.hash()[..8],
+                [0x55, 0xac, 0x04, 0x6e, 0x56, 0xe3, 0x08, 0x9f]
+            );
+
+            let stored = PasswordHash::with_rounds("hunter2", b"salt", 1_000);
+            let stored =
+                PasswordHash::from_parts(stored.salt().to_vec(), stored.rounds(), *stored.hash());
+            assert!(stored.verify("hunter2"));
+            assert!(!stored.verify("hunter3"));
+            assert_ne!(
+                PasswordHash::with_rounds("hunter2", b"pepper", 1_000),
+                stored
+            );
+            assert_ne!(PasswordHash::with_rounds("hunter2", b"salt", 999), stored);
+        }
+    }
+
+    #[test]
+    fn no_auth_policy() {
+        let clients = [
+            "127.0.0.1",
+            "::ffff:127.0.0.1",
+            "192.168.1.2",
+            "fd00::1",
+            "8.8.8.8",
+        ];
+        for (policy, expected) in [
+            (NoAuthPolicy::Never, [false, false, false, false, false]),
+            (NoAuthPolicy::Loopback, [true, true, false, false, false]),
+            (NoAuthPolicy::Lan, [true, true, true, true, false]),
+            (NoAuthPolicy::Always, [true, true, true, true, true]),
+        ] {
+            for (client, expected) in clients.iter().zip(expected) {
+                assert_eq!(
+                    policy.allows(client.parse().unwrap()),
+                    expected,
+                    "{:?} {}",
+                    policy,
+                    client
+                );
+            }
+        }
+        assert_eq!(
+            NoAuthPolicy::Lan.methods("10.0.0.1".parse().unwrap()).len(),
+            2
+        );
+        assert_eq!(
+            NoAuthPolicy::Lan.methods("1.1.1.1".parse().unwrap()).len(),
+            1
+        );
+    }
+}
@@ -0,0 +1,210 @@
+use super::sharded::ShardedMap;
+use super::{
+    states, AuthMethodSuccessState, CheckResult, MethodPreference, NoAuthentication,
+    PasswordAuthentication, Socks5ServerProtocol, SocksServerError, StandardAuthentication,
+    StandardAuthenticationStarted, StateStore,
+};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Password authentication remembered by client IP: once a client authenticated with a
+/// password, the later connections from its IP may use no-authentication.
+///
+/// Convenient for clients which can't be configured with credentials for every
+/// connection, at the cost of trusting everyone behind the same IP.
+///
+/// The IPs are kept in shards with a lock each, so that concurrent accepts rarely wait
+/// for one another, and in a [`StateStore`] shared with the other instances if set.
+#[derive(Debug, Default)]
+pub struct AuthOnceAcceptor {
+    ips: ShardedMap<IpAddr, ()>,
+    store: Option<Arc<dyn StateStore>>,
+    failure_delay: Duration,
+    method_preference: MethodPreference,
+    accepted_versions: Option<Vec<u8>>,
+}
+
+impl AuthOnceAcceptor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Wait `delay` before rejecting wrong credentials, see
+    /// `Socks5ServerProtocol::set_auth_failure_delay`.
+    pub fn set_auth_failure_delay(&mut self, delay: Duration) -> &mut Self {
+        self.failure_delay = delay;
+        self
+    }
+
+    /// Only accept these VER bytes in the username/password subnegotiation, see
+    /// `PasswordAuthenticationImpl::set_accepted_versions`.
+    pub fn set_accepted_versions(&mut self, versions: &[u8]) -> &mut Self {
+        self.accepted_versions = Some(versions.to_vec());
+        self
+    }
+
+    /// With `MethodPreference::Client`, clients already authenticated which offer a
+    /// password first are asked for it again instead of passing without one.
+    pub fn set_method_preference(&mut self, preference: MethodPreference) -> &mut Self {
+        self.method_preference = preference;
+        self
+    }
+
+    /// Share the authenticated IPs with the other instances using `store`: a client
+    /// authenticated on one of them needs no password on the others.
+    ///
+    /// The IPs seen in the store are remembered locally too, `forget` and `clear` only
+    /// act on this instance, remove the `auth-once:<ip>` keys from the store as well.
+    pub fn set_state_store(&mut self, store: Arc<dyn StateStore>) -> &mut Self {
+        self.store = Some(store);
+        self
+    }
+
+    /// Whether `ip` already authenticated with a password on this instance.
+    pub fn is_authenticated(&self, ip: IpAddr) -> bool {
+        self.ips.contains_key(&ip)
+    }
+
+    /// Require a password again from `ip`.
+    pub fn forget(&self, ip: IpAddr) -> bool {
+        self.ips.remove(&ip).is_some()
+    }
+
+    /// Require a password again from every client, e.g. when the credentials changed.
+    pub fn clear(&self) {
+        self.ips.clear();
+    }
+
+    /// Whether `ip` authenticated on this instance or, per the store, on another one.
+    async fn is_authenticated_anywhere(&self, ip: IpAddr) -> bool {
+        if self.is_authenticated(ip) {
+            return true;
+        }
+        let Some(store) = &self.store else {
+            return false;
+        };
+        match store.contains(&auth_once_key(ip)).await {
+            Ok(true) => {
+                self.ips.insert(ip, ());
+                true
+            }
+            Ok(false) => false,
+            Err(err) => {
+                warn!("can't check whether {} authenticated: {}", ip, err);
+                false
+            }
+        }
+    }
+
+    /// Handle the SOCKS5 auth negotiation of a client connecting from `client_ip`.
+    ///
+    /// Clients which already authenticated may use no-authentication, the others must
+    /// send a username and password, checked by `check` as with
+    /// `Socks5ServerProtocol::accept_password_auth`. Returns the result of `check`, or
+    /// `None` when no password was needed.
+    pub async fn accept<T, F, R>(
+        &self,
+        inner: T,
+        client_ip: IpAddr,
+        check: F,
+    ) -> Result<(Socks5ServerProtocol<T, states::Authenticated>, Option<R>), SocksServerError>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+        F: FnOnce(String, String) -> R,
+        R: CheckResult,
+    {
+        let methods: &[StandardAuthentication] = if self.is_authenticated_anywhere(client_ip).await
+        {
+            &[
+                StandardAuthentication::NoAuthentication(NoAuthentication),
+                StandardAuthentication::PasswordAuthentication(PasswordAuthentication),
+            ]
+        } else {
+            &[StandardAuthentication::PasswordAuthentication(
+                PasswordAuthentication,
+            )]
+        };
+
+        let mut proto = Socks5ServerProtocol::start(inner);
+        proto
+            .set_auth_failure_delay(self.failure_delay)
+            .set_method_preference(self.method_preference);
+        match proto.negotiate_auth(methods).await? {
+            StandardAuthenticationStarted::NoAuthentication(auth) => {
+                debug!("{} already authenticated, no password needed", client_ip);
+                Ok((auth.finish_auth(), None))
+            }
+            StandardAuthenticationStarted::PasswordAuthentication(mut auth) => {
+                if let Some(versions) = &self.accepted_versions {
+                    auth.set_accepted_versions(versions);
+                }
+                let (proto, check_result) = auth.check_username_password(check).await?;
+                if self.ips.insert(client_ip, ()).is_none() {
+                    info!(
+                        "{} authenticated, no password needed from now on",
+                        client_ip
+                    );
+                    if let Some(store) = &self.store {
+                        if let Err(err) = store.insert(&auth_once_key(client_ip), None).await {
+                            warn!("can't share that {} authenticated: {}", client_ip, err);
+                        }
+                    }
+                }
+                Ok((proto, Some(check_result)))
+            }
+        }
+    }
+}
+
+fn auth_once_key(ip: IpAddr) -> String {
+    format!("auth-once:{}", ip.to_canonical())
+}
+
+#[cfg(test)]
+mod test {
+    use super::AuthOnceAcceptor;
+    use crate::server::SocksServerError;
+    use std::net::{IpAddr, Ipv4Addr};
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    const PASSWORD_AUTH: [u8; 15] = [
+        5, 1, 2, 1, 5, b'a', b'l', b'i', b'c', b'e', 4, b'p', b'a', b's', b's',
+    ];
+
+    #[tokio::test]
+    async fn password_only_needed_once() {
+        let acceptor = AuthOnceAcceptor::new();
+        let client_ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let check = |user: String, pass: String| user == "alice" && pass == "pass";
+
+        // no-auth isn't accepted before a first authentication
+        let (mut client, server) = duplex(64);
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        let res = acceptor.accept(server, client_ip, check).await;
+        assert!(matches!(
+            res,
+            Err(SocksServerError::AuthMethodUnacceptable(_))
+        ));
+
+        let (mut client, server) = duplex(64);
+        client.write_all(&PASSWORD_AUTH).await.unwrap();
+        let (_, checked) = acceptor.accept(server, client_ip, check).await.unwrap();
+        assert_eq!(checked, Some(true));
+        let mut reply = [0u8; 4];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [5, 2, 1, 0]);
+
+        let (mut client, server) = duplex(64);
+        client.write_all(&[5, 2, 0, 2]).await.unwrap();
+        let (_, checked) = acceptor.accept(server, client_ip, check).await.unwrap();
+        assert_eq!(checked, None);
+        let mut reply = [0u8; 2];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [5, 0]);
+
+        assert!(acceptor.forget(client_ip));
+        assert!(!acceptor.is_authenticated(client_ip));
+    }
+}
@@ -0,0 +1,467 @@
+use super::adaptive_buffer::relay_adaptive;
+use super::memory::BufferReservation;
+use super::{MemoryReservation, SessionAddrs, TeardownMode, TransferOptions};
+use crate::util::target_addr::TargetAddr;
+use std::fmt;
+use std::future::{pending, Future};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+// a peer which doesn't read can't hold a policy close
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+// the buffer of each direction of tokio's copies
+const COPY_BUFFER_SIZE: usize = 8 * 1024;
+
+tokio::task_local! {
+    static SESSION_CLOSER: SessionCloser;
+}
+
+const NO_EOF: u8 = 0;
+const CLIENT_EOF: u8 = 1;
+const TARGET_EOF: u8 = 2;
+
+/// Why a relayed session ended, as returned by [`transfer_until_closed`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CloseReason {
+    /// The client stopped sending first.
+    ClientClosed,
+    /// The target stopped sending first.
+    TargetClosed,
+    /// Nothing was relayed for the idle timeout of the [`TransferOptions`].
+    IdleTimeout,
+    /// The client used up a quota.
+    QuotaExceeded,
+    /// Closed by an administrator.
+    Kicked,
+    /// The server is shutting down.
+    Shutdown,
+    /// Any other policy of the server.
+    Policy(String),
+    /// A connection failed.
+    Error(io::ErrorKind),
+}
+
+impl CloseReason {
+    /// Whether the server ended the session, rather than one of the peers.
+    pub fn is_policy(&self) -> bool {
+        !matches!(
+            self,
+            CloseReason::ClientClosed | CloseReason::TargetClosed | CloseReason::Error(_)
+        )
+    }
+
+    /// How the connections should be closed: a FIN when the session ran its course or
+    /// merely idled, a reset when it was cut short so that the peers don't take the
+    /// truncated stream for a complete one.
+    pub fn teardown_mode(&self) -> TeardownMode {
+        match self {
+            CloseReason::QuotaExceeded | CloseReason::Kicked | CloseReason::Policy(_) => {
+                TeardownMode::Reset
+            }
+            _ => TeardownMode::Graceful,
+        }
+    }
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CloseReason::ClientClosed => f.write_str("closed by the client"),
+            CloseReason::TargetClosed => f.write_str("closed by the target"),
+            CloseReason::IdleTimeout => f.write_str("idle timeout"),
+            CloseReason::QuotaExceeded => f.write_str("quota exceeded"),
+            CloseReason::Kicked => f.write_str("kicked"),
+            CloseReason::Shutdown => f.write_str("server shutting down"),
+            CloseReason::Policy(policy) => write!(f, "closed by policy: {}", policy),
+            CloseReason::Error(kind) => write!(f, "error: {}", kind),
+        }
+    }
+}
+
+/// Ends a session relayed by [`transfer_until_closed`] from elsewhere, e.g. an admin
+/// endpoint or a quota tracker keeping one per session.
+///
+/// The first reason given wins, closing before the relay started ends it right away.
+///
+/// It is also the handle of the session for the views listing them, carrying its
+/// addresses once known, see [`SessionCloser::scope`].
+#[derive(Debug, Clone, Default)]
+pub struct SessionCloser {
+    inner: Arc<CloserInner>,
+}
+
+#[derive(Debug, Default)]
+struct CloserInner {
+    reason: Mutex<Option<CloseReason>>,
+    notify: Notify,
+    addrs: Mutex<Option<SessionAddrs>>,
+    client_addrs: Mutex<Option<(SocketAddr, SocketAddr)>>,
+}
+
+impl SessionCloser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// End the session with `reason`, returning false if it was already closed.
+    pub fn close(&self, reason: CloseReason) -> bool {
+        let mut current = self.inner.reason.lock().unwrap();
+        if current.is_some() {
+            return false;
+        }
+        *current = Some(reason);
+        self.inner.notify.notify_waiters();
+        true
+    }
+
+    /// Why the session was closed, `None` while it runs.
+    pub fn reason(&self) -> Option<CloseReason> {
+        self.inner.reason.lock().unwrap().clone()
+    }
+
+    /// Attach the addresses of both legs, once the outbound connection is open.
+    pub fn set_addrs(&self, addrs: SessionAddrs) {
+        *self.inner.addrs.lock().unwrap() = Some(addrs);
+    }
+
+    /// The addresses of the session, `None` until set.
+    pub fn addrs(&self) -> Option<SessionAddrs> {
+        self.inner.addrs.lock().unwrap().clone()
+    }
+
+    /// The address of the client and the one it connected to, from which the addresses of
+    /// the session are filled in once the outbound connection is open, see
+    /// [`SessionCloser::scope`].
+    pub fn set_client_addrs(&self, client: SocketAddr, server_local: SocketAddr) {
+        *self.inner.client_addrs.lock().unwrap() = Some((client, server_local));
+    }
+
+    /// Fill in the addresses of the session from those of its outbound connection, if
+    /// all are known.
+    pub(crate) fn set_outbound_addrs(
+        &self,
+        local: Option<SocketAddr>,
+        peer: Option<SocketAddr>,
+        target: &TargetAddr,
+    ) {
+        let client_addrs = *self.inner.client_addrs.lock().unwrap();
+        if let (Some((client, server_local)), Some(local), Some(peer)) = (client_addrs, local, peer)
+        {
+            let addrs = SessionAddrs::new(client, server_local, local, peer, target.clone());
+            self.set_addrs(addrs);
+        }
+    }
+
+    /// The closer of the session running this task, if any, see [`SessionCloser::scope`].
+    pub fn current() -> Option<Self> {
+        SESSION_CLOSER.try_with(|closer| closer.clone()).ok()
+    }
+
+    /// Run a session future under this closer, making it available to
+    /// [`SessionCloser::current`].
+    ///
+    /// `run_tcp_proxy_with_options` and `run_tcp_proxy_with_dialer` then fill in its
+    /// addresses, with [`SessionCloser::set_client_addrs`] set, and end their relay when
+    /// it is closed.
+    pub async fn scope<F: Future>(self, session: F) -> F::Output {
+        SESSION_CLOSER.scope(self, session).await
+    }
+
+    /// Wait for [`SessionCloser::close`].
+    pub async fn closed(&self) -> CloseReason {
+        loop {
+            let notified = self.inner.notify.notified();
+            if let Some(reason) = self.reason() {
+                return reason;
+            }
+            notified.await;
+        }
+    }
+}
+
+/// Like [`transfer_with_options`](super::transfer_with_options), until both directions
+/// end, the idle timeout or `closer`, returning why the session ended.
+///
+/// When the server ends the session, both connections are shut down if
+/// [`CloseReason::teardown_mode`] is graceful. Otherwise they are left for the caller to
+/// reset with [`TeardownMode::close`], passing TCP streams by reference.
+pub async fn transfer_until_closed<I, O>(
+    inbound: I,
+    outbound: O,
+    opts: &TransferOptions,
+    closer: &SessionCloser,
+) -> CloseReason
+where
+    I: AsyncRead + AsyncWrite + Unpin,
+    O: AsyncRead + AsyncWrite + Unpin,
+{
+    let activity = Activity::new();
+    let mut inbound = Watched {
+        inner: inbound,
+        activity: &activity,
+        eof: CLIENT_EOF,
+    };
+    let mut outbound = Watched {
+        inner: outbound,
+        activity: &activity,
+        eof: TARGET_EOF,
+    };
+
+    let memory = opts.memory.clone().or_else(MemoryReservation::current);
+    let relay = async {
+        if let Some(sizes) = opts.adaptive_buffers {
+            let half_close = opts.half_close;
+            return relay_adaptive(&mut inbound, &mut outbound, sizes, half_close, memory).await;
+        }
+        let mut buffers = BufferReservation::new(memory);
+        if !buffers.resize(2 * COPY_BUFFER_SIZE) {
+            return Err(io::Error::from(io::ErrorKind::OutOfMemory));
+        }
+        if opts.half_close {
+            return tokio::io::copy_bidirectional(&mut inbound, &mut outbound)
+                .await
+                .map(|_| ());
+        }
+        let (mut inbound_read, mut inbound_write) = tokio::io::split(&mut inbound);
+        let (mut outbound_read, mut outbound_write) = tokio::io::split(&mut outbound);
+        let res = tokio::select! {
+            res = tokio::io::copy(&mut inbound_read, &mut outbound_write) => res,
+            res = tokio::io::copy(&mut outbound_read, &mut inbound_write) => res,
+        };
+        let _ = inbound_write.shutdown().await;
+        let _ = outbound_write.shutdown().await;
+        res.map(|_| ())
+    };
+    let reason = tokio::select! {
+        res = relay => match res {
+            Ok(()) => activity.first_eof(),
+            Err(err) => CloseReason::Error(err.kind()),
+        },
+        reason = closer.closed() => reason,
+        () = activity.idle(opts.idle_timeout) => CloseReason::IdleTimeout,
+    };
+    // later closes are moot, and observers of the closer see why it ended
+    closer.close(reason.clone());
+
+    if reason.is_policy() && reason.teardown_mode() == TeardownMode::Graceful {
+        let _ = tokio::time::timeout(SHUTDOWN_TIMEOUT, async {
+            let _ = inbound.shutdown().await;
+            let _ = outbound.shutdown().await;
+        })
+        .await;
+    }
+    let (sent, received) = activity.bytes();
+    info!("transfer {} ({}, {})", reason, sent, received);
+    reason
+}
+
+/// What the relay saw, shared by both streams.
+struct Activity {
+    start: Instant,
+    // milliseconds from `start`
+    last: AtomicU64,
+    first_eof: AtomicU8,
+    bytes: [AtomicU64; 2],
+}
+
+impl Activity {
+    fn new() -> Self {
+        Activity {
+            start: Instant::now(),
+            last: AtomicU64::new(0),
+            first_eof: AtomicU8::new(NO_EOF),
+            bytes: [AtomicU64::new(0), AtomicU64::new(0)],
+        }
+    }
+
+    /// The bytes sent by the client and by the target.
+    fn bytes(&self) -> (u64, u64) {
+        let [sent, received] = &self.bytes;
+        (
+            sent.load(Ordering::Relaxed),
+            received.load(Ordering::Relaxed),
+        )
+    }
+
+    fn read(&self, eof: u8, len: usize) {
+        let elapsed = self.start.elapsed().as_millis() as u64;
+        self.last.store(elapsed, Ordering::Relaxed);
+        let side = if eof == CLIENT_EOF { 0 } else { 1 };
+        self.bytes[side].fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    fn eof(&self, eof: u8) {
+        let _ = self
+            .first_eof
+            .compare_exchange(NO_EOF, eof, Ordering::Relaxed, Ordering::Relaxed);
+    }
+
+    fn first_eof(&self) -> CloseReason {
+        match self.first_eof.load(Ordering::Relaxed) {
+            CLIENT_EOF => CloseReason::ClientClosed,
+            _ => CloseReason::TargetClosed,
+        }
+    }
+
+    /// Complete once nothing was read for `timeout`, never if `None`.
+    async fn idle(&self, timeout: Option<Duration>) {
+        let Some(timeout) = timeout else {
+            return pending().await;
+        };
+        loop {
+            let last = Duration::from_millis(self.last.load(Ordering::Relaxed));
+            let deadline = self.start + last + timeout;
+            if Instant::now() >= deadline {
+                return;
+            }
+            tokio::time::sleep_until(deadline).await;
+        }
+    }
+}
+
+struct Watched<'a, S> {
+    inner: S,
+    activity: &'a Activity,
+    eof: u8,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Watched<'_, S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = res {
+            match buf.filled().len() - before {
+                0 if buf.remaining() > 0 => self.activity.eof(self.eof),
+                0 => {}
+                len => self.activity.read(self.eof, len),
+            }
+        }
+        res
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Watched<'_, S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{transfer_until_closed, CloseReason, SessionCloser};
+    use crate::server::{TeardownMode, TransferOptions};
+    use std::time::Duration;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn close_reasons() {
+        // the client closes first
+        let (mut client, inbound) = duplex(64);
+        let (outbound, mut target) = duplex(64);
+        let relay = tokio::spawn(async move {
+            let opts = TransferOptions::new();
+            transfer_until_closed(inbound, outbound, &opts, &SessionCloser::new()).await
+        });
+        client.shutdown().await.unwrap();
+        target.read_to_end(&mut vec![]).await.unwrap();
+        drop(target);
+        assert_eq!(relay.await.unwrap(), CloseReason::ClientClosed);
+
+        // nothing relayed for a while
+        let (mut client, inbound) = duplex(64);
+        let (outbound, mut target) = duplex(64);
+        let relay = tokio::spawn(async move {
+            let mut opts = TransferOptions::new();
+            opts.set_idle_timeout(Some(Duration::from_millis(50)));
+            transfer_until_closed(inbound, outbound, &opts, &SessionCloser::new()).await
+        });
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        target.read_exact(&mut buf).await.unwrap();
+        assert_eq!(relay.await.unwrap(), CloseReason::IdleTimeout);
+        // both peers saw a FIN
+        assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+        assert_eq!(target.read(&mut buf).await.unwrap(), 0);
+
+        // kicked from elsewhere
+        let (_client, inbound) = duplex(64);
+        let (outbound, _target) = duplex(64);
+        let closer = SessionCloser::new();
+        let relay = tokio::spawn({
+            let closer = closer.clone();
+            async move {
+                let opts = TransferOptions::new();
+                transfer_until_closed(inbound, outbound, &opts, &closer).await
+            }
+        });
+        assert!(closer.close(CloseReason::Kicked));
+        assert!(!closer.close(CloseReason::Shutdown));
+        let reason = relay.await.unwrap();
+        assert_eq!(reason, CloseReason::Kicked);
+        assert!(reason.is_policy());
+        assert_eq!(reason.teardown_mode(), TeardownMode::Reset);
+        assert_eq!(closer.reason(), Some(CloseReason::Kicked));
+    }
+
+    #[tokio::test]
+    async fn scoped_session_handle() {
+        use crate::server::test::tcp_pair;
+        use crate::server::{run_tcp_proxy_with_options, ConnectOptions, Socks5ServerProtocol};
+        use crate::util::target_addr::TargetAddr;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = TargetAddr::Ip(listener.local_addr().unwrap());
+        let (mut client, server) = tcp_pair().await;
+        let port = listener.local_addr().unwrap().port().to_be_bytes();
+        client
+            .write_all(&[5, 1, 0, 1, 127, 0, 0, 1, port[0], port[1]])
+            .await
+            .unwrap();
+        let (proto, _, _) = Socks5ServerProtocol::skip_auth_this_is_not_rfc_compliant(server)
+            .read_command()
+            .await
+            .unwrap();
+
+        let closer = SessionCloser::new();
+        let client_addr = client.local_addr().unwrap();
+        closer.set_client_addrs(client_addr, client.peer_addr().unwrap());
+        let relay = tokio::spawn(closer.clone().scope(async move {
+            run_tcp_proxy_with_options(proto, &target, &ConnectOptions::new()).await
+        }));
+        let (outbound, _) = listener.accept().await.unwrap();
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+
+        let addrs = closer.addrs().unwrap();
+        assert_eq!(addrs.client_addr(), client_addr);
+        assert_eq!(addrs.outbound_local_addr(), outbound.peer_addr().unwrap());
+        assert_eq!(addrs.outbound_peer_addr(), outbound.local_addr().unwrap());
+        // the relay ends with the session
+        closer.close(CloseReason::Kicked);
+        relay.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,306 @@
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+tokio::task_local! {
+    static SESSION_DEBUG: Arc<AtomicBool>;
+}
+
+/// A session attribute for which verbose logging can be turned on.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum DebugTarget {
+    /// Sessions coming from this source IP.
+    Ip(IpAddr),
+    /// Sessions authenticated with this username.
+    User(String),
+}
+
+/// Registry of sessions to be logged verbosely, each for a bounded time window.
+///
+/// This is meant to be driven by an admin interface, so a production issue can be
+/// debugged for one client without enabling debug logging globally. It only has an
+/// effect on sessions run through [`DebugTargets::instrument`] and when the
+/// [`TargetedLogger`] is installed as the global logger.
+#[derive(Debug, Default)]
+pub struct DebugTargets {
+    /// The deadline of each target, `None` past the range of `Instant`.
+    targets: Mutex<HashMap<DebugTarget, Option<Instant>>>,
+}
+
+impl DebugTargets {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Log sessions matching `target` verbosely for the next `duration`, until disabled
+    /// if it is too long to be represented.
+    pub fn enable(&self, target: DebugTarget, duration: Duration) {
+        // not the username, which is personal data
+        match &target {
+            DebugTarget::Ip(ip) => {
+                info!("Verbose logging enabled for {} during {:?}", ip, duration)
+            }
+            DebugTarget::User(_) => {
+                info!("Verbose logging enabled for a user during {:?}", duration)
+            }
+        }
+        self.targets
+            .lock()
+            .unwrap()
+            .insert(target, Instant::now().checked_add(duration));
+    }
+
+    /// Stop logging sessions matching `target` verbosely, returns whether it was enabled.
+    pub fn disable(&self, target: &DebugTarget) -> bool {
+        self.targets.lock().unwrap().remove(target).is_some()
+    }
+
+    /// The targets currently enabled, with the time left for each of them, `Duration::MAX`
+    /// for those enabled until disabled.
+    pub fn active(&self) -> Vec<(DebugTarget, Duration)> {
+        let now = Instant::now();
+        let mut targets = self.targets.lock().unwrap();
+        targets.retain(|_, deadline| deadline.is_none_or(|deadline| deadline > now));
+        targets
+            .iter()
+            .map(|(target, deadline)| {
+                let left = deadline.map_or(Duration::MAX, |deadline| deadline - now);
+                (target.clone(), left)
+            })
+            .collect()
+    }
+
+    pub fn is_enabled(&self, target: &DebugTarget) -> bool {
+        let mut targets = self.targets.lock().unwrap();
+        match targets.get(target) {
+            Some(deadline) if deadline.is_none_or(|deadline| deadline > Instant::now()) => true,
+            Some(_) => {
+                targets.remove(target);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Run a session future, logging it verbosely if its source IP is targeted.
+    ///
+    /// The username is usually only known after the authentication, use
+    /// [`DebugTargets::note_user`] from within the session once it is.
+    pub async fn instrument<F: Future>(&self, client_ip: IpAddr, session: F) -> F::Output {
+        let flag = Arc::new(AtomicBool::new(
+            self.is_enabled(&DebugTarget::Ip(client_ip)),
+        ));
+        SESSION_DEBUG.scope(flag, session).await
+    }
+
+    /// Mark the current session for verbose logging if `username` is targeted.
+    ///
+    /// Has no effect outside of a session run through [`DebugTargets::instrument`].
+    pub fn note_user(&self, username: &str) {
+        if self.is_enabled(&DebugTarget::User(username.to_owned())) {
+            let _ = SESSION_DEBUG.try_with(|flag| flag.store(true, Ordering::Relaxed));
+        }
+    }
+}
+
+fn current_session_targeted() -> bool {
+    SESSION_DEBUG
+        .try_with(|flag| flag.load(Ordering::Relaxed))
+        .unwrap_or(false)
+}
+
+/// A logger wrapper letting the records of targeted sessions through, whatever the
+/// filter of the wrapped logger is.
+///
+/// Records which the wrapped logger would have filtered out are re-emitted at
+/// `promote_to` level, prefixed with their original level. If the wrapped logger filters
+/// that level out too, they are re-emitted at the most verbose level it lets through,
+/// e.g. `Error` for an `env_logger` left to its default filter.
+pub struct TargetedLogger<L> {
+    inner: L,
+    session_level: Level,
+    promote_to: Level,
+}
+
+impl<L: Log + 'static> TargetedLogger<L> {
+    pub fn new(inner: L) -> Self {
+        TargetedLogger {
+            inner,
+            session_level: Level::Trace,
+            promote_to: Level::Info,
+        }
+    }
+
+    /// The most verbose level emitted for targeted sessions, `Trace` by default.
+    pub fn set_session_level(mut self, level: Level) -> Self {
+        self.session_level = level;
+        self
+    }
+
+    /// The level filtered-out records of targeted sessions are re-emitted at, `Info` by default.
+    pub fn set_promote_to(mut self, level: Level) -> Self {
+        self.promote_to = level;
+        self
+    }
+
+    /// Install as the global logger.
+    ///
+    /// This raises the global max level to `Trace`, the wrapped logger keeps
+    /// filtering the records of every session which isn't targeted.
+    pub fn install(self) -> Result<(), log::SetLoggerError> {
+        log::set_boxed_logger(Box::new(self))?;
+        log::set_max_level(LevelFilter::Trace);
+        Ok(())
+    }
+}
+
+impl<L: Log> TargetedLogger<L> {
+    /// The most verbose level up to `promote_to` which the wrapped logger lets through.
+    fn promoted_level(&self, target: &str) -> Option<Level> {
+        [
+            Level::Trace,
+            Level::Debug,
+            Level::Info,
+            Level::Warn,
+            Level::Error,
+        ]
+        .into_iter()
+        .filter(|level| *level <= self.promote_to)
+        .find(|level| {
+            self.inner
+                .enabled(&Metadata::builder().level(*level).target(target).build())
+        })
+    }
+}
+
+impl<L: Log> Log for TargetedLogger<L> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+            || (metadata.level() <= self.session_level && current_session_targeted())
+    }
+
+    fn log(&self, record: &Record) {
+        if self.inner.enabled(record.metadata()) {
+            self.inner.log(record);
+        } else if record.level() <= self.session_level && current_session_targeted() {
+            let Some(level) = self.promoted_level(record.target()) else {
+                return;
+            };
+            self.inner.log(
+                &Record::builder()
+                    .args(format_args!("[{}] {}", record.level(), record.args()))
+                    .level(level)
+                    .target(record.target())
+                    .module_path(record.module_path())
+                    .file(record.file())
+                    .line(record.line())
+                    .build(),
+            );
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{current_session_targeted, DebugTarget, DebugTargets, TargetedLogger};
+    use log::{Level, Log, Metadata, Record};
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    /// Keeps the records up to `Warn`, like an `env_logger` with `RUST_LOG=warn`.
+    #[derive(Clone, Default)]
+    struct WarnLogger(Arc<Mutex<Vec<(Level, String)>>>);
+
+    impl Log for WarnLogger {
+        fn enabled(&self, metadata: &Metadata) -> bool {
+            metadata.level() <= Level::Warn
+        }
+
+        fn log(&self, record: &Record) {
+            if self.enabled(record.metadata()) {
+                let line = record.args().to_string();
+                self.0.lock().unwrap().push((record.level(), line));
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[tokio::test]
+    async fn targets_only_matching_sessions() {
+        let targets = DebugTargets::new();
+        let watched = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let other = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+        targets.enable(DebugTarget::Ip(watched), Duration::from_secs(60));
+        targets.enable(
+            DebugTarget::User("alice".to_owned()),
+            Duration::from_secs(60),
+        );
+
+        assert!(
+            targets
+                .instrument(watched, async { current_session_targeted() })
+                .await
+        );
+        assert!(
+            !targets
+                .instrument(other, async { current_session_targeted() })
+                .await
+        );
+        let by_user = targets.instrument(other, async {
+            targets.note_user("alice");
+            current_session_targeted()
+        });
+        assert!(by_user.await);
+        assert!(!current_session_targeted());
+    }
+
+    #[test]
+    fn targets_expire() {
+        let targets = DebugTargets::new();
+        let target = DebugTarget::User("bob".to_owned());
+        targets.enable(target.clone(), Duration::ZERO);
+        assert!(!targets.is_enabled(&target));
+        assert!(targets.active().is_empty());
+
+        // too long to be represented, enabled until disabled
+        targets.enable(target.clone(), Duration::MAX);
+        assert!(targets.is_enabled(&target));
+        assert_eq!(targets.active(), [(target.clone(), Duration::MAX)]);
+        assert!(targets.disable(&target));
+    }
+
+    #[tokio::test]
+    async fn records_promoted_past_the_filter() {
+        let records = WarnLogger::default();
+        let logger = TargetedLogger::new(records.clone());
+        let targets = DebugTargets::new();
+        let watched = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        targets.enable(DebugTarget::Ip(watched), Duration::from_secs(60));
+
+        let log = || {
+            logger.log(
+                &Record::builder()
+                    .args(format_args!("relaying"))
+                    .level(Level::Debug)
+                    .target("fast_socks5::server")
+                    .build(),
+            )
+        };
+        log();
+        targets.instrument(watched, async { log() }).await;
+        assert_eq!(
+            *records.0.lock().unwrap(),
+            [(Level::Warn, "[DEBUG] relaying".to_owned())]
+        );
+    }
+}
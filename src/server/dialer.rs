@@ -0,0 +1,441 @@
+use super::{
+    connect_to_target, states, transfer_until_closed, try_notify, ConnectOptions, ErrorContext,
+    SessionCloser, Socks5ServerProtocol, SocksServerError, TransferOptions,
+};
+use crate::client::{self, Socks5Stream};
+use crate::util::proxy_url::ProxyUrl;
+use crate::util::target_addr::TargetAddr;
+use crate::util::target_pattern::{normalize_host, HostPattern};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// A stream opened by a [`Dialer`].
+pub trait DialStream: AsyncRead + AsyncWrite + Unpin + Send {
+    /// The egress address of the connection, replied to the client as BND, `None` if
+    /// unknown.
+    fn local_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    /// The address the connection reached, `None` if unknown.
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+impl DialStream for TcpStream {
+    fn local_addr(&self) -> Option<SocketAddr> {
+        TcpStream::local_addr(self).ok()
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        TcpStream::peer_addr(self).ok()
+    }
+}
+
+/// Through an upstream proxy, the egress address is the BND address it replied with, and
+/// the peer is the proxy.
+impl<S: DialStream> DialStream for Socks5Stream<S> {
+    fn local_addr(&self) -> Option<SocketAddr> {
+        match self.bind_addr()? {
+            TargetAddr::Ip(addr) => Some(*addr),
+            TargetAddr::Domain(..) => None,
+        }
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.get_ref().peer_addr()
+    }
+}
+
+impl<S: DialStream + ?Sized> DialStream for Box<S> {
+    fn local_addr(&self) -> Option<SocketAddr> {
+        (**self).local_addr()
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        (**self).peer_addr()
+    }
+}
+
+pub type DialedStream = Box<dyn DialStream>;
+
+/// Opens the outbound connections of CONNECT commands, directly or through an upstream
+/// proxy, see [`run_tcp_proxy_with_dialer`].
+#[async_trait::async_trait]
+pub trait Dialer: Send + Sync {
+    async fn dial(&self, target: &TargetAddr) -> Result<DialedStream, SocksServerError>;
+}
+
+/// Connect to the targets directly, as [`connect_to_target`] does.
+#[derive(Debug, Clone, Default)]
+pub struct DirectDialer {
+    opts: ConnectOptions,
+}
+
+impl DirectDialer {
+    pub fn new(opts: ConnectOptions) -> Self {
+        DirectDialer { opts }
+    }
+}
+
+#[async_trait::async_trait]
+impl Dialer for DirectDialer {
+    async fn dial(&self, target: &TargetAddr) -> Result<DialedStream, SocksServerError> {
+        Ok(Box::new(connect_to_target(target, &self.opts).await?))
+    }
+}
+
+/// Connect to the targets through a further SOCKS5 proxy.
+#[derive(Debug, Clone)]
+pub struct UpstreamDialer {
+    proxy: ProxyUrl,
+    config: client::Config,
+}
+
+impl UpstreamDialer {
+    pub fn new(proxy: ProxyUrl) -> Self {
+        UpstreamDialer {
+            proxy,
+            config: client::Config::default(),
+        }
+    }
+
+    /// The client settings used with the upstream, e.g. its connect timeout.
+    pub fn set_config(&mut self, config: client::Config) -> &mut Self {
+        self.config = config;
+        self
+    }
+}
+
+#[async_trait::async_trait]
+impl Dialer for UpstreamDialer {
+    async fn dial(&self, target: &TargetAddr) -> Result<DialedStream, SocksServerError> {
+        let (host, port) = match target {
+            TargetAddr::Ip(addr) => (addr.ip().to_string(), addr.port()),
+            TargetAddr::Domain(domain, port) => (domain.clone(), *port),
+        };
+        debug!("Connecting to {} through {}", target, self.proxy);
+        let stream = Socks5Stream::connect_with_url(&self.proxy, host, port, self.config.clone())
+            .await
+            .map_err(|err| SocksServerError::Upstream(Box::new(err)))?;
+        Ok(Box::new(stream))
+    }
+}
+
+/// Where [`DialerRoutes`] sends the targets matching a pattern.
+#[derive(Clone)]
+pub enum Route {
+    Dial(Arc<dyn Dialer>),
+    /// Refuse with `ReplyError::ConnectionNotAllowed`.
+    Block,
+}
+
+impl Route {
+    pub fn dialer<D: Dialer + 'static>(dialer: D) -> Self {
+        Route::Dial(Arc::new(dialer))
+    }
+}
+
+impl fmt::Debug for Route {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Route::Dial(_) => f.write_str("Dial"),
+            Route::Block => f.write_str("Block"),
+        }
+    }
+}
+
+/// A routing table choosing the dialer of each target, turning the server into a
+/// policy-routing egress gateway: direct, through upstream proxy A or B, or blocked.
+///
+/// The routes are checked in the order they were added, the first matching the target
+/// wins, otherwise the default route is used. A pattern is a domain, matching its
+/// subdomains too (`example.com` or `*.example.com`), an IP, a CIDR block (`10.0.0.0/8`)
+/// or `*`, optionally with a port (`example.com:443`, `[::1]:22`), or a port alone
+/// (`:25`). IPs and CIDR blocks only match targets given or resolved as IPs.
+#[derive(Debug, Clone)]
+pub struct DialerRoutes {
+    routes: Vec<(HostPattern, Option<u16>, Route)>,
+    default: Route,
+}
+
+impl DialerRoutes {
+    pub fn new(default: Route) -> Self {
+        DialerRoutes {
+            routes: vec![],
+            default,
+        }
+    }
+
+    /// Send the targets matching `pattern` to `route`, empty patterns are ignored.
+    pub fn add(&mut self, pattern: &str, route: Route) -> &mut Self {
+        let (host, port) = split_port(pattern.trim());
+        let host = if host.is_empty() && port.is_some() {
+            Some(HostPattern::Any)
+        } else {
+            HostPattern::parse(host)
+        };
+        if let Some(host) = host {
+            self.routes.push((host, port, route));
+        }
+        self
+    }
+
+    /// The route of `target`.
+    pub fn route(&self, target: &TargetAddr) -> &Route {
+        let (host, ip) = match target {
+            TargetAddr::Ip(addr) => (String::new(), Some(addr.ip().to_canonical())),
+            TargetAddr::Domain(domain, _) => normalize_host(domain),
+        };
+        self.routes
+            .iter()
+            .find(|(pattern, port, _)| {
+                port.is_none_or(|port| port == target.port()) && pattern.matches(&host, ip)
+            })
+            .map_or(&self.default, |(_, _, route)| route)
+    }
+}
+
+#[async_trait::async_trait]
+impl Dialer for DialerRoutes {
+    async fn dial(&self, target: &TargetAddr) -> Result<DialedStream, SocksServerError> {
+        match self.route(target) {
+            Route::Dial(dialer) => dialer.dial(target).await,
+            Route::Block => {
+                debug!("{} blocked by the routing table", target);
+                Err(SocksServerError::RouteBlocked)
+            }
+        }
+    }
+}
+
+/// Split the port out of `example.com:443`, `[::1]:22` or `:25`, a bare IPv6 having none.
+fn split_port(pattern: &str) -> (&str, Option<u16>) {
+    if let Some(bracketed) = pattern.strip_prefix('[') {
+        if let Some((host, rest)) = bracketed.split_once(']') {
+            let port = rest.strip_prefix(':').and_then(|port| port.parse().ok());
+            return (host, port);
+        }
+    }
+    match pattern.split_once(':') {
+        Some((host, port)) if !port.contains(':') => match port.parse() {
+            Ok(port) => (host, Some(port)),
+            Err(_) => (pattern, None),
+        },
+        _ => (pattern, None),
+    }
+}
+
+/// Like [`super::run_tcp_proxy_with_options`], opening the outbound connection with `dialer`
+/// and relaying with `transfer`.
+pub async fn run_tcp_proxy_with_dialer<T, D>(
+    mut proto: Socks5ServerProtocol<T, states::CommandRead>,
+    addr: &TargetAddr,
+    dialer: &D,
+    transfer: &TransferOptions,
+) -> Result<T, SocksServerError>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+    D: Dialer + ?Sized,
+{
+    let outbound = proto.while_client_connected(dialer.dial(addr)).await?;
+    let mut outbound = try_notify!(proto, outbound);
+    let closer = SessionCloser::current();
+    if let Some(closer) = &closer {
+        closer.set_outbound_addrs(outbound.local_addr(), outbound.peer_addr(), addr);
+    }
+
+    let early_data = proto.take_early_data();
+    if !early_data.is_empty() {
+        try_notify!(
+            proto,
+            outbound
+                .write_all(&early_data)
+                .await
+                .err_when("forwarding early data")
+        );
+    }
+
+    let bind = outbound
+        .local_addr()
+        .unwrap_or(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0));
+    let mut inner = proto.reply_success(bind).await?;
+
+    let closer = closer.unwrap_or_default();
+    transfer_until_closed(&mut inner, outbound, transfer, &closer).await;
+    Ok(inner)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{run_tcp_proxy_with_dialer, split_port, DirectDialer, Route, UpstreamDialer};
+    use crate::server::test::{reset, tcp_pair};
+    use crate::server::{
+        Dialer, DialerRoutes, Socks5ServerProtocol, SocksServerError, TransferOptions,
+    };
+    use crate::util::proxy_url::ProxyUrl;
+    use crate::util::target_addr::TargetAddr;
+    use crate::ReplyError;
+
+    #[tokio::test]
+    async fn routes_per_target() {
+        assert_eq!(split_port("example.com:443"), ("example.com", Some(443)));
+        assert_eq!(split_port("[::1]:22"), ("::1", Some(22)));
+        assert_eq!(split_port("::1"), ("::1", None));
+        assert_eq!(split_port(":25"), ("", Some(25)));
+
+        let upstream_a = Route::dialer(UpstreamDialer::new(ProxyUrl::new("proxy-a", 1080)));
+        let upstream_b = Route::dialer(UpstreamDialer::new(ProxyUrl::new("proxy-b", 1080)));
+        let mut routes = DialerRoutes::new(Route::dialer(DirectDialer::default()));
+        routes
+            .add(":25", Route::Block)
+            .add("*.streaming.example", upstream_a)
+            .add("10.0.0.0/8:443", upstream_b)
+            .add("ads.example", Route::Block);
+
+        let route = |target: &str, port: u16| {
+            let target = match target.parse() {
+                Ok(ip) => TargetAddr::Ip(std::net::SocketAddr::new(ip, port)),
+                Err(_) => TargetAddr::Domain(target.to_owned(), port),
+            };
+            format!("{:?}", routes.route(&target))
+        };
+        assert_eq!(route("mail.example", 25), "Block");
+        assert_eq!(route("cdn.streaming.example", 443), "Dial");
+        assert_eq!(route("tracker.ads.example", 80), "Block");
+        assert_eq!(route("example.com", 80), "Dial");
+        assert_eq!(route("10.1.2.3", 443), "Dial");
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = TargetAddr::Ip(listener.local_addr().unwrap());
+        assert!(routes.dial(&target).await.is_ok());
+        let blocked = TargetAddr::Domain("ads.example".to_owned(), 443);
+        let err = routes.dial(&blocked).await.err().unwrap();
+        assert!(matches!(err, SocksServerError::RouteBlocked));
+        assert!(matches!(
+            err.to_reply_error(),
+            ReplyError::ConnectionNotAllowed
+        ));
+    }
+
+    #[tokio::test]
+    async fn upstream_dial_cancelled_on_client_disconnect() {
+        use std::time::Duration;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // an upstream proxy which accepts, then never answers the handshake
+        let upstream = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = ProxyUrl::new("127.0.0.1", upstream.local_addr().unwrap().port());
+        let dialer = UpstreamDialer::new(proxy);
+
+        let (mut client, server) = tcp_pair().await;
+        client
+            .write_all(&[5, 1, 0, 1, 192, 0, 2, 1, 0, 80])
+            .await
+            .unwrap();
+        let (proto, _, target) = Socks5ServerProtocol::skip_auth_this_is_not_rfc_compliant(server)
+            .read_command()
+            .await
+            .unwrap();
+        let relay = tokio::spawn(async move {
+            run_tcp_proxy_with_dialer(proto, &target, &dialer, &TransferOptions::new())
+                .await
+                .err()
+        });
+        let (mut upstream, _) = upstream.accept().await.unwrap();
+        reset(client);
+
+        let res = tokio::time::timeout(Duration::from_secs(1), relay).await;
+        assert!(matches!(
+            res.unwrap().unwrap(),
+            Some(SocksServerError::ClientDisconnected(_))
+        ));
+        // the connection to the upstream is closed along with the dial
+        let mut buf = [0u8; 16];
+        let mut greeting = 0;
+        loop {
+            match upstream.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => greeting += n,
+            }
+        }
+        assert_eq!(greeting, 3);
+    }
+
+    #[tokio::test]
+    async fn upstream_bind_addr_replied() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // an upstream proxy egressing from 198.51.100.7:4321
+        let upstream = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = ProxyUrl::new("127.0.0.1", upstream.local_addr().unwrap().port());
+        tokio::spawn(async move {
+            let (mut upstream, _) = upstream.accept().await.unwrap();
+            let mut greeting = [0u8; 3];
+            upstream.read_exact(&mut greeting).await.unwrap();
+            upstream.write_all(&[5, 0]).await.unwrap();
+            let mut request = [0u8; 5];
+            upstream.read_exact(&mut request).await.unwrap();
+            let rest = match request[3] {
+                1 => 5,
+                _ => request[4] as usize + 2,
+            };
+            upstream.read_exact(&mut vec![0; rest]).await.unwrap();
+            upstream
+                .write_all(&[5, 0, 0, 1, 198, 51, 100, 7, 0x10, 0xe1])
+                .await
+                .unwrap();
+        });
+
+        let (mut client, server) = tcp_pair().await;
+        client
+            .write_all(&[5, 1, 0, 1, 192, 0, 2, 1, 0, 80])
+            .await
+            .unwrap();
+        let (proto, _, target) = Socks5ServerProtocol::skip_auth_this_is_not_rfc_compliant(server)
+            .read_command()
+            .await
+            .unwrap();
+        tokio::spawn(async move {
+            let dialer = UpstreamDialer::new(proxy);
+            run_tcp_proxy_with_dialer(proto, &target, &dialer, &TransferOptions::new()).await
+        });
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [5, 0, 0, 1, 198, 51, 100, 7, 0x10, 0xe1]);
+    }
+
+    #[tokio::test]
+    async fn dialed_relay_idle_timeout() {
+        use std::time::Duration;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port().to_be_bytes();
+        let (mut client, server) = tcp_pair().await;
+        client
+            .write_all(&[5, 1, 0, 1, 127, 0, 0, 1, port[0], port[1]])
+            .await
+            .unwrap();
+        let (proto, _, target) = Socks5ServerProtocol::skip_auth_this_is_not_rfc_compliant(server)
+            .read_command()
+            .await
+            .unwrap();
+        let relay = tokio::spawn(async move {
+            let mut transfer = TransferOptions::new();
+            transfer.set_idle_timeout(Some(Duration::from_millis(100)));
+            run_tcp_proxy_with_dialer(proto, &target, &DirectDialer::default(), &transfer).await
+        });
+        let (_target, _) = listener.accept().await.unwrap();
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+
+        // the idle session ends with the options of the caller
+        let res = tokio::time::timeout(Duration::from_secs(2), relay).await;
+        assert!(res.unwrap().unwrap().is_ok());
+    }
+}
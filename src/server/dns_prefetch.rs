@@ -0,0 +1,408 @@
+use super::sharded::ShardedMap;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::io;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+struct Entry {
+    ips: Vec<IpAddr>,
+    refresh_at: Instant,
+    /// When a learned domain was last seen in a request, `None` for the given domains.
+    seen: Option<Instant>,
+}
+
+/// The IPs of a list of domains, for routing rules which match on the resolved
+/// addresses of domains (e.g. allow only the IPs of `*.example.com`).
+///
+/// The set is kept up to date in the background by a [`DnsPrefetcher`], so the first
+/// client request isn't penalized by a lookup. IPs are kept past their TTL until they
+/// are successfully refreshed.
+///
+/// [`DomainIpSet::contains`], called for each request, looks up a sharded index of the
+/// IPs rather than locking the whole set.
+///
+/// The subdomains learned from the requests are forgotten once unseen for an hour, and
+/// at most 10000 of them are tracked, see [`DomainIpSet::set_learned_ttl`] and
+/// [`DomainIpSet::set_max_learned`].
+#[derive(Debug)]
+pub struct DomainIpSet {
+    patterns: Vec<String>,
+    entries: Mutex<HashMap<String, Entry>>,
+    // how many tracked domains resolved to each IP
+    ip_index: ShardedMap<IpAddr, usize>,
+    learned_ttl: Duration,
+    max_learned: usize,
+}
+
+impl Default for DomainIpSet {
+    fn default() -> Self {
+        DomainIpSet::new(Vec::<String>::new())
+    }
+}
+
+impl DomainIpSet {
+    /// Track the given domains. A `*.example.com` pattern tracks `example.com`, and
+    /// the subdomains reported by [`DomainIpSet::learn`].
+    pub fn new<I, S>(domains: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let patterns: Vec<String> = domains
+            .into_iter()
+            .map(|d| d.into().to_ascii_lowercase())
+            .collect();
+        let now = Instant::now();
+        let entries = patterns
+            .iter()
+            .map(|p| p.strip_prefix("*.").unwrap_or(p).to_owned())
+            .map(|domain| {
+                let entry = Entry {
+                    ips: vec![],
+                    refresh_at: now,
+                    seen: None,
+                };
+                (domain, entry)
+            })
+            .collect();
+        DomainIpSet {
+            patterns,
+            entries: Mutex::new(entries),
+            ip_index: ShardedMap::new(),
+            learned_ttl: Duration::from_secs(3600),
+            max_learned: 10_000,
+        }
+    }
+
+    /// Forget the learned domains not seen in a request for `ttl`, an hour by default.
+    pub fn set_learned_ttl(&mut self, ttl: Duration) -> &mut Self {
+        self.learned_ttl = ttl;
+        self
+    }
+
+    /// Track at most `n` learned domains, 10000 by default, the least recently seen
+    /// being forgotten first.
+    pub fn set_max_learned(&mut self, n: usize) -> &mut Self {
+        self.max_learned = n;
+        self
+    }
+
+    /// Whether `domain` is covered by one of the tracked domains or patterns.
+    pub fn matches_domain(&self, domain: &str) -> bool {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        self.patterns
+            .iter()
+            .any(|pattern| match pattern.strip_prefix("*.") {
+                Some(suffix) => {
+                    domain == suffix
+                        || (domain.len() > suffix.len()
+                            && domain.ends_with(suffix)
+                            && domain.as_bytes()[domain.len() - suffix.len() - 1] == b'.')
+                }
+                None => domain == *pattern,
+            })
+    }
+
+    /// Start tracking a domain seen in a client request if it matches a pattern,
+    /// returns whether it does.
+    pub fn learn(&self, domain: &str) -> bool {
+        if !self.matches_domain(domain) {
+            return false;
+        }
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(entry) = entries.get_mut(&domain) {
+            if entry.seen.is_some() {
+                entry.seen = Some(now);
+            }
+            return true;
+        }
+        self.forget_expired(&mut entries, now);
+        let learned = entries.values().filter(|entry| entry.seen.is_some());
+        if learned.count() >= self.max_learned {
+            let oldest = entries
+                .iter()
+                .filter_map(|(domain, entry)| Some((entry.seen?, domain.clone())))
+                .min();
+            match oldest {
+                Some((_, oldest)) => self.forget(&mut entries, &oldest),
+                None => return true,
+            }
+        }
+        entries.insert(
+            domain,
+            Entry {
+                ips: vec![],
+                refresh_at: now,
+                seen: Some(now),
+            },
+        );
+        true
+    }
+
+    /// Forget the learned domains unseen for the learned TTL.
+    fn forget_expired(&self, entries: &mut HashMap<String, Entry>, now: Instant) {
+        let expired: Vec<_> = entries
+            .iter()
+            .filter(|(_, entry)| {
+                entry
+                    .seen
+                    .is_some_and(|seen| now.saturating_duration_since(seen) >= self.learned_ttl)
+            })
+            .map(|(domain, _)| domain.clone())
+            .collect();
+        for domain in expired {
+            trace!("Forgetting the learned domain {}", domain);
+            self.forget(entries, &domain);
+        }
+    }
+
+    fn forget(&self, entries: &mut HashMap<String, Entry>, domain: &str) {
+        if let Some(entry) = entries.remove(domain) {
+            self.unindex(&entry.ips);
+        }
+    }
+
+    /// Remove the IPs of a domain from the index, under the lock of the entries so
+    /// that concurrent updates of a domain can't skew the counts.
+    fn unindex(&self, ips: &[IpAddr]) {
+        for ip in ips {
+            let mut shard = self.ip_index.write_shard(ip);
+            if let Some(count) = shard.get_mut(ip) {
+                *count -= 1;
+                if *count == 0 {
+                    shard.remove(ip);
+                }
+            }
+        }
+    }
+
+    /// Whether `ip` is one of the resolved IPs of the tracked domains.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.ip_index.contains_key(&ip)
+    }
+
+    /// The last resolved IPs of a tracked domain.
+    pub fn ips(&self, domain: &str) -> Vec<IpAddr> {
+        self.entries
+            .lock()
+            .unwrap()
+            .get(&domain.to_ascii_lowercase())
+            .map(|entry| entry.ips.clone())
+            .unwrap_or_default()
+    }
+
+    /// The tracked domains due for a refresh, most overdue first, at most `budget` of them.
+    pub fn due(&self, budget: usize) -> Vec<String> {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        self.forget_expired(&mut entries, now);
+        let mut due: Vec<_> = entries
+            .iter()
+            .filter(|(_, entry)| entry.refresh_at <= now)
+            .map(|(domain, entry)| (entry.refresh_at, domain.clone()))
+            .collect();
+        due.sort();
+        due.into_iter()
+            .take(budget)
+            .map(|(_, domain)| domain)
+            .collect()
+    }
+
+    /// Record the result of a lookup, the domain is due again after `ttl`.
+    pub fn update(&self, domain: &str, ips: Vec<IpAddr>, ttl: Duration) {
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(domain) {
+            self.unindex(&entry.ips);
+            for ip in &ips {
+                *self.ip_index.write_shard(ip).entry(*ip).or_insert(0) += 1;
+            }
+            entry.ips = ips;
+            entry.refresh_at = Instant::now() + ttl;
+        }
+    }
+
+    /// Postpone the refresh of a domain whose lookup failed, keeping its previous IPs.
+    fn postpone(&self, domain: &str, delay: Duration) {
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(domain) {
+            entry.refresh_at = Instant::now() + delay;
+        }
+    }
+}
+
+/// Resolve a domain with the system resolver, which doesn't tell the TTL.
+pub async fn system_lookup(domain: String) -> io::Result<(Vec<IpAddr>, Option<Duration>)> {
+    let ips: HashSet<IpAddr> = tokio::net::lookup_host((domain.as_str(), 0))
+        .await?
+        .map(|addr| addr.ip())
+        .collect();
+    Ok((ips.into_iter().collect(), None))
+}
+
+/// Background task keeping a [`DomainIpSet`] resolved, with a budget of lookups.
+#[derive(Debug, Clone)]
+pub struct DnsPrefetcher {
+    interval: Duration,
+    budget: usize,
+    default_ttl: Duration,
+    min_ttl: Duration,
+    retry_delay: Duration,
+}
+
+impl Default for DnsPrefetcher {
+    fn default() -> Self {
+        DnsPrefetcher {
+            interval: Duration::from_secs(1),
+            budget: 10,
+            default_ttl: Duration::from_secs(300),
+            min_ttl: Duration::from_secs(30),
+            retry_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+impl DnsPrefetcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How often the due domains are looked up.
+    pub fn set_interval(&mut self, interval: Duration) -> &mut Self {
+        self.interval = interval;
+        self
+    }
+
+    /// The maximum number of lookups per interval, to spare the resolver.
+    pub fn set_budget(&mut self, budget: usize) -> &mut Self {
+        self.budget = budget;
+        self
+    }
+
+    /// The refresh period when the resolver doesn't tell the TTL.
+    pub fn set_default_ttl(&mut self, ttl: Duration) -> &mut Self {
+        self.default_ttl = ttl;
+        self
+    }
+
+    /// The shortest refresh period, whatever the TTL of the records.
+    pub fn set_min_ttl(&mut self, ttl: Duration) -> &mut Self {
+        self.min_ttl = ttl;
+        self
+    }
+
+    /// How long to wait before looking up a domain again after a failure.
+    pub fn set_retry_delay(&mut self, delay: Duration) -> &mut Self {
+        self.retry_delay = delay;
+        self
+    }
+
+    /// Look up the domains currently due, within the budget.
+    pub async fn refresh<F, R>(&self, set: &DomainIpSet, lookup: &F)
+    where
+        F: Fn(String) -> R,
+        R: Future<Output = io::Result<(Vec<IpAddr>, Option<Duration>)>>,
+    {
+        for domain in set.due(self.budget) {
+            match lookup(domain.clone()).await {
+                Ok((ips, ttl)) => {
+                    let ttl = ttl.unwrap_or(self.default_ttl).max(self.min_ttl);
+                    trace!("Prefetched {} -> {:?}, next in {:?}", domain, ips, ttl);
+                    set.update(&domain, ips, ttl);
+                }
+                Err(err) => {
+                    debug!("Prefetching {} failed: {}", domain, err);
+                    set.postpone(&domain, self.retry_delay);
+                }
+            }
+        }
+    }
+
+    /// Keep `set` resolved with `lookup` until the returned task is aborted.
+    ///
+    /// Use `system_lookup` as `lookup` unless a resolver telling the TTLs is available.
+    pub fn spawn<F, R>(self, set: Arc<DomainIpSet>, lookup: F) -> tokio::task::JoinHandle<()>
+    where
+        F: Fn(String) -> R + Send + Sync + 'static,
+        R: Future<Output = io::Result<(Vec<IpAddr>, Option<Duration>)>> + Send,
+    {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(self.interval);
+            loop {
+                interval.tick().await;
+                self.refresh(&set, &lookup).await;
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DnsPrefetcher, DomainIpSet};
+    use std::io;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn prefetches_within_budget() {
+        let set = DomainIpSet::new(["a.example", "b.example", "*.c.example"]);
+        assert!(set.matches_domain("www.c.example"));
+        assert!(!set.matches_domain("wwwc.example"));
+        assert!(set.learn("www.c.example"));
+        assert!(!set.learn("d.example"));
+
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let lookup = |domain: String| async move {
+            match domain.as_str() {
+                "b.example" => Err(io::Error::other("SERVFAIL")),
+                _ => Ok((vec![ip], Some(Duration::from_secs(3600)))),
+            }
+        };
+
+        let mut prefetcher = DnsPrefetcher::new();
+        prefetcher.set_budget(2);
+        prefetcher.refresh(&set, &lookup).await;
+        assert_eq!(set.due(10).len(), 2);
+        prefetcher.refresh(&set, &lookup).await;
+        assert!(set.due(10).is_empty());
+
+        assert!(set.contains(ip));
+        assert_eq!(set.ips("www.c.example"), vec![ip]);
+        assert!(set.ips("b.example").is_empty());
+
+        // the IP is dropped once no tracked domain resolves to it
+        let other = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+        for domain in ["a.example", "c.example", "www.c.example"] {
+            set.update(domain, vec![other], Duration::from_secs(60));
+        }
+        assert!(!set.contains(ip));
+        assert!(set.contains(other));
+    }
+
+    #[tokio::test]
+    async fn learned_domains_bounded() {
+        let mut set = DomainIpSet::new(["*.example"]);
+        set.set_max_learned(2)
+            .set_learned_ttl(Duration::from_millis(50));
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        for domain in ["a.example", "b.example"] {
+            assert!(set.learn(domain));
+            set.update(domain, vec![ip], Duration::from_secs(60));
+        }
+
+        // the least recently seen is forgotten, along with its IPs
+        assert!(set.learn("a.example"));
+        assert!(set.learn("c.example"));
+        assert_eq!(set.ips("a.example"), vec![ip]);
+        assert!(set.ips("b.example").is_empty());
+        assert!(set.contains(ip));
+
+        // unseen for the TTL, the given domain stays
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(set.due(10), ["example"]);
+        assert!(set.ips("a.example").is_empty());
+        assert!(!set.contains(ip));
+    }
+}
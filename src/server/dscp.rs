@@ -0,0 +1,96 @@
+use super::AclRequest;
+use std::collections::HashMap;
+
+type DscpRule = Box<dyn Fn(&AclRequest<'_>) -> Option<u8> + Send + Sync>;
+
+/// Chooses the DSCP value of each session, to set with
+/// `SocketOptions::set_dscp` on the options it connects with.
+///
+/// The value of the authenticated user wins, then the first rule giving one, then the
+/// default.
+pub struct DscpPolicy {
+    users: HashMap<String, u8>,
+    rules: Vec<DscpRule>,
+    default: Option<u8>,
+}
+
+impl std::fmt::Debug for DscpPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DscpPolicy")
+            .field("users", &self.users)
+            .field("rules", &self.rules.len())
+            .field("default", &self.default)
+            .finish()
+    }
+}
+
+impl Default for DscpPolicy {
+    fn default() -> Self {
+        DscpPolicy::new(None)
+    }
+}
+
+impl DscpPolicy {
+    /// A policy marking with `default` when nothing else applies, `None` leaving the
+    /// system default.
+    pub fn new(default: Option<u8>) -> Self {
+        DscpPolicy {
+            users: HashMap::new(),
+            rules: vec![],
+            default,
+        }
+    }
+
+    pub fn set_user<S: Into<String>>(&mut self, username: S, dscp: u8) -> &mut Self {
+        self.users.insert(username.into(), dscp);
+        self
+    }
+
+    /// Add a rule, checked after the ones already added, e.g. marking a target port.
+    pub fn push<F>(&mut self, rule: F) -> &mut Self
+    where
+        F: Fn(&AclRequest<'_>) -> Option<u8> + Send + Sync + 'static,
+    {
+        self.rules.push(Box::new(rule));
+        self
+    }
+
+    pub fn dscp(&self, request: &AclRequest<'_>) -> Option<u8> {
+        request
+            .username
+            .and_then(|username| self.users.get(username).copied())
+            .or_else(|| self.rules.iter().find_map(|rule| rule(request)))
+            .or(self.default)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::DscpPolicy;
+    use crate::server::AclRequest;
+    use crate::util::target_addr::TargetAddr;
+    use crate::Socks5Command;
+
+    #[test]
+    fn dscp_per_user_and_rule() {
+        let mut policy = DscpPolicy::new(Some(0));
+        policy
+            .set_user("voip", 46)
+            .push(|req: &AclRequest<'_>| (req.target.port() == 22).then_some(16));
+
+        let target = TargetAddr::Domain("example.com".to_owned(), 22);
+        let mut req = AclRequest {
+            client_ip: "192.0.2.1".parse().unwrap(),
+            username: Some("voip"),
+            command: Socks5Command::TCPConnect,
+            target: &target,
+        };
+        assert_eq!(policy.dscp(&req), Some(46));
+        req.username = Some("bulk");
+        assert_eq!(policy.dscp(&req), Some(16));
+        let other = TargetAddr::Domain("example.com".to_owned(), 443);
+        req.target = &other;
+        assert_eq!(policy.dscp(&req), Some(0));
+        assert_eq!(DscpPolicy::default().dscp(&req), None);
+    }
+}
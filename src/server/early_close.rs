@@ -0,0 +1,217 @@
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Detects the clients which close the connection right after a successful reply without
+/// sending anything, which is what port scanners and proxy checkers usually do.
+///
+/// Such sessions are counted apart and logged at debug level, instead of showing up as
+/// transfer errors (they often end with a RST). A hook can be set to feed them into a
+/// ban or tarpit mechanism.
+pub struct EarlyCloseDetector {
+    window: Duration,
+    early_closes: AtomicU64,
+    on_early_close: Option<Box<dyn Fn(IpAddr) + Send + Sync>>,
+}
+
+impl std::fmt::Debug for EarlyCloseDetector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EarlyCloseDetector")
+            .field("window", &self.window)
+            .field("early_closes", &self.early_closes)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for EarlyCloseDetector {
+    fn default() -> Self {
+        EarlyCloseDetector::new(Duration::from_millis(500))
+    }
+}
+
+impl EarlyCloseDetector {
+    /// Sessions closed by the client within `window` of the reply, without data and before
+    /// the target closed, are early closes.
+    pub fn new(window: Duration) -> Self {
+        EarlyCloseDetector {
+            window,
+            early_closes: AtomicU64::new(0),
+            on_early_close: None,
+        }
+    }
+
+    /// Call `hook` with the client IP of each early-closed session.
+    pub fn set_on_early_close<F>(&mut self, hook: F) -> &mut Self
+    where
+        F: Fn(IpAddr) + Send + Sync + 'static,
+    {
+        self.on_early_close = Some(Box::new(hook));
+        self
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// How many early-closed sessions were detected so far.
+    pub fn early_closes(&self) -> u64 {
+        self.early_closes.load(Ordering::Relaxed)
+    }
+
+    /// Same as `transfer`, to be called right after replying success to `client_ip`.
+    ///
+    /// Returns whether the session was an early close.
+    pub async fn transfer<I, O>(&self, client_ip: IpAddr, inbound: I, outbound: O) -> bool
+    where
+        I: AsyncRead + AsyncWrite + Unpin,
+        O: AsyncRead + AsyncWrite + Unpin,
+    {
+        let started = Instant::now();
+        let mut inbound = ReadCounter::new(inbound);
+        let mut outbound = ReadCounter::new(outbound);
+        let res = tokio::io::copy_bidirectional(&mut inbound, &mut outbound).await;
+
+        // a target closing or resetting first isn't the client's doing
+        let client_first = match (inbound.closed, outbound.closed) {
+            (Some(client), Some(target)) => client <= target,
+            (client, _) => client.is_some(),
+        };
+        if inbound.read == 0 && client_first && started.elapsed() < self.window {
+            debug!(
+                "{} closed the connection {:?} after the reply without sending data ({:?})",
+                client_ip,
+                started.elapsed(),
+                res.err()
+            );
+            self.early_closes.fetch_add(1, Ordering::Relaxed);
+            if let Some(hook) = &self.on_early_close {
+                hook(client_ip);
+            }
+            return true;
+        }
+
+        match res {
+            Ok(res) => info!("transfer closed ({}, {})", res.0, res.1),
+            Err(err) => error!("transfer error: {:?}", err),
+        };
+        false
+    }
+}
+
+/// Counts the bytes read from a side, and notes when it closed: EOF, or an error.
+struct ReadCounter<T> {
+    inner: T,
+    read: u64,
+    closed: Option<Instant>,
+}
+
+impl<T> ReadCounter<T> {
+    fn new(inner: T) -> Self {
+        ReadCounter {
+            inner,
+            read: 0,
+            closed: None,
+        }
+    }
+
+    fn note_closed(&mut self, closed: bool) {
+        if closed && self.closed.is_none() {
+            self.closed = Some(Instant::now());
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for ReadCounter<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = buf.filled().len() - before;
+        self.read += read as u64;
+        let closed = match &res {
+            Poll::Ready(Ok(())) => read == 0 && buf.remaining() > 0,
+            Poll::Ready(Err(_)) => true,
+            Poll::Pending => false,
+        };
+        self.note_closed(closed);
+        res
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for ReadCounter<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let res = Pin::new(&mut self.inner).poll_write(cx, buf);
+        self.note_closed(matches!(res, Poll::Ready(Err(_))));
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::EarlyCloseDetector;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn counts_silent_early_closes() {
+        let client_ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let flagged = Arc::new(Mutex::new(vec![]));
+        let mut detector = EarlyCloseDetector::new(Duration::from_secs(5));
+        detector.set_on_early_close({
+            let flagged = flagged.clone();
+            move |ip| flagged.lock().unwrap().push(ip)
+        });
+
+        let (inbound, client) = duplex(64);
+        let (outbound, remote) = duplex(64);
+        drop((client, remote));
+        assert!(detector.transfer(client_ip, inbound, outbound).await);
+
+        let (inbound, mut client) = duplex(64);
+        let (outbound, mut remote) = duplex(64);
+        client.write_all(b"GET /").await.unwrap();
+        client.shutdown().await.unwrap();
+        let echo = tokio::spawn(async move {
+            let mut buf = [0u8; 5];
+            remote.read_exact(&mut buf).await.unwrap();
+        });
+        assert!(!detector.transfer(client_ip, inbound, outbound).await);
+        echo.await.unwrap();
+
+        // the target closes first, e.g. a service refusing the client
+        let (inbound, client) = duplex(64);
+        let (outbound, remote) = duplex(64);
+        drop(remote);
+        let transfer = detector.transfer(client_ip, inbound, outbound);
+        let close_client = async {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            drop(client);
+        };
+        let (early, ()) = tokio::join!(transfer, close_client);
+        assert!(!early);
+
+        assert_eq!(detector.early_closes(), 1);
+        assert_eq!(*flagged.lock().unwrap(), vec![client_ip]);
+    }
+}
@@ -0,0 +1,76 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// A client stream returned by `Socks5ServerProtocol::reply_success_buffered`, reading the
+/// data the client sent before the reply first.
+#[derive(Debug)]
+pub struct EarlyDataStream<T> {
+    inner: T,
+    early_data: Vec<u8>,
+    pos: usize,
+}
+
+impl<T> EarlyDataStream<T> {
+    pub(crate) fn new(inner: T, early_data: Vec<u8>) -> Self {
+        EarlyDataStream {
+            inner,
+            early_data,
+            pos: 0,
+        }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// The client stream and the early data not read yet.
+    pub fn into_parts(mut self) -> (T, Vec<u8>) {
+        self.early_data.drain(..self.pos);
+        (self.inner, self.early_data)
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for EarlyDataStream<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.pos < this.early_data.len() {
+            let n = buf.remaining().min(this.early_data.len() - this.pos);
+            buf.put_slice(&this.early_data[this.pos..this.pos + n]);
+            this.pos += n;
+            if this.pos == this.early_data.len() {
+                this.early_data = Vec::new();
+                this.pos = 0;
+            }
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for EarlyDataStream<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,135 @@
+use crate::util::target_addr::TargetAddr;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// How an [`EgressPool`] picks the source IP of each outbound connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EgressStrategy {
+    /// Each IP in turn.
+    #[default]
+    RoundRobin,
+    /// The same IP for all the connections of a user, round-robin without a user.
+    PerUser,
+    /// The same IP for all the connections to a target host.
+    HashTarget,
+}
+
+/// The public IPs of a host to spread the outbound connections over, see
+/// `ConnectOptions::set_egress_pool`.
+///
+/// Only the IPs of the family of the target are candidates: with none, the system
+/// picks the source IP as usual.
+#[derive(Debug)]
+pub struct EgressPool {
+    ips: Vec<IpAddr>,
+    strategy: EgressStrategy,
+    next: AtomicUsize,
+}
+
+impl EgressPool {
+    pub fn new<I: IntoIterator<Item = IpAddr>>(ips: I, strategy: EgressStrategy) -> Self {
+        EgressPool {
+            ips: ips.into_iter().collect(),
+            strategy,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn ips(&self) -> &[IpAddr] {
+        &self.ips
+    }
+
+    /// The source IP of a connection of `user` to `target`, resolved to `peer`.
+    pub fn select(
+        &self,
+        user: Option<&str>,
+        target: &TargetAddr,
+        peer: SocketAddr,
+    ) -> Option<IpAddr> {
+        let candidates: Vec<IpAddr> = self
+            .ips
+            .iter()
+            .copied()
+            .filter(|ip| ip.is_ipv4() == peer.is_ipv4())
+            .collect();
+        if candidates.is_empty() {
+            return None;
+        }
+        let index = match (self.strategy, user) {
+            (EgressStrategy::PerUser, Some(user)) => stable_hash(user),
+            (EgressStrategy::HashTarget, _) => match target {
+                TargetAddr::Ip(addr) => stable_hash(addr.ip()),
+                TargetAddr::Domain(domain, _) => stable_hash(domain.to_lowercase()),
+            },
+            _ => self.next.fetch_add(1, Ordering::Relaxed),
+        };
+        Some(candidates[index % candidates.len()])
+    }
+}
+
+/// The same for a value across connections, unlike `RandomState`.
+fn stable_hash<T: Hash>(value: T) -> usize {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish() as usize
+}
+
+#[cfg(test)]
+mod test {
+    use super::{EgressPool, EgressStrategy};
+    use crate::util::target_addr::TargetAddr;
+    use std::net::{IpAddr, SocketAddr};
+
+    #[test]
+    fn select_source_ip() {
+        let ips: Vec<IpAddr> = ["192.0.2.1", "192.0.2.2", "2001:db8::1"]
+            .iter()
+            .map(|ip| ip.parse().unwrap())
+            .collect();
+        let peer: SocketAddr = "198.51.100.1:443".parse().unwrap();
+        let target = TargetAddr::Domain("example.com".to_owned(), 443);
+
+        let pool = EgressPool::new(ips.clone(), EgressStrategy::RoundRobin);
+        let picks: Vec<_> = (0..4)
+            .map(|_| pool.select(None, &target, peer).unwrap())
+            .collect();
+        assert_eq!(picks, [ips[0], ips[1], ips[0], ips[1]]);
+        let peer_v6: SocketAddr = "[2001:db8::2]:443".parse().unwrap();
+        assert_eq!(pool.select(None, &target, peer_v6), Some(ips[2]));
+        let v4_only = EgressPool::new(ips[..2].to_vec(), EgressStrategy::RoundRobin);
+        assert_eq!(v4_only.select(None, &target, peer_v6), None);
+
+        let pool = EgressPool::new(ips.clone(), EgressStrategy::PerUser);
+        let alice = pool.select(Some("alice"), &target, peer);
+        for _ in 0..4 {
+            assert_eq!(pool.select(Some("alice"), &target, peer), alice);
+        }
+
+        let pool = EgressPool::new(ips, EgressStrategy::HashTarget);
+        let upper = TargetAddr::Domain("EXAMPLE.com".to_owned(), 80);
+        assert_eq!(
+            pool.select(Some("alice"), &target, peer),
+            pool.select(Some("bob"), &upper, peer)
+        );
+    }
+
+    // the whole 127.0.0.0/8 is local on linux
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn bind_source_ip() {
+        use crate::server::{connect_to_target, ConnectOptions};
+        use std::sync::Arc;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = TargetAddr::Ip(listener.local_addr().unwrap());
+        let source: IpAddr = "127.0.0.2".parse().unwrap();
+        let mut opts = ConnectOptions::new();
+        opts.set_egress_pool(Arc::new(EgressPool::new(
+            [source, "::1".parse().unwrap()],
+            EgressStrategy::RoundRobin,
+        )));
+        let outbound = connect_to_target(&target, &opts).await.unwrap();
+        assert_eq!(outbound.local_addr().unwrap().ip(), source);
+    }
+}
@@ -0,0 +1,318 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{sleep, Instant, Sleep};
+
+// how long a session may save its share for, the burst it can send after idling
+const BURST: Duration = Duration::from_millis(100);
+// the smallest saved share, so that the reads of a session don't get tiny
+const MIN_BURST: f64 = 4096.0;
+// the bytes a throttled session waits for before reading again
+const MIN_READ: f64 = 1024.0;
+const MAX_WAIT: Duration = Duration::from_millis(100);
+
+/// A global bandwidth budget shared by the sessions in proportion to their weights.
+///
+/// The sessions not using their share leave it to the others, so that a bulk download
+/// gets the whole budget alone but can't starve the interactive sessions: each of them
+/// keeps getting its weighted share, and a short burst after idling. Join with
+/// [`FairShare::join`], then wrap both legs of the session with
+/// [`BandwidthShare::wrap`], which paces their reads; a budget for each direction takes
+/// two `FairShare`s.
+#[derive(Debug, Clone)]
+pub struct FairShare {
+    inner: Arc<Shared>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    bytes_per_second: f64,
+    weights: Mutex<Weights>,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct Weights {
+    users: HashMap<String, u32>,
+    default: u32,
+}
+
+#[derive(Debug)]
+struct State {
+    last_refill: Instant,
+    next_id: u64,
+    sessions: HashMap<u64, Slot>,
+    total_weight: f64,
+}
+
+#[derive(Debug)]
+struct Slot {
+    weight: f64,
+    tokens: f64,
+}
+
+impl FairShare {
+    pub fn new(bytes_per_second: u64) -> Self {
+        FairShare {
+            inner: Arc::new(Shared {
+                bytes_per_second: bytes_per_second.max(1) as f64,
+                weights: Mutex::new(Weights {
+                    users: HashMap::new(),
+                    default: 1,
+                }),
+                state: Mutex::new(State {
+                    last_refill: Instant::now(),
+                    next_id: 0,
+                    sessions: HashMap::new(),
+                    total_weight: 0.0,
+                }),
+            }),
+        }
+    }
+
+    /// The weight of the sessions of `username`, e.g. 4 for 4 times the share of the
+    /// others. Applies to the sessions joining afterwards.
+    pub fn set_user_weight<S: Into<String>>(&self, username: S, weight: u32) -> &Self {
+        let mut weights = self.inner.weights.lock().unwrap();
+        weights.users.insert(username.into(), weight.max(1));
+        self
+    }
+
+    /// The weight of the users without one and of the anonymous clients, 1 by default.
+    pub fn set_default_weight(&self, weight: u32) -> &Self {
+        self.inner.weights.lock().unwrap().default = weight.max(1);
+        self
+    }
+
+    /// Add a session of `username`, sharing the budget until the returned handle and
+    /// the streams it wrapped are dropped.
+    pub fn join(&self, username: Option<&str>) -> BandwidthShare {
+        let weight = {
+            let weights = self.inner.weights.lock().unwrap();
+            username
+                .and_then(|username| weights.users.get(username))
+                .copied()
+                .unwrap_or(weights.default)
+        };
+        let mut state = self.inner.state.lock().unwrap();
+        state.refill(self.inner.bytes_per_second);
+        let id = state.next_id;
+        state.next_id += 1;
+        let weight = f64::from(weight);
+        state.total_weight += weight;
+        state.sessions.insert(
+            id,
+            Slot {
+                weight,
+                tokens: 0.0,
+            },
+        );
+        BandwidthShare {
+            member: Arc::new(Member {
+                id,
+                shared: self.inner.clone(),
+            }),
+        }
+    }
+
+    pub fn sessions(&self) -> usize {
+        self.inner.state.lock().unwrap().sessions.len()
+    }
+}
+
+impl State {
+    /// Hand out the budget accrued since the last refill by weight, the share the full
+    /// sessions can't save going to the others.
+    fn refill(&mut self, bytes_per_second: f64) {
+        let now = Instant::now();
+        let mut budget = bytes_per_second * (now - self.last_refill).as_secs_f64();
+        self.last_refill = now;
+        let capacity = |slot: &Slot, total_weight: f64| {
+            (bytes_per_second * slot.weight / total_weight * BURST.as_secs_f64()).max(MIN_BURST)
+        };
+        let total_weight = self.total_weight;
+        while budget > 0.5 {
+            let hungry_weight: f64 = self
+                .sessions
+                .values()
+                .filter(|slot| slot.tokens < capacity(slot, total_weight))
+                .map(|slot| slot.weight)
+                .sum();
+            if hungry_weight == 0.0 {
+                break;
+            }
+            let mut given = 0.0;
+            for slot in self.sessions.values_mut() {
+                let room = capacity(slot, total_weight) - slot.tokens;
+                if room > 0.0 {
+                    let share = (budget * slot.weight / hungry_weight).min(room);
+                    slot.tokens += share;
+                    given += share;
+                }
+            }
+            budget -= given;
+        }
+    }
+}
+
+/// The membership of a session in a [`FairShare`], for the streams of the session.
+#[derive(Debug, Clone)]
+pub struct BandwidthShare {
+    member: Arc<Member>,
+}
+
+#[derive(Debug)]
+struct Member {
+    id: u64,
+    shared: Arc<Shared>,
+}
+
+impl Drop for Member {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        if let Some(slot) = state.sessions.remove(&self.id) {
+            state.total_weight -= slot.weight;
+        }
+    }
+}
+
+impl BandwidthShare {
+    /// Pace the reads from `stream` to the share of the session.
+    pub fn wrap<S>(&self, stream: S) -> FairShareStream<S> {
+        FairShareStream {
+            inner: stream,
+            share: self.clone(),
+            sleep: None,
+        }
+    }
+
+    /// How many bytes may be read now, or how long to wait for them.
+    fn allowance(&self, wanted: usize) -> Result<usize, Duration> {
+        let shared = &self.member.shared;
+        let mut state = shared.state.lock().unwrap();
+        state.refill(shared.bytes_per_second);
+        let total_weight = state.total_weight;
+        let slot = state
+            .sessions
+            .get(&self.member.id)
+            .expect("a session stays until its share is dropped");
+        if slot.tokens >= MIN_READ.min(wanted as f64) {
+            return Ok((slot.tokens as usize).clamp(1, wanted));
+        }
+        let rate = shared.bytes_per_second * slot.weight / total_weight;
+        let wait = Duration::from_secs_f64((MIN_READ - slot.tokens) / rate);
+        Err(wait.clamp(Duration::from_millis(1), MAX_WAIT))
+    }
+
+    fn consume(&self, bytes: usize) {
+        let mut state = self.member.shared.state.lock().unwrap();
+        if let Some(slot) = state.sessions.get_mut(&self.member.id) {
+            slot.tokens -= bytes as f64;
+        }
+    }
+}
+
+/// A stream whose reads are paced by a [`BandwidthShare`], its writes passing through.
+#[derive(Debug)]
+pub struct FairShareStream<S> {
+    inner: S,
+    share: BandwidthShare,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> FairShareStream<S> {
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for FairShareStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        loop {
+            if let Some(sleep) = self.sleep.as_mut() {
+                if sleep.as_mut().poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+                self.sleep = None;
+            }
+            match self.share.allowance(buf.remaining()) {
+                Ok(allowed) => {
+                    let mut limited = ReadBuf::new(buf.initialize_unfilled_to(allowed));
+                    let res = Pin::new(&mut self.inner).poll_read(cx, &mut limited);
+                    let len = limited.filled().len();
+                    if let Poll::Ready(Ok(())) = res {
+                        buf.advance(len);
+                        self.share.consume(len);
+                    }
+                    return res;
+                }
+                Err(wait) => self.sleep = Some(Box::pin(sleep(wait))),
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for FairShareStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::FairShare;
+    use std::time::Duration;
+    use tokio::io::{repeat, AsyncReadExt};
+    use tokio::time::Instant;
+
+    #[tokio::test(start_paused = true)]
+    async fn weighted_sharing() {
+        let budget = FairShare::new(100_000);
+        budget.set_user_weight("bulk", 3);
+        let download = |username: &'static str| {
+            let share = budget.join(Some(username));
+            tokio::spawn(async move {
+                let mut stream = share.wrap(repeat(0));
+                let (mut buf, mut total) = (vec![0u8; 16 * 1024], 0);
+                let end = Instant::now() + Duration::from_secs(4);
+                while Instant::now() < end {
+                    total += stream.read(&mut buf).await.unwrap();
+                }
+                total
+            })
+        };
+        let (interactive, bulk) = (download("alice"), download("bulk"));
+        let (interactive, bulk) = (interactive.await.unwrap(), bulk.await.unwrap());
+        assert!((380_000..=420_000).contains(&(interactive + bulk)));
+        assert!((85_000..=115_000).contains(&interactive), "{}", interactive);
+        assert_eq!(budget.sessions(), 0);
+
+        // alone, a session gets the whole budget
+        let alone = download("alice").await.unwrap();
+        assert!((380_000..=420_000).contains(&alone), "{}", alone);
+    }
+}
@@ -0,0 +1,321 @@
+use super::{SessionId, StreamTap, TapAction, TapDirection};
+use pcap_file::pcapng::blocks::enhanced_packet::EnhancedPacketBlock;
+use pcap_file::pcapng::blocks::interface_description::{
+    InterfaceDescriptionBlock, InterfaceDescriptionOption,
+};
+use pcap_file::pcapng::PcapNgWriter;
+use pcap_file::{DataLink, PcapError};
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::io::{self, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+
+// keeps the synthetic packets under the IPv4 total length limit
+const MAX_SEGMENT: usize = 65_000;
+
+/// A chunk of relayed data, as sent on the channel of [`FlowExporter::to_channel`].
+#[derive(Debug, Clone)]
+pub struct FlowRecord {
+    /// The session the tap was made in, see `SessionId::current`.
+    pub session: Option<SessionId>,
+    pub client: SocketAddr,
+    pub target: SocketAddr,
+    pub direction: TapDirection,
+    pub timestamp: SystemTime,
+    pub len: usize,
+    /// The data itself, only for the targets allowed with [`FlowExporter::allow_payloads`].
+    pub payload: Option<Vec<u8>>,
+}
+
+enum FlowSink {
+    Pcapng(Mutex<PcapNgWriter<Box<dyn Write + Send>>>),
+    Channel(mpsc::Sender<FlowRecord>),
+}
+
+/// Exports the relayed traffic for debugging, to a pcapng file readable by Wireshark or
+/// to a channel.
+///
+/// Only the metadata (addresses, direction, size) is exported by default, the payloads
+/// are only kept for the targets explicitly allowed. Use [`FlowExporter::tap`] with
+/// `transfer_tapped` for each session to export.
+pub struct FlowExporter {
+    sink: FlowSink,
+    payload_targets: HashSet<IpAddr>,
+}
+
+impl std::fmt::Debug for FlowExporter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FlowExporter")
+            .field("payload_targets", &self.payload_targets)
+            .finish_non_exhaustive()
+    }
+}
+
+impl FlowExporter {
+    /// Write a pcapng capture to `writer`, each chunk of data being a synthetic TCP packet
+    /// between the client and the target.
+    ///
+    /// The writes are blocking, so this is meant for debugging rather than production.
+    pub fn to_pcapng<W: Write + Send + 'static>(writer: W) -> io::Result<Self> {
+        let writer: Box<dyn Write + Send> = Box::new(writer);
+        let mut writer = PcapNgWriter::new(writer).map_err(pcap_error)?;
+        // raw IPv4/IPv6 packets without link layer, no snap length, and the nanosecond
+        // timestamps written by pcap-file
+        let interface = InterfaceDescriptionBlock {
+            linktype: DataLink::RAW,
+            snaplen: 0,
+            options: vec![InterfaceDescriptionOption::IfTsResol(9)],
+        };
+        writer.write_pcapng_block(interface).map_err(pcap_error)?;
+        writer.get_mut().flush()?;
+
+        Ok(FlowExporter {
+            sink: FlowSink::Pcapng(Mutex::new(writer)),
+            payload_targets: HashSet::new(),
+        })
+    }
+
+    /// Send a [`FlowRecord`] for each chunk of data on `tx`, the records are dropped
+    /// when the channel is full.
+    pub fn to_channel(tx: mpsc::Sender<FlowRecord>) -> Self {
+        FlowExporter {
+            sink: FlowSink::Channel(tx),
+            payload_targets: HashSet::new(),
+        }
+    }
+
+    /// Export the full payloads exchanged with `target`.
+    pub fn allow_payloads(&mut self, target: IpAddr) -> &mut Self {
+        self.payload_targets.insert(target);
+        self
+    }
+
+    /// A tap exporting the session between `client` and `target`.
+    pub fn tap(&self, client: SocketAddr, target: SocketAddr) -> FlowTap<'_> {
+        FlowTap {
+            exporter: self,
+            session: SessionId::current(),
+            client,
+            target,
+            with_payload: self.payload_targets.contains(&target.ip()),
+            client_seq: AtomicU32::new(0),
+            target_seq: AtomicU32::new(0),
+        }
+    }
+}
+
+/// The [`StreamTap`] of one session, see [`FlowExporter::tap`].
+#[derive(Debug)]
+pub struct FlowTap<'a> {
+    exporter: &'a FlowExporter,
+    session: Option<SessionId>,
+    client: SocketAddr,
+    target: SocketAddr,
+    with_payload: bool,
+    client_seq: AtomicU32,
+    target_seq: AtomicU32,
+}
+
+impl StreamTap for FlowTap<'_> {
+    fn on_chunk(&self, direction: TapDirection, chunk: &[u8]) -> TapAction {
+        let timestamp = SystemTime::now();
+        match &self.exporter.sink {
+            FlowSink::Channel(tx) => {
+                let record = FlowRecord {
+                    session: self.session,
+                    client: self.client,
+                    target: self.target,
+                    direction,
+                    timestamp,
+                    len: chunk.len(),
+                    payload: self.with_payload.then(|| chunk.to_vec()),
+                };
+                if tx.try_send(record).is_err() {
+                    debug!("flow export channel full or closed, record dropped");
+                }
+            }
+            FlowSink::Pcapng(writer) => {
+                let mut writer = writer.lock().unwrap();
+                for segment in chunk.chunks(MAX_SEGMENT) {
+                    if let Err(err) = self.write_packet(&mut writer, direction, timestamp, segment)
+                    {
+                        warn!("flow export write error: {}", err);
+                        break;
+                    }
+                }
+            }
+        }
+        TapAction::Continue
+    }
+}
+
+impl FlowTap<'_> {
+    fn write_packet(
+        &self,
+        writer: &mut PcapNgWriter<Box<dyn Write + Send>>,
+        direction: TapDirection,
+        timestamp: SystemTime,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        let (src, dst, seq, ack) = match direction {
+            TapDirection::ClientToTarget => {
+                (self.client, self.target, &self.client_seq, &self.target_seq)
+            }
+            TapDirection::TargetToClient => {
+                (self.target, self.client, &self.target_seq, &self.client_seq)
+            }
+        };
+        let seq = seq.fetch_add(payload.len() as u32, Ordering::Relaxed);
+        let ack = ack.load(Ordering::Relaxed);
+
+        let mut packet = ip_header(src.ip(), dst.ip(), 20 + payload.len());
+        // TCP header with PSH+ACK, the checksum is left to zero
+        packet.extend_from_slice(&src.port().to_be_bytes());
+        packet.extend_from_slice(&dst.port().to_be_bytes());
+        packet.extend_from_slice(&seq.to_be_bytes());
+        packet.extend_from_slice(&ack.to_be_bytes());
+        packet.extend_from_slice(&[5 << 4, 0x18, 0xff, 0xff, 0, 0, 0, 0]);
+        let original_len = packet.len() + payload.len();
+        if self.with_payload {
+            packet.extend_from_slice(payload);
+        }
+
+        let block = EnhancedPacketBlock {
+            interface_id: 0,
+            timestamp: timestamp.duration_since(UNIX_EPOCH).unwrap_or_default(),
+            original_len: original_len as u32,
+            data: Cow::Borrowed(&packet),
+            options: vec![],
+        };
+        writer.write_pcapng_block(block).map_err(pcap_error)?;
+        writer.get_mut().flush()
+    }
+}
+
+fn ip_header(src: IpAddr, dst: IpAddr, payload_len: usize) -> Vec<u8> {
+    let mut header = vec![];
+    match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            header.extend_from_slice(&[0x45, 0]);
+            header.extend_from_slice(&((20 + payload_len) as u16).to_be_bytes());
+            // no id, don't fragment, TTL 64, TCP, checksum set below
+            header.extend_from_slice(&[0, 0, 0x40, 0, 64, 6, 0, 0]);
+            header.extend_from_slice(&src.octets());
+            header.extend_from_slice(&dst.octets());
+            let sum = header
+                .chunks(2)
+                .map(|w| u16::from_be_bytes([w[0], w[1]]) as u32)
+                .sum::<u32>();
+            let sum = (sum & 0xffff) + (sum >> 16);
+            let checksum = !((sum & 0xffff) + (sum >> 16)) as u16;
+            header[10..12].copy_from_slice(&checksum.to_be_bytes());
+        }
+        _ => {
+            // a family mismatch between the client and the target is shown as IPv6
+            let to_v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            header.extend_from_slice(&[0x60, 0, 0, 0]);
+            header.extend_from_slice(&(payload_len as u16).to_be_bytes());
+            header.extend_from_slice(&[6, 64]);
+            header.extend_from_slice(&to_v6(src).octets());
+            header.extend_from_slice(&to_v6(dst).octets());
+        }
+    }
+    header
+}
+
+fn pcap_error(err: PcapError) -> io::Error {
+    match err {
+        PcapError::IoError(err) => err,
+        err => io::Error::other(err),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{FlowExporter, StreamTap, TapDirection};
+    use pcap_file::pcapng::{Block, PcapNgReader};
+    use pcap_file::DataLink;
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use tokio::sync::mpsc;
+
+    #[derive(Clone, Default)]
+    struct Capture(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn pcapng_metadata_and_payloads() {
+        let client = "192.0.2.1:40000".parse().unwrap();
+        let secret = "198.51.100.1:443".parse().unwrap();
+        let debugged = "198.51.100.2:80".parse().unwrap();
+
+        let capture = Capture::default();
+        let mut exporter = FlowExporter::to_pcapng(capture.clone()).unwrap();
+        exporter.allow_payloads("198.51.100.2".parse().unwrap());
+        let before = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+
+        exporter
+            .tap(client, secret)
+            .on_chunk(TapDirection::ClientToTarget, b"hidden");
+        exporter
+            .tap(client, debugged)
+            .on_chunk(TapDirection::TargetToClient, b"shown");
+
+        let data = capture.0.lock().unwrap().clone();
+        let mut reader = PcapNgReader::new(&data[..]).unwrap();
+        let Block::InterfaceDescription(interface) = reader.next_block().unwrap().unwrap() else {
+            panic!("interface description expected");
+        };
+        assert_eq!(interface.linktype, DataLink::RAW);
+        let mut packet = || match reader.next_block().unwrap().unwrap() {
+            Block::EnhancedPacket(packet) => packet.into_owned(),
+            block => panic!("enhanced packet expected, got {:?}", block),
+        };
+        // metadata only: 40 bytes of headers captured out of 46
+        let first = packet();
+        assert_eq!((first.data.len(), first.original_len), (40, 46));
+        assert!(!first.data.windows(6).any(|w| w == b"hidden"));
+        assert!(first.timestamp >= before);
+        let second = packet();
+        assert!(second.data.ends_with(b"shown"));
+        assert_eq!(second.data[12..16], [198, 51, 100, 2]);
+        assert!(reader.next_block().is_none());
+    }
+
+    #[test]
+    fn channel_records() {
+        let (tx, mut rx) = mpsc::channel(1);
+        let exporter = FlowExporter::to_channel(tx);
+        let tap = exporter.tap(
+            "192.0.2.1:40000".parse().unwrap(),
+            "198.51.100.1:443".parse().unwrap(),
+        );
+        tap.on_chunk(TapDirection::ClientToTarget, b"hello");
+        // dropped, the channel is full
+        tap.on_chunk(TapDirection::TargetToClient, b"world");
+
+        let record = rx.try_recv().unwrap();
+        assert_eq!(record.direction, TapDirection::ClientToTarget);
+        assert_eq!(record.len, 5);
+        assert!(record.payload.is_none());
+        assert!(rx.try_recv().is_err());
+    }
+}
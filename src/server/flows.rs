@@ -0,0 +1,100 @@
+use super::udp::udp_bind_random_port;
+use super::{
+    connect_to_target, resolve_reported, transfer, ConnectOptions, ErrorContext, SocksServerError,
+};
+use crate::util::target_addr::TargetAddr;
+use std::net::{IpAddr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::UdpSocket;
+
+/// Relay a TCP flow to `target` until it is done, without any SOCKS handshake.
+///
+/// This is for frontends synthesizing the flows themselves, such as a TUN device
+/// terminating TCP in userspace for a VPN-to-SOCKS gateway: `stream` is its end of the
+/// flow, and `target` the destination it was addressed to.
+pub async fn relay_tcp_flow<S>(
+    stream: S,
+    target: TargetAddr,
+    opts: &ConnectOptions,
+) -> Result<(), SocksServerError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let outbound = connect_to_target(&target, opts).await?;
+    debug!("Relaying TCP flow to {}", target);
+    transfer(stream, outbound).await;
+    Ok(())
+}
+
+/// A UDP socket connected to `target`, for a frontend to relay the payloads of one UDP
+/// flow with `send`/`recv`, without any SOCKS handshake.
+///
+/// The socket is bound on `bind_ip`, or on any address of the host if `None`, and a
+/// domain target is resolved following `opts`.
+pub async fn connect_udp_flow(
+    target: TargetAddr,
+    bind_ip: Option<IpAddr>,
+    opts: &ConnectOptions,
+) -> Result<UdpSocket, SocksServerError> {
+    let mut target = resolve_reported(
+        &target,
+        opts.resolution,
+        opts.static_hosts.as_deref(),
+        opts.resolution_hook.as_ref(),
+    )
+    .await?
+    .into_iter()
+    .next()
+    .ok_or(SocksServerError::Bug("no socket addrs"))?;
+    let socket = udp_bind_random_port(bind_ip)
+        .and_then(|socket| UdpSocket::from_std(socket.into()))
+        .err_when("binding udp flow socket")?;
+    // a dual-stack socket reaches IPv4 targets through mapped addresses
+    if let (Ok(SocketAddr::V6(_)), SocketAddr::V4(v4)) = (socket.local_addr(), target) {
+        target = SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port());
+    }
+    socket
+        .connect(target)
+        .await
+        .err_when("connecting udp flow socket")?;
+    Ok(socket)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{connect_udp_flow, relay_tcp_flow};
+    use crate::server::ConnectOptions;
+    use crate::util::target_addr::TargetAddr;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, UdpSocket};
+
+    #[tokio::test]
+    async fn synthesized_flows() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = TargetAddr::Ip(listener.local_addr().unwrap());
+        let (mut frontend, flow) = duplex(64);
+        let relay =
+            tokio::spawn(async move { relay_tcp_flow(flow, target, &ConnectOptions::new()).await });
+        let (mut remote, _) = listener.accept().await.unwrap();
+        frontend.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        remote.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        drop(remote);
+        drop(frontend);
+        relay.await.unwrap().unwrap();
+
+        let remote = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target = TargetAddr::Ip(remote.local_addr().unwrap());
+        let flow = connect_udp_flow(target, None, &ConnectOptions::new())
+            .await
+            .unwrap();
+        flow.send(b"ping").await.unwrap();
+        let mut buf = [0u8; 8];
+        let (len, from) = remote.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"ping");
+        remote.send_to(b"pong", from).await.unwrap();
+        let len = flow.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"pong");
+    }
+}
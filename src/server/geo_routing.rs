@@ -0,0 +1,284 @@
+use crate::ReplyError;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+/// The username tag selecting the egress country, as in `alice-country-de`.
+const COUNTRY_TAG: &str = "-country-";
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum GeoRouteError {
+    #[error("Invalid country code `{0}`, expected two ASCII letters")]
+    InvalidCountry(String),
+    #[error("No upstream available for country `{0}`")]
+    NoUpstreamForCountry(String),
+    #[error("No upstream available")]
+    NoUpstream,
+}
+
+impl GeoRouteError {
+    pub fn to_reply_error(&self) -> ReplyError {
+        match self {
+            GeoRouteError::InvalidCountry(_) => ReplyError::ConnectionNotAllowed,
+            GeoRouteError::NoUpstreamForCountry(_) | GeoRouteError::NoUpstream => {
+                ReplyError::NetworkUnreachable
+            }
+        }
+    }
+}
+
+/// What happens when a client asks for a country without any upstream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GeoFallback {
+    /// Use the default upstreams instead.
+    Default,
+    /// Fail the request, the client asked for a geography it can't get.
+    #[default]
+    Reject,
+}
+
+/// Split the egress country tag out of a username, e.g. `alice-country-de-session-1`
+/// gives `("alice-session-1", Some("de"))`.
+///
+/// The remaining username is the one to check the password against. The country code
+/// is returned as written, see [`GeoRouter::route`] for its validation.
+pub fn split_country_tag(username: &str) -> (String, Option<&str>) {
+    let Some(start) = username.find(COUNTRY_TAG) else {
+        return (username.to_owned(), None);
+    };
+    let tagged = &username[start + COUNTRY_TAG.len()..];
+    let (country, rest) = match tagged.find('-') {
+        Some(end) => tagged.split_at(end),
+        None => (tagged, ""),
+    };
+    (format!("{}{}", &username[..start], rest), Some(country))
+}
+
+type TagParseFn = dyn Fn(&str) -> (String, Option<String>) + Send + Sync;
+
+/// Splits the egress country out of a username, into the username to check the password
+/// against and the country code as written. [`split_country_tag`] by default.
+#[derive(Clone, Default)]
+pub struct CountryTagParser {
+    parse: Option<Arc<TagParseFn>>,
+}
+
+impl CountryTagParser {
+    /// Parse the usernames with `parse`, for another tagging scheme, e.g. `de.alice`.
+    pub fn new<F>(parse: F) -> Self
+    where
+        F: Fn(&str) -> (String, Option<String>) + Send + Sync + 'static,
+    {
+        CountryTagParser {
+            parse: Some(Arc::new(parse)),
+        }
+    }
+
+    pub fn split(&self, username: &str) -> (String, Option<String>) {
+        match &self.parse {
+            Some(parse) => parse(username),
+            None => {
+                let (username, country) = split_country_tag(username);
+                (username, country.map(str::to_owned))
+            }
+        }
+    }
+}
+
+impl fmt::Debug for CountryTagParser {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.parse {
+            Some(_) => f.write_str("CountryTagParser(custom)"),
+            None => f.write_str("CountryTagParser(-country-)"),
+        }
+    }
+}
+
+/// Upstream groups tagged with the country they egress from, selected by the
+/// `-country-XX` tag of the client's username, or another tag, see
+/// [`GeoRouter::set_tag_parser`].
+///
+/// `U` is whatever identifies an upstream for the caller, e.g. the address of a further
+/// SOCKS5 server.
+#[derive(Debug, Clone)]
+pub struct GeoRouter<U> {
+    groups: HashMap<String, Vec<U>>,
+    default: Vec<U>,
+    fallback: GeoFallback,
+    tag_parser: CountryTagParser,
+}
+
+impl<U> Default for GeoRouter<U> {
+    fn default() -> Self {
+        GeoRouter {
+            groups: HashMap::new(),
+            default: Vec::new(),
+            fallback: GeoFallback::default(),
+            tag_parser: CountryTagParser::default(),
+        }
+    }
+}
+
+impl<U> GeoRouter<U> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set what to do when no upstream is tagged with the requested country.
+    pub fn set_fallback(&mut self, fallback: GeoFallback) -> &mut Self {
+        self.fallback = fallback;
+        self
+    }
+
+    /// Split the country out of the usernames with `parser`.
+    pub fn set_tag_parser(&mut self, parser: CountryTagParser) -> &mut Self {
+        self.tag_parser = parser;
+        self
+    }
+
+    /// The parser of the country tags, e.g. to get the username to authenticate.
+    pub fn tag_parser(&self) -> &CountryTagParser {
+        &self.tag_parser
+    }
+
+    /// Add an upstream egressing from `country`, or a default one if `None`.
+    pub fn add_upstream(
+        &mut self,
+        country: Option<&str>,
+        upstream: U,
+    ) -> Result<&mut Self, GeoRouteError> {
+        match country {
+            Some(country) => self
+                .groups
+                .entry(normalize_country(country)?)
+                .or_default()
+                .push(upstream),
+            None => self.default.push(upstream),
+        }
+        Ok(self)
+    }
+
+    /// Remove the upstreams matching `predicate` from every group.
+    pub fn remove_upstreams<F: FnMut(&U) -> bool>(&mut self, mut predicate: F) {
+        self.default.retain(|u| !predicate(u));
+        for group in self.groups.values_mut() {
+            group.retain(|u| !predicate(u));
+        }
+        self.groups.retain(|_, group| !group.is_empty());
+    }
+
+    /// The upstream groups by country code, the default ones under `None`.
+    pub fn groups(&self) -> impl Iterator<Item = (Option<&str>, &[U])> {
+        std::iter::once((None, self.default.as_slice()))
+            .filter(|(_, group)| !group.is_empty())
+            .chain(
+                self.groups
+                    .iter()
+                    .map(|(country, group)| (Some(country.as_str()), group.as_slice())),
+            )
+    }
+
+    /// Select the upstream group for a client's username, along with the username
+    /// without its country tag.
+    pub fn route(&self, username: &str) -> Result<(String, &[U]), GeoRouteError> {
+        let (username, country) = self.tag_parser.split(username);
+        let group = match country.as_deref() {
+            None => &self.default,
+            Some(country) => {
+                let country = normalize_country(country)?;
+                match (self.groups.get(&country), self.fallback) {
+                    (Some(group), _) => group,
+                    (None, GeoFallback::Default) => {
+                        debug!(
+                            "No upstream for country {}, using the default ones",
+                            country
+                        );
+                        &self.default
+                    }
+                    (None, GeoFallback::Reject) => {
+                        return Err(GeoRouteError::NoUpstreamForCountry(country))
+                    }
+                }
+            }
+        };
+        if group.is_empty() {
+            return Err(GeoRouteError::NoUpstream);
+        }
+        Ok((username, group))
+    }
+}
+
+fn normalize_country(country: &str) -> Result<String, GeoRouteError> {
+    if country.len() == 2 && country.bytes().all(|b| b.is_ascii_alphabetic()) {
+        Ok(country.to_ascii_lowercase())
+    } else {
+        Err(GeoRouteError::InvalidCountry(country.to_owned()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{split_country_tag, CountryTagParser, GeoFallback, GeoRouteError, GeoRouter};
+
+    #[test]
+    fn splits_country_tag() {
+        assert_eq!(split_country_tag("alice"), ("alice".to_owned(), None));
+        assert_eq!(
+            split_country_tag("alice-country-de"),
+            ("alice".to_owned(), Some("de"))
+        );
+        assert_eq!(
+            split_country_tag("alice-country-DE-session-1"),
+            ("alice-session-1".to_owned(), Some("DE"))
+        );
+    }
+
+    #[test]
+    fn routes_by_country() {
+        let mut router = GeoRouter::new();
+        router
+            .add_upstream(Some("DE"), "de-1")
+            .unwrap()
+            .add_upstream(None, "any-1")
+            .unwrap();
+
+        assert_eq!(
+            router.route("alice-country-de").unwrap(),
+            ("alice".to_owned(), &["de-1"][..])
+        );
+        assert_eq!(router.route("alice").unwrap().1, &["any-1"]);
+        assert_eq!(
+            router.route("alice-country-fr"),
+            Err(GeoRouteError::NoUpstreamForCountry("fr".to_owned()))
+        );
+        assert_eq!(
+            router.route("alice-country-xyz"),
+            Err(GeoRouteError::InvalidCountry("xyz".to_owned()))
+        );
+
+        router.set_fallback(GeoFallback::Default);
+        assert_eq!(router.route("alice-country-fr").unwrap().1, &["any-1"]);
+
+        router.remove_upstreams(|u| *u == "any-1");
+        assert_eq!(
+            router.route("alice-country-fr"),
+            Err(GeoRouteError::NoUpstream)
+        );
+
+        // another scheme, e.g. `de.alice`
+        router.set_tag_parser(CountryTagParser::new(|username| {
+            match username.split_once('.') {
+                Some((country, username)) => (username.to_owned(), Some(country.to_owned())),
+                None => (username.to_owned(), None),
+            }
+        }));
+        assert_eq!(
+            router.route("de.alice").unwrap(),
+            ("alice".to_owned(), &["de-1"][..])
+        );
+        assert_eq!(
+            router.tag_parser().split("alice-country-de"),
+            ("alice-country-de".to_owned(), None)
+        );
+    }
+}
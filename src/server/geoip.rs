@@ -0,0 +1,222 @@
+use super::{AclAction, AclRequest, AclRule};
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+/// Finds the country of an IP, as an ISO 3166-1 alpha-2 code such as `"DE"`.
+///
+/// Implemented by [`GeoIpDatabase`] with the `geoip` feature, and by closures for other
+/// sources.
+pub trait CountryLookup: Send + Sync {
+    fn country(&self, ip: IpAddr) -> Option<String>;
+}
+
+impl<F> CountryLookup for F
+where
+    F: Fn(IpAddr) -> Option<String> + Send + Sync,
+{
+    fn country(&self, ip: IpAddr) -> Option<String> {
+        self(ip)
+    }
+}
+
+/// A MaxMind GeoIP2 or GeoLite2 country (or city) database.
+#[cfg(feature = "geoip")]
+pub struct GeoIpDatabase {
+    reader: maxminddb::Reader<Vec<u8>>,
+}
+
+#[cfg(feature = "geoip")]
+impl std::fmt::Debug for GeoIpDatabase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GeoIpDatabase")
+            .field("database_type", &self.reader.metadata.database_type)
+            .finish()
+    }
+}
+
+#[cfg(feature = "geoip")]
+impl GeoIpDatabase {
+    /// Load an `.mmdb` file, e.g. `GeoLite2-Country.mmdb`.
+    pub fn open<P: AsRef<std::path::Path>>(path: P) -> Result<Self, maxminddb::MaxMindDBError> {
+        Ok(GeoIpDatabase {
+            reader: maxminddb::Reader::open_readfile(path)?,
+        })
+    }
+}
+
+#[cfg(feature = "geoip")]
+impl CountryLookup for GeoIpDatabase {
+    fn country(&self, ip: IpAddr) -> Option<String> {
+        let record: maxminddb::geoip2::Country = self.reader.lookup(ip).ok()?;
+        record.country?.iso_code.map(str::to_owned)
+    }
+}
+
+#[derive(Debug, Default)]
+struct CountrySet {
+    allowed: HashSet<String>,
+    denied: HashSet<String>,
+}
+
+impl CountrySet {
+    fn denies(&self, country: Option<&str>) -> bool {
+        match country {
+            Some(country) => {
+                self.denied.contains(country)
+                    || (!self.allowed.is_empty() && !self.allowed.contains(country))
+            }
+            // unknown countries only pass when there is no allow list
+            None => !self.allowed.is_empty(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.allowed.is_empty() && self.denied.is_empty()
+    }
+}
+
+/// An access rule denying client source countries and/or target countries.
+///
+/// Each side has an optional allow list, the countries not in it being denied, and a
+/// deny list. The rule doesn't apply to the requests it doesn't deny. With target
+/// countries set, domain targets are denied since their country is unknown: check the
+/// ACL again once the domain is resolved, with the IP it resolved to as target.
+pub struct CountryRule {
+    lookup: Arc<dyn CountryLookup>,
+    clients: CountrySet,
+    targets: CountrySet,
+}
+
+impl std::fmt::Debug for CountryRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CountryRule")
+            .field("clients", &self.clients)
+            .field("targets", &self.targets)
+            .finish_non_exhaustive()
+    }
+}
+
+impl CountryRule {
+    pub fn new(lookup: Arc<dyn CountryLookup>) -> Self {
+        CountryRule {
+            lookup,
+            clients: CountrySet::default(),
+            targets: CountrySet::default(),
+        }
+    }
+
+    /// Only accept the clients from these countries.
+    pub fn allow_clients_from<I: IntoIterator<Item = S>, S: AsRef<str>>(
+        &mut self,
+        countries: I,
+    ) -> &mut Self {
+        extend_codes(&mut self.clients.allowed, countries);
+        self
+    }
+
+    /// Deny the clients from these countries.
+    pub fn deny_clients_from<I: IntoIterator<Item = S>, S: AsRef<str>>(
+        &mut self,
+        countries: I,
+    ) -> &mut Self {
+        extend_codes(&mut self.clients.denied, countries);
+        self
+    }
+
+    /// Only accept the targets in these countries.
+    pub fn allow_targets_in<I: IntoIterator<Item = S>, S: AsRef<str>>(
+        &mut self,
+        countries: I,
+    ) -> &mut Self {
+        extend_codes(&mut self.targets.allowed, countries);
+        self
+    }
+
+    /// Deny the targets in these countries.
+    pub fn deny_targets_in<I: IntoIterator<Item = S>, S: AsRef<str>>(
+        &mut self,
+        countries: I,
+    ) -> &mut Self {
+        extend_codes(&mut self.targets.denied, countries);
+        self
+    }
+}
+
+fn extend_codes<I: IntoIterator<Item = S>, S: AsRef<str>>(set: &mut HashSet<String>, codes: I) {
+    set.extend(codes.into_iter().map(|c| c.as_ref().to_ascii_uppercase()));
+}
+
+impl AclRule for CountryRule {
+    fn evaluate(&self, request: &AclRequest<'_>) -> Option<AclAction> {
+        if !self.clients.is_empty() {
+            let country = self.lookup.country(request.client_ip);
+            if self.clients.denies(country.as_deref()) {
+                debug!("client {} from {:?} denied", request.client_ip, country);
+                return Some(AclAction::Deny);
+            }
+        }
+        if !self.targets.is_empty() {
+            let Some(ip) = request.target_ip() else {
+                debug!("unresolved target {} denied", request.target);
+                return Some(AclAction::Deny);
+            };
+            let country = self.lookup.country(ip);
+            if self.targets.denies(country.as_deref()) {
+                debug!("target {} in {:?} denied", ip, country);
+                return Some(AclAction::Deny);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::CountryRule;
+    use crate::server::{AccessControl, AclAction, AclRequest};
+    use crate::util::target_addr::TargetAddr;
+    use crate::Socks5Command;
+    use std::net::IpAddr;
+    use std::sync::Arc;
+
+    #[test]
+    fn client_and_target_countries() {
+        let lookup = |ip: IpAddr| match ip.to_string().as_str() {
+            "192.0.2.1" => Some("DE".to_owned()),
+            "192.0.2.2" => Some("KP".to_owned()),
+            "198.51.100.1" => Some("US".to_owned()),
+            "198.51.100.2" => Some("RU".to_owned()),
+            _ => None,
+        };
+        let mut rule = CountryRule::new(Arc::new(lookup));
+        rule.allow_clients_from(["de", "fr"])
+            .deny_targets_in(["ru"]);
+        let mut acl = AccessControl::new(AclAction::Allow);
+        acl.push(rule);
+
+        let check = |client: &str, target: &str| {
+            let target = TargetAddr::Ip(format!("{}:443", target).parse().unwrap());
+            acl.check(&AclRequest {
+                client_ip: client.parse().unwrap(),
+                username: None,
+                command: Socks5Command::TCPConnect,
+                target: &target,
+            })
+        };
+        assert_eq!(check("192.0.2.1", "198.51.100.1"), AclAction::Allow);
+        assert_eq!(check("192.0.2.2", "198.51.100.1"), AclAction::Deny);
+        assert_eq!(check("192.0.2.3", "198.51.100.1"), AclAction::Deny);
+        assert_eq!(check("192.0.2.1", "198.51.100.2"), AclAction::Deny);
+        assert_eq!(check("192.0.2.1", "198.51.100.3"), AclAction::Allow);
+
+        let domain = TargetAddr::Domain("example.ru".to_owned(), 443);
+        let request = AclRequest {
+            client_ip: "192.0.2.1".parse().unwrap(),
+            username: None,
+            command: Socks5Command::TCPConnect,
+            target: &domain,
+        };
+        assert_eq!(acl.check(&request), AclAction::Deny);
+    }
+}
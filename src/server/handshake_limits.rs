@@ -0,0 +1,370 @@
+use super::SocksServerError;
+use crate::consts;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+tokio::task_local! {
+    /// Set once the request of the session is read, see `HandshakeLimits::watch`.
+    static HANDSHAKE_DONE: Arc<AtomicBool>;
+}
+
+/// Bounds the time and the bytes a client may take to go through the method selection,
+/// authentication and request, so that slow or half-open handshakes can't pin tasks and
+/// memory indefinitely.
+#[derive(Debug, Clone)]
+pub struct HandshakeLimits {
+    timeout: Duration,
+    max_bytes: usize,
+    diagnostics: bool,
+}
+
+impl Default for HandshakeLimits {
+    fn default() -> Self {
+        HandshakeLimits {
+            timeout: Duration::from_secs(10),
+            max_bytes: 2048,
+            diagnostics: false,
+        }
+    }
+}
+
+/// The most handshake bytes kept for [`SocksServerError::MalformedHandshake`].
+const MAX_DIAGNOSTIC_BYTES: usize = 64;
+
+impl HandshakeLimits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The whole handshake must be done within `timeout`, 10 seconds by default.
+    pub fn set_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// The most bytes read from the client during the handshake, 2048 by default.
+    ///
+    /// The largest standard handshake (password authentication and a domain name) is
+    /// about 1 KiB, this mostly matters for custom authentication methods.
+    pub fn set_max_bytes(&mut self, n: usize) -> &mut Self {
+        self.max_bytes = n;
+        self
+    }
+
+    /// Keep the first bytes of the handshake, and return a
+    /// [`SocksServerError::MalformedHandshake`] with them when it can't be parsed, to
+    /// diagnose broken clients from the logs. Off by default.
+    ///
+    /// The password of password authentication is masked, but the bytes still include
+    /// the username, enable it for debugging only.
+    pub fn set_diagnostics(&mut self, value: bool) -> &mut Self {
+        self.diagnostics = value;
+        self
+    }
+
+    /// Run `handshake` on `inner` within the limits.
+    ///
+    /// `handshake` gets `inner` wrapped in a [`HandshakeStream`] and should go up to
+    /// reading the command, e.g. `Socks5ServerProtocol::accept_no_auth(s).await?.read_command().await`.
+    /// The limits are lifted when it returns, so they don't apply to the relay.
+    pub async fn run<T, F, Fut, R>(&self, inner: T, handshake: F) -> Result<R, SocksServerError>
+    where
+        F: FnOnce(HandshakeStream<T>) -> Fut,
+        Fut: Future<Output = Result<R, SocksServerError>>,
+    {
+        let budget = Arc::new(Budget {
+            remaining: AtomicUsize::new(self.max_bytes),
+            armed: AtomicBool::new(true),
+            exceeded: AtomicBool::new(false),
+            trace: self.diagnostics.then(|| Mutex::new(Vec::new())),
+            method: AtomicU8::new(consts::SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE),
+        });
+        let stream = HandshakeStream {
+            inner,
+            budget: budget.clone(),
+        };
+        let res = tokio::time::timeout(self.timeout, handshake(stream)).await;
+        budget.armed.store(false, Ordering::Relaxed);
+        match res {
+            Err(_) => Err(SocksServerError::HandshakeTimeout(self.timeout)),
+            Ok(Err(_)) if budget.exceeded.load(Ordering::Relaxed) => {
+                Err(SocksServerError::HandshakeTooLarge(self.max_bytes))
+            }
+            Ok(Err(err)) if err.is_malformed() => match &budget.trace {
+                Some(trace) => Err(SocksServerError::MalformedHandshake {
+                    bytes: hex(
+                        &trace.lock().unwrap(),
+                        budget.method.load(Ordering::Relaxed),
+                    ),
+                    position: self.max_bytes - budget.remaining.load(Ordering::Relaxed),
+                    source: Box::new(err),
+                }),
+                None => Err(err),
+            },
+            Ok(res) => {
+                handshake_done();
+                res
+            }
+        }
+    }
+
+    /// Run `session`, dropping it if it hasn't read the request within the timeout, for
+    /// `Socks5Listener::serve` which hands the raw stream to its handler: the byte budget
+    /// can't apply there.
+    pub(crate) async fn watch<F: Future<Output = ()>>(&self, session: F) {
+        let done = Arc::new(AtomicBool::new(false));
+        let session = HANDSHAKE_DONE.scope(done.clone(), session);
+        tokio::pin!(session);
+        tokio::select! {
+            _ = &mut session => return,
+            _ = tokio::time::sleep(self.timeout) => {}
+        }
+        if !done.load(Ordering::Relaxed) {
+            debug!("handshake not completed within {:?}, closing", self.timeout);
+            return;
+        }
+        session.await
+    }
+}
+
+/// Lift the timeout of `HandshakeLimits::watch` for the current session, if any.
+pub(crate) fn handshake_done() {
+    let _ = HANDSHAKE_DONE.try_with(|done| done.store(true, Ordering::Relaxed));
+}
+
+/// The handshake `bytes` in hex, with the password masked if `method` is password
+/// authentication.
+fn hex(bytes: &[u8], method: u8) -> String {
+    let mut password = 0..0;
+    if method == consts::SOCKS5_AUTH_METHOD_PASSWORD {
+        // VER NMETHODS METHODS, then VER ULEN UNAME PLEN PASSWD
+        let subnegotiation = bytes.get(1).map_or(bytes.len(), |&n| 2 + n as usize);
+        let plen = bytes
+            .get(subnegotiation + 1)
+            .map_or(bytes.len(), |&n| subnegotiation + 2 + n as usize);
+        let len = bytes.get(plen).map_or(0, |&n| n as usize);
+        password = plen + 1..plen + 1 + len;
+    }
+    let hex: Vec<_> = bytes
+        .iter()
+        .enumerate()
+        .map(|(i, b)| match password.contains(&i) {
+            true => "**".to_owned(),
+            false => format!("{:02x}", b),
+        })
+        .collect();
+    hex.join(" ")
+}
+
+#[derive(Debug)]
+struct Budget {
+    remaining: AtomicUsize,
+    armed: AtomicBool,
+    exceeded: AtomicBool,
+    /// The first bytes read, with `HandshakeLimits::set_diagnostics`.
+    trace: Option<Mutex<Vec<u8>>>,
+    /// The auth method selected by the server, to mask the password in the trace.
+    method: AtomicU8,
+}
+
+/// A client stream going through [`HandshakeLimits::run`], counting the bytes read until
+/// the handshake is done.
+#[derive(Debug)]
+pub struct HandshakeStream<T> {
+    inner: T,
+    budget: Arc<Budget>,
+}
+
+impl<T> HandshakeStream<T> {
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for HandshakeStream<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = buf.filled().len() - before;
+        let budget = &self.budget;
+        if read > 0 && budget.armed.load(Ordering::Relaxed) {
+            let remaining = budget.remaining.load(Ordering::Relaxed);
+            if read > remaining {
+                budget.exceeded.store(true, Ordering::Relaxed);
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "handshake too large",
+                )));
+            }
+            budget.remaining.store(remaining - read, Ordering::Relaxed);
+            if let Some(trace) = &budget.trace {
+                let mut trace = trace.lock().unwrap();
+                let keep = read.min(MAX_DIAGNOSTIC_BYTES.saturating_sub(trace.len()));
+                trace.extend_from_slice(&buf.filled()[before..before + keep]);
+            }
+        }
+        res
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for HandshakeStream<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let budget = &self.budget;
+        if let (Some(_), [consts::SOCKS5_VERSION, method]) = (&budget.trace, buf) {
+            // the method selection reply, the only two-byte one
+            let _ = budget.method.compare_exchange(
+                consts::SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE,
+                *method,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            );
+        }
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::HandshakeLimits;
+    use crate::server::{Socks5ServerProtocol, SocksServerError};
+    use std::time::Duration;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn slow_and_large_handshakes() {
+        let mut limits = HandshakeLimits::new();
+        limits
+            .set_timeout(Duration::from_millis(50))
+            .set_max_bytes(16);
+
+        // stalls after the method selection
+        let (mut client, server) = duplex(64);
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        let res = limits
+            .run(server, |s| async {
+                Socks5ServerProtocol::accept_no_auth(s)
+                    .await?
+                    .read_command()
+                    .await
+            })
+            .await;
+        assert!(matches!(res, Err(SocksServerError::HandshakeTimeout(_))));
+
+        let (mut client, server) = duplex(512);
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        client.write_all(&[5, 1, 0, 3, 20]).await.unwrap();
+        client.write_all(&[b'a'; 22]).await.unwrap();
+        let res = limits
+            .run(server, |s| async {
+                Socks5ServerProtocol::accept_no_auth(s)
+                    .await?
+                    .read_command()
+                    .await
+            })
+            .await;
+        assert!(matches!(res, Err(SocksServerError::HandshakeTooLarge(16))));
+
+        // within the limits, and lifted afterwards
+        let (mut client, server) = duplex(512);
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        client
+            .write_all(&[5, 1, 0, 1, 192, 0, 2, 1, 0, 80])
+            .await
+            .unwrap();
+        client.write_all(&[0u8; 64]).await.unwrap();
+        let (proto, _, _) = limits
+            .run(server, |s| async {
+                Socks5ServerProtocol::accept_no_auth(s)
+                    .await?
+                    .read_command()
+                    .await
+            })
+            .await
+            .unwrap();
+        let mut stream = proto
+            .reply_success("0.0.0.0:0".parse().unwrap())
+            .await
+            .unwrap();
+        let mut buf = [0u8; 64];
+        stream.read_exact(&mut buf).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn malformed_handshake_diagnostics() {
+        let mut limits = HandshakeLimits::new();
+        limits.set_diagnostics(true);
+        let (mut client, server) = duplex(64);
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        client.write_all(&[5, 1, 0, 9]).await.unwrap();
+        let res = limits
+            .run(server, |s| async {
+                Socks5ServerProtocol::accept_no_auth(s)
+                    .await?
+                    .read_command()
+                    .await
+            })
+            .await;
+        let Err(err) = res else {
+            panic!("an unknown address type was accepted");
+        };
+        assert!(matches!(
+            &err,
+            SocksServerError::MalformedHandshake { position: 7, .. }
+        ));
+        assert_eq!(
+            err.to_string(),
+            "Malformed handshake at byte 7 [05 01 00 05 01 00 09]: Unknown address type"
+        );
+
+        // the password is masked
+        let (mut client, server) = duplex(64);
+        client.write_all(&[5, 1, 2]).await.unwrap();
+        client
+            .write_all(&[1, 1, b'a', 2, b'p', b'w'])
+            .await
+            .unwrap();
+        client.write_all(&[5, 1, 0, 9]).await.unwrap();
+        let res = limits
+            .run(server, |s| async {
+                Socks5ServerProtocol::accept_password_auth(s, |_, _| true)
+                    .await?
+                    .0
+                    .read_command()
+                    .await
+            })
+            .await;
+        let Err(err) = res else {
+            panic!("an unknown address type was accepted");
+        };
+        assert_eq!(
+            err.to_string(),
+            "Malformed handshake at byte 13 [05 01 02 01 01 61 02 ** ** 05 01 00 09]: Unknown address type"
+        );
+    }
+}
@@ -0,0 +1,125 @@
+use std::fmt;
+use std::net::SocketAddr;
+
+/// The state of a [`super::Socks5Listener`], see [`super::Socks5Listener::health`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct HealthSnapshot {
+    /// Whether `serve` is running and not shedding load.
+    pub accepting: bool,
+    pub active_sessions: usize,
+    /// Sessions which panicked since the start.
+    pub panicked_sessions: u64,
+    /// See `Socks5Listener::set_capacity`.
+    pub capacity: Option<usize>,
+    pub listeners: Vec<ListenerHealth>,
+}
+
+/// The sessions of one of the listening sockets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ListenerHealth {
+    pub local_addr: SocketAddr,
+    pub active_sessions: usize,
+}
+
+impl HealthSnapshot {
+    /// Whether new clients can be taken: accepting, and below the capacity if any.
+    pub fn is_ready(&self) -> bool {
+        self.accepting
+            && self
+                .capacity
+                .is_none_or(|capacity| self.active_sessions < capacity)
+    }
+}
+
+/// One `key value` line per gauge, e.g. for a health endpoint.
+impl fmt::Display for HealthSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "ready {}", self.is_ready())?;
+        writeln!(f, "accepting {}", self.accepting)?;
+        writeln!(f, "panicked_sessions {}", self.panicked_sessions)?;
+        writeln!(f, "active_sessions {}", self.active_sessions)?;
+        if let Some(capacity) = self.capacity {
+            writeln!(f, "capacity {}", capacity)?;
+        }
+        for listener in &self.listeners {
+            writeln!(
+                f,
+                "listener {} {}",
+                listener.local_addr, listener.active_sessions
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Answer every HTTP request on `listener` with the current snapshot, `200 OK` when ready
+/// and `503 Service Unavailable` otherwise, for orchestrators probing the proxy.
+///
+/// The request itself isn't looked at, so any path works.
+#[cfg(feature = "admin")]
+pub async fn serve_health_http<F>(listener: tokio::net::TcpListener, snapshot: F)
+where
+    F: Fn() -> HealthSnapshot,
+{
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    loop {
+        let (mut stream, _) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                error!("health endpoint accept error = {:?}", err);
+                continue;
+            }
+        };
+        let health = snapshot();
+        tokio::spawn(async move {
+            // the request fits in one read, and is ignored anyway
+            let mut request = [0u8; 1024];
+            let _ = stream.read(&mut request).await;
+            let status = match health.is_ready() {
+                true => "200 OK",
+                false => "503 Service Unavailable",
+            };
+            let body = health.to_string();
+            let response = format!(
+                "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            );
+            let _ = stream.write_all(response.as_bytes()).await;
+        });
+    }
+}
+
+#[cfg(all(test, feature = "admin"))]
+mod test {
+    use super::{serve_health_http, HealthSnapshot};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[tokio::test]
+    async fn health_endpoint() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_health_http(listener, || HealthSnapshot {
+            accepting: true,
+            active_sessions: 2,
+            panicked_sessions: 0,
+            capacity: Some(2),
+            listeners: vec![],
+        }));
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"GET /health HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 503"), "{}", response);
+        assert!(response.ends_with("active_sessions 2\ncapacity 2\n"));
+    }
+}
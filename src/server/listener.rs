@@ -0,0 +1,543 @@
+use super::{
+    catch_panic, ConnectionRateLimiter, HandshakeLimits, HealthSnapshot, ListenerHealth,
+    MemoryBudget, MemoryReservation, SessionId, SessionTasks,
+};
+use crate::consts;
+use crate::util::socket_options::SocketOptions;
+use socket2::{Domain, Socket, Type};
+use std::future::{poll_fn, Future};
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::Poll;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Notify;
+
+/// What [`Socks5Listener::serve`] does over the high-water mark of
+/// [`Socks5Listener::set_load_shedding`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShedMode {
+    /// Stop accepting, the new clients wait in the listen backlog.
+    Pause,
+    /// Accept and refuse the method negotiation right away, so clients fail fast.
+    Reject,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct LoadShedding {
+    high: usize,
+    low: usize,
+    mode: ShedMode,
+}
+
+/// A set of listening sockets accepting SOCKS clients as one, e.g. `0.0.0.0:1080` and
+/// `[::]:1080` for a dual-stack server, or several ports.
+#[derive(Debug)]
+pub struct Socks5Listener {
+    listeners: Vec<TcpListener>,
+    // rotates the first listener polled, so a busy one can't starve the others
+    next: AtomicUsize,
+    // for each listener, one clone held by each session spawned by `serve`
+    sessions: Vec<Arc<()>>,
+    rate_limiter: Option<ConnectionRateLimiter>,
+    capacity: Option<usize>,
+    serving: AtomicBool,
+    load_shedding: Option<LoadShedding>,
+    shedding: AtomicBool,
+    session_ended: Arc<Notify>,
+    memory_budget: Option<MemoryBudget>,
+    handshake_limits: Option<HandshakeLimits>,
+    tasks: Arc<SessionTasks>,
+    panicked: Arc<AtomicU64>,
+}
+
+impl Socks5Listener {
+    /// Bind every address, failing if any of them can't be bound.
+    ///
+    /// IPv6 sockets are bound IPv6-only, so that the same port can be bound on both
+    /// `0.0.0.0` and `[::]`.
+    pub async fn bind<I, A>(addrs: I) -> io::Result<Self>
+    where
+        I: IntoIterator<Item = A>,
+        A: ToSocketAddrs,
+    {
+        Self::bind_with_options(addrs, &SocketOptions::default()).await
+    }
+
+    /// Like [`Socks5Listener::bind`], setting up the listening sockets with `options`,
+    /// e.g. TCP Fast Open.
+    pub async fn bind_with_options<I, A>(addrs: I, options: &SocketOptions) -> io::Result<Self>
+    where
+        I: IntoIterator<Item = A>,
+        A: ToSocketAddrs,
+    {
+        let mut listeners = vec![];
+        for addrs in addrs {
+            for addr in addrs.to_socket_addrs()? {
+                listeners.push(bind_listener(addr, options)?);
+                info!("Listening @ {}", addr);
+            }
+        }
+        if listeners.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no address to listen on",
+            ));
+        }
+        Ok(Self::from_listeners(listeners))
+    }
+
+    /// Use listeners bound by the caller.
+    pub fn from_listeners(listeners: Vec<TcpListener>) -> Self {
+        Socks5Listener {
+            sessions: listeners.iter().map(|_| Arc::new(())).collect(),
+            listeners,
+            next: AtomicUsize::new(0),
+            rate_limiter: None,
+            capacity: None,
+            serving: AtomicBool::new(false),
+            load_shedding: None,
+            shedding: AtomicBool::new(false),
+            session_ended: Arc::new(Notify::new()),
+            memory_budget: None,
+            handshake_limits: None,
+            tasks: Arc::new(SessionTasks::new()),
+            panicked: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Close right away the connections of the clients over `limiter`'s rate, in
+    /// [`Socks5Listener::serve`].
+    pub fn set_connection_rate_limit(&mut self, limiter: ConnectionRateLimiter) -> &mut Self {
+        self.rate_limiter = Some(limiter);
+        self
+    }
+
+    /// Use the listeners passed with the `LISTEN_FDS` protocol, by systemd socket
+    /// activation or by a previous instance of the server handing over its sockets for a
+    /// zero-downtime restart.
+    ///
+    /// Returns `None` when no listener was passed, e.g. to fall back on [`Socks5Listener::bind`].
+    #[cfg(feature = "socket-activation")]
+    pub fn from_env() -> io::Result<Option<Self>> {
+        let mut fds = listenfd::ListenFd::from_env();
+        let mut listeners = vec![];
+        for idx in 0..fds.len() {
+            // fails if the fd isn't a TCP listener, `None` if it was already taken
+            if let Some(listener) = fds.take_tcp_listener(idx)? {
+                listener.set_nonblocking(true)?;
+                info!("Listening @ {} (inherited)", listener.local_addr()?);
+                listeners.push(TcpListener::from_std(listener)?);
+            }
+        }
+        if listeners.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Self::from_listeners(listeners)))
+    }
+
+    pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.listeners.iter().map(|l| l.local_addr()).collect()
+    }
+
+    /// How many sessions the server is sized for, only reported by
+    /// [`Socks5Listener::health`].
+    pub fn set_capacity(&mut self, sessions: usize) -> &mut Self {
+        self.capacity = Some(sessions);
+        self
+    }
+
+    /// Shed load in [`Socks5Listener::serve`] once `high` sessions are running, until they
+    /// are down to `low`, so that a flood of clients can't take the server down.
+    pub fn set_load_shedding(&mut self, high: usize, low: usize, mode: ShedMode) -> &mut Self {
+        self.load_shedding = Some(LoadShedding {
+            high,
+            low: low.min(high),
+            mode,
+        });
+        self
+    }
+
+    /// Refuse the new clients in [`Socks5Listener::serve`] while `budget` can't hold
+    /// another session, each session reserving its buffers until it ends: the handshake
+    /// ones on accept, the relay ones as they are allocated, see [`MemoryReservation::current`].
+    pub fn set_memory_budget(&mut self, budget: MemoryBudget) -> &mut Self {
+        self.memory_budget = Some(budget);
+        self
+    }
+
+    /// Close the sessions of [`Socks5Listener::serve`] which haven't read the request
+    /// within the timeout of `limits`, never by default.
+    ///
+    /// Only for handlers running a SOCKS5 handshake up to `read_command`, the others
+    /// (e.g. a transparent proxy or a mux server) would be closed after the timeout. The
+    /// handler gets the raw stream, so the byte budget of `limits` doesn't apply, see
+    /// [`HandshakeLimits::run`] for it.
+    pub fn set_handshake_limits(&mut self, limits: Option<HandshakeLimits>) -> &mut Self {
+        self.handshake_limits = limits;
+        self
+    }
+
+    /// Spawn the sessions of [`Socks5Listener::serve`] in `tasks`, e.g. to cap them or
+    /// handle their results, or to share the tasks between listeners.
+    pub fn set_session_tasks(&mut self, tasks: Arc<SessionTasks>) -> &mut Self {
+        self.tasks = tasks;
+        self
+    }
+
+    pub fn session_tasks(&self) -> &Arc<SessionTasks> {
+        &self.tasks
+    }
+
+    /// Once [`Socks5Listener::serve`] is stopped, wait up to `grace` for the sessions to
+    /// end, then abort the others, returning how many were.
+    pub async fn shutdown(&self, grace: Duration) -> usize {
+        info!(
+            "Shutting down, waiting up to {:?} for {} sessions",
+            grace,
+            self.tasks.len()
+        );
+        let aborted = self.tasks.shutdown(grace).await;
+        if aborted > 0 {
+            warn!(
+                "{} sessions still running after {:?}, aborted",
+                aborted, grace
+            );
+        }
+        aborted
+    }
+
+    /// Whether load is being shed, see [`Socks5Listener::set_load_shedding`].
+    pub fn is_shedding(&self) -> bool {
+        self.shedding.load(Ordering::Relaxed)
+    }
+
+    /// How many sessions spawned by [`Socks5Listener::serve`] are still running.
+    pub fn active_sessions(&self) -> usize {
+        self.sessions.iter().map(|s| Arc::strong_count(s) - 1).sum()
+    }
+
+    /// How many sessions spawned by [`Socks5Listener::serve`] panicked.
+    pub fn panicked_sessions(&self) -> u64 {
+        self.panicked.load(Ordering::Relaxed)
+    }
+
+    /// The state of the listeners, for a health or readiness probe.
+    pub fn health(&self) -> HealthSnapshot {
+        let listeners = self
+            .listeners
+            .iter()
+            .zip(&self.sessions)
+            .filter_map(|(listener, sessions)| {
+                Some(ListenerHealth {
+                    local_addr: listener.local_addr().ok()?,
+                    active_sessions: Arc::strong_count(sessions) - 1,
+                })
+            })
+            .collect();
+        HealthSnapshot {
+            accepting: self.serving.load(Ordering::Relaxed) && !self.is_shedding(),
+            active_sessions: self.active_sessions(),
+            panicked_sessions: self.panicked_sessions(),
+            capacity: self.capacity,
+            listeners,
+        }
+    }
+
+    /// Accept the next client on any of the listeners, along with the local address it
+    /// was accepted on.
+    pub async fn accept(&self) -> io::Result<(TcpStream, SocketAddr, SocketAddr)> {
+        let (res, _) = self.accept_indexed().await;
+        res
+    }
+
+    /// Like `accept`, along with the index of the listener.
+    async fn accept_indexed(&self) -> (io::Result<(TcpStream, SocketAddr, SocketAddr)>, usize) {
+        poll_fn(|cx| {
+            let n = self.listeners.len();
+            let start = self.next.load(Ordering::Relaxed);
+            for i in 0..n {
+                let listener = &self.listeners[(start + i) % n];
+                if let Poll::Ready(res) = listener.poll_accept(cx) {
+                    self.next.store((start + i + 1) % n, Ordering::Relaxed);
+                    let local_addr = listener.local_addr();
+                    let res = res.and_then(|(stream, peer)| Ok((stream, peer, local_addr?)));
+                    return Poll::Ready((res, (start + i) % n));
+                }
+            }
+            Poll::Pending
+        })
+        .await
+    }
+
+    /// Accept clients on all the listeners forever, handling each of them in its own task
+    /// of the [`SessionTasks`], which pauses accepting while they are at their limit.
+    ///
+    /// State shared by all the listeners (authentication, ACLs...) can be captured by
+    /// `handler`. Accept errors are logged, and don't stop the loop. The clients over the
+    /// connection rate limit, if any, are closed without calling `handler`, and so are
+    /// the clients over the load shedding high-water mark in `ShedMode::Reject` or over
+    /// the memory budget.
+    ///
+    /// Each session runs under a new [`SessionId`], see [`SessionId::current`]. A panic in a
+    /// session is logged with its id and client and counted, see
+    /// [`Socks5Listener::panicked_sessions`], the other sessions going on. The sessions
+    /// still without a request after the handshake timeout, if any, are dropped, see
+    /// [`Socks5Listener::set_handshake_limits`].
+    pub async fn serve<F, R>(&self, handler: F)
+    where
+        F: Fn(TcpStream, SocketAddr) -> R,
+        R: Future<Output = ()> + Send + 'static,
+    {
+        self.serving.store(true, Ordering::Relaxed);
+        let _serving = Serving(&self.serving);
+        loop {
+            if self.update_shedding() == Some(ShedMode::Pause) {
+                self.session_ended.notified().await;
+                continue;
+            }
+            match self.accept_indexed().await {
+                (Ok((socket, client_addr, _)), idx) => {
+                    if let Some(limiter) = &self.rate_limiter {
+                        if !limiter.check(client_addr.ip()) {
+                            debug!("{} over the connection rate limit, closing", client_addr);
+                            continue;
+                        }
+                    }
+                    if self.update_shedding() == Some(ShedMode::Reject) {
+                        debug!("shedding load, rejecting {}", client_addr);
+                        refuse(socket);
+                        continue;
+                    }
+                    let reservation = match &self.memory_budget {
+                        Some(budget) => match budget.reserve_session() {
+                            Some(reservation) => Some(Arc::new(reservation)),
+                            None => {
+                                debug!("memory budget exhausted, rejecting {}", client_addr);
+                                refuse(socket);
+                                continue;
+                            }
+                        },
+                        None => None,
+                    };
+                    let id = SessionId::next();
+                    debug!("session {} accepted from {}", id, client_addr);
+                    let session = handler(socket, client_addr);
+                    let guard = self.sessions[idx].clone();
+                    let session_ended = self.session_ended.clone();
+                    let panicked = self.panicked.clone();
+                    let limits = self.handshake_limits.clone();
+                    let session = MemoryReservation::scope(reservation, async move {
+                        match limits {
+                            Some(limits) => limits.watch(session).await,
+                            None => session.await,
+                        }
+                    });
+                    let task = id.scope(async move {
+                        if let Err(err) = catch_panic(session).await {
+                            error!("{} (client {})", err, client_addr);
+                            panicked.fetch_add(1, Ordering::Relaxed);
+                        }
+                        drop(guard);
+                        session_ended.notify_one();
+                    });
+                    self.tasks.spawn(task).await;
+                }
+                (Err(err), _) => error!("accept error = {:?}", err),
+            }
+        }
+    }
+}
+
+impl Socks5Listener {
+    /// Start or stop shedding load, with hysteresis, returning how while shedding.
+    fn update_shedding(&self) -> Option<ShedMode> {
+        let shed = self.load_shedding?;
+        let active = self.active_sessions();
+        if active >= shed.high && !self.shedding.swap(true, Ordering::Relaxed) {
+            warn!("{} sessions running, shedding load", active);
+        } else if active <= shed.low && self.shedding.swap(false, Ordering::Relaxed) {
+            info!("down to {} sessions, accepting again", active);
+        }
+        self.is_shedding().then_some(shed.mode)
+    }
+}
+
+/// Refuse the method negotiation of a client turned away, so it fails fast.
+fn refuse(mut socket: TcpStream) {
+    tokio::spawn(async move {
+        let _ = socket
+            .write_all(&[
+                consts::SOCKS5_VERSION,
+                consts::SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE,
+            ])
+            .await;
+    });
+}
+
+/// Clears the serving flag when `serve` stops, i.e. is dropped.
+struct Serving<'a>(&'a AtomicBool);
+
+impl Drop for Serving<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+}
+
+fn bind_listener(addr: SocketAddr, options: &SocketOptions) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    #[cfg(not(windows))]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    options.apply_to_listener(&socket);
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::Socks5Listener;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::net::TcpStream;
+
+    #[tokio::test]
+    async fn accepts_on_every_address() {
+        let listener = Socks5Listener::bind(["127.0.0.1:0", "127.0.0.1:0"])
+            .await
+            .unwrap();
+        let addrs = listener.local_addrs().unwrap();
+        assert_eq!(addrs.len(), 2);
+
+        for addr in addrs {
+            let client = TcpStream::connect(addr).await.unwrap();
+            let (_, peer, local) = listener.accept().await.unwrap();
+            assert_eq!(peer, client.local_addr().unwrap());
+            assert_eq!(local, addr);
+        }
+    }
+
+    #[tokio::test]
+    async fn health_snapshot() {
+        let mut listener = Socks5Listener::bind(["127.0.0.1:0", "127.0.0.1:0"])
+            .await
+            .unwrap();
+        listener.set_capacity(1);
+        let addrs = listener.local_addrs().unwrap();
+        let health = listener.health();
+        assert!(!health.accepting && !health.is_ready());
+
+        let listener = Arc::new(listener);
+        let server = listener.clone();
+        tokio::spawn(async move {
+            server
+                .serve(|_, _| tokio::time::sleep(Duration::from_secs(60)))
+                .await
+        });
+        let _client = TcpStream::connect(addrs[1]).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let health = listener.health();
+        assert!(health.accepting);
+        assert_eq!(health.active_sessions, 1);
+        assert_eq!(health.listeners[0].active_sessions, 0);
+        assert_eq!(health.listeners[1].active_sessions, 1);
+        // at capacity
+        assert!(!health.is_ready());
+    }
+
+    #[tokio::test]
+    async fn load_shedding() {
+        use super::ShedMode;
+        use tokio::io::AsyncReadExt;
+        use tokio::sync::Semaphore;
+
+        let mut listener = Socks5Listener::bind(["127.0.0.1:0"]).await.unwrap();
+        listener.set_load_shedding(2, 1, ShedMode::Reject);
+        let addr = listener.local_addrs().unwrap()[0];
+        let listener = Arc::new(listener);
+        // the sessions run until a permit is added
+        let done = Arc::new(Semaphore::new(0));
+        let server = listener.clone();
+        let sessions = done.clone();
+        tokio::spawn(async move {
+            server
+                .serve(move |_, _| {
+                    let done = sessions.clone();
+                    async move {
+                        done.acquire().await.unwrap().forget();
+                    }
+                })
+                .await
+        });
+
+        let mut clients = vec![];
+        for _ in 0..2 {
+            clients.push(TcpStream::connect(addr).await.unwrap());
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let mut rejected = TcpStream::connect(addr).await.unwrap();
+        let mut reply = [0u8; 2];
+        rejected.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [5, 0xff]);
+        assert!(listener.is_shedding());
+
+        // accepting again once down to 1 session
+        done.add_permits(1);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        clients.push(TcpStream::connect(addr).await.unwrap());
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(listener.active_sessions(), 2);
+    }
+
+    #[tokio::test]
+    async fn handshake_timeout() {
+        use crate::server::{HandshakeLimits, Socks5ServerProtocol};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut listener = Socks5Listener::bind(["127.0.0.1:0"]).await.unwrap();
+        let mut limits = HandshakeLimits::new();
+        limits.set_timeout(Duration::from_millis(100));
+        listener.set_handshake_limits(Some(limits));
+        let addr = listener.local_addrs().unwrap()[0];
+        let listener = Arc::new(listener);
+        let server = listener.clone();
+        tokio::spawn(async move {
+            server
+                .serve(|socket, _| async move {
+                    let Ok(proto) = Socks5ServerProtocol::accept_no_auth(socket).await else {
+                        return;
+                    };
+                    if let Ok((proto, _, _)) = proto.read_command().await {
+                        // past the handshake, not closed anymore
+                        let _stream = proto.reply_success("0.0.0.0:0".parse().unwrap()).await;
+                        tokio::time::sleep(Duration::from_secs(60)).await;
+                    }
+                })
+                .await
+        });
+
+        // stalls after the method selection
+        let mut silent = TcpStream::connect(addr).await.unwrap();
+        silent.write_all(&[5, 1, 0]).await.unwrap();
+        let mut done = TcpStream::connect(addr).await.unwrap();
+        done.write_all(&[5, 1, 0, 5, 1, 0, 1, 192, 0, 2, 1, 0, 80])
+            .await
+            .unwrap();
+        let mut reply = [0u8; 12];
+        done.read_exact(&mut reply).await.unwrap();
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(listener.active_sessions(), 1);
+        let mut method = [0u8; 2];
+        silent.read_exact(&mut method).await.unwrap();
+        assert_eq!(silent.read(&mut method).await.unwrap(), 0);
+    }
+}
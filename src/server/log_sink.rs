@@ -0,0 +1,163 @@
+use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// lines written between two flushes, at most
+const BATCH: usize = 256;
+
+/// Hands log, audit or metrics lines over to a dedicated writer thread through a
+/// bounded channel, so that a slow disk or stderr consumer never stalls the relays.
+///
+/// When the channel is full the lines are dropped and counted, the writer reporting how
+/// many were lost. Install it as the logger of the `log` crate with
+/// [`AsyncLogSink::install`], or emit lines directly with [`AsyncLogSink::emit`].
+#[derive(Debug, Clone)]
+pub struct AsyncLogSink {
+    tx: SyncSender<String>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl AsyncLogSink {
+    /// Start a thread writing the lines to `writer`, up to `capacity` of them waiting.
+    ///
+    /// The thread stops once every clone of the sink is dropped.
+    pub fn new<W: Write + Send + 'static>(writer: W, capacity: usize) -> Self {
+        let (tx, rx) = sync_channel(capacity.max(1));
+        let dropped = Arc::new(AtomicU64::new(0));
+        let reported = dropped.clone();
+        std::thread::Builder::new()
+            .name("socks5-log".to_owned())
+            .spawn(move || write_lines(rx, writer, &reported))
+            .expect("failed to spawn the log writer thread");
+        AsyncLogSink { tx, dropped }
+    }
+
+    /// Queue a line without waiting, returning `false` if it was dropped.
+    pub fn emit<S: Into<String>>(&self, line: S) -> bool {
+        match self.tx.try_send(line.into()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+        }
+    }
+
+    /// How many lines were dropped so far.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Set the sink as the logger of the `log` crate, for the records up to `level`.
+    pub fn install(self, level: LevelFilter) -> Result<(), SetLoggerError> {
+        log::set_boxed_logger(Box::new(self))?;
+        log::set_max_level(level);
+        Ok(())
+    }
+}
+
+impl Log for AsyncLogSink {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        self.emit(format!(
+            "{}.{:03} {:<5} {}: {}",
+            now.as_secs(),
+            now.subsec_millis(),
+            record.level(),
+            record.target(),
+            record.args()
+        ));
+    }
+
+    fn flush(&self) {}
+}
+
+/// Write the lines in batches until the senders are gone, reporting the drops.
+fn write_lines<W: Write>(rx: Receiver<String>, mut writer: W, dropped: &AtomicU64) {
+    let mut reported = 0;
+    while let Ok(line) = rx.recv() {
+        let _ = writeln!(writer, "{}", line);
+        for line in rx.try_iter().take(BATCH - 1) {
+            let _ = writeln!(writer, "{}", line);
+        }
+        let total = dropped.load(Ordering::Relaxed);
+        if total > reported {
+            let _ = writeln!(writer, "{} log lines dropped", total - reported);
+            reported = total;
+        }
+        let _ = writer.flush();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::AsyncLogSink;
+    use std::io::{self, Write};
+    use std::sync::mpsc::{channel, Receiver};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    /// Blocks each write until allowed to proceed, like a stalled disk.
+    struct StalledWriter {
+        output: Arc<Mutex<Vec<u8>>>,
+        proceed: Receiver<()>,
+    }
+
+    impl Write for StalledWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let _ = self.proceed.recv();
+            self.output.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn drops_when_stalled() {
+        let output = Arc::new(Mutex::new(vec![]));
+        let (proceed, rx) = channel();
+        let sink = AsyncLogSink::new(
+            StalledWriter {
+                output: output.clone(),
+                proceed: rx,
+            },
+            2,
+        );
+
+        // never blocks, even though the writer is stuck on the first line
+        let start = Instant::now();
+        let sent = (0..10).filter(|i| sink.emit(format!("line {}", i))).count();
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(sent < 10);
+        assert_eq!(sink.dropped(), 10 - sent as u64);
+
+        drop(sink);
+        // let the remaining writes through
+        for _ in 0..100 {
+            let _ = proceed.send(());
+        }
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !String::from_utf8_lossy(&output.lock().unwrap()).contains("dropped") {
+            assert!(Instant::now() < deadline, "no drop report");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let output = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+        assert!(output.starts_with("line 0\n"));
+        assert!(output.contains(&format!("{} log lines dropped", 10 - sent)));
+    }
+}
@@ -0,0 +1,241 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+tokio::task_local! {
+    static SESSION_MEMORY: Arc<MemoryReservation>;
+}
+
+/// A cap on the buffer memory of the running sessions, accounted by the sessions
+/// themselves rather than measured from the allocator, to keep small hosts from running
+/// out of memory under load.
+///
+/// Each session reserves an estimate of its handshake buffers when it starts, see
+/// [`MemoryBudget::reserve_session`], and the sessions which can't are refused. The relay
+/// and UDP buffers are then reserved as they are allocated, from the reservation set in
+/// the `TransferOptions` or `UdpAssociation`, or the one of the session under a
+/// `Socks5Listener`, see [`MemoryReservation::current`]. Clones share the same budget.
+#[derive(Debug, Clone)]
+pub struct MemoryBudget {
+    inner: Arc<Budget>,
+    session_bytes: usize,
+}
+
+#[derive(Debug)]
+struct Budget {
+    cap: usize,
+    used: AtomicUsize,
+}
+
+impl MemoryBudget {
+    /// The handshake and early data buffers.
+    pub const DEFAULT_SESSION_BYTES: usize = 4 * 1024;
+
+    /// A budget of `cap` bytes for all the sessions.
+    pub fn new(cap: usize) -> Self {
+        MemoryBudget {
+            inner: Arc::new(Budget {
+                cap,
+                used: AtomicUsize::new(0),
+            }),
+            session_bytes: Self::DEFAULT_SESSION_BYTES,
+        }
+    }
+
+    /// How much a session reserves when it starts, `DEFAULT_SESSION_BYTES` by default.
+    pub fn set_session_bytes(&mut self, bytes: usize) -> &mut Self {
+        self.session_bytes = bytes;
+        self
+    }
+
+    pub fn cap(&self) -> usize {
+        self.inner.cap
+    }
+
+    /// How many bytes are reserved by the running sessions.
+    pub fn used(&self) -> usize {
+        self.inner.used.load(Ordering::Relaxed)
+    }
+
+    /// Reserve the memory of a new session, `None` if the budget is exhausted.
+    pub fn reserve_session(&self) -> Option<MemoryReservation> {
+        self.try_reserve(self.session_bytes)
+    }
+
+    /// Reserve `bytes`, `None` if it would exceed the cap.
+    pub fn try_reserve(&self, bytes: usize) -> Option<MemoryReservation> {
+        let reservation = MemoryReservation {
+            budget: self.inner.clone(),
+            bytes: AtomicUsize::new(0),
+        };
+        reservation.grow(bytes).then_some(reservation)
+    }
+}
+
+/// Memory reserved from a [`MemoryBudget`], given back when dropped.
+#[derive(Debug)]
+pub struct MemoryReservation {
+    budget: Arc<Budget>,
+    bytes: AtomicUsize,
+}
+
+impl MemoryReservation {
+    pub fn bytes(&self) -> usize {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    /// Reserve `bytes` more for the same session, e.g. for the buffers of a UDP
+    /// association. Returns `false`, reserving nothing, if it would exceed the cap.
+    pub fn grow(&self, bytes: usize) -> bool {
+        let budget = &self.budget;
+        let reserved = budget
+            .used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                used.checked_add(bytes).filter(|&total| total <= budget.cap)
+            })
+            .is_ok();
+        if reserved {
+            self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        }
+        reserved
+    }
+
+    /// Give `bytes` of the reservation back, e.g. once a buffer shrank.
+    pub fn release(&self, bytes: usize) {
+        let released = self
+            .bytes
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |b| {
+                Some(b.saturating_sub(bytes))
+            })
+            .map_or(0, |before| before.min(bytes));
+        self.budget.used.fetch_sub(released, Ordering::Relaxed);
+    }
+
+    /// The reservation of the session running this task, set by `Socks5Listener` when it
+    /// has a memory budget.
+    pub fn current() -> Option<Arc<MemoryReservation>> {
+        SESSION_MEMORY.try_with(Arc::clone).ok()
+    }
+
+    /// Run a session future with `reservation` as its [`MemoryReservation::current`].
+    pub(crate) async fn scope<F: Future>(
+        reservation: Option<Arc<MemoryReservation>>,
+        session: F,
+    ) -> F::Output {
+        match reservation {
+            Some(reservation) => SESSION_MEMORY.scope(reservation, session).await,
+            None => session.await,
+        }
+    }
+}
+
+impl Drop for MemoryReservation {
+    fn drop(&mut self) {
+        let bytes = *self.bytes.get_mut();
+        self.budget.used.fetch_sub(bytes, Ordering::Relaxed);
+    }
+}
+
+/// The part of a reservation held by a buffer, following its size and given back when
+/// dropped. Without a reservation, the buffers are unaccounted.
+#[derive(Debug)]
+pub(crate) struct BufferReservation {
+    reservation: Option<Arc<MemoryReservation>>,
+    bytes: usize,
+}
+
+impl BufferReservation {
+    pub(crate) fn new(reservation: Option<Arc<MemoryReservation>>) -> Self {
+        BufferReservation {
+            reservation,
+            bytes: 0,
+        }
+    }
+
+    /// Account for the buffer being `len` bytes, `false` if growing it exceeds the budget.
+    pub(crate) fn resize(&mut self, len: usize) -> bool {
+        if let Some(reservation) = &self.reservation {
+            if len > self.bytes {
+                if !reservation.grow(len - self.bytes) {
+                    return false;
+                }
+            } else {
+                reservation.release(self.bytes - len);
+            }
+        }
+        self.bytes = len;
+        true
+    }
+}
+
+impl Drop for BufferReservation {
+    fn drop(&mut self) {
+        self.resize(0);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{BufferReservation, MemoryBudget, MemoryReservation};
+    use crate::server::{transfer_with_options, CloseReason, TransferOptions};
+    use std::sync::Arc;
+    use tokio::io::duplex;
+
+    #[test]
+    fn sessions_within_budget() {
+        let mut budget = MemoryBudget::new(100);
+        budget.set_session_bytes(40);
+        let first = budget.reserve_session().unwrap();
+        let second = budget.reserve_session().unwrap();
+        assert!(budget.reserve_session().is_none());
+        assert!(!second.grow(40));
+        assert!(second.grow(20));
+        assert_eq!((second.bytes(), budget.used()), (60, 100));
+
+        drop(first);
+        assert_eq!(budget.used(), 60);
+        assert!(budget.clone().reserve_session().is_some());
+        drop(second);
+        assert_eq!(budget.used(), 0);
+    }
+
+    #[test]
+    fn buffers_follow_their_size() {
+        let budget = MemoryBudget::new(100);
+        let session = Arc::new(budget.try_reserve(10).unwrap());
+        let mut buffer = BufferReservation::new(Some(session.clone()));
+        assert!(buffer.resize(60));
+        assert!(!buffer.resize(100));
+        assert!(buffer.resize(30));
+        assert_eq!((session.bytes(), budget.used()), (40, 40));
+        drop(buffer);
+        assert_eq!(budget.used(), 10);
+        session.release(20);
+        assert_eq!(budget.used(), 0);
+    }
+
+    #[tokio::test]
+    async fn relay_buffers_reserved() {
+        let budget = MemoryBudget::new(10 * 1024);
+        let session = Arc::new(budget.try_reserve(1024).unwrap());
+        let (_client, inbound) = duplex(64);
+        let (outbound, _target) = duplex(64);
+        let reason = MemoryReservation::scope(
+            Some(session.clone()),
+            transfer_with_options(inbound, outbound, &TransferOptions::new()),
+        )
+        .await;
+        // two 8 KiB buffers don't fit
+        assert_eq!(reason, CloseReason::Error(std::io::ErrorKind::OutOfMemory));
+
+        let mut opts = TransferOptions::new();
+        opts.set_adaptive_buffers(1024, 64 * 1024)
+            .set_memory_reservation(session.clone());
+        let (client, inbound) = duplex(64);
+        let (outbound, target) = duplex(64);
+        drop((client, target));
+        let reason = transfer_with_options(inbound, outbound, &opts).await;
+        assert!(!matches!(reason, CloseReason::Error(_)));
+        assert_eq!(budget.used(), 1024);
+    }
+}
@@ -0,0 +1,88 @@
+use super::SessionId;
+use std::future::Future;
+use std::io;
+use tokio::net::windows::named_pipe::{NamedPipeServer, ServerOptions};
+
+/// Accepts local SOCKS clients on a Windows named pipe, e.g. `\\.\pipe\socks5`, instead
+/// of a TCP port.
+///
+/// Each accepted pipe instance is a stream to run [`Socks5ServerProtocol`] on, the same
+/// as a `TcpStream`.
+///
+/// [`Socks5ServerProtocol`]: super::Socks5ServerProtocol
+#[derive(Debug)]
+pub struct NamedPipeListener {
+    name: String,
+    // the instance waiting for the next client
+    next: NamedPipeServer,
+}
+
+impl NamedPipeListener {
+    /// Create the pipe, failing if another process already did.
+    pub fn bind<S: Into<String>>(name: S) -> io::Result<Self> {
+        let name = name.into();
+        let next = ServerOptions::new()
+            .first_pipe_instance(true)
+            .reject_remote_clients(true)
+            .create(&name)?;
+        info!("Listening @ {}", name);
+        Ok(NamedPipeListener { name, next })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Accept the next client.
+    pub async fn accept(&mut self) -> io::Result<NamedPipeServer> {
+        self.next.connect().await?;
+        // create the next instance before handing this one out, so that clients
+        // connecting meanwhile don't find the pipe missing
+        let next = ServerOptions::new()
+            .reject_remote_clients(true)
+            .create(&self.name)?;
+        Ok(std::mem::replace(&mut self.next, next))
+    }
+
+    /// Accept clients forever, handling each of them in its own task under a new
+    /// [`SessionId`], like `Socks5Listener::serve`.
+    pub async fn serve<F, R>(&mut self, handler: F)
+    where
+        F: Fn(NamedPipeServer) -> R,
+        R: Future<Output = ()> + Send + 'static,
+    {
+        loop {
+            match self.accept().await {
+                Ok(pipe) => {
+                    let id = SessionId::next();
+                    debug!("session {} accepted on {}", id, self.name);
+                    tokio::spawn(id.scope(handler(pipe)));
+                }
+                Err(err) => error!("accept error = {:?}", err),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::NamedPipeListener;
+    use crate::server::Socks5ServerProtocol;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::windows::named_pipe::ClientOptions;
+
+    #[tokio::test]
+    async fn handshake_over_pipe() {
+        let name = format!(r"\\.\pipe\fast-socks5-test-{}", std::process::id());
+        let mut listener = NamedPipeListener::bind(name.as_str()).unwrap();
+
+        let mut client = ClientOptions::new().open(&name).unwrap();
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        let pipe = listener.accept().await.unwrap();
+        Socks5ServerProtocol::accept_no_auth(pipe).await.unwrap();
+
+        let mut reply = [0u8; 2];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [5, 0]);
+    }
+}
@@ -0,0 +1,99 @@
+use super::SessionId;
+use std::any::Any;
+use std::fmt;
+use std::future::Future;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// A panic caught in a session, see [`catch_panic`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionPanic {
+    pub session: Option<SessionId>,
+    pub message: String,
+}
+
+impl fmt::Display for SessionPanic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.session {
+            Some(id) => write!(f, "session {} panicked: {}", id, self.message),
+            None => write!(f, "session panicked: {}", self.message),
+        }
+    }
+}
+
+impl std::error::Error for SessionPanic {}
+
+/// Run `session`, turning a panic in it into a [`SessionPanic`] instead of unwinding
+/// through the caller, e.g. the accept loop polling it.
+///
+/// The state the session shared stays usable: the locks of the crate recover from a
+/// panic of their holder.
+pub fn catch_panic<F: Future>(session: F) -> CatchPanic<F> {
+    CatchPanic {
+        session: Box::pin(session),
+    }
+}
+
+/// The future of [`catch_panic`].
+pub struct CatchPanic<F> {
+    session: Pin<Box<F>>,
+}
+
+impl<F> fmt::Debug for CatchPanic<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CatchPanic").finish_non_exhaustive()
+    }
+}
+
+impl<F: Future> Future for CatchPanic<F> {
+    type Output = Result<F::Output, SessionPanic>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let session = self.session.as_mut();
+        match catch_unwind(AssertUnwindSafe(|| session.poll(cx))) {
+            Ok(poll) => poll.map(Ok),
+            Err(payload) => Poll::Ready(Err(SessionPanic {
+                session: SessionId::current(),
+                message: panic_message(payload.as_ref()),
+            })),
+        }
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    match payload.downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => match payload.downcast_ref::<String>() {
+            Some(message) => message.clone(),
+            None => "unknown payload".to_owned(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::catch_panic;
+    use crate::server::SessionId;
+
+    fn boom(n: u32) -> u32 {
+        panic!("boom {}", n)
+    }
+
+    #[tokio::test]
+    async fn panics_are_caught() {
+        assert_eq!(catch_panic(async { 1 }).await, Ok(1));
+
+        let id = SessionId::next();
+        let err = id
+            .scope(catch_panic(async {
+                tokio::task::yield_now().await;
+                boom(42)
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(err.session, Some(id));
+        assert_eq!(err.message, "boom 42");
+        assert_eq!(err.to_string(), format!("session {} panicked: boom 42", id));
+    }
+}
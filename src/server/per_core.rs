@@ -0,0 +1,158 @@
+use super::Socks5Listener;
+use socket2::{Domain, Socket, Type};
+use std::future::Future;
+use std::io;
+use std::net::{SocketAddr, TcpListener as StdTcpListener, ToSocketAddrs};
+use std::sync::Arc;
+use tokio::net::TcpListener;
+
+/// Runs a server as one single-threaded runtime per core, each accepting on its own
+/// `SO_REUSEPORT` copy of the listeners, the kernel spreading the clients among them.
+///
+/// Sessions never move between cores and share no accept queue, for the most
+/// connections per second on big machines. State shared by all the cores (ACLs,
+/// counters...) still needs to be `Send + Sync`.
+#[derive(Debug)]
+pub struct PerCoreServer {
+    // the listeners of each core
+    cores: Vec<Vec<StdTcpListener>>,
+}
+
+impl PerCoreServer {
+    /// Bind every address once per core, or `threads` times if given.
+    ///
+    /// A port 0 is picked once and shared by all the cores.
+    pub fn bind<A: ToSocketAddrs>(addrs: A, threads: Option<usize>) -> io::Result<Self> {
+        let threads = match threads {
+            Some(threads) => threads.max(1),
+            None => std::thread::available_parallelism().map_or(1, |n| n.get()),
+        };
+        let mut cores: Vec<Vec<StdTcpListener>> = (0..threads).map(|_| vec![]).collect();
+        for addr in addrs.to_socket_addrs()? {
+            let first = bind_reuse_port(addr)?;
+            let addr = first.local_addr()?;
+            info!("Listening @ {} on {} cores", addr, threads);
+            cores[0].push(first);
+            for core in &mut cores[1..] {
+                core.push(bind_reuse_port(addr)?);
+            }
+        }
+        if cores[0].is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no address to listen on",
+            ));
+        }
+        Ok(PerCoreServer { cores })
+    }
+
+    pub fn threads(&self) -> usize {
+        self.cores.len()
+    }
+
+    pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.cores[0].iter().map(|l| l.local_addr()).collect()
+    }
+
+    /// Run `serve` on each core with its index and its listener, blocking until all of
+    /// them return, e.g. `|_, listener| async move { listener.serve(handler).await }`.
+    ///
+    /// The tasks spawned by `serve`, such as the sessions of [`Socks5Listener::serve`],
+    /// stay on its core.
+    pub fn run<S, F>(self, serve: S) -> io::Result<()>
+    where
+        S: Fn(usize, Socks5Listener) -> F + Send + Sync + 'static,
+        F: Future<Output = ()>,
+    {
+        let serve = Arc::new(serve);
+        let mut threads = vec![];
+        for (idx, listeners) in self.cores.into_iter().enumerate() {
+            let serve = serve.clone();
+            let thread = std::thread::Builder::new()
+                .name(format!("socks5-core-{}", idx))
+                .spawn(move || -> io::Result<()> {
+                    let runtime = tokio::runtime::Builder::new_current_thread()
+                        .enable_all()
+                        .build()?;
+                    runtime.block_on(async move {
+                        let listeners = listeners
+                            .into_iter()
+                            .map(TcpListener::from_std)
+                            .collect::<io::Result<_>>()?;
+                        serve(idx, Socks5Listener::from_listeners(listeners)).await;
+                        Ok(())
+                    })
+                })?;
+            threads.push(thread);
+        }
+        let mut res = Ok(());
+        for thread in threads {
+            match thread.join() {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => res = Err(err),
+                Err(panic) => std::panic::resume_unwind(panic),
+            }
+        }
+        res
+    }
+}
+
+fn bind_reuse_port(addr: SocketAddr) -> io::Result<StdTcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    Ok(socket.into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::PerCoreServer;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn accepts_on_every_core() {
+        let server = PerCoreServer::bind("127.0.0.1:0", Some(2)).unwrap();
+        assert_eq!(server.threads(), 2);
+        let addr = server.local_addrs().unwrap()[0];
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let run = std::thread::spawn({
+            let accepted = accepted.clone();
+            move || {
+                server.run(move |idx, listener| {
+                    let accepted = accepted.clone();
+                    async move {
+                        // until the clients are done
+                        let accept = Duration::from_millis(500);
+                        while let Ok(Ok((mut socket, _, _))) =
+                            tokio::time::timeout(accept, listener.accept()).await
+                        {
+                            accepted.fetch_add(1, Ordering::Relaxed);
+                            let _ = socket.write_all(&[idx as u8]).await;
+                        }
+                    }
+                })
+            }
+        });
+
+        for _ in 0..8 {
+            let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let mut core = [0u8];
+            client.read_exact(&mut core).await.unwrap();
+            assert!(core[0] < 2);
+        }
+        tokio::task::spawn_blocking(move || run.join().unwrap())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(accepted.load(Ordering::Relaxed), 8);
+    }
+}
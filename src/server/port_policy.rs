@@ -0,0 +1,244 @@
+use super::{AclAction, AclRequest, AclRule, StreamTap, TapAction, TapDirection};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// The SMTP ports, routinely abused for spam through open proxies.
+pub const SMTP_PORTS: [u16; 3] = [25, 465, 587];
+
+/// An access rule on the target port, with an allow list and a deny list.
+///
+/// Denies the ports in the deny list, and when there is an allow list, the ports not in
+/// it. Doesn't apply to the other requests.
+#[derive(Debug, Clone, Default)]
+pub struct PortRule {
+    allowed: HashSet<u16>,
+    denied: HashSet<u16>,
+}
+
+impl PortRule {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A rule denying the [`SMTP_PORTS`].
+    pub fn block_smtp() -> Self {
+        let mut rule = Self::new();
+        rule.deny_ports(SMTP_PORTS);
+        rule
+    }
+
+    /// Only accept these target ports.
+    pub fn allow_ports<I: IntoIterator<Item = u16>>(&mut self, ports: I) -> &mut Self {
+        self.allowed.extend(ports);
+        self
+    }
+
+    pub fn deny_ports<I: IntoIterator<Item = u16>>(&mut self, ports: I) -> &mut Self {
+        self.denied.extend(ports);
+        self
+    }
+}
+
+impl AclRule for PortRule {
+    fn evaluate(&self, request: &AclRequest<'_>) -> Option<AclAction> {
+        let port = request.target.port();
+        let denied = self.denied.contains(&port)
+            || (!self.allowed.is_empty() && !self.allowed.contains(&port));
+        denied.then_some(AclAction::Deny)
+    }
+}
+
+/// An application protocol recognized by [`ProtocolPolicy`] in the first bytes relayed,
+/// or by `HandshakeSniffer` instead of a SOCKS handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SniffedProtocol {
+    /// A client greeting with `EHLO`/`HELO`, or a server banner announcing SMTP.
+    Smtp,
+    /// A plain HTTP/1.x request.
+    Http,
+    /// A TLS handshake record.
+    Tls,
+    /// An SSH identification string.
+    Ssh,
+}
+
+const HTTP_METHODS: [&[u8]; 8] = [
+    b"GET ",
+    b"POST ",
+    b"HEAD ",
+    b"PUT ",
+    b"DELETE ",
+    b"OPTIONS ",
+    b"PATCH ",
+    b"CONNECT ",
+];
+
+impl SniffedProtocol {
+    /// Guess the protocol from the first chunk sent in `direction`.
+    pub fn sniff(direction: TapDirection, chunk: &[u8]) -> Option<Self> {
+        let line = chunk.split(|&b| b == b'\n').next().unwrap_or_default();
+        let upper = line.to_ascii_uppercase();
+        match direction {
+            TapDirection::ClientToTarget => {
+                if upper.starts_with(b"EHLO ") || upper.starts_with(b"HELO ") {
+                    return Some(SniffedProtocol::Smtp);
+                }
+                let is_method = HTTP_METHODS.iter().any(|method| line.starts_with(method));
+                if is_method && line.windows(7).any(|w| w == b" HTTP/1") {
+                    return Some(SniffedProtocol::Http);
+                }
+                None
+            }
+            TapDirection::TargetToClient => {
+                let is_banner = line.starts_with(b"220 ") || line.starts_with(b"220-");
+                (is_banner && upper.windows(4).any(|w| w == b"SMTP"))
+                    .then_some(SniffedProtocol::Smtp)
+            }
+        }
+    }
+
+    /// Guess the protocol of a client from the first bytes of the connection, which may
+    /// be too short to hold the whole greeting.
+    pub fn sniff_handshake(prefix: &[u8]) -> Option<Self> {
+        // a prefix of `pattern`, or starting with it
+        let matches = |pattern: &[u8]| {
+            let n = prefix.len().min(pattern.len());
+            n > 0 && prefix[..n] == pattern[..n]
+        };
+        if matches(&[0x16, 0x03]) {
+            Some(SniffedProtocol::Tls)
+        } else if matches(b"SSH-") {
+            Some(SniffedProtocol::Ssh)
+        } else if HTTP_METHODS.iter().any(|method| matches(method)) {
+            Some(SniffedProtocol::Http)
+        } else {
+            None
+        }
+    }
+}
+
+/// Aborts the sessions carrying some protocols, recognized by their first bytes, e.g. to
+/// stop SMTP spam on non-standard ports. Complements [`PortRule`].
+///
+/// Use [`ProtocolPolicy::tap`] with `transfer_tapped` for each session.
+#[derive(Debug, Clone, Default)]
+pub struct ProtocolPolicy {
+    actions: HashMap<SniffedProtocol, AclAction>,
+}
+
+impl ProtocolPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set what to do with the sessions recognized as `protocol`, the others are allowed.
+    pub fn set_action(&mut self, protocol: SniffedProtocol, action: AclAction) -> &mut Self {
+        self.actions.insert(protocol, action);
+        self
+    }
+
+    /// The tap enforcing the policy on one session.
+    pub fn tap(&self) -> ProtocolTap<'_> {
+        ProtocolTap {
+            policy: self,
+            client_seen: AtomicBool::new(false),
+            target_seen: AtomicBool::new(false),
+        }
+    }
+}
+
+/// The [`StreamTap`] of one session, see [`ProtocolPolicy::tap`].
+#[derive(Debug)]
+pub struct ProtocolTap<'a> {
+    policy: &'a ProtocolPolicy,
+    client_seen: AtomicBool,
+    target_seen: AtomicBool,
+}
+
+impl StreamTap for ProtocolTap<'_> {
+    fn on_chunk(&self, direction: TapDirection, chunk: &[u8]) -> TapAction {
+        let seen = match direction {
+            TapDirection::ClientToTarget => &self.client_seen,
+            TapDirection::TargetToClient => &self.target_seen,
+        };
+        // only the first chunk of each direction is looked at
+        if seen.swap(true, Ordering::Relaxed) {
+            return TapAction::Continue;
+        }
+        match SniffedProtocol::sniff(direction, chunk) {
+            Some(protocol) if self.policy.actions.get(&protocol) == Some(&AclAction::Deny) => {
+                info!("{:?} detected, aborting the session", protocol);
+                TapAction::Abort
+            }
+            _ => TapAction::Continue,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{PortRule, ProtocolPolicy, SniffedProtocol};
+    use crate::server::{AclAction, AclRequest, AclRule, StreamTap, TapAction, TapDirection};
+    use crate::util::target_addr::TargetAddr;
+    use crate::Socks5Command;
+
+    #[test]
+    fn port_rules() {
+        let mut rule = PortRule::block_smtp();
+        rule.allow_ports([25, 80, 443]);
+        let evaluate = |port| {
+            let target = TargetAddr::Domain("example.com".to_owned(), port);
+            rule.evaluate(&AclRequest {
+                client_ip: "192.0.2.1".parse().unwrap(),
+                username: None,
+                command: Socks5Command::TCPConnect,
+                target: &target,
+            })
+        };
+        assert_eq!(evaluate(443), None);
+        assert_eq!(evaluate(25), Some(AclAction::Deny));
+        assert_eq!(evaluate(8080), Some(AclAction::Deny));
+    }
+
+    #[test]
+    fn sniffs_smtp_and_http() {
+        use TapDirection::*;
+        assert_eq!(
+            SniffedProtocol::sniff(TargetToClient, b"220 mx.example.com ESMTP Postfix\r\n"),
+            Some(SniffedProtocol::Smtp)
+        );
+        assert_eq!(
+            SniffedProtocol::sniff(ClientToTarget, b"ehlo spammer\r\n"),
+            Some(SniffedProtocol::Smtp)
+        );
+        assert_eq!(
+            SniffedProtocol::sniff(ClientToTarget, b"GET / HTTP/1.1\r\nHost: a\r\n\r\n"),
+            Some(SniffedProtocol::Http)
+        );
+        assert_eq!(
+            SniffedProtocol::sniff(ClientToTarget, b"\x16\x03\x01"),
+            None
+        );
+        assert_eq!(
+            SniffedProtocol::sniff(TargetToClient, b"220 FTP ready\r\n"),
+            None
+        );
+
+        let mut policy = ProtocolPolicy::new();
+        policy.set_action(SniffedProtocol::Smtp, AclAction::Deny);
+        let tap = policy.tap();
+        assert_eq!(
+            tap.on_chunk(ClientToTarget, b"GET / HTTP/1.1\r\n"),
+            TapAction::Continue
+        );
+        assert_eq!(
+            tap.on_chunk(TargetToClient, b"220 smtp.example.com\r\n"),
+            TapAction::Abort
+        );
+        // the SMTP greeting is sniffed only as a first chunk
+        assert_eq!(
+            tap.on_chunk(ClientToTarget, b"EHLO a\r\n"),
+            TapAction::Continue
+        );
+    }
+}
@@ -0,0 +1,624 @@
+use super::{
+    transfer_until_closed, CloseReason, SessionCloser, SocksServerError, StateStore,
+    TransferOptions,
+};
+use std::collections::HashMap;
+use std::fmt;
+use std::future::pending;
+use std::io;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Instant;
+
+// how often the running sessions with a time quota add their time, so that the
+// concurrent sessions of a key see each other's
+const CHARGE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Whose usage a quota counts.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum QuotaKey {
+    User(String),
+    Ip(IpAddr),
+}
+
+impl fmt::Display for QuotaKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QuotaKey::User(user) => write!(f, "user {}", user),
+            QuotaKey::Ip(ip) => write!(f, "ip {}", ip),
+        }
+    }
+}
+
+/// The limits of a key, `None` being unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QuotaLimits {
+    /// Bytes relayed, both ways.
+    pub bytes: Option<u64>,
+    /// Time connected, summed over the sessions.
+    pub time: Option<Duration>,
+}
+
+/// What a key used so far.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QuotaUsage {
+    pub bytes: u64,
+    pub time: Duration,
+}
+
+impl QuotaUsage {
+    pub fn exceeds(&self, limits: &QuotaLimits) -> bool {
+        limits.bytes.is_some_and(|bytes| self.bytes >= bytes)
+            || limits.time.is_some_and(|time| self.time >= time)
+    }
+}
+
+/// Where a [`QuotaManager`] persists the usage, to survive restarts.
+#[async_trait::async_trait]
+pub trait QuotaStore: Send + Sync {
+    async fn load(&self) -> io::Result<Vec<(QuotaKey, QuotaUsage)>>;
+    async fn save(&self, usage: Vec<(QuotaKey, QuotaUsage)>) -> io::Result<()>;
+}
+
+#[derive(Debug, Default)]
+struct Counter {
+    bytes: AtomicU64,
+    millis: AtomicU64,
+    // the totals of the `StateStore` at the last sync
+    synced_bytes: AtomicU64,
+    synced_millis: AtomicU64,
+}
+
+impl Counter {
+    fn usage(&self) -> QuotaUsage {
+        QuotaUsage {
+            bytes: self.bytes.load(Ordering::Relaxed),
+            time: Duration::from_millis(self.millis.load(Ordering::Relaxed)),
+        }
+    }
+
+    fn set(&self, usage: QuotaUsage) {
+        let millis = usage.time.as_millis() as u64;
+        self.bytes.store(usage.bytes, Ordering::Relaxed);
+        self.millis.store(millis, Ordering::Relaxed);
+        self.synced_bytes.store(usage.bytes, Ordering::Relaxed);
+        self.synced_millis.store(millis, Ordering::Relaxed);
+    }
+}
+
+/// Push what was counted since the last sync to the counter `key` of `store`, and take
+/// its total, keeping what was counted meanwhile.
+async fn sync_value<S: StateStore + ?Sized>(
+    store: &S,
+    key: &str,
+    value: &AtomicU64,
+    synced: &AtomicU64,
+) -> io::Result<()> {
+    let current = value.load(Ordering::Relaxed);
+    let delta = current.saturating_sub(synced.load(Ordering::Relaxed));
+    let total = store.increment(key, delta).await?;
+    // wrapping, the total is lower after the counter was reset in the store
+    value.fetch_add(total.wrapping_sub(current), Ordering::Relaxed);
+    synced.store(total, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Byte and time quotas per user or client IP: the sessions of a key over its limits
+/// are refused by [`QuotaManager::begin`], and the running ones ended with
+/// [`CloseReason::QuotaExceeded`] by [`QuotaSession::relay`].
+///
+/// The usage stays in memory: save it periodically to a [`QuotaStore`], load it on
+/// startup, and [`QuotaManager::reset_all`] at the start of each billing period.
+#[derive(Debug)]
+pub struct QuotaManager {
+    default: QuotaLimits,
+    limits: Mutex<HashMap<QuotaKey, QuotaLimits>>,
+    usage: Mutex<HashMap<QuotaKey, Arc<Counter>>>,
+}
+
+impl QuotaManager {
+    /// A manager applying `default` to the keys without limits of their own.
+    pub fn new(default: QuotaLimits) -> Self {
+        QuotaManager {
+            default,
+            limits: Mutex::default(),
+            usage: Mutex::default(),
+        }
+    }
+
+    pub fn set_limits(&self, key: QuotaKey, limits: QuotaLimits) -> &Self {
+        self.limits.lock().unwrap().insert(key, limits);
+        self
+    }
+
+    pub fn limits(&self, key: &QuotaKey) -> QuotaLimits {
+        let limits = self.limits.lock().unwrap();
+        limits.get(key).copied().unwrap_or(self.default)
+    }
+
+    pub fn usage(&self, key: &QuotaKey) -> QuotaUsage {
+        let usage = self.usage.lock().unwrap();
+        usage.get(key).map(|c| c.usage()).unwrap_or_default()
+    }
+
+    pub fn is_exceeded(&self, key: &QuotaKey) -> bool {
+        self.usage(key).exceeds(&self.limits(key))
+    }
+
+    pub fn reset(&self, key: &QuotaKey) {
+        let mut usage = self.usage.lock().unwrap();
+        if let Some(counter) = usage.get(key) {
+            counter.set(QuotaUsage::default());
+        }
+        prune_idle(&mut usage);
+    }
+
+    pub fn reset_all(&self) {
+        let mut usage = self.usage.lock().unwrap();
+        for counter in usage.values() {
+            counter.set(QuotaUsage::default());
+        }
+        prune_idle(&mut usage);
+    }
+
+    /// The usage of every key, the time of the running sessions counted up to the last
+    /// second for those with a time quota, and not yet for the others.
+    pub fn snapshot(&self) -> Vec<(QuotaKey, QuotaUsage)> {
+        let mut usage = self.usage.lock().unwrap();
+        prune_idle(&mut usage);
+        usage
+            .iter()
+            .map(|(key, counter)| (key.clone(), counter.usage()))
+            .collect()
+    }
+
+    /// Replace the usage of the given keys.
+    pub fn restore<I: IntoIterator<Item = (QuotaKey, QuotaUsage)>>(&self, entries: I) {
+        for (key, usage) in entries {
+            self.counter(key).set(usage);
+        }
+    }
+
+    pub async fn load<S: QuotaStore + ?Sized>(&self, store: &S) -> io::Result<()> {
+        self.restore(store.load().await?);
+        Ok(())
+    }
+
+    pub async fn save<S: QuotaStore + ?Sized>(&self, store: &S) -> io::Result<()> {
+        store.save(self.snapshot()).await
+    }
+
+    /// Share the usage with the other instances through `store`: push what this one
+    /// counted since the last sync and take the totals, so that the limits apply to
+    /// the sum. Call it periodically, e.g. every few seconds.
+    ///
+    /// The counters are the `quota:<key>:bytes` and `quota:<key>:millis` keys of the
+    /// store, e.g. `quota:user alice:bytes`: to reset a key everywhere, remove them
+    /// before resetting it on each instance.
+    pub async fn sync<S: StateStore + ?Sized>(&self, store: &S) -> io::Result<()> {
+        let counters: Vec<(QuotaKey, Arc<Counter>)> = {
+            let mut usage = self.usage.lock().unwrap();
+            prune_idle(&mut usage);
+            usage.iter().map(|(k, c)| (k.clone(), c.clone())).collect()
+        };
+        for (key, counter) in counters {
+            let bytes = format!("quota:{}:bytes", key);
+            sync_value(store, &bytes, &counter.bytes, &counter.synced_bytes).await?;
+            let millis = format!("quota:{}:millis", key);
+            sync_value(store, &millis, &counter.millis, &counter.synced_millis).await?;
+        }
+        Ok(())
+    }
+
+    fn counter(&self, key: QuotaKey) -> Arc<Counter> {
+        self.usage.lock().unwrap().entry(key).or_default().clone()
+    }
+
+    /// Start a session counting against `keys`, e.g. the user and the client IP, or
+    /// refuse it with [`SocksServerError::QuotaExceeded`] if one of them is used up.
+    pub fn begin<I>(&self, keys: I) -> Result<QuotaSession, SocksServerError>
+    where
+        I: IntoIterator<Item = QuotaKey>,
+    {
+        let mut counters = vec![];
+        for key in keys {
+            let limits = self.limits(&key);
+            let counter = self.counter(key);
+            if counter.usage().exceeds(&limits) {
+                return Err(SocksServerError::QuotaExceeded);
+            }
+            counters.push((counter, limits));
+        }
+        Ok(QuotaSession {
+            counters,
+            closer: SessionCloser::new(),
+            charged: Mutex::new(Instant::now()),
+        })
+    }
+}
+
+/// Forget the keys with nothing used and no running session.
+fn prune_idle(usage: &mut HashMap<QuotaKey, Arc<Counter>>) {
+    usage.retain(|_, counter| {
+        Arc::strong_count(counter) > 1 || counter.usage() != QuotaUsage::default()
+    });
+}
+
+/// A session counted by a [`QuotaManager`], its time being added every second while
+/// relaying with a time quota, and when it is dropped.
+#[derive(Debug)]
+pub struct QuotaSession {
+    counters: Vec<(Arc<Counter>, QuotaLimits)>,
+    closer: SessionCloser,
+    // up to when the time was added to the counters
+    charged: Mutex<Instant>,
+}
+
+impl QuotaSession {
+    /// The closer of the session, to end it from elsewhere as well.
+    pub fn closer(&self) -> &SessionCloser {
+        &self.closer
+    }
+
+    /// Like [`transfer_until_closed`], counting the bytes relayed and ending with
+    /// [`CloseReason::QuotaExceeded`] once a quota is used up.
+    pub async fn relay<I, O>(self, inbound: I, outbound: O, opts: &TransferOptions) -> CloseReason
+    where
+        I: AsyncRead + AsyncWrite + Unpin,
+        O: AsyncRead + AsyncWrite + Unpin,
+    {
+        let inbound = Counted {
+            inner: inbound,
+            session: &self,
+        };
+        let outbound = Counted {
+            inner: outbound,
+            session: &self,
+        };
+        let watchdog = async {
+            while let Some(left) = self.time_left() {
+                if left.is_zero() {
+                    self.closer.close(CloseReason::QuotaExceeded);
+                    break;
+                }
+                tokio::time::sleep(left.min(CHARGE_INTERVAL)).await;
+                self.charge();
+            }
+            pending::<()>().await
+        };
+        tokio::select! {
+            reason = transfer_until_closed(inbound, outbound, opts, &self.closer) => reason,
+            () = watchdog => unreachable!("the watchdog never completes"),
+        }
+    }
+
+    /// The time until the first time quota is used up, from the last charge.
+    fn time_left(&self) -> Option<Duration> {
+        self.counters
+            .iter()
+            .filter_map(|(counter, limits)| {
+                let time = limits.time?;
+                Some(time.saturating_sub(counter.usage().time))
+            })
+            .min()
+    }
+
+    /// Add the time since the last charge to the counters.
+    fn charge(&self) {
+        let mut charged = self.charged.lock().unwrap();
+        let millis = charged.elapsed().as_millis() as u64;
+        // the remainder under a millisecond is left for the next charge
+        *charged += Duration::from_millis(millis);
+        for (counter, _) in &self.counters {
+            counter.millis.fetch_add(millis, Ordering::Relaxed);
+        }
+    }
+
+    fn count(&self, len: usize) {
+        for (counter, limits) in &self.counters {
+            let bytes = counter.bytes.fetch_add(len as u64, Ordering::Relaxed) + len as u64;
+            if limits.bytes.is_some_and(|limit| bytes >= limit) {
+                self.closer.close(CloseReason::QuotaExceeded);
+            }
+        }
+    }
+}
+
+impl Drop for QuotaSession {
+    fn drop(&mut self) {
+        self.charge();
+    }
+}
+
+struct Counted<'a, S> {
+    inner: S,
+    session: &'a QuotaSession,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<'_, S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = res {
+            let len = buf.filled().len() - before;
+            if len > 0 {
+                self.session.count(len);
+            }
+        }
+        res
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<'_, S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// A [`QuotaStore`] in a text file, one key per line: the bytes, the milliseconds, then
+/// `user <name>` or `ip <ip>`.
+///
+/// Saving writes a temporary file next to it first, so that a crash never leaves it
+/// half written.
+#[derive(Debug, Clone)]
+pub struct FileQuotaStore {
+    path: PathBuf,
+}
+
+impl FileQuotaStore {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        FileQuotaStore { path: path.into() }
+    }
+}
+
+#[async_trait::async_trait]
+impl QuotaStore for FileQuotaStore {
+    /// Nothing if the file doesn't exist yet.
+    async fn load(&self) -> io::Result<Vec<(QuotaKey, QuotaUsage)>> {
+        let path = self.path.clone();
+        let content = tokio::task::spawn_blocking(move || std::fs::read_to_string(path))
+            .await
+            .map_err(io::Error::other)?;
+        match content {
+            Ok(content) => parse_usage(&content),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(vec![]),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn save(&self, usage: Vec<(QuotaKey, QuotaUsage)>) -> io::Result<()> {
+        let mut content = String::new();
+        for (key, usage) in usage {
+            // a name can't span lines
+            if key.to_string().contains('\n') {
+                continue;
+            }
+            content.push_str(&format!(
+                "{} {} {}\n",
+                usage.bytes,
+                usage.time.as_millis(),
+                key
+            ));
+        }
+        let path = self.path.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut tmp = path.clone().into_os_string();
+            tmp.push(".tmp");
+            std::fs::write(&tmp, content)?;
+            std::fs::rename(&tmp, &path)
+        })
+        .await
+        .map_err(io::Error::other)?
+    }
+}
+
+fn parse_usage(content: &str) -> io::Result<Vec<(QuotaKey, QuotaUsage)>> {
+    let mut usage = vec![];
+    for (i, line) in content.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {}: invalid quota usage", i + 1),
+            )
+        };
+        let mut fields = line.splitn(4, ' ');
+        let mut next = || fields.next().ok_or_else(invalid);
+        let bytes = next()?.parse().map_err(|_| invalid())?;
+        let millis = next()?.parse().map_err(|_| invalid())?;
+        let key = match (next()?, next()?) {
+            ("user", user) => QuotaKey::User(user.to_owned()),
+            ("ip", ip) => QuotaKey::Ip(ip.parse().map_err(|_| invalid())?),
+            _ => return Err(invalid()),
+        };
+        let time = Duration::from_millis(millis);
+        usage.push((key, QuotaUsage { bytes, time }));
+    }
+    Ok(usage)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        FileQuotaStore, QuotaKey, QuotaLimits, QuotaManager, QuotaStore, QuotaUsage,
+        CHARGE_INTERVAL,
+    };
+    use crate::server::{
+        CloseReason, MemoryStateStore, SocksServerError, StateStore, TransferOptions,
+    };
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn byte_quota_and_persistence() {
+        let alice = QuotaKey::User("alice smith".to_owned());
+        let ip = QuotaKey::Ip("192.0.2.1".parse().unwrap());
+        let quotas = QuotaManager::new(QuotaLimits::default());
+        quotas.set_limits(
+            alice.clone(),
+            QuotaLimits {
+                bytes: Some(10),
+                time: None,
+            },
+        );
+
+        let session = quotas.begin([alice.clone(), ip.clone()]).unwrap();
+        let (mut client, inbound) = duplex(64);
+        let (outbound, mut target) = duplex(64);
+        let relay = tokio::spawn(async move {
+            session
+                .relay(inbound, outbound, &TransferOptions::new())
+                .await
+        });
+        client.write_all(b"0123456789abcdef").await.unwrap();
+        let mut buf = [0u8; 16];
+        let _ = target.read(&mut buf).await.unwrap();
+        assert_eq!(relay.await.unwrap(), CloseReason::QuotaExceeded);
+        assert!(quotas.usage(&alice).bytes >= 10);
+        assert!(quotas.is_exceeded(&alice));
+        assert!(!quotas.is_exceeded(&ip));
+        assert!(matches!(
+            quotas.begin([ip.clone(), alice.clone()]),
+            Err(SocksServerError::QuotaExceeded)
+        ));
+
+        let path = std::env::temp_dir().join(format!("fast-socks5-quota-{}", std::process::id()));
+        let store = FileQuotaStore::new(&path);
+        quotas.save(&store).await.unwrap();
+        let restored = QuotaManager::new(QuotaLimits::default());
+        restored.load(&store).await.unwrap();
+        assert_eq!(restored.usage(&alice), quotas.usage(&alice));
+        assert_eq!(restored.usage(&ip), quotas.usage(&ip));
+        std::fs::remove_file(&path).unwrap();
+        assert!(store.load().await.unwrap().is_empty());
+
+        quotas.reset_all();
+        assert_eq!(quotas.usage(&alice), QuotaUsage::default());
+        assert!(quotas.begin([alice]).is_ok());
+    }
+
+    #[tokio::test]
+    async fn shared_usage() {
+        let store = MemoryStateStore::new();
+        let key = QuotaKey::User("alice".to_owned());
+        let (a, b) = (
+            QuotaManager::new(QuotaLimits::default()),
+            QuotaManager::new(QuotaLimits::default()),
+        );
+        a.counter(key.clone()).bytes.fetch_add(5, Ordering::Relaxed);
+        a.sync(&store).await.unwrap();
+        b.counter(key.clone()).bytes.fetch_add(3, Ordering::Relaxed);
+        b.sync(&store).await.unwrap();
+        assert_eq!(b.usage(&key).bytes, 8);
+        a.counter(key.clone()).bytes.fetch_add(1, Ordering::Relaxed);
+        a.sync(&store).await.unwrap();
+        assert_eq!(a.usage(&key).bytes, 9);
+
+        // reset everywhere
+        store.remove("quota:user alice:bytes").await.unwrap();
+        a.reset(&key);
+        a.sync(&store).await.unwrap();
+        assert_eq!(a.usage(&key).bytes, 0);
+        b.sync(&store).await.unwrap();
+        assert_eq!(b.usage(&key).bytes, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn time_quota() {
+        let key = QuotaKey::Ip("192.0.2.1".parse().unwrap());
+        let quotas = QuotaManager::new(QuotaLimits {
+            bytes: None,
+            time: Some(Duration::from_secs(60)),
+        });
+        quotas.restore([(
+            key.clone(),
+            QuotaUsage {
+                bytes: 0,
+                time: Duration::from_secs(50),
+            },
+        )]);
+        let session = quotas.begin([key.clone()]).unwrap();
+        let (_client, inbound) = duplex(64);
+        let (outbound, _target) = duplex(64);
+        let reason = session
+            .relay(inbound, outbound, &TransferOptions::new())
+            .await;
+        assert_eq!(reason, CloseReason::QuotaExceeded);
+        assert_eq!(quotas.usage(&key).time, Duration::from_secs(60));
+        assert!(quotas.begin([key]).is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn concurrent_sessions_share_time_quota() {
+        let key = QuotaKey::Ip("192.0.2.1".parse().unwrap());
+        let quotas = QuotaManager::new(QuotaLimits {
+            bytes: None,
+            time: Some(Duration::from_secs(10)),
+        });
+        let start = tokio::time::Instant::now();
+        let relays: Vec<_> = (0..4)
+            .map(|_| {
+                let session = quotas.begin([key.clone()]).unwrap();
+                tokio::spawn(async move {
+                    let (_client, inbound) = duplex(64);
+                    let (outbound, _target) = duplex(64);
+                    session
+                        .relay(inbound, outbound, &TransferOptions::new())
+                        .await
+                })
+            })
+            .collect();
+        for relay in relays {
+            assert_eq!(relay.await.unwrap(), CloseReason::QuotaExceeded);
+        }
+        // 4 sessions use up 10 seconds in about 2.5, overshooting by a charge each at most
+        assert!(start.elapsed() <= Duration::from_secs(4));
+        assert!(quotas.usage(&key).time <= Duration::from_secs(10) + 4 * CHARGE_INTERVAL);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_keys_pruned() {
+        let quotas = QuotaManager::new(QuotaLimits::default());
+        let used = QuotaKey::User("alice".to_owned());
+        let unused = QuotaKey::User("bob".to_owned());
+        quotas.restore([(
+            used.clone(),
+            QuotaUsage {
+                bytes: 1,
+                time: Duration::ZERO,
+            },
+        )]);
+        // kept while a session runs
+        let session = quotas.begin([unused]).unwrap();
+        assert_eq!(quotas.snapshot().len(), 2);
+        drop(session);
+        assert_eq!(quotas.snapshot().len(), 1);
+        quotas.reset_all();
+        assert!(quotas.snapshot().is_empty());
+    }
+}
@@ -0,0 +1,176 @@
+use super::sharded::shard_count;
+use lru::LruCache;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::net::{IpAddr, Ipv6Addr};
+use std::num::NonZeroUsize;
+use std::sync::{Mutex, PoisonError};
+use std::time::Instant;
+
+// the IPs tracked by default, the least recently seen ones being forgotten beyond
+const DEFAULT_MAX_TRACKED_IPS: usize = 65536;
+
+/// Limits the rate of new connections from each client IP with a token bucket, e.g.
+/// 20 per second with bursts of 40.
+///
+/// Meant to be checked right after accepting, see [`super::Socks5Listener::set_connection_rate_limit`],
+/// before any handshake work is spent on abusive clients. It doesn't limit the bandwidth.
+///
+/// The IPv6 clients share a bucket per /64, the block usually handed to a single host.
+#[derive(Debug)]
+pub struct ConnectionRateLimiter {
+    per_second: f64,
+    burst: f64,
+    shards: Box<[Mutex<LruCache<IpAddr, Bucket>>]>,
+    hasher: RandomState,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl ConnectionRateLimiter {
+    /// Allow `per_second` new connections per second and IP on average, and up to `burst`
+    /// at once.
+    pub fn new(per_second: f64, burst: u32) -> Self {
+        let mut limiter = ConnectionRateLimiter {
+            per_second,
+            burst: burst as f64,
+            shards: Box::new([]),
+            hasher: RandomState::new(),
+        };
+        limiter.set_max_tracked_ips(DEFAULT_MAX_TRACKED_IPS);
+        limiter
+    }
+
+    /// Track up to about `max` IPs (65536 by default), the least recently seen ones being
+    /// forgotten beyond. Resets the buckets.
+    pub fn set_max_tracked_ips(&mut self, max: usize) -> &mut Self {
+        let shards = shard_count();
+        let capacity = NonZeroUsize::new(max.div_ceil(shards)).unwrap_or(NonZeroUsize::MIN);
+        self.shards = (0..shards)
+            .map(|_| Mutex::new(LruCache::new(capacity)))
+            .collect();
+        self
+    }
+
+    /// Take a token for a new connection from `ip`, returns `false` if it's over the limit.
+    pub fn check(&self, ip: IpAddr) -> bool {
+        self.check_at(ip, Instant::now())
+    }
+
+    fn check_at(&self, ip: IpAddr, now: Instant) -> bool {
+        let key = bucket_key(ip);
+        let hash = self.hasher.hash_one(key) as usize;
+        let mut buckets = self.shards[hash & (self.shards.len() - 1)]
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        // forget the least recently seen IP once its bucket is full again
+        if let Some((_, bucket)) = buckets.peek_lru() {
+            if self.tokens_at(bucket, now) >= self.burst {
+                buckets.pop_lru();
+            }
+        }
+        let bucket = buckets.get_or_insert_mut(key, || Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        if self.refill(bucket, now) < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    fn refill(&self, bucket: &mut Bucket, now: Instant) -> f64 {
+        bucket.tokens = self.tokens_at(bucket, now);
+        bucket.updated = now;
+        bucket.tokens
+    }
+
+    fn tokens_at(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * self.per_second).min(self.burst)
+    }
+
+    /// How many IPs are tracked.
+    pub fn tracked_ips(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|s| s.lock().unwrap_or_else(PoisonError::into_inner).len())
+            .sum()
+    }
+}
+
+/// The IP itself, or its /64 for IPv6.
+fn bucket_key(ip: IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V6(ip) => {
+            let prefix = u128::from(ip) & !(u128::MAX >> 64);
+            IpAddr::V6(Ipv6Addr::from(prefix))
+        }
+        ip => ip,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ConnectionRateLimiter;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn token_bucket_per_ip() {
+        let limiter = ConnectionRateLimiter::new(2.0, 3);
+        let abuser = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let other = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            assert!(limiter.check_at(abuser, start));
+        }
+        assert!(!limiter.check_at(abuser, start));
+        assert!(limiter.check_at(other, start));
+
+        // 2 per second: one token back after half a second
+        let later = start + Duration::from_millis(500);
+        assert!(limiter.check_at(abuser, later));
+        assert!(!limiter.check_at(abuser, later));
+    }
+
+    #[test]
+    fn ipv6_per_prefix() {
+        let limiter = ConnectionRateLimiter::new(1.0, 1);
+        let now = Instant::now();
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert!(limiter.check_at(ip("2001:db8::1"), now));
+        assert!(!limiter.check_at(ip("2001:db8::ffff:2"), now));
+        assert!(limiter.check_at(ip("2001:db8:0:1::1"), now));
+        assert!(limiter.check_at(ip("::ffff:192.0.2.1"), now));
+        assert!(!limiter.check_at(ip("192.0.2.1"), now));
+    }
+
+    #[test]
+    fn tracked_ips_capped() {
+        let mut limiter = ConnectionRateLimiter::new(1.0, 1);
+        limiter.set_max_tracked_ips(1);
+        let now = Instant::now();
+        for i in 0..=255 {
+            limiter.check_at(IpAddr::V4(Ipv4Addr::new(192, 0, 2, i)), now);
+        }
+        assert!(limiter.tracked_ips() <= limiter.shards.len());
+
+        // the idle IPs are forgotten once their bucket is full again
+        let limiter = ConnectionRateLimiter::new(1.0, 1);
+        for i in 0..=255 {
+            limiter.check_at(IpAddr::V4(Ipv4Addr::new(192, 0, 2, i)), now);
+        }
+        let later = now + Duration::from_secs(1);
+        for i in 0..=255 {
+            limiter.check_at(IpAddr::V4(Ipv4Addr::new(198, 51, 100, i)), later);
+        }
+        assert!(limiter.tracked_ips() < 512);
+    }
+}
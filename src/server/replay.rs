@@ -0,0 +1,306 @@
+use super::{
+    verify_credentials, NoAuthentication, PasswordAuthentication, Socks5ServerProtocol,
+    SocksServerError, StandardAuthentication, StandardAuthenticationStarted,
+};
+use crate::util::secret::Secret;
+use crate::util::target_addr::TargetAddr;
+use crate::{ReplyError, Socks5Command};
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// The authentication setup of the server a handshake is replayed against.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReplayAuth {
+    /// Only accept the no-authentication method.
+    NoAuth,
+    /// Only accept username/password, with any credentials.
+    AnyPassword,
+    /// Only accept username/password, with these credentials.
+    Password {
+        username: String,
+        password: Secret<String>,
+    },
+    /// Accept both methods, preferring username/password with any credentials.
+    PasswordOrNoAuth,
+    /// Don't negotiate anything, the recording starts with the command request.
+    SkipAuth,
+}
+
+/// A state transition of the server observed while replaying a handshake.
+#[derive(Debug)]
+pub enum ReplayStep {
+    /// The client offered `offered` methods and the server selected one of them.
+    MethodSelected { offered: Vec<u8>, selected: u8 },
+    /// The username/password sub-negotiation was read and checked.
+    PasswordChecked { username: String, accepted: bool },
+    /// The authentication is done, the server now waits for the command request.
+    Authenticated,
+    /// The command request was parsed.
+    CommandRead {
+        cmd: Socks5Command,
+        target_addr: TargetAddr,
+    },
+    /// The server replied to the command request.
+    Replied(ReplyError),
+}
+
+/// The outcome of [`replay_handshake`].
+#[derive(Debug)]
+pub struct ReplayReport {
+    /// Each transition, along with the bytes the server wrote to reach it.
+    pub steps: Vec<(ReplayStep, Vec<u8>)>,
+    /// The error the server stopped on, if any.
+    pub error: Option<SocksServerError>,
+    /// The bytes the server wrote after the last transition, usually a rejection.
+    pub trailing_output: Vec<u8>,
+    /// How many bytes of the recording weren't read by the server.
+    pub unconsumed: usize,
+}
+
+impl ReplayReport {
+    pub fn is_success(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+impl fmt::Display for ReplayReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (step, output) in &self.steps {
+            writeln!(f, "{:?}", step)?;
+            if !output.is_empty() {
+                writeln!(f, "  <- {}", hex(output))?;
+            }
+        }
+        if let Some(err) = &self.error {
+            writeln!(f, "Failed: {}", err)?;
+        }
+        if !self.trailing_output.is_empty() {
+            writeln!(f, "  <- {}", hex(&self.trailing_output))?;
+        }
+        write!(
+            f,
+            "{} byte(s) of the recording left unread",
+            self.unconsumed
+        )
+    }
+}
+
+/// An in-memory client socket, reading from a recording and capturing what the server writes.
+struct ReplayStream<'a> {
+    input: &'a [u8],
+    output: Arc<Mutex<Vec<u8>>>,
+}
+
+impl AsyncRead for ReplayStream<'_> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let n = self.input.len().min(buf.remaining());
+        buf.put_slice(&self.input[..n]);
+        self.input = &self.input[n..];
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for ReplayStream<'_> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.output.lock().unwrap().extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+struct Recorder {
+    output: Arc<Mutex<Vec<u8>>>,
+    steps: Vec<(ReplayStep, Vec<u8>)>,
+}
+
+impl Recorder {
+    fn step(&mut self, step: ReplayStep) {
+        let output = std::mem::take(&mut *self.output.lock().unwrap());
+        self.steps.push((step, output));
+    }
+}
+
+/// Replay a recorded client handshake against the server protocol, without any network.
+///
+/// Once the command request is read, the server answers it with `command_reply` instead
+/// of executing it. The report lists every state transition along with the bytes the
+/// server replied, which helps to find out why a given client gets rejected.
+pub async fn replay_handshake(
+    input: &[u8],
+    auth: ReplayAuth,
+    command_reply: ReplyError,
+) -> ReplayReport {
+    let output = Arc::new(Mutex::new(vec![]));
+    let mut stream = ReplayStream {
+        input,
+        output: output.clone(),
+    };
+    let mut recorder = Recorder {
+        output: output.clone(),
+        steps: vec![],
+    };
+    let error = replay(&mut stream, &mut recorder, auth, command_reply)
+        .await
+        .err();
+    let trailing_output = std::mem::take(&mut *output.lock().unwrap());
+    ReplayReport {
+        steps: recorder.steps,
+        error,
+        trailing_output,
+        unconsumed: stream.input.len(),
+    }
+}
+
+async fn replay(
+    stream: &mut ReplayStream<'_>,
+    recorder: &mut Recorder,
+    auth: ReplayAuth,
+    command_reply: ReplyError,
+) -> Result<(), SocksServerError> {
+    let offered = match stream.input {
+        [_version, len, methods @ ..] => methods.iter().take(*len as usize).copied().collect(),
+        _ => vec![],
+    };
+    let (methods, credentials) = match auth {
+        ReplayAuth::SkipAuth => (vec![], None),
+        ReplayAuth::NoAuth => (
+            vec![StandardAuthentication::NoAuthentication(NoAuthentication)],
+            None,
+        ),
+        ReplayAuth::AnyPassword => (StandardAuthentication::allow_no_auth(false).to_vec(), None),
+        ReplayAuth::PasswordOrNoAuth => {
+            (StandardAuthentication::allow_no_auth(true).to_vec(), None)
+        }
+        ReplayAuth::Password { username, password } => (
+            vec![StandardAuthentication::PasswordAuthentication(
+                PasswordAuthentication,
+            )],
+            Some((username, password)),
+        ),
+    };
+
+    let proto = if methods.is_empty() {
+        Socks5ServerProtocol::skip_auth_this_is_not_rfc_compliant(stream)
+    } else {
+        let started = Socks5ServerProtocol::start(stream)
+            .negotiate_auth(&methods)
+            .await?;
+        match started {
+            StandardAuthenticationStarted::NoAuthentication(auth) => {
+                recorder.step(ReplayStep::MethodSelected {
+                    offered,
+                    selected: crate::consts::SOCKS5_AUTH_METHOD_NONE,
+                });
+                Socks5ServerProtocol::finish_auth(auth)
+            }
+            StandardAuthenticationStarted::PasswordAuthentication(auth) => {
+                recorder.step(ReplayStep::MethodSelected {
+                    offered,
+                    selected: crate::consts::SOCKS5_AUTH_METHOD_PASSWORD,
+                });
+                let (username, password, auth) = auth.read_username_password().await?;
+                let accepted = match &credentials {
+                    Some((u, p)) => verify_credentials(&username, &password, u, p.expose()),
+                    None => true,
+                };
+                if !accepted {
+                    auth.reject().await?;
+                    recorder.step(ReplayStep::PasswordChecked { username, accepted });
+                    return Err(SocksServerError::AuthenticationRejected);
+                }
+                let auth = auth.accept().await?;
+                recorder.step(ReplayStep::PasswordChecked { username, accepted });
+                Socks5ServerProtocol::finish_auth(auth)
+            }
+        }
+    };
+    recorder.step(ReplayStep::Authenticated);
+
+    let (proto, cmd, target_addr) = proto.read_command().await?;
+    recorder.step(ReplayStep::CommandRead { cmd, target_addr });
+
+    match command_reply {
+        ReplyError::Succeeded => {
+            proto
+                .reply_success(SocketAddr::from(([0, 0, 0, 0], 0)))
+                .await?;
+        }
+        reply => proto.reply_error(&reply).await?,
+    }
+    recorder.step(ReplayStep::Replied(command_reply));
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{replay_handshake, ReplayAuth, ReplayStep};
+    use crate::server::SocksServerError;
+    use crate::ReplyError;
+
+    #[tokio::test]
+    async fn replays_password_handshake() {
+        let mut input = vec![5, 2, 0, 2];
+        input.extend_from_slice(&[1, 5, b'a', b'l', b'i', b'c', b'e', 3, b'p', b'w', b'd']);
+        input.extend_from_slice(&[5, 1, 0, 1, 192, 0, 2, 1, 0, 80]);
+
+        let report = replay_handshake(&input, ReplayAuth::AnyPassword, ReplyError::Succeeded).await;
+        assert!(report.is_success(), "{}", report);
+        assert_eq!(report.steps.len(), 5);
+        assert_eq!(report.steps[0].1, vec![5, 2]);
+        assert!(matches!(
+            &report.steps[1],
+            (ReplayStep::PasswordChecked { username, accepted: true }, out)
+                if username == "alice" && out == &vec![1, 0]
+        ));
+        assert_eq!(report.steps[4].1, vec![5, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(report.unconsumed, 0);
+
+        let auth = ReplayAuth::Password {
+            username: "alice".to_owned(),
+            password: "pwd".to_owned().into(),
+        };
+        assert!(!format!("{:?}", auth).contains("pwd"));
+        let report = replay_handshake(&input, auth, ReplyError::Succeeded).await;
+        assert!(report.is_success(), "{}", report);
+    }
+
+    #[tokio::test]
+    async fn reports_rejection() {
+        let report =
+            replay_handshake(&[5, 1, 0], ReplayAuth::AnyPassword, ReplyError::Succeeded).await;
+        assert!(report.steps.is_empty());
+        assert!(matches!(
+            report.error,
+            Some(SocksServerError::AuthMethodUnacceptable(_))
+        ));
+        assert_eq!(report.trailing_output, vec![5, 0xff]);
+    }
+}
@@ -0,0 +1,115 @@
+use super::{SessionId, StaticHosts};
+use crate::util::target_addr::{AddrError, ResolutionPreference, TargetAddr};
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Where the addresses of a domain came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResolverKind {
+    /// An entry of the [`StaticHosts`].
+    StaticHosts,
+    /// The system resolver.
+    System,
+}
+
+/// The resolution of the domain of a command, as reported to the hook set with
+/// `set_resolution_hook`, e.g. to audit DNS leaks.
+#[derive(Debug, Clone)]
+pub struct ResolutionRecord {
+    /// See `SessionId::current`.
+    pub session: Option<SessionId>,
+    pub hostname: String,
+    /// The addresses in the order they are tried, empty if the resolution failed.
+    pub addrs: Vec<SocketAddr>,
+    pub resolver: ResolverKind,
+    pub latency: Duration,
+    pub error: Option<String>,
+}
+
+/// Called after each resolution of a domain, so it should be quick.
+#[derive(Clone)]
+pub(crate) struct ResolutionHook(pub(crate) Arc<dyn Fn(&ResolutionRecord) + Send + Sync>);
+
+impl fmt::Debug for ResolutionHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ResolutionHook")
+    }
+}
+
+/// Resolve `addr` with `static_hosts` first, reporting the domains resolved to `hook`.
+pub(crate) async fn resolve_reported(
+    addr: &TargetAddr,
+    preference: ResolutionPreference,
+    static_hosts: Option<&StaticHosts>,
+    hook: Option<&ResolutionHook>,
+) -> Result<Vec<SocketAddr>, AddrError> {
+    let TargetAddr::Domain(domain, _) = addr else {
+        return addr.resolve_all(preference).await;
+    };
+    let start = Instant::now();
+    let (resolver, res) = match static_hosts {
+        Some(hosts) if hosts.lookup(domain).is_some() => (
+            ResolverKind::StaticHosts,
+            hosts.resolve_all(addr, preference).await,
+        ),
+        _ => (ResolverKind::System, addr.resolve_all(preference).await),
+    };
+    if let Some(hook) = hook {
+        let (addrs, error) = match &res {
+            Ok(addrs) => (addrs.clone(), None),
+            Err(err) => (vec![], Some(err.to_string())),
+        };
+        (hook.0)(&ResolutionRecord {
+            session: SessionId::current(),
+            hostname: domain.clone(),
+            addrs,
+            resolver,
+            latency: start.elapsed(),
+            error,
+        });
+    }
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use super::{resolve_reported, ResolutionHook, ResolutionRecord, ResolverKind};
+    use crate::server::{SessionId, StaticHosts};
+    use crate::util::target_addr::{ResolutionPreference, TargetAddr};
+    use std::sync::{Arc, Mutex};
+
+    #[tokio::test]
+    async fn report_resolutions() {
+        let records: Arc<Mutex<Vec<ResolutionRecord>>> = Arc::default();
+        let hook = ResolutionHook(Arc::new({
+            let records = records.clone();
+            move |record: &ResolutionRecord| records.lock().unwrap().push(record.clone())
+        }));
+        let mut hosts = StaticHosts::new();
+        hosts.insert("db.internal", ["10.0.0.1".parse().unwrap()]);
+
+        let preference = ResolutionPreference::Ipv6Only;
+        let session = SessionId::next();
+        for addr in [
+            TargetAddr::Ip("10.0.0.2:80".parse().unwrap()),
+            TargetAddr::Domain("db.internal".to_owned(), 80),
+            TargetAddr::Domain("localhost".to_owned(), 80),
+        ] {
+            let resolve = resolve_reported(&addr, preference, Some(&hosts), Some(&hook));
+            let _ = session.scope(resolve).await;
+        }
+
+        // IPs aren't reported
+        let records = records.lock().unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].hostname, "db.internal");
+        assert_eq!(records[0].session, Some(session));
+        assert_eq!(records[0].resolver, ResolverKind::StaticHosts);
+        assert!(records[0].addrs.is_empty());
+        assert!(records[0].error.is_some());
+        assert_eq!(records[1].hostname, "localhost");
+        assert_eq!(records[1].resolver, ResolverKind::System);
+    }
+}
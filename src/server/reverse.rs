@@ -0,0 +1,224 @@
+use super::SessionId;
+use socket2::{SockRef, TcpKeepalive};
+use std::future::Future;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::Semaphore;
+
+/// Serves SOCKS clients over connections dialed out to a rendezvous point, for servers
+/// which can't accept connections themselves, e.g. agents behind a NAT.
+///
+/// A few idle connections are kept open to the rendezvous, which pairs each of them with
+/// a client and forwards its bytes: an idle connection becomes a session when its first
+/// byte arrives, and a new idle connection is dialed to replace it.
+#[derive(Debug, Clone)]
+pub struct ReverseListener {
+    rendezvous: String,
+    idle: usize,
+    retry_delay: Duration,
+    max_retry_delay: Duration,
+    idle_timeout: Option<Duration>,
+    tcp_keepalive: Option<Duration>,
+}
+
+impl ReverseListener {
+    pub fn new<A: Into<String>>(rendezvous: A) -> Self {
+        ReverseListener {
+            rendezvous: rendezvous.into(),
+            idle: 4,
+            retry_delay: Duration::from_secs(5),
+            max_retry_delay: Duration::from_secs(300),
+            idle_timeout: None,
+            tcp_keepalive: Some(Duration::from_secs(60)),
+        }
+    }
+
+    /// How many idle connections are kept open to the rendezvous, 4 by default.
+    pub fn set_idle_connections(&mut self, n: usize) -> &mut Self {
+        self.idle = n.max(1);
+        self
+    }
+
+    /// Wait before dialing again after failing to reach the rendezvous, 5s by default,
+    /// doubling on each failure in a row up to the max retry delay.
+    pub fn set_retry_delay(&mut self, delay: Duration) -> &mut Self {
+        self.retry_delay = delay;
+        self
+    }
+
+    /// The longest wait between two attempts to reach the rendezvous, 5 minutes by default.
+    pub fn set_max_retry_delay(&mut self, delay: Duration) -> &mut Self {
+        self.max_retry_delay = delay;
+        self
+    }
+
+    /// Replace the idle connections not paired within `timeout`, none by default, so that
+    /// the ones silently dropped on the way (e.g. by a NAT) don't linger.
+    pub fn set_idle_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Enable TCP keepalive on the connections to the rendezvous dialed by
+    /// [`serve`](Self::serve), probing after `idle` without traffic, 60s by default.
+    /// `None` disables it.
+    pub fn set_tcp_keepalive(&mut self, idle: Option<Duration>) -> &mut Self {
+        self.tcp_keepalive = idle;
+        self
+    }
+
+    /// Serve clients forever, handling each session in its own task under a new
+    /// [`SessionId`], like `Socks5Listener::serve`.
+    pub async fn serve<F, R>(&self, handler: F)
+    where
+        F: Fn(TcpStream) -> R + Send + Sync + 'static,
+        R: Future<Output = ()> + Send + 'static,
+    {
+        let connect = || async {
+            let stream = TcpStream::connect(&self.rendezvous).await?;
+            if let Some(idle) = self.tcp_keepalive {
+                SockRef::from(&stream).set_tcp_keepalive(&TcpKeepalive::new().with_time(idle))?;
+            }
+            Ok(stream)
+        };
+        let paired = |stream: TcpStream| async move {
+            let mut first = [0u8; 1];
+            matches!(stream.peek(&mut first).await, Ok(n) if n > 0).then_some(stream)
+        };
+        self.serve_paired(connect, paired, handler).await
+    }
+
+    /// Like [`serve`](Self::serve), over the connections opened by `connect`, e.g. TLS
+    /// ones to the rendezvous. The first bytes of a session are buffered in the
+    /// `BufReader` handed to `handler`.
+    pub async fn serve_with<C, CF, S, F, R>(&self, connect: C, handler: F)
+    where
+        C: Fn() -> CF,
+        CF: Future<Output = io::Result<S>>,
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+        F: Fn(BufReader<S>) -> R + Send + Sync + 'static,
+        R: Future<Output = ()> + Send + 'static,
+    {
+        let paired = |stream: S| async move {
+            let mut stream = BufReader::new(stream);
+            matches!(stream.fill_buf().await, Ok(buf) if !buf.is_empty()).then_some(stream)
+        };
+        self.serve_paired(connect, paired, handler).await
+    }
+
+    async fn serve_paired<C, CF, S, P, PF, T, F, R>(&self, connect: C, paired: P, handler: F)
+    where
+        C: Fn() -> CF,
+        CF: Future<Output = io::Result<S>>,
+        P: Fn(S) -> PF,
+        PF: Future<Output = Option<T>> + Send + 'static,
+        T: Send + 'static,
+        F: Fn(T) -> R + Send + Sync + 'static,
+        R: Future<Output = ()> + Send + 'static,
+    {
+        let handler = Arc::new(handler);
+        let idle = Arc::new(Semaphore::new(self.idle));
+        let mut retry_delay = self.retry_delay;
+        loop {
+            let permit = idle
+                .clone()
+                .acquire_owned()
+                .await
+                .expect("the semaphore is never closed");
+            let stream = match connect().await {
+                Ok(stream) => stream,
+                Err(err) => {
+                    warn!(
+                        "can't reach the rendezvous {}, retrying in {:?}: {}",
+                        self.rendezvous, retry_delay, err
+                    );
+                    tokio::time::sleep(retry_delay).await;
+                    retry_delay = (retry_delay * 2).min(self.max_retry_delay);
+                    continue;
+                }
+            };
+            retry_delay = self.retry_delay;
+            debug!("idle connection to the rendezvous {}", self.rendezvous);
+            let handler = handler.clone();
+            let paired = paired(stream);
+            let idle_timeout = self.idle_timeout;
+            tokio::spawn(async move {
+                let paired = match idle_timeout {
+                    Some(timeout) => tokio::time::timeout(timeout, paired).await.ok().flatten(),
+                    None => paired.await,
+                };
+                drop(permit);
+                let Some(stream) = paired else {
+                    debug!("idle connection closed by the rendezvous or timed out");
+                    return;
+                };
+                let id = SessionId::next();
+                debug!("session {} paired by the rendezvous", id);
+                id.scope(handler(stream)).await;
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ReverseListener;
+    use crate::server::Socks5ServerProtocol;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[tokio::test]
+    async fn serves_paired_connections() {
+        let rendezvous = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut agent = ReverseListener::new(rendezvous.local_addr().unwrap().to_string());
+        agent.set_idle_connections(1);
+        tokio::spawn(async move {
+            agent
+                .serve(|stream| async move {
+                    let _ = Socks5ServerProtocol::accept_no_auth(stream).await;
+                })
+                .await
+        });
+
+        for _ in 0..2 {
+            let (mut client, _) = rendezvous.accept().await.unwrap();
+            client.write_all(&[5, 1, 0]).await.unwrap();
+            let mut reply = [0u8; 2];
+            client.read_exact(&mut reply).await.unwrap();
+            assert_eq!(reply, [5, 0]);
+        }
+    }
+
+    #[tokio::test]
+    async fn idle_connections_replaced() {
+        let rendezvous = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = rendezvous.local_addr().unwrap();
+        let mut agent = ReverseListener::new(addr.to_string());
+        agent
+            .set_idle_connections(1)
+            .set_idle_timeout(Duration::from_millis(50));
+        tokio::spawn(async move {
+            agent
+                .serve_with(
+                    || TcpStream::connect(addr),
+                    |stream| async move {
+                        let _ = Socks5ServerProtocol::accept_no_auth(stream).await;
+                    },
+                )
+                .await
+        });
+
+        // the first idle connection times out and is replaced
+        let (mut stale, _) = rendezvous.accept().await.unwrap();
+        let (mut client, _) = rendezvous.accept().await.unwrap();
+        assert_eq!(stale.read(&mut [0u8; 1]).await.unwrap(), 0);
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        let mut reply = [0u8; 2];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [5, 0]);
+    }
+}
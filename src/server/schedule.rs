@@ -0,0 +1,275 @@
+use super::{AclAction, AclRequest, AclRule, CloseReason, SessionCloser};
+use crate::ConfigError;
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DAY: u64 = 24 * 3600;
+const WEEK: u64 = 7 * DAY;
+const DAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+/// Opening hours on days of the week, e.g. `mon-fri 09:00-18:00; sat 10:00-12:00`.
+///
+/// Each window is `<days> <HH:MM>-<HH:MM>`, the days being `*`, one day, a range
+/// `mon-fri` or a list `mon,wed`. A window ending before it starts runs past midnight,
+/// e.g. `sun 22:00-02:00` for a maintenance window. The times are UTC, shifted by
+/// [`Schedule::set_utc_offset`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Schedule {
+    // start and end, in seconds since monday 00:00
+    windows: Vec<(u64, u64)>,
+    utc_offset: i64,
+}
+
+impl Schedule {
+    pub fn parse(schedule: &str) -> Result<Self, ConfigError> {
+        let mut err = ConfigError::new();
+        let mut windows = vec![];
+        for window in schedule.split(';').map(str::trim).filter(|w| !w.is_empty()) {
+            match parse_window(window) {
+                Some(days) => windows.extend(days),
+                None => {
+                    err.check(false, format!("invalid time window `{}`", window));
+                }
+            }
+        }
+        err.into_result()?;
+        Ok(Schedule {
+            windows,
+            utc_offset: 0,
+        })
+    }
+
+    /// The offset of the local time of the schedule from UTC, e.g. `+02:00` as 120
+    /// minutes.
+    pub fn set_utc_offset(&mut self, minutes: i32) -> &mut Self {
+        self.utc_offset = i64::from(minutes) * 60;
+        self
+    }
+
+    pub fn is_open_at(&self, time: SystemTime) -> bool {
+        let now = self.week_seconds(time);
+        self.window_end(now).is_some()
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.is_open_at(SystemTime::now())
+    }
+
+    /// How long until the schedule closes, `None` if it is closed at `time` or never
+    /// closes.
+    pub fn closes_in(&self, time: SystemTime) -> Option<Duration> {
+        let now = self.week_seconds(time);
+        let mut end = self.window_end(now)?;
+        // follow the windows overlapping or touching each other
+        while let Some(next) = self.window_end(end) {
+            if end - now >= WEEK {
+                return None;
+            }
+            end = next;
+        }
+        Some(Duration::from_secs(end - now))
+    }
+
+    fn week_seconds(&self, time: SystemTime) -> u64 {
+        let unix = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        // the epoch was a thursday
+        (unix as i64 + self.utc_offset + 3 * DAY as i64).rem_euclid(WEEK as i64) as u64
+    }
+
+    /// The furthest end of the windows open at `at`, seconds since a monday, past the
+    /// end of the week for the windows wrapping over it.
+    fn window_end(&self, at: u64) -> Option<u64> {
+        let at_in_week = at % WEEK;
+        let base = at - at_in_week;
+        self.windows
+            .iter()
+            .flat_map(|&(start, end)| {
+                [at_in_week, at_in_week + WEEK]
+                    .into_iter()
+                    .filter(move |t| start <= *t && *t < end)
+                    .map(move |t| base + end - (t - at_in_week))
+            })
+            .max()
+    }
+}
+
+/// `<days> <HH:MM>-<HH:MM>` as one window per day.
+fn parse_window(window: &str) -> Option<Vec<(u64, u64)>> {
+    let (days, hours) = window.split_once(char::is_whitespace)?;
+    let (start, end) = hours.trim().split_once('-')?;
+    let (start, end) = (parse_time(start)?, parse_time(end)?);
+    let end = if end <= start { end + DAY } else { end };
+    let mut windows = vec![];
+    for days in days.split(',') {
+        let (first, last) = match days {
+            "*" => (0, 6),
+            _ => match days.split_once('-') {
+                Some((first, last)) => (parse_day(first)?, parse_day(last)?),
+                None => (parse_day(days)?, parse_day(days)?),
+            },
+        };
+        let mut day = first;
+        loop {
+            windows.push((day * DAY + start, day * DAY + end));
+            if day == last {
+                break;
+            }
+            day = (day + 1) % 7;
+        }
+    }
+    Some(windows)
+}
+
+fn parse_day(day: &str) -> Option<u64> {
+    let day = day.to_ascii_lowercase();
+    DAYS.iter().position(|d| *d == day).map(|d| d as u64)
+}
+
+fn parse_time(time: &str) -> Option<u64> {
+    let (hours, minutes) = time.split_once(':')?;
+    let (hours, minutes): (u64, u64) = (hours.parse().ok()?, minutes.parse().ok()?);
+    match (hours, minutes) {
+        (24, 0) => Some(DAY),
+        (0..=23, 0..=59) => Some(hours * 3600 + minutes * 60),
+        _ => None,
+    }
+}
+
+/// When the users may use the proxy, per user or for everyone: an [`AclRule`] denying
+/// the commands outside of the windows, and [`SchedulePolicy::enforce`] to end the
+/// sessions still running when a window closes.
+#[derive(Debug, Clone, Default)]
+pub struct SchedulePolicy {
+    users: HashMap<String, Schedule>,
+    default: Option<Schedule>,
+}
+
+impl SchedulePolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The schedule of the users without one of their own, and of the anonymous
+    /// clients. `None`, the default, allows them at any time.
+    pub fn set_default(&mut self, schedule: Option<Schedule>) -> &mut Self {
+        self.default = schedule;
+        self
+    }
+
+    pub fn set_user<S: Into<String>>(&mut self, username: S, schedule: Schedule) -> &mut Self {
+        self.users.insert(username.into(), schedule);
+        self
+    }
+
+    pub fn schedule(&self, username: Option<&str>) -> Option<&Schedule> {
+        username
+            .and_then(|username| self.users.get(username))
+            .or(self.default.as_ref())
+    }
+
+    pub fn is_open_at(&self, username: Option<&str>, time: SystemTime) -> bool {
+        self.schedule(username)
+            .is_none_or(|schedule| schedule.is_open_at(time))
+    }
+
+    /// Close the session of `username` with `CloseReason::Policy` once its schedule
+    /// closes, or right away if it is closed. The task ends with the session.
+    pub fn enforce(&self, username: Option<&str>, closer: SessionCloser) {
+        let Some(schedule) = self.schedule(username).cloned() else {
+            return;
+        };
+        tokio::spawn(async move {
+            loop {
+                let Some(wait) = schedule.closes_in(SystemTime::now()) else {
+                    if !schedule.is_open() {
+                        close_out_of_hours(&closer);
+                    }
+                    return;
+                };
+                tokio::select! {
+                    _ = closer.closed() => return,
+                    _ = tokio::time::sleep(wait) => {}
+                }
+            }
+        });
+    }
+}
+
+fn close_out_of_hours(closer: &SessionCloser) {
+    let reason = CloseReason::Policy("outside of the schedule".to_owned());
+    if closer.close(reason) {
+        info!("session closed by its schedule");
+    }
+}
+
+impl AclRule for SchedulePolicy {
+    fn evaluate(&self, request: &AclRequest<'_>) -> Option<AclAction> {
+        (!self.is_open_at(request.username, SystemTime::now())).then_some(AclAction::Deny)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Schedule, SchedulePolicy};
+    use crate::server::{CloseReason, SessionCloser};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    // monday 2024-01-01 00:00 UTC
+    const MONDAY: u64 = 1704067200;
+
+    fn at(day: u64, hour: u64, minute: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(MONDAY + day * 86400 + hour * 3600 + minute * 60)
+    }
+
+    #[test]
+    fn windows() {
+        let office = Schedule::parse("mon-fri 09:00-18:00; sat 10:00-12:00").unwrap();
+        assert!(office.is_open_at(at(0, 9, 0)));
+        assert!(!office.is_open_at(at(0, 18, 0)));
+        assert!(office.is_open_at(at(5, 11, 0)));
+        assert!(!office.is_open_at(at(6, 11, 0)));
+        assert_eq!(
+            office.closes_in(at(4, 17, 30)),
+            Some(Duration::from_secs(1800))
+        );
+        assert_eq!(office.closes_in(at(4, 19, 0)), None);
+
+        // over midnight and the end of the week
+        let mut night = Schedule::parse("sun 22:00-02:00").unwrap();
+        assert!(night.is_open_at(at(6, 23, 0)));
+        assert!(night.is_open_at(at(7, 1, 0)));
+        assert_eq!(
+            night.closes_in(at(6, 23, 0)),
+            Some(Duration::from_secs(3 * 3600))
+        );
+        night.set_utc_offset(120);
+        assert!(night.is_open_at(at(6, 21, 0)));
+
+        let always = Schedule::parse("* 00:00-24:00").unwrap();
+        assert!(always.is_open_at(at(3, 5, 0)));
+        assert_eq!(always.closes_in(at(3, 5, 0)), None);
+
+        let err = Schedule::parse("mon-fri 9-18; sat 10:00-12:00; funday 10:00-11:00");
+        assert_eq!(err.unwrap_err().issues().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn policy() {
+        let mut policy = SchedulePolicy::new();
+        policy
+            .set_default(Some(Schedule::parse("mon-fri 09:00-18:00").unwrap()))
+            .set_user("ops", Schedule::parse("* 00:00-24:00").unwrap());
+        assert!(!policy.is_open_at(None, at(5, 10, 0)));
+        assert!(policy.is_open_at(Some("ops"), at(5, 10, 0)));
+        assert!(!policy.is_open_at(Some("alice"), at(5, 10, 0)));
+
+        // a schedule closed now ends the session right away
+        let closed = SystemTime::now() + Duration::from_secs(12 * 3600);
+        let secs = closed.duration_since(UNIX_EPOCH).unwrap().as_secs() % 86400;
+        let window = format!("* {:02}:00-{:02}:00", secs / 3600, secs / 3600 + 1);
+        let closer = SessionCloser::new();
+        policy.set_user("night", Schedule::parse(&window).unwrap());
+        policy.enforce(Some("night"), closer.clone());
+        assert!(matches!(closer.closed().await, CloseReason::Policy(_)));
+    }
+}
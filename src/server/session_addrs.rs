@@ -0,0 +1,137 @@
+use crate::util::target_addr::TargetAddr;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use tokio::net::TcpStream;
+
+/// The addresses of both legs of a relayed session, for logs and NAT-table-like views
+/// of who is connected to what through which egress IP.
+///
+/// Attach them to the session with `SessionCloser::set_addrs`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionAddrs {
+    client: SocketAddr,
+    server_local: SocketAddr,
+    outbound_local: SocketAddr,
+    outbound_peer: SocketAddr,
+    target: TargetAddr,
+}
+
+impl SessionAddrs {
+    pub fn new(
+        client: SocketAddr,
+        server_local: SocketAddr,
+        outbound_local: SocketAddr,
+        outbound_peer: SocketAddr,
+        target: TargetAddr,
+    ) -> Self {
+        SessionAddrs {
+            client,
+            server_local,
+            outbound_local,
+            outbound_peer,
+            target,
+        }
+    }
+
+    /// The addresses of the `client` connection and of the `outbound` one, opened to
+    /// `target`.
+    pub fn from_streams(
+        client: &TcpStream,
+        outbound: &TcpStream,
+        target: &TargetAddr,
+    ) -> io::Result<Self> {
+        Ok(Self::new(
+            client.peer_addr()?,
+            client.local_addr()?,
+            outbound.local_addr()?,
+            outbound.peer_addr()?,
+            target.clone(),
+        ))
+    }
+
+    /// The address of the client.
+    pub fn client_addr(&self) -> SocketAddr {
+        self.client
+    }
+
+    /// The address of the server the client connected to.
+    pub fn server_local_addr(&self) -> SocketAddr {
+        self.server_local
+    }
+
+    /// The egress address of the outbound connection.
+    pub fn outbound_local_addr(&self) -> SocketAddr {
+        self.outbound_local
+    }
+
+    /// The address the outbound connection reached, the target once resolved.
+    pub fn outbound_peer_addr(&self) -> SocketAddr {
+        self.outbound_peer
+    }
+
+    /// The target requested by the client.
+    pub fn target_addr(&self) -> &TargetAddr {
+        &self.target
+    }
+}
+
+impl fmt::Display for SessionAddrs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} -> {} via {} -> {}",
+            self.client, self.server_local, self.outbound_local, self.target
+        )?;
+        if let TargetAddr::Domain(..) = self.target {
+            write!(f, " ({})", self.outbound_peer)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::SessionAddrs;
+    use crate::server::SessionCloser;
+    use crate::util::target_addr::TargetAddr;
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[tokio::test]
+    async fn both_legs() {
+        let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let remote = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(proxy.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (session, _) = proxy.accept().await.unwrap();
+        let outbound = TcpStream::connect(remote.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        let target =
+            TargetAddr::Domain("localhost".to_owned(), remote.local_addr().unwrap().port());
+        let addrs = SessionAddrs::from_streams(&session, &outbound, &target).unwrap();
+        assert_eq!(addrs.client_addr(), client.local_addr().unwrap());
+        assert_eq!(addrs.server_local_addr(), proxy.local_addr().unwrap());
+        assert_eq!(addrs.outbound_local_addr(), outbound.local_addr().unwrap());
+        assert_eq!(addrs.outbound_peer_addr(), remote.local_addr().unwrap());
+        assert_eq!(addrs.target_addr(), &target);
+        assert_eq!(
+            addrs.to_string(),
+            format!(
+                "{} -> {} via {} -> {} ({})",
+                client.local_addr().unwrap(),
+                proxy.local_addr().unwrap(),
+                outbound.local_addr().unwrap(),
+                target,
+                remote.local_addr().unwrap()
+            )
+        );
+
+        let closer = SessionCloser::new();
+        assert_eq!(closer.addrs(), None);
+        closer.set_addrs(addrs.clone());
+        assert_eq!(closer.clone().addrs(), Some(addrs));
+    }
+}
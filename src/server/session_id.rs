@@ -0,0 +1,116 @@
+use log::{Log, Metadata, Record};
+use std::fmt;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+tokio::task_local! {
+    static SESSION_ID: SessionId;
+}
+
+/// Identifies a client session from its accept to its end, for correlating the logs,
+/// errors and metrics of a session with a client complaint.
+///
+/// Ids are unique within a process and very unlikely to repeat across restarts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SessionId(u64);
+
+impl SessionId {
+    /// A new id, for a session just accepted.
+    pub fn next() -> Self {
+        static NEXT: OnceLock<AtomicU64> = OnceLock::new();
+        let next = NEXT.get_or_init(|| {
+            // start from the clock so that ids of successive runs don't overlap
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            AtomicU64::new(now.as_micros() as u64)
+        });
+        SessionId(next.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+
+    /// The id of the session running this task, if any, see [`SessionId::scope`].
+    pub fn current() -> Option<Self> {
+        SESSION_ID.try_with(|id| *id).ok()
+    }
+
+    /// Run a session future under this id, making it available to [`SessionId::current`]
+    /// and to the [`SessionLogger`].
+    pub async fn scope<F: Future>(self, session: F) -> F::Output {
+        SESSION_ID.scope(self, session).await
+    }
+}
+
+impl fmt::Display for SessionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+/// A logger wrapper prefixing the records emitted within a session with its id, as
+/// `[session 0005f1c2a3b4d5e6] ...`.
+pub struct SessionLogger<L> {
+    inner: L,
+}
+
+impl<L: Log + 'static> SessionLogger<L> {
+    pub fn new(inner: L) -> Self {
+        SessionLogger { inner }
+    }
+
+    /// Install as the global logger, keeping the current max level.
+    pub fn install(self) -> Result<(), log::SetLoggerError> {
+        log::set_boxed_logger(Box::new(self))
+    }
+}
+
+impl<L: Log> Log for SessionLogger<L> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        match SessionId::current() {
+            Some(id) => self.inner.log(
+                &Record::builder()
+                    .args(format_args!("[session {}] {}", id, record.args()))
+                    .metadata(record.metadata().clone())
+                    .module_path(record.module_path())
+                    .file(record.file())
+                    .line(record.line())
+                    .build(),
+            ),
+            None => self.inner.log(record),
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::SessionId;
+
+    #[tokio::test]
+    async fn ids_are_scoped_to_sessions() {
+        let (a, b) = (SessionId::next(), SessionId::next());
+        assert_ne!(a, b);
+        assert_eq!(a.to_string().len(), 16);
+
+        assert_eq!(SessionId::current(), None);
+        let seen = a
+            .scope(async {
+                tokio::task::yield_now().await;
+                SessionId::current()
+            })
+            .await;
+        assert_eq!(seen, Some(a));
+    }
+}
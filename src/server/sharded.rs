@@ -0,0 +1,141 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+use std::sync::{PoisonError, RwLock, RwLockWriteGuard};
+
+// shards per core, so that concurrent lookups rarely land on the same lock
+const SHARDS_PER_CORE: usize = 4;
+
+/// A map split into shards with a lock each, picked by the hash of the key, for the
+/// state looked up on every accept (auth-once IPs, rate limit buckets, ACL sets).
+///
+/// A shard stays usable after a session panicked holding its lock, the maps being left
+/// consistent by every update.
+pub(crate) struct ShardedMap<K, V> {
+    shards: Box<[RwLock<HashMap<K, V>>]>,
+    hasher: RandomState,
+}
+
+impl<K: Hash + Eq, V> ShardedMap<K, V> {
+    pub(crate) fn new() -> Self {
+        ShardedMap {
+            shards: (0..shard_count()).map(|_| RwLock::default()).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    fn shard(&self, key: &K) -> &RwLock<HashMap<K, V>> {
+        let hash = self.hasher.hash_one(key) as usize;
+        &self.shards[hash & (self.shards.len() - 1)]
+    }
+
+    pub(crate) fn contains_key(&self, key: &K) -> bool {
+        self.shard(key)
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .contains_key(key)
+    }
+
+    pub(crate) fn insert(&self, key: K, value: V) -> Option<V> {
+        self.shard(&key)
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(key, value)
+    }
+
+    pub(crate) fn remove(&self, key: &K) -> Option<V> {
+        self.shard(key)
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(key)
+    }
+
+    /// The shard of `key`, locked for writing, to update several entries at once.
+    pub(crate) fn write_shard(&self, key: &K) -> RwLockWriteGuard<'_, HashMap<K, V>> {
+        self.shard(key)
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub(crate) fn clear(&self) {
+        for shard in self.shards.iter() {
+            shard
+                .write()
+                .unwrap_or_else(PoisonError::into_inner)
+                .clear();
+        }
+    }
+
+    /// The number of entries, locking the shards in turn.
+    pub(crate) fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|s| s.read().unwrap_or_else(PoisonError::into_inner).len())
+            .sum()
+    }
+}
+
+/// How many shards to split the state looked up on every accept into, a power of two.
+pub(crate) fn shard_count() -> usize {
+    let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+    (cores * SHARDS_PER_CORE).next_power_of_two()
+}
+
+impl<K: Hash + Eq, V> Default for ShardedMap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Hash + Eq, V> fmt::Debug for ShardedMap<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ShardedMap")
+            .field("shards", &self.shards.len())
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{shard_count, ShardedMap};
+    use std::sync::Arc;
+
+    #[test]
+    fn sharded_map() {
+        let map = Arc::new(ShardedMap::new());
+        assert!(shard_count().is_power_of_two());
+        let threads: Vec<_> = (0..4u32)
+            .map(|t| {
+                let map = map.clone();
+                std::thread::spawn(move || {
+                    for i in 0..1000 {
+                        map.insert(t * 1000 + i, i);
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(map.len(), 4000);
+        assert!(map.contains_key(&3999));
+        assert_eq!(map.remove(&3999), Some(999));
+        *map.write_shard(&0).get_mut(&0).unwrap() += 1;
+        assert_eq!(map.remove(&0), Some(1));
+        map.clear();
+        assert_eq!(map.len(), 0);
+
+        // a panic holding a shard doesn't lock it up
+        let poisoner = map.clone();
+        std::thread::spawn(move || {
+            let _shard = poisoner.write_shard(&7);
+            panic!("session bug");
+        })
+        .join()
+        .unwrap_err();
+        map.insert(7, 7);
+        assert!(map.contains_key(&7));
+    }
+}
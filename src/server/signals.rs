@@ -0,0 +1,47 @@
+use super::Socks5Listener;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::signal::unix::{signal, SignalKind};
+
+/// Serve `listener` like [`Socks5Listener::serve`] until SIGTERM or SIGINT, calling
+/// `reload` on each SIGHUP.
+///
+/// On shutdown, the listener stops accepting clients and the running sessions get up to
+/// `grace` to finish, after which they are aborted and this returns. `reload` is where
+/// configuration or credentials are read again, the sessions are served meanwhile.
+pub async fn run_with_signals<F, R, L, LR>(
+    listener: &Socks5Listener,
+    handler: F,
+    mut reload: L,
+    grace: Duration,
+) -> io::Result<()>
+where
+    F: Fn(TcpStream, SocketAddr) -> R,
+    R: Future<Output = ()> + Send + 'static,
+    L: FnMut() -> LR,
+    LR: Future<Output = ()>,
+{
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+    let mut sighup = signal(SignalKind::hangup())?;
+
+    let serve = listener.serve(handler);
+    tokio::pin!(serve);
+    loop {
+        tokio::select! {
+            _ = &mut serve => unreachable!("the accept loop never ends"),
+            _ = sigterm.recv() => break,
+            _ = sigint.recv() => break,
+            _ = sighup.recv() => {
+                info!("SIGHUP received, reloading");
+                reload().await;
+            }
+        }
+    }
+
+    listener.shutdown(grace).await;
+    Ok(())
+}
@@ -0,0 +1,101 @@
+use super::{SniffedProtocol, SocksServerError};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+/// Looks at the first bytes of new connections, without consuming them, to turn away the
+/// clients which aren't speaking SOCKS, e.g. browsers, TLS or SSH clients pointed at the
+/// wrong port, and port scanners.
+///
+/// They are reported as [`SocksServerError::NotSocks`] instead of a parse failure, and
+/// counted. Since nothing is consumed, the stream can still be handed to another server.
+#[derive(Debug)]
+pub struct HandshakeSniffer {
+    timeout: Duration,
+    rejected: AtomicU64,
+}
+
+impl Default for HandshakeSniffer {
+    fn default() -> Self {
+        HandshakeSniffer {
+            timeout: Duration::from_secs(10),
+            rejected: AtomicU64::new(0),
+        }
+    }
+}
+
+impl HandshakeSniffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How long to wait for the first bytes, 10 seconds by default.
+    pub fn set_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Check that `stream` starts like a SOCKS4 or SOCKS5 handshake.
+    ///
+    /// Returns `SocksServerError::NotSocks` with the protocol recognized otherwise, e.g.
+    /// to close the connection, or hand HTTP clients to a web server.
+    pub async fn check(&self, stream: &TcpStream) -> Result<(), SocksServerError> {
+        let mut prefix = [0u8; 8];
+        let n = match tokio::time::timeout(self.timeout, stream.peek(&mut prefix)).await {
+            Err(_) => return Err(SocksServerError::HandshakeTimeout(self.timeout)),
+            Ok(res) => res.map_err(|source| SocksServerError::Io {
+                source,
+                context: "sniffing the handshake",
+            })?,
+        };
+        match prefix[..n] {
+            [] => Err(SocksServerError::EOF),
+            [4, ..] | [5, ..] => Ok(()),
+            _ => {
+                let protocol = SniffedProtocol::sniff_handshake(&prefix[..n]);
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+                debug!("not a SOCKS client: {:?}", protocol);
+                Err(SocksServerError::NotSocks(protocol))
+            }
+        }
+    }
+
+    /// How many connections were turned away.
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::HandshakeSniffer;
+    use crate::server::{SniffedProtocol, SocksServerError};
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[tokio::test]
+    async fn foreign_protocols() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let sniffer = HandshakeSniffer::new();
+        for (greeting, expected) in [
+            (&b"\x05\x01\x00"[..], None),
+            (b"\x16\x03\x01\x02\x00", Some(Some(SniffedProtocol::Tls))),
+            (b"GET / HTTP/1.1\r\n", Some(Some(SniffedProtocol::Http))),
+            (b"SSH-2.0-OpenSSH_9.6\r\n", Some(Some(SniffedProtocol::Ssh))),
+            (b"\x00\x00", Some(None)),
+        ] {
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            client.write_all(greeting).await.unwrap();
+            let (server, _) = listener.accept().await.unwrap();
+            match (sniffer.check(&server).await, expected) {
+                (Ok(()), None) => {}
+                (Err(SocksServerError::NotSocks(protocol)), Some(expected)) => {
+                    assert_eq!(protocol, expected)
+                }
+                (res, _) => panic!("{:?} sniffed as {:?}", greeting, res),
+            }
+        }
+        assert_eq!(sniffer.rejected(), 4);
+    }
+}
@@ -0,0 +1,148 @@
+use super::sharded::ShardedMap;
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// State shared by the server instances behind a load balancer: flags with an optional
+/// expiry and counters, by key. Used by the auth-once acceptor, the [`BanList`] and the
+/// quotas, see `AuthOnceAcceptor::set_state_store` and `QuotaManager::sync`.
+///
+/// [`MemoryStateStore`] keeps it in the process, `RedisStateStore` (with the `redis`
+/// feature) in a Redis server.
+#[async_trait::async_trait]
+pub trait StateStore: fmt::Debug + Send + Sync {
+    /// Whether `key` is set and not expired.
+    async fn contains(&self, key: &str) -> io::Result<bool>;
+
+    /// Set `key`, expiring after `ttl` if any.
+    async fn insert(&self, key: &str, ttl: Option<Duration>) -> io::Result<()>;
+
+    async fn remove(&self, key: &str) -> io::Result<()>;
+
+    /// Add `delta` to the counter `key`, created at zero, returning its new value.
+    async fn increment(&self, key: &str, delta: u64) -> io::Result<u64>;
+}
+
+/// A [`StateStore`] in the memory of the process, for a single instance.
+#[derive(Debug, Default)]
+pub struct MemoryStateStore {
+    entries: ShardedMap<String, (u64, Option<Instant>)>,
+}
+
+impl MemoryStateStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait::async_trait]
+impl StateStore for MemoryStateStore {
+    async fn contains(&self, key: &str) -> io::Result<bool> {
+        let key = key.to_owned();
+        let mut shard = self.entries.write_shard(&key);
+        match shard.get(&key) {
+            Some((_, Some(expires))) if *expires <= Instant::now() => {
+                shard.remove(&key);
+                Ok(false)
+            }
+            entry => Ok(entry.is_some()),
+        }
+    }
+
+    async fn insert(&self, key: &str, ttl: Option<Duration>) -> io::Result<()> {
+        let expires = ttl.map(|ttl| Instant::now() + ttl);
+        self.entries.insert(key.to_owned(), (1, expires));
+        Ok(())
+    }
+
+    async fn remove(&self, key: &str) -> io::Result<()> {
+        self.entries.remove(&key.to_owned());
+        Ok(())
+    }
+
+    async fn increment(&self, key: &str, delta: u64) -> io::Result<u64> {
+        let key = key.to_owned();
+        let mut shard = self.entries.write_shard(&key);
+        let (value, _) = shard.entry(key).or_insert((0, None));
+        *value += delta;
+        Ok(*value)
+    }
+}
+
+/// Client IPs banned for a while, e.g. after repeated authentication failures or an
+/// early close flagged by an `EarlyCloseDetector`, shared through a [`StateStore`].
+#[derive(Debug, Clone)]
+pub struct BanList {
+    store: Arc<dyn StateStore>,
+}
+
+impl Default for BanList {
+    fn default() -> Self {
+        BanList::new(Arc::new(MemoryStateStore::new()))
+    }
+}
+
+impl BanList {
+    pub fn new(store: Arc<dyn StateStore>) -> Self {
+        BanList { store }
+    }
+
+    pub async fn ban(&self, ip: IpAddr, duration: Duration) -> io::Result<()> {
+        info!("{} banned for {:?}", ip, duration);
+        self.store.insert(&ban_key(ip), Some(duration)).await
+    }
+
+    pub async fn unban(&self, ip: IpAddr) -> io::Result<()> {
+        self.store.remove(&ban_key(ip)).await
+    }
+
+    /// Whether `ip` is banned, `false` if the store can't tell, so that an unreachable
+    /// store doesn't lock every client out.
+    pub async fn is_banned(&self, ip: IpAddr) -> bool {
+        match self.store.contains(&ban_key(ip)).await {
+            Ok(banned) => banned,
+            Err(err) => {
+                warn!("can't check the ban of {}: {}", ip, err);
+                false
+            }
+        }
+    }
+}
+
+fn ban_key(ip: IpAddr) -> String {
+    format!("ban:{}", ip.to_canonical())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{BanList, MemoryStateStore, StateStore};
+    use std::time::Duration;
+
+    #[tokio::test(start_paused = true)]
+    async fn memory_store_and_bans() {
+        let store = MemoryStateStore::new();
+        assert!(!store.contains("a").await.unwrap());
+        store
+            .insert("a", Some(Duration::from_secs(1)))
+            .await
+            .unwrap();
+        assert!(store.contains("a").await.unwrap());
+        assert_eq!(store.increment("n", 2).await.unwrap(), 2);
+        assert_eq!(store.increment("n", 3).await.unwrap(), 5);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(!store.contains("a").await.unwrap());
+        store.remove("n").await.unwrap();
+        assert_eq!(store.increment("n", 1).await.unwrap(), 1);
+
+        let bans = BanList::default();
+        let ip = "192.0.2.1".parse().unwrap();
+        bans.ban(ip, Duration::from_secs(60)).await.unwrap();
+        assert!(bans.is_banned(ip).await);
+        assert!(bans.is_banned("::ffff:192.0.2.1".parse().unwrap()).await);
+        bans.unban(ip).await.unwrap();
+        assert!(!bans.is_banned(ip).await);
+    }
+}
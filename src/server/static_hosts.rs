@@ -0,0 +1,173 @@
+use crate::util::target_addr::{AddrError, ResolutionPreference, TargetAddr};
+use crate::ConfigError;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+
+/// Static addresses of domains, consulted before DNS, as with `/etc/hosts`: for
+/// split-horizon names, or to pin services to specific IPs.
+///
+/// A name `*.example.com` matches the subdomains of `example.com`, the longest matching
+/// suffix winning, while exact names take precedence. Names are case-insensitive.
+#[derive(Debug, Clone, Default)]
+pub struct StaticHosts {
+    exact: HashMap<String, Vec<IpAddr>>,
+    wildcards: HashMap<String, Vec<IpAddr>>,
+}
+
+impl StaticHosts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parse the `/etc/hosts` format: an IP followed by its names on each line, `#`
+    /// starting a comment.
+    pub fn parse(hosts: &str) -> Result<Self, ConfigError> {
+        let mut static_hosts = StaticHosts::new();
+        let mut err = ConfigError::new();
+        for (i, line) in hosts.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut fields = line.split_whitespace();
+            let Some(ip) = fields.next() else {
+                continue;
+            };
+            let Ok(ip) = ip.parse::<IpAddr>() else {
+                err.check(false, format!("line {}: invalid IP `{}`", i + 1, ip));
+                continue;
+            };
+            let mut names = fields.peekable();
+            err.check(
+                names.peek().is_some(),
+                format!("line {}: no name for {}", i + 1, ip),
+            );
+            for name in names {
+                static_hosts.insert(name, [ip]);
+            }
+        }
+        err.into_result().map(|()| static_hosts)
+    }
+
+    /// Map `name`, or the subdomains of `example.com` for `*.example.com`, to `ips`,
+    /// adding to the IPs it already had.
+    pub fn insert<I>(&mut self, name: &str, ips: I) -> &mut Self
+    where
+        I: IntoIterator<Item = IpAddr>,
+    {
+        let name = name.trim_end_matches('.').to_lowercase();
+        let (map, name) = match name.strip_prefix("*.") {
+            Some(suffix) => (&mut self.wildcards, suffix.to_owned()),
+            None => (&mut self.exact, name),
+        };
+        map.entry(name).or_default().extend(ips);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.exact.is_empty() && self.wildcards.is_empty()
+    }
+
+    /// The static IPs of `domain`, `None` if it's left to DNS.
+    pub fn lookup(&self, domain: &str) -> Option<&[IpAddr]> {
+        let domain = domain.trim_end_matches('.').to_lowercase();
+        if let Some(ips) = self.exact.get(&domain) {
+            return Some(ips);
+        }
+        domain
+            .match_indices('.')
+            .find_map(|(i, _)| self.wildcards.get(&domain[i + 1..]))
+            .map(Vec::as_slice)
+    }
+
+    /// Like [`TargetAddr::resolve_all`], with the static IPs of the domain if any.
+    pub async fn resolve_all(
+        &self,
+        addr: &TargetAddr,
+        preference: ResolutionPreference,
+    ) -> Result<Vec<SocketAddr>, AddrError> {
+        let TargetAddr::Domain(domain, port) = addr else {
+            return addr.resolve_all(preference).await;
+        };
+        let Some(ips) = self.lookup(domain) else {
+            return addr.resolve_all(preference).await;
+        };
+        debug!("static addresses of {}: {:?}", domain, ips);
+        let addrs = ips.iter().map(|ip| SocketAddr::new(*ip, *port));
+        let addrs = preference.apply(addrs);
+        if addrs.is_empty() {
+            return Err(AddrError::NoDNSRecords);
+        }
+        Ok(addrs)
+    }
+
+    /// Like [`TargetAddr::resolve_dns_with`], with the static IPs of the domain if any.
+    pub async fn resolve(
+        &self,
+        addr: TargetAddr,
+        preference: ResolutionPreference,
+    ) -> Result<TargetAddr, AddrError> {
+        match addr {
+            TargetAddr::Ip(_) => Ok(addr),
+            TargetAddr::Domain(_, _) => Ok(TargetAddr::Ip(
+                self.resolve_all(&addr, preference).await?[0],
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::StaticHosts;
+    use crate::util::target_addr::{ResolutionPreference, TargetAddr};
+
+    #[tokio::test]
+    async fn static_hosts() {
+        let mut hosts = StaticHosts::parse(
+            "# pinned egress\n\
+             10.0.0.1 db.internal db\n\
+             10.0.0.2 *.svc.internal   # any service\n\
+             10.0.0.3 *.api.svc.internal\n\
+             ::1 db.internal\n",
+        )
+        .unwrap();
+        hosts.insert("*.Example.com.", ["192.0.2.1".parse().unwrap()]);
+
+        for (domain, expected) in [
+            ("DB.internal.", Some(vec!["10.0.0.1", "::1"])),
+            ("db", Some(vec!["10.0.0.1"])),
+            ("a.svc.internal", Some(vec!["10.0.0.2"])),
+            ("v1.api.svc.internal", Some(vec!["10.0.0.3"])),
+            ("svc.internal", None),
+            ("www.example.com", Some(vec!["192.0.2.1"])),
+            ("other.test", None),
+        ] {
+            let expected: Option<Vec<_>> =
+                expected.map(|ips| ips.iter().map(|ip| ip.parse().unwrap()).collect());
+            assert_eq!(
+                hosts.lookup(domain).map(<[_]>::to_vec),
+                expected,
+                "{}",
+                domain
+            );
+        }
+
+        let target = TargetAddr::Domain("db.internal".to_owned(), 5432);
+        let resolved = hosts
+            .resolve(target.clone(), ResolutionPreference::PreferIpv6)
+            .await
+            .unwrap();
+        assert_eq!(resolved, TargetAddr::Ip("[::1]:5432".parse().unwrap()));
+        let addrs = hosts
+            .resolve_all(&target, ResolutionPreference::Ipv4Only)
+            .await
+            .unwrap();
+        assert_eq!(addrs, ["10.0.0.1:5432".parse().unwrap()]);
+
+        let err = StaticHosts::parse("10.0.0.1\nnot-an-ip host\n").unwrap_err();
+        assert_eq!(
+            err.issues(),
+            [
+                "line 1: no name for 10.0.0.1",
+                "line 2: invalid IP `not-an-ip`"
+            ]
+        );
+    }
+}
@@ -0,0 +1,159 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// The direction of the data seen by a [`StreamTap`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TapDirection {
+    ClientToTarget,
+    TargetToClient,
+}
+
+/// What the relay does after a [`StreamTap`] saw a chunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TapAction {
+    Continue,
+    /// Stop relaying, the chunk isn't forwarded and both connections are closed.
+    ///
+    /// Pass the TCP streams by reference to close them with a [`TeardownMode`](super::TeardownMode).
+    Abort,
+}
+
+/// Sees the data relayed by [`transfer_tapped`] in both directions, e.g. for protocol
+/// analytics, bandwidth sampling or debugging captures, and may abort the session.
+///
+/// Called with each chunk as it is read, before it is forwarded, so it should be quick.
+/// Implemented by closures taking `(TapDirection, &[u8])`.
+pub trait StreamTap {
+    fn on_chunk(&self, direction: TapDirection, chunk: &[u8]) -> TapAction;
+}
+
+impl<F> StreamTap for F
+where
+    F: Fn(TapDirection, &[u8]) -> TapAction,
+{
+    fn on_chunk(&self, direction: TapDirection, chunk: &[u8]) -> TapAction {
+        self(direction, chunk)
+    }
+}
+
+/// Same as `transfer`, showing the relayed data to `tap`.
+///
+/// Returns the number of bytes sent to the target and to the client, or an error of kind
+/// `ConnectionAborted` if `tap` aborted the session.
+pub async fn transfer_tapped<I, O, T>(inbound: I, outbound: O, tap: &T) -> io::Result<(u64, u64)>
+where
+    I: AsyncRead + AsyncWrite + Unpin,
+    O: AsyncRead + AsyncWrite + Unpin,
+    T: StreamTap + ?Sized,
+{
+    let mut inbound = Tapped {
+        inner: inbound,
+        tap,
+        direction: TapDirection::ClientToTarget,
+    };
+    let mut outbound = Tapped {
+        inner: outbound,
+        tap,
+        direction: TapDirection::TargetToClient,
+    };
+    let res = tokio::io::copy_bidirectional(&mut inbound, &mut outbound).await;
+    match &res {
+        Ok(res) => info!("transfer closed ({}, {})", res.0, res.1),
+        Err(err) if err.kind() == io::ErrorKind::ConnectionAborted => {
+            info!("transfer aborted: {}", err)
+        }
+        Err(err) => error!("transfer error: {:?}", err),
+    };
+    res
+}
+
+struct Tapped<'a, S, T: ?Sized> {
+    inner: S,
+    tap: &'a T,
+    direction: TapDirection,
+}
+
+impl<S: AsyncRead + Unpin, T: StreamTap + ?Sized> AsyncRead for Tapped<'_, S, T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let chunk = &buf.filled()[before..];
+        if !chunk.is_empty() && self.tap.on_chunk(self.direction, chunk) == TapAction::Abort {
+            buf.set_filled(before);
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "aborted by the stream tap",
+            )));
+        }
+        res
+    }
+}
+
+impl<S: AsyncWrite + Unpin, T: ?Sized> AsyncWrite for Tapped<'_, S, T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{transfer_tapped, TapAction, TapDirection};
+    use std::io;
+    use std::sync::Mutex;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn tap_sees_and_aborts() {
+        let seen = Mutex::new(vec![]);
+        let tap = |direction: TapDirection, chunk: &[u8]| {
+            seen.lock().unwrap().push((direction, chunk.to_vec()));
+            if chunk.starts_with(b"QUIT") {
+                TapAction::Abort
+            } else {
+                TapAction::Continue
+            }
+        };
+
+        let (inbound, mut client) = duplex(64);
+        let (outbound, mut remote) = duplex(64);
+        let peers = async {
+            client.write_all(b"PING").await.unwrap();
+            let mut buf = [0u8; 4];
+            remote.read_exact(&mut buf).await.unwrap();
+            remote.write_all(b"PONG").await.unwrap();
+            client.read_exact(&mut buf).await.unwrap();
+            client.write_all(b"QUIT").await.unwrap();
+            // the aborting chunk isn't forwarded
+            assert_eq!(remote.read(&mut buf).await.unwrap(), 0);
+        };
+        let (res, ()) = tokio::join!(transfer_tapped(inbound, outbound, &tap), peers);
+
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::ConnectionAborted);
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![
+                (TapDirection::ClientToTarget, b"PING".to_vec()),
+                (TapDirection::TargetToClient, b"PONG".to_vec()),
+                (TapDirection::ClientToTarget, b"QUIT".to_vec()),
+            ]
+        );
+    }
+}
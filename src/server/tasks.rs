@@ -0,0 +1,210 @@
+use std::fmt;
+use std::future::{poll_fn, Future};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tokio::task::{AbortHandle, JoinError, JoinSet};
+
+type ResultHandler<T> = Box<dyn Fn(Result<T, JoinError>) + Send + Sync>;
+
+/// The tasks of the sessions, so that the server can cap how many run at once, handle
+/// their results in one place and wait for them on shutdown, instead of detaching
+/// them with `tokio::spawn`.
+///
+/// [`Socks5Listener::serve`](super::Socks5Listener::serve) spawns its sessions in one,
+/// see `Socks5Listener::set_session_tasks`.
+pub struct SessionTasks<T = ()> {
+    set: Mutex<JoinSet<T>>,
+    limit: Option<Arc<Semaphore>>,
+    on_result: Option<ResultHandler<T>>,
+}
+
+impl<T> fmt::Debug for SessionTasks<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SessionTasks")
+            .field("tasks", &self.set.lock().unwrap().len())
+            .field("limit", &self.limit.as_ref().map(|l| l.available_permits()))
+            .finish()
+    }
+}
+
+impl<T: Send + 'static> Default for SessionTasks<T> {
+    fn default() -> Self {
+        SessionTasks {
+            set: Mutex::new(JoinSet::new()),
+            limit: None,
+            on_result: None,
+        }
+    }
+}
+
+impl<T: Send + 'static> SessionTasks<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Run at most `max` tasks at once, [`SessionTasks::spawn`] waiting for one to end.
+    pub fn set_limit(&mut self, max: usize) -> &mut Self {
+        self.limit = Some(Arc::new(Semaphore::new(max)));
+        self
+    }
+
+    /// Called with the result of each task as it ends, panics and aborts included. By
+    /// default the output is dropped and the panics logged.
+    pub fn set_result_handler<F>(&mut self, handler: F) -> &mut Self
+    where
+        F: Fn(Result<T, JoinError>) + Send + Sync + 'static,
+    {
+        self.on_result = Some(Box::new(handler));
+        self
+    }
+
+    /// Spawn `task`, once there is room for it under the limit.
+    pub async fn spawn<F>(&self, task: F) -> AbortHandle
+    where
+        F: Future<Output = T> + Send + 'static,
+    {
+        let permit = match &self.limit {
+            Some(limit) => Some(limit.clone().acquire_owned().await.expect("never closed")),
+            None => None,
+        };
+        self.spawn_with(task, permit)
+    }
+
+    /// Spawn `task` if there is room for it under the limit, or hand it back.
+    pub fn try_spawn<F>(&self, task: F) -> Result<AbortHandle, F>
+    where
+        F: Future<Output = T> + Send + 'static,
+    {
+        let permit = match &self.limit {
+            Some(limit) => match limit.clone().try_acquire_owned() {
+                Ok(permit) => Some(permit),
+                Err(_) => return Err(task),
+            },
+            None => None,
+        };
+        Ok(self.spawn_with(task, permit))
+    }
+
+    fn spawn_with<F, P>(&self, task: F, permit: Option<P>) -> AbortHandle
+    where
+        F: Future<Output = T> + Send + 'static,
+        P: Send + 'static,
+    {
+        // the ended tasks are only reaped here and in `len`, keep them from piling up
+        self.reap();
+        self.set.lock().unwrap().spawn(async move {
+            let output = task.await;
+            drop(permit);
+            output
+        })
+    }
+
+    /// How many tasks are running.
+    pub fn len(&self) -> usize {
+        self.reap()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Wait for every task, up to `grace`, then abort the ones still running, returning
+    /// how many were.
+    pub async fn shutdown(&self, grace: Duration) -> usize {
+        let drained = tokio::time::timeout(grace, async {
+            while let Some(res) = self.join_next().await {
+                self.handle(res);
+            }
+        })
+        .await;
+        if drained.is_ok() {
+            return 0;
+        }
+        let aborted = {
+            let mut set = self.set.lock().unwrap();
+            set.abort_all();
+            set.len()
+        };
+        while let Some(res) = self.join_next().await {
+            self.handle(res);
+        }
+        aborted
+    }
+
+    /// Handle the results of the ended tasks, outside of the lock so that the handler
+    /// may use `self`, returning how many are still running.
+    fn reap(&self) -> usize {
+        let (ended, running) = {
+            let mut set = self.set.lock().unwrap();
+            let ended: Vec<_> = std::iter::from_fn(|| set.try_join_next()).collect();
+            (ended, set.len())
+        };
+        ended.into_iter().for_each(|res| self.handle(res));
+        running
+    }
+
+    async fn join_next(&self) -> Option<Result<T, JoinError>> {
+        poll_fn(|cx| self.set.lock().unwrap().poll_join_next(cx)).await
+    }
+
+    fn handle(&self, res: Result<T, JoinError>) {
+        match &self.on_result {
+            Some(handler) => handler(res),
+            None => {
+                if let Err(err) = res {
+                    if err.is_panic() {
+                        error!("session task panicked: {}", err);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::SessionTasks;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::time::{sleep, Instant};
+
+    #[tokio::test(start_paused = true)]
+    async fn limit_results_and_shutdown() {
+        let (sum, failed) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let mut tasks = SessionTasks::new();
+        let (s, f) = (sum.clone(), failed.clone());
+        tasks.set_limit(2).set_result_handler(move |res| {
+            match res {
+                Ok(n) => s.fetch_add(n, Ordering::Relaxed),
+                Err(_) => f.fetch_add(1, Ordering::Relaxed),
+            };
+        });
+
+        let start = Instant::now();
+        for n in 1..=3 {
+            tasks
+                .spawn(async move {
+                    sleep(Duration::from_secs(1)).await;
+                    n
+                })
+                .await;
+        }
+        // the third one waited for a slot
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+        assert_eq!(tasks.len(), 1);
+        tasks
+            .spawn(async {
+                sleep(Duration::from_secs(60)).await;
+                0
+            })
+            .await;
+        assert!(tasks.try_spawn(async { 0 }).is_err());
+
+        assert_eq!(tasks.shutdown(Duration::from_secs(5)).await, 1);
+        assert_eq!(sum.load(Ordering::Relaxed), 6);
+        assert_eq!(failed.load(Ordering::Relaxed), 1);
+        assert!(tasks.is_empty());
+    }
+}
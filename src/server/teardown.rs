@@ -0,0 +1,59 @@
+use socket2::SockRef;
+use std::io;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+/// How a connection is closed when a session is killed by policy, e.g. quota exceeded,
+/// admin kick or a [`TapAction::Abort`](super::TapAction::Abort).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TeardownMode {
+    /// Send a FIN after the data already written, the peer sees a clean EOF.
+    #[default]
+    Graceful,
+    /// Reset the connection with `SO_LINGER` set to zero, dropping the unsent data: the
+    /// peer sees an error right away.
+    Reset,
+}
+
+impl TeardownMode {
+    /// Close a connection of a killed session.
+    pub async fn close(self, mut stream: TcpStream) -> io::Result<()> {
+        match self {
+            TeardownMode::Graceful => stream.shutdown().await,
+            TeardownMode::Reset => {
+                debug!("resetting connection to {:?}", stream.peer_addr());
+                // dropped just after, so SO_LINGER can't block
+                SockRef::from(&stream).set_linger(Some(Duration::ZERO))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::TeardownMode;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[tokio::test]
+    async fn reset_or_fin() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        for mode in [TeardownMode::Graceful, TeardownMode::Reset] {
+            let mut client = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (session, _) = listener.accept().await.unwrap();
+            mode.close(session).await.unwrap();
+
+            let mut buf = [0u8; 8];
+            let res = client.read(&mut buf).await;
+            match mode {
+                TeardownMode::Graceful => assert_eq!(res.unwrap(), 0),
+                TeardownMode::Reset => {
+                    assert_eq!(res.unwrap_err().kind(), std::io::ErrorKind::ConnectionReset)
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,274 @@
+use super::{verify_password, Authentication};
+use crate::util::secret::Secret;
+use crate::ConfigError;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A time-based one-time password generator (RFC 6238, HMAC-SHA-1), compatible with
+/// the usual authenticator apps.
+#[derive(Debug, Clone)]
+pub struct Totp {
+    secret: Secret<Vec<u8>>,
+    digits: u32,
+    step: Duration,
+    skew: u64,
+}
+
+impl Totp {
+    /// 6 digits every 30 seconds, accepting the codes of the previous and next steps
+    /// for the clocks that drift.
+    pub fn new(secret: Vec<u8>) -> Self {
+        Totp {
+            secret: Secret::new(secret),
+            digits: 6,
+            step: Duration::from_secs(30),
+            skew: 1,
+        }
+    }
+
+    /// The secret as shown by the apps, in base32 (RFC 4648), spaces and padding ignored.
+    pub fn from_base32(secret: &str) -> Result<Self, ConfigError> {
+        let mut err = ConfigError::new();
+        let secret = decode_base32(secret);
+        err.check(
+            matches!(&secret, Some(secret) if !secret.is_empty()),
+            "invalid base32 TOTP secret",
+        );
+        err.into_result()?;
+        Ok(Totp::new(secret.unwrap_or_default()))
+    }
+
+    /// From 6 to 8 digits.
+    pub fn set_digits(&mut self, digits: u32) -> &mut Self {
+        self.digits = digits.clamp(6, 8);
+        self
+    }
+
+    pub fn set_step(&mut self, step: Duration) -> &mut Self {
+        self.step = step.max(Duration::from_secs(1));
+        self
+    }
+
+    /// How many steps before or after the current one are still accepted.
+    pub fn set_skew(&mut self, skew: u64) -> &mut Self {
+        self.skew = skew;
+        self
+    }
+
+    /// The code at `time`, in seconds since the Unix epoch.
+    pub fn code_at(&self, time: u64) -> String {
+        self.code(time / self.step.as_secs())
+    }
+
+    /// The step of `code` if it is valid at `time`, in seconds since the Unix epoch.
+    pub fn verify_at(&self, code: &str, time: u64) -> Option<u64> {
+        let current = time / self.step.as_secs();
+        let first = current.saturating_sub(self.skew);
+        // every step is checked, so that the time doesn't tell which one matched
+        (first..=current + self.skew).fold(None, |found, step| {
+            match verify_password(code, &self.code(step)) {
+                true => Some(step),
+                false => found,
+            }
+        })
+    }
+
+    /// The step of `code` if it is valid now.
+    pub fn verify(&self, code: &str) -> Option<u64> {
+        self.verify_at(code, unix_time())
+    }
+
+    fn code(&self, step: u64) -> String {
+        let hash = hmac_sha1(self.secret.expose(), &step.to_be_bytes());
+        let offset = usize::from(hash[19] & 0xf);
+        let value = u32::from_be_bytes(hash[offset..offset + 4].try_into().unwrap()) & 0x7fff_ffff;
+        format!(
+            "{:0width$}",
+            value % 10u32.pow(self.digits),
+            width = self.digits as usize
+        )
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs())
+}
+
+/// The check of the code appended to the password, `password:code`, after the password
+/// itself was accepted: a TOTP with [`TotpUsers`], or e.g. an API token looked up in a
+/// database.
+#[async_trait::async_trait]
+pub trait SecondFactor: Send + Sync {
+    async fn verify(&self, username: &str, code: &str) -> bool;
+}
+
+/// The TOTP generator of each user, each code being accepted only once.
+#[derive(Debug, Default)]
+pub struct TotpUsers {
+    users: HashMap<String, Totp>,
+    // the step of the last code used by each user
+    last_steps: Mutex<HashMap<String, u64>>,
+}
+
+impl TotpUsers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert<U: Into<String>>(&mut self, username: U, totp: Totp) -> &mut Self {
+        self.users.insert(username.into(), totp);
+        self
+    }
+
+    fn verify_at(&self, username: &str, code: &str, time: u64) -> bool {
+        let Some(step) = self
+            .users
+            .get(username)
+            .and_then(|totp| totp.verify_at(code, time))
+        else {
+            return false;
+        };
+        let mut last_steps = self.last_steps.lock().unwrap();
+        match last_steps.get(username) {
+            Some(last) if *last >= step => {
+                warn!("TOTP code used again");
+                false
+            }
+            _ => {
+                last_steps.insert(username.to_owned(), step);
+                true
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl SecondFactor for TotpUsers {
+    async fn verify(&self, username: &str, code: &str) -> bool {
+        self.verify_at(username, code, unix_time())
+    }
+}
+
+/// Password authentication with a second factor, without changing the protocol: the
+/// clients send `password:code` as the password, `inner` checks the password and
+/// `factor` the code.
+pub struct TwoFactorAuth<A, F> {
+    inner: A,
+    factor: F,
+}
+
+impl<A, F> TwoFactorAuth<A, F> {
+    pub fn new(inner: A, factor: F) -> Self {
+        TwoFactorAuth { inner, factor }
+    }
+}
+
+/// Split the password sent by a client into the password and the code after the last
+/// `:`, for the checks done outside of [`TwoFactorAuth`].
+pub fn split_second_factor(password: &str) -> Option<(&str, &str)> {
+    password.rsplit_once(':')
+}
+
+#[async_trait::async_trait]
+impl<A, F> Authentication for TwoFactorAuth<A, F>
+where
+    A: Authentication,
+    A::Item: Send,
+    F: SecondFactor,
+{
+    type Item = A::Item;
+
+    async fn authenticate(&self, credentials: Option<(String, String)>) -> Option<Self::Item> {
+        let Some((username, password)) = credentials else {
+            return self.inner.authenticate(None).await;
+        };
+        let (password, code) = split_second_factor(&password)?;
+        // the code is checked last, so that a wrong password doesn't use it up
+        let item = self
+            .inner
+            .authenticate(Some((username.clone(), password.to_owned())))
+            .await?;
+        if self.factor.verify(&username, code).await {
+            Some(item)
+        } else {
+            debug!("wrong second factor");
+            None
+        }
+    }
+}
+
+fn decode_base32(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = vec![];
+    let (mut buffer, mut bits) = (0u32, 0);
+    for c in text.chars().filter(|c| !c.is_whitespace() && *c != '=') {
+        let value = match c.to_ascii_uppercase() {
+            c @ 'A'..='Z' => c as u32 - 'A' as u32,
+            c @ '2'..='7' => c as u32 - '2' as u32 + 26,
+            _ => return None,
+        };
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    Some(bytes)
+}
+
+fn hmac_sha1(key: &[u8], message: &[u8]) -> [u8; 20] {
+    use hmac::{Hmac, Mac};
+    let mut mac = Hmac::<sha1::Sha1>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(message);
+    mac.finalize().into_bytes().into()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Totp, TotpUsers, TwoFactorAuth};
+    use crate::server::{Authentication, SimpleUserPassword};
+
+    #[test]
+    fn rfc6238_vectors() {
+        let mut totp = Totp::from_base32("GEZDGNBVGY3TQOJQ GEZDGNBVGY3TQOJQ").unwrap();
+        totp.set_digits(8);
+        for (time, code) in [
+            (59, "94287082"),
+            (1111111109, "07081804"),
+            (1111111111, "14050471"),
+            (1234567890, "89005924"),
+            (2000000000, "69279037"),
+        ] {
+            assert_eq!(totp.code_at(time), code, "{}", time);
+        }
+        assert_eq!(totp.verify_at("94287082", 59 + 30), Some(1));
+        assert_eq!(totp.verify_at("94287082", 59 + 60), None);
+        assert!(Totp::from_base32("not base32!").is_err());
+    }
+
+    #[tokio::test]
+    async fn two_factor_auth() {
+        let totp = Totp::new(b"12345678901234567890".to_vec());
+        let code = totp.code_at(super::unix_time());
+        let mut users = TotpUsers::new();
+        users.insert("alice", totp);
+        let auth = TwoFactorAuth::new(
+            SimpleUserPassword {
+                username: "alice".to_owned(),
+                password: "hunter2".to_owned(),
+            },
+            users,
+        );
+        let login = |password: String| auth.authenticate(Some(("alice".to_owned(), password)));
+
+        assert!(login("hunter2".to_owned()).await.is_none());
+        assert!(login(format!("hunter3:{}", code)).await.is_none());
+        assert!(login(format!("hunter2:{}", code)).await.is_some());
+        // a code is only good once
+        assert!(login(format!("hunter2:{}", code)).await.is_none());
+    }
+}
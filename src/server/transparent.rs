@@ -0,0 +1,140 @@
+use super::{
+    connect_to_target, transfer_with_options, ConnectOptions, ErrorContext, SocksServerError,
+};
+use crate::util::target_addr::TargetAddr;
+use socket2::{Domain, SockRef, Socket, Type};
+use std::io;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use tokio::net::{TcpListener, TcpStream};
+
+/// How the connections are intercepted by the firewall.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransparentMode {
+    /// The iptables `REDIRECT` (or `DNAT`) target: the original destination is read with
+    /// `SO_ORIGINAL_DST`.
+    Redirect,
+    /// The iptables `TPROXY` target: the original destination is the local address of
+    /// the connection, on a listener bound with [`bind_transparent`].
+    Tproxy,
+}
+
+/// Bind a listener with `IP_TRANSPARENT`, to accept the connections intercepted by
+/// `TPROXY`. Needs `CAP_NET_ADMIN`.
+pub fn bind_transparent(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    socket.set_ip_transparent(true)?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Accepts connections intercepted by the firewall instead of SOCKS clients, and relays
+/// them to their original destination like a CONNECT command, making the server a
+/// transparent egress proxy. Linux only.
+#[derive(Debug, Clone)]
+pub struct TransparentProxy {
+    mode: TransparentMode,
+    listen_addr: SocketAddr,
+    connect: ConnectOptions,
+}
+
+impl TransparentProxy {
+    /// A proxy for the connections accepted on `listener`.
+    pub fn new(mode: TransparentMode, listener: &TcpListener) -> io::Result<Self> {
+        Ok(TransparentProxy {
+            mode,
+            listen_addr: listener.local_addr()?,
+            connect: ConnectOptions::default(),
+        })
+    }
+
+    /// Connect to the original destinations, and relay to them, with `opts`.
+    pub fn set_connect_options(&mut self, opts: ConnectOptions) -> &mut Self {
+        self.connect = opts;
+        self
+    }
+
+    /// The destination `stream` was intercepted on its way to.
+    ///
+    /// Fails for the connections made to the listener directly rather than intercepted,
+    /// which would otherwise be relayed to the listener itself.
+    pub fn original_dst(&self, stream: &TcpStream) -> io::Result<SocketAddr> {
+        let local_addr = stream.local_addr()?;
+        let dst = match self.mode {
+            TransparentMode::Redirect => {
+                let socket = SockRef::from(stream);
+                let dst = if local_addr.is_ipv6() {
+                    socket.original_dst_ipv6()
+                } else {
+                    socket.original_dst()
+                };
+                // not NATed: conntrack may still report the listener itself
+                dst.ok()
+                    .and_then(|dst| dst.as_socket())
+                    .filter(|dst| *dst != local_addr)
+            }
+            // with TPROXY, the connections made to the listener itself are the ones to a
+            // local address on its port
+            TransparentMode::Tproxy => Some(local_addr).filter(|a| !self.is_listener(*a)),
+        };
+        dst.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "not an intercepted connection")
+        })
+    }
+
+    fn is_listener(&self, addr: SocketAddr) -> bool {
+        if addr.port() != self.listen_addr.port() {
+            false
+        } else if self.listen_addr.ip().is_unspecified() {
+            is_local_ip(addr.ip())
+        } else {
+            addr == self.listen_addr
+        }
+    }
+
+    /// Relay an accepted connection to its original destination until it is done.
+    pub async fn serve_client(&self, stream: TcpStream) -> Result<(), SocksServerError> {
+        let dst = self
+            .original_dst(&stream)
+            .err_when("reading the original destination")?;
+        debug!("Intercepted connection to {}", dst);
+        let outbound = connect_to_target(&TargetAddr::Ip(dst), &self.connect).await?;
+        let reason = transfer_with_options(stream, outbound, &self.connect.transfer).await;
+        debug!("Intercepted connection to {} closed: {:?}", dst, reason);
+        Ok(())
+    }
+}
+
+/// Whether `ip` is an address of this host, which only these can be bound without
+/// `IP_TRANSPARENT`.
+fn is_local_ip(ip: IpAddr) -> bool {
+    UdpSocket::bind(SocketAddr::new(ip, 0)).is_ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{is_local_ip, TransparentMode, TransparentProxy};
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[tokio::test]
+    async fn direct_connections_refused() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        for mode in [TransparentMode::Redirect, TransparentMode::Tproxy] {
+            let proxy = TransparentProxy::new(mode, &listener).unwrap();
+            let _client = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (stream, _) = listener.accept().await.unwrap();
+            assert!(proxy.original_dst(&stream).is_err());
+            assert!(proxy.serve_client(stream).await.is_err());
+        }
+    }
+
+    #[test]
+    fn local_ips() {
+        assert!(is_local_ip("127.0.0.1".parse().unwrap()));
+        assert!(!is_local_ip("192.0.2.1".parse().unwrap()));
+    }
+}
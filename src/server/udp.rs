@@ -0,0 +1,1457 @@
+use super::memory::BufferReservation;
+use super::udp_shared::run_udp_proxy_shared;
+use super::{
+    resolve_reported, states, try_notify, ErrorContext, MemoryReservation, ResolutionHook,
+    ResolutionRecord, Socks5ServerProtocol, SocksServerError, StaticHosts, UdpSharedRelay,
+};
+use crate::util::target_addr::{ResolutionPreference, TargetAddr};
+use crate::{new_udp_header, parse_udp_request, ConfigError};
+use socket2::{Domain, SockRef, Socket, Type};
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio::net::UdpSocket;
+use tokio::try_join;
+
+pub(crate) fn udp_bind_random_port(addr: Option<IpAddr>) -> io::Result<Socket> {
+    if let Some(addr) = addr {
+        let sock_addr = SocketAddr::new(addr, 0);
+        let socket = Socket::new(Domain::for_address(sock_addr), Type::DGRAM, None)?;
+        socket.bind(&sock_addr.into())?;
+        Ok(socket)
+    } else {
+        const V4_UNSPEC: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+        const V6_UNSPEC: SocketAddr = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0);
+        Socket::new(Domain::IPV6, Type::DGRAM, None)
+            .and_then(|socket| socket.set_only_v6(false).map(|_| socket))
+            .and_then(|socket| socket.bind(&V6_UNSPEC.into()).map(|_| socket))
+            .or_else(|_| {
+                Socket::new(Domain::IPV4, Type::DGRAM, None)
+                    .and_then(|socket| socket.bind(&V4_UNSPEC.into()).map(|_| socket))
+            })
+    }
+    .and_then(|socket| socket.set_nonblocking(true).map(|_| socket))
+}
+
+/// Bind the socket facing the remote peers, on `addr` or any IP, for `family`.
+pub(crate) fn udp_bind_outbound(
+    addr: Option<IpAddr>,
+    family: UdpAddressFamily,
+) -> io::Result<Socket> {
+    let (domain, unspecified) = match family {
+        UdpAddressFamily::DualStack => return udp_bind_random_port(addr),
+        UdpAddressFamily::Ipv4Only => (Domain::IPV4, IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+        UdpAddressFamily::Ipv6Only { .. } => (Domain::IPV6, IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+    };
+    let sock_addr = SocketAddr::new(addr.unwrap_or(unspecified), 0);
+    if Domain::for_address(sock_addr) != domain {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "outbound bind IP {} isn't of the {:?} family",
+                sock_addr.ip(),
+                family
+            ),
+        ));
+    }
+    let socket = Socket::new(domain, Type::DGRAM, None)?;
+    if domain == Domain::IPV6 {
+        socket.set_only_v6(true)?;
+    }
+    socket.bind(&sock_addr.into())?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+/// The address families the relay reaches the targets of a UDP association with, see
+/// [`UdpAssociation::set_address_family`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UdpAddressFamily {
+    /// Both, on a dual-stack IPv6 socket sending to the IPv4 targets at their
+    /// IPv4-mapped address, or IPv4 only where IPv6 is disabled.
+    ///
+    /// A socket bound to a given IPv6 can't send to IPv4-mapped addresses, the IPv4
+    /// targets are then unreachable.
+    #[default]
+    DualStack,
+    Ipv4Only,
+    /// For IPv6-only hosts. The IPv4 targets are reached through a NAT64 gateway, at
+    /// their address appended to the /96 `nat64_prefix`, e.g. `64:ff9b::` (RFC 6052),
+    /// or are unreachable without one.
+    Ipv6Only {
+        nat64_prefix: Option<Ipv6Addr>,
+    },
+}
+
+/// How the outbound socket of a relay reaches the IPv4 targets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Ipv4Reach {
+    Direct,
+    Mapped,
+    Nat64([u8; 12]),
+    Unreachable,
+}
+
+/// The targets the outbound socket of a relay can send to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct OutboundReach {
+    ipv4: Ipv4Reach,
+    ipv6: bool,
+}
+
+impl OutboundReach {
+    pub(super) fn of(outbound: &UdpSocket, family: UdpAddressFamily) -> io::Result<Self> {
+        let local = outbound.local_addr()?;
+        if local.is_ipv4() {
+            return Ok(OutboundReach {
+                ipv4: Ipv4Reach::Direct,
+                ipv6: false,
+            });
+        }
+        let ipv4 = match family {
+            UdpAddressFamily::Ipv6Only {
+                nat64_prefix: Some(prefix),
+            } => Ipv4Reach::Nat64(prefix.octets()[..12].try_into().unwrap()),
+            UdpAddressFamily::Ipv6Only { nat64_prefix: None } => Ipv4Reach::Unreachable,
+            _ if local.ip().is_unspecified() && !SockRef::from(outbound).only_v6()? => {
+                Ipv4Reach::Mapped
+            }
+            _ => Ipv4Reach::Unreachable,
+        };
+        Ok(OutboundReach { ipv4, ipv6: true })
+    }
+
+    /// Which resolved addresses of a domain target to use.
+    fn preference(&self) -> ResolutionPreference {
+        match (self.ipv4, self.ipv6) {
+            (_, false) => ResolutionPreference::Ipv4Only,
+            (Ipv4Reach::Unreachable, _) => ResolutionPreference::Ipv6Only,
+            // native IPv6 rather than through the gateway
+            (Ipv4Reach::Nat64(_), _) => ResolutionPreference::PreferIpv6,
+            _ => ResolutionPreference::System,
+        }
+    }
+
+    /// The address to send to for `target`, `None` if it is unreachable.
+    fn map(&self, target: SocketAddr) -> Option<SocketAddr> {
+        let ip = match target.ip().to_canonical() {
+            IpAddr::V4(v4) => match self.ipv4 {
+                Ipv4Reach::Direct => IpAddr::V4(v4),
+                Ipv4Reach::Mapped => IpAddr::V6(v4.to_ipv6_mapped()),
+                Ipv4Reach::Nat64(prefix) => {
+                    let mut octets = [0; 16];
+                    octets[..12].copy_from_slice(&prefix);
+                    octets[12..].copy_from_slice(&v4.octets());
+                    IpAddr::V6(Ipv6Addr::from(octets))
+                }
+                Ipv4Reach::Unreachable => return None,
+            },
+            IpAddr::V6(v6) if self.ipv6 => IpAddr::V6(v6),
+            IpAddr::V6(_) => return None,
+        };
+        Some(SocketAddr::new(ip, target.port()))
+    }
+}
+
+/// The address a reply from `remote` is relayed as, undoing the mapping of
+/// [`OutboundReach::map`]: clients don't tend to expect v6-mapped or NAT64 addresses
+/// when they sent to v4 ones.
+fn unmap_remote(remote: SocketAddr, family: UdpAddressFamily) -> SocketAddr {
+    let IpAddr::V6(v6) = remote.ip() else {
+        return remote;
+    };
+    let v4 = match family {
+        UdpAddressFamily::Ipv6Only {
+            nat64_prefix: Some(prefix),
+        } if v6.octets()[..12] == prefix.octets()[..12] => {
+            let [.., a, b, c, d] = v6.octets();
+            Some(Ipv4Addr::new(a, b, c, d))
+        }
+        _ => v6.to_ipv4_mapped(),
+    };
+    v4.map_or(remote, |v4| SocketAddr::new(IpAddr::V4(v4), remote.port()))
+}
+
+/// How the UDP relay decides which client address datagrams are accepted from.
+///
+/// Many clients send `0.0.0.0:0` (or `[::]:0`) as DST.ADDR in the UDP ASSOCIATE request
+/// and only reveal their real source address with the first datagram, so the relay
+/// always learns the peer address late.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UdpPeerBinding {
+    /// Accept datagrams from any source and reply to whichever sent the latest one.
+    Follow,
+    /// Lock onto the source of the first datagram, datagrams from any other source are dropped.
+    ///
+    /// The default: with `Follow`, any host reaching the relay port can take the replies
+    /// of the association over with a single datagram.
+    #[default]
+    LockFirst,
+}
+
+/// The settings of [`run_udp_proxy_with_options`].
+///
+/// The datagram size limit, NAT filtering and timeouts and per-datagram access checks
+/// are settings of the [`UdpAssociation`].
+#[derive(Debug)]
+pub struct UdpProxyOptions {
+    reply_ip: IpAddr,
+    peer_bind_ip: Option<IpAddr>,
+    peer_socket: Option<Socket>,
+    outbound_bind_ip: Option<IpAddr>,
+    binding: UdpPeerBinding,
+    association: Option<Arc<UdpAssociation>>,
+    shared_relay: Option<(Arc<UdpSharedRelay>, IpAddr)>,
+}
+
+impl UdpProxyOptions {
+    /// `reply_ip` is the address sent to the client to reach the relay, usually the
+    /// public IP of the server.
+    pub fn new(reply_ip: IpAddr) -> Self {
+        UdpProxyOptions {
+            reply_ip,
+            peer_bind_ip: None,
+            peer_socket: None,
+            outbound_bind_ip: None,
+            binding: UdpPeerBinding::default(),
+            association: None,
+            shared_relay: None,
+        }
+    }
+
+    /// Bind the socket facing the client on this IP, any IP by default.
+    pub fn set_peer_bind_ip(&mut self, ip: IpAddr) -> &mut Self {
+        self.peer_bind_ip = Some(ip);
+        self
+    }
+
+    /// Use a socket bound by the caller for the client side, e.g. in a fixed port range.
+    pub fn set_peer_socket(&mut self, socket: Socket) -> &mut Self {
+        self.peer_socket = Some(socket);
+        self
+    }
+
+    /// Bind the socket facing the remote peers on this IP, any IP by default.
+    pub fn set_outbound_bind_ip(&mut self, ip: IpAddr) -> &mut Self {
+        self.outbound_bind_ip = Some(ip);
+        self
+    }
+
+    pub fn set_peer_binding(&mut self, binding: UdpPeerBinding) -> &mut Self {
+        self.binding = binding;
+        self
+    }
+
+    /// Relay on an association created by the caller, see `transfer_udp_association`.
+    pub fn set_association(&mut self, association: Arc<UdpAssociation>) -> &mut Self {
+        self.association = Some(association);
+        self
+    }
+
+    /// Relay the client, connected from `client_ip`, on the port of `relay` instead of a
+    /// port of its own. The client side socket settings and peer binding are ignored.
+    pub fn set_shared_relay(&mut self, relay: Arc<UdpSharedRelay>, client_ip: IpAddr) -> &mut Self {
+        self.shared_relay = Some((relay, client_ip));
+        self
+    }
+}
+
+/// Handle the associate command by running a UDP proxy until the connection is done.
+pub async fn run_udp_proxy_with_options<T: AsyncRead + AsyncWrite + Unpin>(
+    proto: Socks5ServerProtocol<T, states::CommandRead>,
+    addr: &TargetAddr,
+    opts: UdpProxyOptions,
+) -> Result<T, SocksServerError> {
+    let association = opts.association.unwrap_or_default();
+    try_notify!(
+        proto,
+        association.validate().map_err(SocksServerError::from)
+    );
+    if let Some((relay, client_ip)) = opts.shared_relay {
+        return run_udp_proxy_shared(
+            proto,
+            addr,
+            &relay,
+            client_ip,
+            opts.reply_ip,
+            opts.outbound_bind_ip,
+            association,
+        )
+        .await;
+    }
+    let peer_sock = match opts.peer_socket {
+        Some(socket) => socket.set_nonblocking(true).map(|_| socket),
+        None => udp_bind_random_port(opts.peer_bind_ip),
+    };
+    let outbound_bind_ip = opts.outbound_bind_ip;
+    run_udp_proxy_on(proto, peer_sock, opts.reply_ip, move |inbound| async move {
+        let outbound = udp_bind_outbound(outbound_bind_ip, association.address_family())
+            .err_when("binding outbound udp socket")?;
+        transfer_udp_association(inbound, outbound, opts.binding, association).await
+    })
+    .await
+}
+
+/// Handle the associate command by running a UDP proxy until the connection is done.
+///
+/// See [`run_udp_proxy_with_options`] for more settings.
+pub async fn run_udp_proxy<T: AsyncRead + AsyncWrite + Unpin>(
+    proto: Socks5ServerProtocol<T, states::CommandRead>,
+    addr: &TargetAddr,
+    peer_bind_ip: Option<IpAddr>,
+    reply_ip: IpAddr,
+    outbound_bind_ip: Option<IpAddr>,
+) -> Result<T, SocksServerError> {
+    run_udp_proxy_with_binding(
+        proto,
+        addr,
+        peer_bind_ip,
+        reply_ip,
+        outbound_bind_ip,
+        UdpPeerBinding::default(),
+    )
+    .await
+}
+
+/// Same as `run_udp_proxy`, but with an explicit policy for late-binding the client's address.
+pub async fn run_udp_proxy_with_binding<T: AsyncRead + AsyncWrite + Unpin>(
+    proto: Socks5ServerProtocol<T, states::CommandRead>,
+    addr: &TargetAddr,
+    peer_bind_ip: Option<IpAddr>,
+    reply_ip: IpAddr,
+    outbound_bind_ip: Option<IpAddr>,
+    binding: UdpPeerBinding,
+) -> Result<T, SocksServerError> {
+    let mut opts = UdpProxyOptions::new(reply_ip);
+    opts.peer_bind_ip = peer_bind_ip;
+    opts.outbound_bind_ip = outbound_bind_ip;
+    opts.set_peer_binding(binding);
+    run_udp_proxy_with_options(proto, addr, opts).await
+}
+
+/// Handle the associate command by running a UDP proxy until the connection is done.
+///
+/// This version allows passing in a custom transfer function while reusing the initialization code.
+pub async fn run_udp_proxy_custom<T, F, R>(
+    proto: Socks5ServerProtocol<T, states::CommandRead>,
+    _addr: &TargetAddr,
+    peer_bind_ip: Option<IpAddr>,
+    reply_ip: IpAddr,
+    transfer: F,
+) -> Result<T, SocksServerError>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+    F: FnOnce(Socket) -> R,
+    R: Future<Output = Result<(), SocksServerError>>,
+{
+    // The DST.ADDR and DST.PORT fields contain the address and port that
+    // the client expects to use to send UDP datagrams on for the
+    // association. The server MAY use this information to limit access
+    // to the association.
+    // @see Page 6, https://datatracker.ietf.org/doc/html/rfc1928.
+    //
+    // We do NOT limit the access from the client based on DST.ADDR: clients behind a NAT
+    // can't know their public address, and most of them send `0.0.0.0:0` anyway.
+    // The peer is learned from the first datagram instead, see `UdpPeerBinding`.
+
+    // By default, listen on a UDP6 socket, so that the client can connect
+    // to it with either IPv4 or IPv6.
+    run_udp_proxy_on(
+        proto,
+        udp_bind_random_port(peer_bind_ip),
+        reply_ip,
+        transfer,
+    )
+    .await
+}
+
+async fn run_udp_proxy_on<T, F, R>(
+    proto: Socks5ServerProtocol<T, states::CommandRead>,
+    peer_sock: io::Result<Socket>,
+    reply_ip: IpAddr,
+    transfer: F,
+) -> Result<T, SocksServerError>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+    F: FnOnce(Socket) -> R,
+    R: Future<Output = Result<(), SocksServerError>>,
+{
+    let peer_sock = try_notify!(proto, peer_sock.err_when("binding client udp socket"));
+
+    let peer_addr = try_notify!(
+        proto,
+        peer_sock.local_addr().err_when("getting peer's local addr")
+    );
+
+    let reply_port = peer_addr
+        .as_socket()
+        .ok_or(SocksServerError::Bug("addr not IP"))?
+        .port();
+
+    // Respect the pre-populated reply IP address.
+    let mut inner = proto
+        .reply_success(SocketAddr::new(reply_ip, reply_port))
+        .await?;
+
+    let udp_fut = transfer(peer_sock);
+    let tcp_fut = wait_on_tcp(&mut inner);
+    match try_join!(udp_fut, tcp_fut) {
+        Ok(_) => warn!("unreachable"),
+        Err(SocksServerError::EOF) => debug!("EOF on controlling TCP stream, closed UDP proxy"),
+        Err(err) => warn!("while UDP proxying: {err}"),
+    }
+    Ok(inner)
+}
+
+/// Wait until a TCP stream (that's not supposed to receive anything) closes.
+///
+/// This is intended for cancelling the `transfer_udp` task.
+pub async fn wait_on_tcp<I>(stream: &mut I) -> Result<(), SocksServerError>
+where
+    I: AsyncRead + Unpin,
+{
+    let mut buf = [0; 1];
+    match stream.read(&mut buf).await {
+        Ok(0) => Err(SocksServerError::EOF),
+        Ok(_) => Err(SocksServerError::UnexpectedUdpControlGarbage(buf[0])),
+        Err(err) => Err(err).err_when("waiting on UDP control stream"),
+    }
+}
+
+/// The default maximum size of the datagrams exchanged with the client of an association.
+pub const DEFAULT_MAX_DATAGRAM_SIZE: usize = 8192;
+
+// RSV FRAG ATYP, then a domain name of 255 bytes with its length, and the port
+const MAX_UDP_HEADER_LEN: usize = 262;
+
+/// What the relay does with a datagram exceeding the maximum size of its association.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UdpOversizePolicy {
+    /// Silently drop the datagram, as a router would.
+    #[default]
+    Drop,
+    /// Forward the beginning of the payload which fits in the limit.
+    Truncate,
+    /// Tear down the whole association when the client sends one. Those of the remote
+    /// peers are dropped, so that any host can't close the associations it reaches.
+    CloseAssociation,
+}
+
+/// Which remote peers are allowed to send datagrams back to the client of an association.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UdpNatFilter {
+    /// Any remote peer may reach the client once the association exists (full-cone NAT).
+    ///
+    /// This is what STUN, games and most peer-to-peer protocols expect.
+    #[default]
+    FullCone,
+    /// Only remote IPs the client has sent a datagram to may reach it.
+    AddressRestricted,
+    /// Only the exact remote `ip:port` pairs the client has sent a datagram to may reach it.
+    PortRestricted,
+}
+
+/// The mapping table of a UDP association: every remote peer the client talks to,
+/// with the time it was last active.
+///
+/// Entries which stay idle longer than `idle_timeout` are expired, after which a
+/// restricted filter no longer lets that peer reach the client.
+#[derive(Debug)]
+pub struct UdpNatTable {
+    filter: UdpNatFilter,
+    idle_timeout: Duration,
+    peers: Mutex<HashMap<SocketAddr, Instant>>,
+}
+
+impl Default for UdpNatTable {
+    fn default() -> Self {
+        UdpNatTable::new(UdpNatFilter::default(), Duration::from_secs(300))
+    }
+}
+
+impl UdpNatTable {
+    pub fn new(filter: UdpNatFilter, idle_timeout: Duration) -> Self {
+        UdpNatTable {
+            filter,
+            idle_timeout,
+            peers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn filter(&self) -> UdpNatFilter {
+        self.filter
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        self.idle_timeout
+    }
+
+    /// Record a datagram sent by the client to `remote`.
+    pub fn record_outbound(&self, remote: SocketAddr) {
+        let now = Instant::now();
+        let mut peers = self.peers.lock().unwrap();
+        if peers.insert(remote, now).is_none() {
+            // only sweep when the table grows, so steady flows stay cheap
+            peers.retain(|addr, last_seen| {
+                *addr == remote || now.duration_since(*last_seen) < self.idle_timeout
+            });
+            debug!("UDP association now maps {} remote peer(s)", peers.len());
+        }
+    }
+
+    /// Check whether a datagram from `remote` may be forwarded to the client,
+    /// refreshing the matching mapping if so.
+    pub fn allow_inbound(&self, remote: SocketAddr) -> bool {
+        let now = Instant::now();
+        let mut peers = self.peers.lock().unwrap();
+        let idle_timeout = self.idle_timeout;
+        let refresh = |last_seen: &mut Instant| {
+            if now.duration_since(*last_seen) < idle_timeout {
+                *last_seen = now;
+                true
+            } else {
+                false
+            }
+        };
+
+        match self.filter {
+            UdpNatFilter::FullCone => {
+                if let Some(last_seen) = peers.get_mut(&remote) {
+                    refresh(last_seen);
+                }
+                true
+            }
+            UdpNatFilter::AddressRestricted => peers
+                .iter_mut()
+                .filter(|(addr, _)| addr.ip() == remote.ip())
+                .fold(false, |allowed, (_, last_seen)| {
+                    refresh(last_seen) || allowed
+                }),
+            UdpNatFilter::PortRestricted => peers.get_mut(&remote).is_some_and(refresh),
+        }
+    }
+
+    /// Remove all the mappings which have been idle for too long, returning how many were removed.
+    pub fn expire(&self) -> usize {
+        let now = Instant::now();
+        let mut peers = self.peers.lock().unwrap();
+        let before = peers.len();
+        peers.retain(|_, last_seen| now.duration_since(*last_seen) < self.idle_timeout);
+        before - peers.len()
+    }
+
+    /// The remote peers currently mapped in this association.
+    pub fn peers(&self) -> Vec<SocketAddr> {
+        self.peers.lock().unwrap().keys().copied().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Counters of a UDP association, updated by the relay while it runs.
+#[derive(Debug, Default)]
+pub struct UdpRelayStats {
+    packets_to_remote: AtomicU64,
+    bytes_to_remote: AtomicU64,
+    packets_to_client: AtomicU64,
+    bytes_to_client: AtomicU64,
+    dropped_oversize: AtomicU64,
+    dropped_fragmented: AtomicU64,
+    dropped_malformed: AtomicU64,
+    dropped_unresolved: AtomicU64,
+    dropped_filtered: AtomicU64,
+    dropped_send_error: AtomicU64,
+    dropped_unreachable_family: AtomicU64,
+    truncated: AtomicU64,
+}
+
+/// A point-in-time copy of [`UdpRelayStats`].
+///
+/// Byte counts are payload sizes, excluding the SOCKS5 UDP request header.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UdpRelayStatsSnapshot {
+    pub packets_to_remote: u64,
+    pub bytes_to_remote: u64,
+    pub packets_to_client: u64,
+    pub bytes_to_client: u64,
+    /// Datagrams exceeding the maximum size of the association.
+    pub dropped_oversize: u64,
+    /// Datagrams with a non-zero FRAG field, fragmentation isn't supported.
+    pub dropped_fragmented: u64,
+    /// Datagrams from the client with an unparsable SOCKS5 UDP header.
+    pub dropped_malformed: u64,
+    /// Datagrams from the client whose target couldn't be resolved.
+    pub dropped_unresolved: u64,
+    /// Datagrams from a source the association doesn't accept, see
+    /// `UdpPeerBinding` and `UdpNatFilter`.
+    pub dropped_filtered: u64,
+    /// Datagrams which couldn't be sent out.
+    pub dropped_send_error: u64,
+    /// Datagrams to a target of an address family the relay can't reach, see
+    /// `UdpAddressFamily`.
+    pub dropped_unreachable_family: u64,
+    /// Datagrams exceeding the maximum size which were forwarded truncated, see `UdpOversizePolicy`.
+    pub truncated: u64,
+}
+
+impl UdpRelayStats {
+    fn incr(counter: &AtomicU64, n: usize) {
+        counter.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> UdpRelayStatsSnapshot {
+        let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        UdpRelayStatsSnapshot {
+            packets_to_remote: get(&self.packets_to_remote),
+            bytes_to_remote: get(&self.bytes_to_remote),
+            packets_to_client: get(&self.packets_to_client),
+            bytes_to_client: get(&self.bytes_to_client),
+            dropped_oversize: get(&self.dropped_oversize),
+            dropped_fragmented: get(&self.dropped_fragmented),
+            dropped_malformed: get(&self.dropped_malformed),
+            dropped_unresolved: get(&self.dropped_unresolved),
+            dropped_filtered: get(&self.dropped_filtered),
+            dropped_send_error: get(&self.dropped_send_error),
+            dropped_unreachable_family: get(&self.dropped_unreachable_family),
+            truncated: get(&self.truncated),
+        }
+    }
+}
+
+impl UdpRelayStatsSnapshot {
+    /// Total number of datagrams dropped, whatever the reason.
+    pub fn dropped(&self) -> u64 {
+        self.dropped_oversize
+            + self.dropped_fragmented
+            + self.dropped_malformed
+            + self.dropped_unresolved
+            + self.dropped_filtered
+            + self.dropped_send_error
+            + self.dropped_unreachable_family
+    }
+}
+
+/// The shared state of a running UDP association: its client, mapping table and counters.
+pub struct UdpAssociation {
+    client: Mutex<Option<SocketAddr>>,
+    nat: UdpNatTable,
+    stats: UdpRelayStats,
+    max_datagram_size: usize,
+    oversize_policy: UdpOversizePolicy,
+    address_family: UdpAddressFamily,
+    target_filter: Option<Box<dyn Fn(SocketAddr) -> bool + Send + Sync>>,
+    memory: Option<Arc<MemoryReservation>>,
+    static_hosts: Option<Arc<StaticHosts>>,
+    resolution_hook: Option<ResolutionHook>,
+}
+
+impl std::fmt::Debug for UdpAssociation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UdpAssociation")
+            .field("client", &self.client)
+            .field("nat", &self.nat)
+            .field("stats", &self.stats)
+            .field("max_datagram_size", &self.max_datagram_size)
+            .field("oversize_policy", &self.oversize_policy)
+            .field("address_family", &self.address_family)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for UdpAssociation {
+    fn default() -> Self {
+        UdpAssociation::new(UdpNatTable::default())
+    }
+}
+
+impl UdpAssociation {
+    pub fn new(nat: UdpNatTable) -> Self {
+        UdpAssociation {
+            client: Mutex::new(None),
+            nat,
+            stats: UdpRelayStats::default(),
+            max_datagram_size: DEFAULT_MAX_DATAGRAM_SIZE,
+            oversize_policy: UdpOversizePolicy::default(),
+            address_family: UdpAddressFamily::default(),
+            target_filter: None,
+            memory: None,
+            static_hosts: None,
+            resolution_hook: None,
+        }
+    }
+
+    /// Send the datagrams for the domains found in `hosts` to their static addresses,
+    /// without DNS.
+    pub fn set_static_hosts(&mut self, hosts: Arc<StaticHosts>) -> &mut Self {
+        self.static_hosts = Some(hosts);
+        self
+    }
+
+    /// Report each resolution of a datagram target to `hook`, e.g. to audit DNS leaks.
+    pub fn set_resolution_hook<F>(&mut self, hook: F) -> &mut Self
+    where
+        F: Fn(&ResolutionRecord) + Send + Sync + 'static,
+    {
+        self.resolution_hook = Some(ResolutionHook(Arc::new(hook)));
+        self
+    }
+
+    /// Reserve the relay buffers from `reservation`, the relay failing with an
+    /// `OutOfMemory` error when they don't fit. Defaults to [`MemoryReservation::current`].
+    pub fn set_memory_reservation(&mut self, reservation: Arc<MemoryReservation>) -> &mut Self {
+        self.memory = Some(reservation);
+        self
+    }
+
+    /// Only relay the datagrams to the resolved targets accepted by `filter`, the others
+    /// are dropped, e.g. to apply an `AccessControl` to each datagram.
+    pub fn set_target_filter<F>(&mut self, filter: F) -> &mut Self
+    where
+        F: Fn(SocketAddr) -> bool + Send + Sync + 'static,
+    {
+        self.target_filter = Some(Box::new(filter));
+        self
+    }
+
+    /// Set the maximum size of the datagrams exchanged with the client, SOCKS5 UDP header included.
+    ///
+    /// The relay buffers are sized after it, raise it up to 65535 for large DNS replies
+    /// or QUIC with big initial packets. Defaults to `DEFAULT_MAX_DATAGRAM_SIZE`.
+    pub fn set_max_datagram_size(&mut self, n: usize) -> &mut Self {
+        self.max_datagram_size = n;
+        self
+    }
+
+    /// Set what to do with the datagrams exceeding the maximum size, they are dropped by default.
+    pub fn set_oversize_policy(&mut self, policy: UdpOversizePolicy) -> &mut Self {
+        self.oversize_policy = policy;
+        self
+    }
+
+    /// Set the address families the targets are reached with, and the outbound socket
+    /// bound for, dual-stack by default.
+    pub fn set_address_family(&mut self, family: UdpAddressFamily) -> &mut Self {
+        self.address_family = family;
+        self
+    }
+
+    pub fn address_family(&self) -> UdpAddressFamily {
+        self.address_family
+    }
+
+    pub fn max_datagram_size(&self) -> usize {
+        self.max_datagram_size
+    }
+
+    pub fn oversize_policy(&self) -> UdpOversizePolicy {
+        self.oversize_policy
+    }
+
+    /// Check the settings, reporting every issue found.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut err = ConfigError::new();
+        err.check(
+            self.max_datagram_size > MAX_UDP_HEADER_LEN,
+            format!(
+                "max datagram size of {} can't fit a SOCKS5 UDP header and data",
+                self.max_datagram_size
+            ),
+        )
+        .check(
+            self.max_datagram_size <= u16::MAX as usize,
+            format!(
+                "max datagram size of {} is larger than any UDP datagram",
+                self.max_datagram_size
+            ),
+        );
+        if let UdpAddressFamily::Ipv6Only {
+            nat64_prefix: Some(prefix),
+        } = self.address_family
+        {
+            err.check(
+                prefix.segments()[6..] == [0, 0],
+                format!("NAT64 prefix {} isn't a /96", prefix),
+            );
+        }
+        err.into_result()
+    }
+
+    /// Apply the oversize policy to a datagram of `size` bytes, returning how many bytes
+    /// of it may be relayed or `None` if it must be dropped.
+    fn fit(&self, size: usize) -> Result<Option<usize>, SocksServerError> {
+        let max = self.max_datagram_size;
+        if size <= max {
+            return Ok(Some(size));
+        }
+        match self.oversize_policy {
+            UdpOversizePolicy::Drop => {
+                debug!("Discard UDP packet which doesn't fit in {} bytes.", max);
+                UdpRelayStats::incr(&self.stats.dropped_oversize, 1);
+                Ok(None)
+            }
+            UdpOversizePolicy::Truncate => {
+                debug!("Truncate UDP packet to {} bytes.", max);
+                UdpRelayStats::incr(&self.stats.truncated, 1);
+                Ok(Some(max))
+            }
+            UdpOversizePolicy::CloseAssociation => {
+                UdpRelayStats::incr(&self.stats.dropped_oversize, 1);
+                Err(SocksServerError::UdpDatagramTooLarge { size, max })
+            }
+        }
+    }
+
+    /// The client address datagrams are relayed back to, once the client sent its first one.
+    pub fn client_addr(&self) -> Option<SocketAddr> {
+        *self.client.lock().unwrap()
+    }
+
+    pub(super) fn set_client_addr(&self, addr: SocketAddr) {
+        *self.client.lock().unwrap() = Some(addr);
+    }
+
+    pub fn nat(&self) -> &UdpNatTable {
+        &self.nat
+    }
+
+    pub fn stats(&self) -> UdpRelayStatsSnapshot {
+        self.stats.snapshot()
+    }
+}
+
+async fn handle_udp_request(
+    inbound: &UdpSocket,
+    outbound: &UdpSocket,
+    reach: OutboundReach,
+    binding: UdpPeerBinding,
+    assoc: &UdpAssociation,
+    buf: &mut [u8],
+) -> Result<(), SocksServerError> {
+    let stats = &assoc.stats;
+    let (size, client_addr) = inbound
+        .recv_from(buf)
+        .await
+        .err_when("udp receiving from")?;
+    debug!("Server recieve udp from {}", client_addr);
+
+    // The inbound socket is not connected to the client: the kernel would then silently
+    // filter other sources, which must be accounted for and allowed by `Follow`.
+    {
+        let mut client = assoc.client.lock().unwrap();
+        match (binding, *client) {
+            (UdpPeerBinding::LockFirst, Some(locked)) if locked != client_addr => {
+                debug!("Discard UDP packet from {client_addr}, association locked onto {locked}");
+                UdpRelayStats::incr(&stats.dropped_filtered, 1);
+                return Ok(());
+            }
+            (_, Some(current)) if current == client_addr => {}
+            _ => {
+                debug!("UDP association bound to client {}", client_addr);
+                *client = Some(client_addr);
+            }
+        }
+    }
+
+    relay_udp_request(outbound, reach, assoc, &buf[..size]).await
+}
+
+/// Relay a datagram of the client of `assoc` to its target.
+pub(super) async fn relay_udp_request(
+    outbound: &UdpSocket,
+    reach: OutboundReach,
+    assoc: &UdpAssociation,
+    datagram: &[u8],
+) -> Result<(), SocksServerError> {
+    let stats = &assoc.stats;
+
+    // The buffer is one byte larger than the limit, a datagram filling it was truncated
+    let Some(size) = assoc.fit(datagram.len())? else {
+        return Ok(());
+    };
+
+    let parsed = parse_udp_request(&datagram[..size])
+        .await
+        .and_then(|(frag, addr, data)| Ok((frag, addr.canonicalize()?, data)));
+    let (frag, target_addr, data) = match parsed {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            UdpRelayStats::incr(&stats.dropped_malformed, 1);
+            return Err(err.into());
+        }
+    };
+
+    if frag != 0 {
+        debug!("Discard UDP frag packets sliently.");
+        UdpRelayStats::incr(&stats.dropped_fragmented, 1);
+        return Ok(());
+    }
+
+    debug!("Server forward to packet to {}", target_addr);
+    let target_addr = async {
+        resolve_reported(
+            &target_addr,
+            reach.preference(),
+            assoc.static_hosts.as_deref(),
+            assoc.resolution_hook.as_ref(),
+        )
+        .await?
+        .into_iter()
+        .next()
+        .ok_or(SocksServerError::Bug("no socket addrs"))
+    };
+    let target_addr = match target_addr.await {
+        Ok(addr) => addr,
+        Err(err) => {
+            UdpRelayStats::incr(&stats.dropped_unresolved, 1);
+            return Err(err);
+        }
+    };
+
+    if let Some(filter) = &assoc.target_filter {
+        if !filter(target_addr) {
+            debug!("Discard UDP packet to filtered target {}", target_addr);
+            UdpRelayStats::incr(&stats.dropped_filtered, 1);
+            return Ok(());
+        }
+    }
+
+    let Some(target_addr) = reach.map(target_addr) else {
+        UdpRelayStats::incr(&stats.dropped_unreachable_family, 1);
+        return Err(SocksServerError::UdpFamilyUnreachable(target_addr));
+    };
+    assoc.nat.record_outbound(target_addr);
+    if let Err(err) = outbound.send_to(data, target_addr).await {
+        UdpRelayStats::incr(&stats.dropped_send_error, 1);
+        return Err(err).err_when("udp sending to");
+    }
+    UdpRelayStats::incr(&stats.packets_to_remote, 1);
+    UdpRelayStats::incr(&stats.bytes_to_remote, data.len());
+    Ok(())
+}
+
+async fn handle_udp_requests(
+    inbound: &UdpSocket,
+    outbound: &UdpSocket,
+    binding: UdpPeerBinding,
+    assoc: &UdpAssociation,
+) -> Result<(), SocksServerError> {
+    let mut buf = vec![0u8; assoc.max_datagram_size + 1];
+    let reach =
+        OutboundReach::of(outbound, assoc.address_family).err_when("udp outbound local addr")?;
+    loop {
+        match handle_udp_request(inbound, outbound, reach, binding, assoc, &mut buf).await {
+            Ok(_) => trace!("handled udp response"),
+            Err(err @ SocksServerError::UdpDatagramTooLarge { .. }) => return Err(err),
+            Err(err) => debug!("error in handling udp response: {err}"),
+        }
+    }
+}
+
+async fn handle_udp_response(
+    inbound: &UdpSocket,
+    outbound: &UdpSocket,
+    assoc: &UdpAssociation,
+    buf: &mut [u8],
+) -> Result<(), SocksServerError> {
+    let stats = &assoc.stats;
+    let (size, remote_addr) = outbound
+        .recv_from(buf)
+        .await
+        .err_when("udp receiving from")?;
+    debug!("Recieve packet from {}", remote_addr);
+
+    if !assoc.nat.allow_inbound(remote_addr) {
+        debug!("Discard UDP packet from unmapped peer {}", remote_addr);
+        UdpRelayStats::incr(&stats.dropped_filtered, 1);
+        return Ok(());
+    }
+
+    let remote_addr = unmap_remote(remote_addr, assoc.address_family);
+
+    let Some(client_addr) = assoc.client_addr() else {
+        debug!("Discard UDP packet from {}, no client yet", remote_addr);
+        UdpRelayStats::incr(&stats.dropped_filtered, 1);
+        return Ok(());
+    };
+
+    let mut data = new_udp_header(remote_addr)?;
+    let fit = match assoc.fit(data.len() + size) {
+        // counted as dropped
+        Err(SocksServerError::UdpDatagramTooLarge { .. }) => None,
+        fit => fit?,
+    };
+    let Some(len) = fit else {
+        return Ok(());
+    };
+    // truncated below the header, with a maximum size `validate` rejects
+    let Some(size) = len.checked_sub(data.len()) else {
+        debug!(
+            "Discard UDP packet, its header doesn't fit in {} bytes.",
+            len
+        );
+        UdpRelayStats::incr(&stats.dropped_oversize, 1);
+        return Ok(());
+    };
+    data.extend_from_slice(&buf[..size]);
+    if let Err(err) = inbound.send_to(&data, client_addr).await {
+        UdpRelayStats::incr(&stats.dropped_send_error, 1);
+        return Err(err).err_when("udp sending");
+    }
+    UdpRelayStats::incr(&stats.packets_to_client, 1);
+    UdpRelayStats::incr(&stats.bytes_to_client, size);
+
+    Ok(())
+}
+
+pub(super) async fn handle_udp_responses(
+    inbound: &UdpSocket,
+    outbound: &UdpSocket,
+    assoc: &UdpAssociation,
+) -> Result<(), SocksServerError> {
+    let mut buf = vec![0u8; assoc.max_datagram_size + 1];
+    loop {
+        match handle_udp_response(inbound, outbound, assoc, &mut buf).await {
+            Ok(_) => trace!("handled udp response"),
+            Err(err) => debug!("error in handling udp response: {err}"),
+        }
+    }
+}
+
+/// Run a bidirectional UDP SOCKS proxy for a given pair of inbound (SOCKS client) and outbound sockets.
+pub async fn transfer_udp(inbound: Socket, outbound: Socket) -> Result<(), SocksServerError> {
+    transfer_udp_with_binding(inbound, outbound, UdpPeerBinding::default()).await
+}
+
+/// Same as `transfer_udp`, but with an explicit policy for late-binding the client's address.
+pub async fn transfer_udp_with_binding(
+    inbound: Socket,
+    outbound: Socket,
+    binding: UdpPeerBinding,
+) -> Result<(), SocksServerError> {
+    transfer_udp_association(inbound, outbound, binding, Default::default()).await
+}
+
+/// Same as `transfer_udp_with_binding`, but on an association created by the caller,
+/// which decides the mapping table behaviour and can keep a handle to it to read the
+/// counters of the relay while it runs.
+pub async fn transfer_udp_association(
+    inbound: Socket,
+    outbound: Socket,
+    binding: UdpPeerBinding,
+    assoc: Arc<UdpAssociation>,
+) -> Result<(), SocksServerError> {
+    let inbound = UdpSocket::from_std(inbound.into()).err_when("wrapping inbound socket")?;
+    let outbound = UdpSocket::from_std(outbound.into()).err_when("wrapping outbound socket")?;
+    // the buffer of each direction
+    let memory = assoc.memory.clone().or_else(MemoryReservation::current);
+    let mut buffers = BufferReservation::new(memory);
+    if !buffers.resize(2 * (assoc.max_datagram_size + 1)) {
+        return Err(io::Error::from(io::ErrorKind::OutOfMemory)).err_when("reserving udp buffers");
+    }
+    let req_fut = handle_udp_requests(&inbound, &outbound, binding, &assoc);
+    let res_fut = handle_udp_responses(&inbound, &outbound, &assoc);
+    try_join!(req_fut, res_fut).map(|_| ())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        transfer_udp_association, udp_bind_outbound, udp_bind_random_port, unmap_remote, Ipv4Reach,
+        OutboundReach, UdpAddressFamily, UdpAssociation, UdpNatFilter, UdpNatTable,
+        UdpOversizePolicy, UdpPeerBinding,
+    };
+    use crate::new_udp_header;
+    use crate::server::SocksServerError;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::net::UdpSocket;
+
+    #[tokio::test]
+    async fn udp_lock_first_drops_other_sources() {
+        let localhost = Some(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        let inbound = udp_bind_random_port(localhost).unwrap();
+        let outbound = udp_bind_random_port(localhost).unwrap();
+        let relay_addr = inbound.local_addr().unwrap().as_socket().unwrap();
+        let assoc = Arc::new(UdpAssociation::default());
+        tokio::spawn(transfer_udp_association(
+            inbound,
+            outbound,
+            UdpPeerBinding::LockFirst,
+            assoc.clone(),
+        ));
+
+        let remote = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let first = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let second = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let mut packet = new_udp_header(remote.local_addr().unwrap()).unwrap();
+        packet.extend_from_slice(b"first");
+        first.send_to(&packet, relay_addr).await.unwrap();
+
+        let mut buf = [0u8; 64];
+        let (len, relay_out) = remote.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"first");
+
+        let mut packet = new_udp_header(remote.local_addr().unwrap()).unwrap();
+        packet.extend_from_slice(b"second");
+        second.send_to(&packet, relay_addr).await.unwrap();
+        let dropped = tokio::time::timeout(Duration::from_millis(200), remote.recv_from(&mut buf));
+        assert!(dropped.await.is_err());
+
+        remote.send_to(b"reply", relay_out).await.unwrap();
+        let len = first.recv(&mut buf).await.unwrap();
+        assert!(buf[..len].ends_with(b"reply"));
+
+        // counters are updated right after the datagram is sent
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let stats = assoc.stats();
+        assert_eq!(stats.packets_to_remote, 1);
+        assert_eq!(stats.bytes_to_remote, 5);
+        assert_eq!(stats.packets_to_client, 1);
+        assert_eq!(stats.dropped_filtered, 1);
+        assert_eq!(stats.dropped(), 1);
+        assert_eq!(assoc.nat().peers(), vec![remote.local_addr().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn udp_proxy_options() {
+        use crate::server::{run_udp_proxy_with_options, Socks5ServerProtocol, UdpProxyOptions};
+        use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+        let allowed = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let denied = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let denied_addr = denied.local_addr().unwrap();
+        let mut assoc = UdpAssociation::default();
+        assoc.set_target_filter(move |target| target != denied_addr);
+        let assoc = Arc::new(assoc);
+
+        let peer_socket = udp_bind_random_port(Some(IpAddr::V4(Ipv4Addr::LOCALHOST))).unwrap();
+        let relay_port = peer_socket
+            .local_addr()
+            .unwrap()
+            .as_socket()
+            .unwrap()
+            .port();
+        let mut opts = UdpProxyOptions::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)));
+        opts.set_peer_socket(peer_socket)
+            .set_association(assoc.clone());
+
+        let (mut control, server) = duplex(64);
+        control
+            .write_all(&[5, 1, 0, 5, 3, 0, 1, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+        let (proto, _, target) = Socks5ServerProtocol::accept_no_auth(server)
+            .await
+            .unwrap()
+            .read_command()
+            .await
+            .unwrap();
+        let relay =
+            tokio::spawn(async move { run_udp_proxy_with_options(proto, &target, opts).await });
+
+        let mut reply = [0u8; 12];
+        control.read_exact(&mut reply).await.unwrap();
+        let mut port = [0u8; 2];
+        port.copy_from_slice(&reply[10..]);
+        assert_eq!(reply[..10], [5, 0, 5, 0, 0, 1, 192, 0, 2, 1]);
+        assert_eq!(u16::from_be_bytes(port), relay_port);
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let relay_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), relay_port);
+        for (remote, payload) in [(&denied, &b"no"[..]), (&allowed, &b"yes"[..])] {
+            let mut packet = new_udp_header(remote.local_addr().unwrap()).unwrap();
+            packet.extend_from_slice(payload);
+            client.send_to(&packet, relay_addr).await.unwrap();
+        }
+        let mut buf = [0u8; 64];
+        let (len, _) = allowed.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"yes");
+        assert_eq!(assoc.stats().dropped_filtered, 1);
+
+        drop(control);
+        relay.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn udp_oversize_policies() {
+        let localhost = Some(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        let remote = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut packet = new_udp_header(remote.local_addr().unwrap()).unwrap();
+        let header_len = packet.len();
+        packet.extend_from_slice(&[7u8; 64]);
+
+        let inbound = udp_bind_random_port(localhost).unwrap();
+        let relay_addr = inbound.local_addr().unwrap().as_socket().unwrap();
+        let mut assoc = UdpAssociation::default();
+        assoc
+            .set_max_datagram_size(header_len + 16)
+            .set_oversize_policy(UdpOversizePolicy::Truncate);
+        let assoc = Arc::new(assoc);
+        tokio::spawn(transfer_udp_association(
+            inbound,
+            udp_bind_random_port(localhost).unwrap(),
+            UdpPeerBinding::default(),
+            assoc.clone(),
+        ));
+        client.send_to(&packet, relay_addr).await.unwrap();
+        let mut buf = [0u8; 128];
+        let len = remote.recv(&mut buf).await.unwrap();
+        assert_eq!(len, 16);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(assoc.stats().truncated, 1);
+
+        let inbound = udp_bind_random_port(localhost).unwrap();
+        let relay_addr = inbound.local_addr().unwrap().as_socket().unwrap();
+        let mut assoc = UdpAssociation::default();
+        assoc
+            .set_max_datagram_size(header_len + 16)
+            .set_oversize_policy(UdpOversizePolicy::CloseAssociation);
+        let relay = tokio::spawn(transfer_udp_association(
+            inbound,
+            udp_bind_random_port(localhost).unwrap(),
+            UdpPeerBinding::default(),
+            Arc::new(assoc),
+        ));
+        client.send_to(&packet, relay_addr).await.unwrap();
+        let closed = tokio::time::timeout(Duration::from_secs(1), relay).await;
+        assert!(matches!(
+            closed.unwrap().unwrap(),
+            Err(SocksServerError::UdpDatagramTooLarge { .. })
+        ));
+
+        // a remote peer can't close the association
+        let inbound = udp_bind_random_port(localhost).unwrap();
+        let relay_addr = inbound.local_addr().unwrap().as_socket().unwrap();
+        let mut assoc = UdpAssociation::default();
+        assoc
+            .set_max_datagram_size(header_len + 16)
+            .set_oversize_policy(UdpOversizePolicy::CloseAssociation);
+        let assoc = Arc::new(assoc);
+        let relay = tokio::spawn(transfer_udp_association(
+            inbound,
+            udp_bind_random_port(localhost).unwrap(),
+            UdpPeerBinding::default(),
+            assoc.clone(),
+        ));
+        client
+            .send_to(&packet[..header_len + 4], relay_addr)
+            .await
+            .unwrap();
+        let mut buf = [0u8; 128];
+        let (_, outbound) = remote.recv_from(&mut buf).await.unwrap();
+        remote.send_to(&[7u8; 64], outbound).await.unwrap();
+        remote.send_to(b"small", outbound).await.unwrap();
+        let len = client.recv(&mut buf).await.unwrap();
+        assert!(buf[..len].ends_with(b"small"));
+        assert_eq!(assoc.stats().dropped_oversize, 1);
+        assert!(!relay.is_finished());
+
+        // truncated below the header of a reply, which is dropped
+        let inbound = udp_bind_random_port(localhost).unwrap();
+        let relay_addr = inbound.local_addr().unwrap().as_socket().unwrap();
+        let outbound = udp_bind_random_port(localhost).unwrap();
+        let outbound_addr = outbound.local_addr().unwrap().as_socket().unwrap();
+        let mut assoc = UdpAssociation::default();
+        assoc
+            .set_max_datagram_size(header_len - 2)
+            .set_oversize_policy(UdpOversizePolicy::Truncate);
+        assert!(assoc.validate().is_err());
+        let assoc = Arc::new(assoc);
+        let relay = tokio::spawn(transfer_udp_association(
+            inbound,
+            outbound,
+            UdpPeerBinding::default(),
+            assoc.clone(),
+        ));
+        client.send_to(&packet, relay_addr).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        remote.send_to(b"reply", outbound_addr).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(assoc.stats().dropped_oversize, 1);
+        assert!(!relay.is_finished());
+    }
+
+    #[tokio::test]
+    async fn udp_association_validated() {
+        use crate::server::{run_udp_proxy_with_options, Socks5ServerProtocol, UdpProxyOptions};
+        use crate::ReplyError;
+        use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+        let (mut control, server) = duplex(64);
+        control
+            .write_all(&[5, 1, 0, 5, 3, 0, 1, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+        let (proto, _, target) = Socks5ServerProtocol::accept_no_auth(server)
+            .await
+            .unwrap()
+            .read_command()
+            .await
+            .unwrap();
+        let mut assoc = UdpAssociation::default();
+        assoc.set_max_datagram_size(4);
+        let mut opts = UdpProxyOptions::new(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        opts.set_association(Arc::new(assoc));
+        let err = run_udp_proxy_with_options(proto, &target, opts)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, SocksServerError::InvalidConfig(_)), "{}", err);
+        // the method selection, then the reply to the command
+        let mut reply = [0u8; 4];
+        control.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[3], ReplyError::GeneralFailure.as_u8());
+    }
+
+    #[tokio::test]
+    async fn udp_follow_switches_to_latest_source() {
+        let localhost = Some(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        let inbound = udp_bind_random_port(localhost).unwrap();
+        let outbound = udp_bind_random_port(localhost).unwrap();
+        let relay_addr = inbound.local_addr().unwrap().as_socket().unwrap();
+        tokio::spawn(transfer_udp_association(
+            inbound,
+            outbound,
+            UdpPeerBinding::Follow,
+            Arc::new(UdpAssociation::default()),
+        ));
+
+        let remote = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let first = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let second = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut buf = [0u8; 64];
+        let mut relay_out = None;
+        for (client, payload) in [(&first, b"first"), (&second, b"again")] {
+            let mut packet = new_udp_header(remote.local_addr().unwrap()).unwrap();
+            packet.extend_from_slice(payload);
+            client.send_to(&packet, relay_addr).await.unwrap();
+            let (len, from) = remote.recv_from(&mut buf).await.unwrap();
+            assert_eq!(&buf[..len], payload);
+            relay_out = Some(from);
+        }
+
+        // the replies go to the source of the latest datagram
+        remote.send_to(b"reply", relay_out.unwrap()).await.unwrap();
+        let len = second.recv(&mut buf).await.unwrap();
+        assert!(buf[..len].ends_with(b"reply"));
+    }
+
+    #[test]
+    fn address_family_mapping() {
+        let v4: SocketAddr = "192.0.2.1:53".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:53".parse().unwrap();
+        let mapped: SocketAddr = "[::ffff:192.0.2.1]:53".parse().unwrap();
+        let nat64: SocketAddr = "[64:ff9b::192.0.2.1]:53".parse().unwrap();
+
+        let ipv4_only = OutboundReach {
+            ipv4: Ipv4Reach::Direct,
+            ipv6: false,
+        };
+        assert_eq!(ipv4_only.map(mapped), Some(v4));
+        assert_eq!(ipv4_only.map(v6), None);
+        let dual_stack = OutboundReach {
+            ipv4: Ipv4Reach::Mapped,
+            ipv6: true,
+        };
+        assert_eq!(dual_stack.map(v4), Some(mapped));
+        assert_eq!(dual_stack.map(v6), Some(v6));
+        let prefix: Ipv6Addr = "64:ff9b::".parse().unwrap();
+        let behind_nat64 = OutboundReach {
+            ipv4: Ipv4Reach::Nat64(prefix.octets()[..12].try_into().unwrap()),
+            ipv6: true,
+        };
+        assert_eq!(behind_nat64.map(v4), Some(nat64));
+        let ipv6_only = OutboundReach {
+            ipv4: Ipv4Reach::Unreachable,
+            ipv6: true,
+        };
+        assert_eq!(ipv6_only.map(v4), None);
+
+        // replies come back from the addresses of the client's targets
+        let family = UdpAddressFamily::Ipv6Only {
+            nat64_prefix: Some(prefix),
+        };
+        assert_eq!(unmap_remote(nat64, family), v4);
+        assert_eq!(unmap_remote(mapped, UdpAddressFamily::DualStack), v4);
+        assert_eq!(unmap_remote(nat64, UdpAddressFamily::DualStack), nat64);
+        assert_eq!(unmap_remote(v6, family), v6);
+
+        let mut assoc = UdpAssociation::default();
+        assoc.set_address_family(UdpAddressFamily::Ipv6Only {
+            nat64_prefix: Some("64:ff9b::1:0".parse().unwrap()),
+        });
+        assert!(assoc.validate().is_err());
+        let localhost = Some(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        assert!(udp_bind_outbound(localhost, family).is_err());
+    }
+
+    #[tokio::test]
+    async fn udp_unreachable_family() {
+        let localhost = Some(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let inbound = udp_bind_random_port(localhost).unwrap();
+        let relay_addr = inbound.local_addr().unwrap().as_socket().unwrap();
+        let mut assoc = UdpAssociation::default();
+        assoc.set_address_family(UdpAddressFamily::Ipv4Only);
+        let assoc = Arc::new(assoc);
+        tokio::spawn(transfer_udp_association(
+            inbound,
+            udp_bind_outbound(None, UdpAddressFamily::Ipv4Only).unwrap(),
+            UdpPeerBinding::default(),
+            assoc.clone(),
+        ));
+
+        let mut packet = new_udp_header("[2001:db8::1]:53".parse::<SocketAddr>().unwrap()).unwrap();
+        packet.extend_from_slice(b"ping");
+        client.send_to(&packet, relay_addr).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let stats = assoc.stats();
+        assert_eq!(stats.dropped_unreachable_family, 1);
+        assert_eq!(stats.packets_to_remote, 0);
+    }
+
+    #[test]
+    fn nat_table_filters() {
+        let contacted: SocketAddr = "192.0.2.1:3478".parse().unwrap();
+        let same_ip: SocketAddr = "192.0.2.1:3479".parse().unwrap();
+        let stranger: SocketAddr = "198.51.100.7:3478".parse().unwrap();
+
+        let full_cone = UdpNatTable::new(UdpNatFilter::FullCone, Duration::from_secs(60));
+        full_cone.record_outbound(contacted);
+        assert!(full_cone.allow_inbound(stranger));
+
+        let restricted = UdpNatTable::new(UdpNatFilter::AddressRestricted, Duration::from_secs(60));
+        restricted.record_outbound(contacted);
+        assert!(restricted.allow_inbound(same_ip));
+        assert!(!restricted.allow_inbound(stranger));
+
+        let port_restricted =
+            UdpNatTable::new(UdpNatFilter::PortRestricted, Duration::from_secs(60));
+        port_restricted.record_outbound(contacted);
+        assert!(port_restricted.allow_inbound(contacted));
+        assert!(!port_restricted.allow_inbound(same_ip));
+    }
+
+    #[test]
+    fn nat_table_expires_idle_peers() {
+        let peer: SocketAddr = "192.0.2.1:3478".parse().unwrap();
+        let nat = UdpNatTable::new(UdpNatFilter::PortRestricted, Duration::ZERO);
+        nat.record_outbound(peer);
+        assert!(!nat.allow_inbound(peer));
+        assert_eq!(nat.expire(), 1);
+        assert!(nat.is_empty());
+    }
+}
@@ -0,0 +1,314 @@
+use super::udp::{handle_udp_responses, relay_udp_request, udp_bind_outbound, OutboundReach};
+use super::{
+    states, try_notify, wait_on_tcp, ErrorContext, Socks5ServerProtocol, SocksServerError,
+    UdpAssociation,
+};
+use crate::util::target_addr::TargetAddr;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::try_join;
+
+// datagrams queued for an association before they are dropped
+const QUEUE_LEN: usize = 64;
+
+type Datagram = (SocketAddr, Vec<u8>);
+
+/// A UDP relay socket on a single well-known port shared by all the associations, for
+/// deployments behind firewalls which only open one UDP port.
+///
+/// The datagrams are dispatched by client address: a new association takes the address
+/// its client declared in the ASSOCIATE request if it gave a port, or else the first
+/// unknown source address of its client's IP, like `UdpPeerBinding::LockFirst`. Run
+/// [`UdpSharedRelay::run`] in a task, and relay the associations on it with
+/// `UdpProxyOptions::set_shared_relay`.
+#[derive(Debug)]
+pub struct UdpSharedRelay {
+    socket: UdpSocket,
+    routes: Mutex<Routes>,
+}
+
+#[derive(Debug, Default)]
+struct Routes {
+    bound: HashMap<SocketAddr, mpsc::Sender<Datagram>>,
+    /// associations waiting for their first datagram, by client IP and in order
+    pending: HashMap<IpAddr, VecDeque<mpsc::Sender<Datagram>>>,
+}
+
+impl UdpSharedRelay {
+    pub async fn bind(addr: SocketAddr) -> io::Result<Self> {
+        let socket = UdpSocket::bind(addr).await?;
+        info!("Shared UDP relay @ {}", socket.local_addr()?);
+        Ok(UdpSharedRelay {
+            socket,
+            routes: Mutex::new(Routes::default()),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Dispatch the datagrams received to their associations, forever.
+    pub async fn run(&self) {
+        let mut buf = vec![0u8; u16::MAX as usize + 1];
+        loop {
+            let (size, from) = match self.socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(err) => {
+                    debug!("shared UDP relay receive error: {}", err);
+                    continue;
+                }
+            };
+            let Some(tx) = self.route(from) else {
+                debug!("Discard UDP packet from {}, no association", from);
+                continue;
+            };
+            if tx.try_send((from, buf[..size].to_vec())).is_err() {
+                debug!("Discard UDP packet from {}, association busy", from);
+            }
+        }
+    }
+
+    fn route(&self, from: SocketAddr) -> Option<mpsc::Sender<Datagram>> {
+        // IPv4 clients of a dual-stack relay show up as mapped addresses
+        let from = SocketAddr::new(from.ip().to_canonical(), from.port());
+        let mut routes = self.routes.lock().unwrap();
+        if let Some(tx) = routes.bound.get(&from) {
+            return Some(tx.clone());
+        }
+        let pending = routes.pending.get_mut(&from.ip().to_canonical())?;
+        let tx = loop {
+            let tx = pending.pop_front()?;
+            if !tx.is_closed() {
+                break tx;
+            }
+        };
+        debug!("UDP association bound to client {}", from);
+        routes.bound.insert(from, tx.clone());
+        Some(tx)
+    }
+
+    /// Register an association of a client from `client_ip`, which declared it would
+    /// send from `port`, or `0` if it didn't tell.
+    fn register(&self, client_ip: IpAddr, port: u16) -> Registration<'_> {
+        let (tx, rx) = mpsc::channel(QUEUE_LEN);
+        let mut routes = self.routes.lock().unwrap();
+        if port != 0 {
+            let client = SocketAddr::new(client_ip.to_canonical(), port);
+            routes.bound.insert(client, tx.clone());
+        } else {
+            routes
+                .pending
+                .entry(client_ip.to_canonical())
+                .or_default()
+                .push_back(tx.clone());
+        }
+        Registration {
+            relay: self,
+            tx,
+            rx,
+        }
+    }
+
+    /// How many associations are relayed, waiting for their first datagram or not.
+    pub fn associations(&self) -> usize {
+        let routes = self.routes.lock().unwrap();
+        routes.bound.len() + routes.pending.values().map(VecDeque::len).sum::<usize>()
+    }
+}
+
+struct Registration<'a> {
+    relay: &'a UdpSharedRelay,
+    tx: mpsc::Sender<Datagram>,
+    rx: mpsc::Receiver<Datagram>,
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        let mut routes = self.relay.routes.lock().unwrap();
+        routes.bound.retain(|_, tx| !tx.same_channel(&self.tx));
+        routes.pending.retain(|_, pending| {
+            pending.retain(|tx| !tx.same_channel(&self.tx));
+            !pending.is_empty()
+        });
+    }
+}
+
+/// Handle the associate command of a client from `client_ip`, which declared `addr`, on
+/// the shared relay.
+pub(super) async fn run_udp_proxy_shared<T>(
+    proto: Socks5ServerProtocol<T, states::CommandRead>,
+    addr: &TargetAddr,
+    relay: &UdpSharedRelay,
+    client_ip: IpAddr,
+    reply_ip: IpAddr,
+    outbound_bind_ip: Option<IpAddr>,
+    assoc: Arc<UdpAssociation>,
+) -> Result<T, SocksServerError>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let outbound = try_notify!(
+        proto,
+        udp_bind_outbound(outbound_bind_ip, assoc.address_family())
+            .and_then(|socket| UdpSocket::from_std(socket.into()))
+            .err_when("binding outbound udp socket")
+    );
+    let reach = try_notify!(
+        proto,
+        OutboundReach::of(&outbound, assoc.address_family()).err_when("udp outbound local addr")
+    );
+    let relay_port = try_notify!(
+        proto,
+        relay.local_addr().err_when("getting shared relay addr")
+    )
+    .port();
+
+    let mut registration = relay.register(client_ip, addr.port());
+    let mut inner = proto
+        .reply_success(SocketAddr::new(reply_ip, relay_port))
+        .await?;
+
+    let req_fut = async {
+        while let Some((from, datagram)) = registration.rx.recv().await {
+            assoc.set_client_addr(from);
+            if let Err(err) = relay_udp_request(&outbound, reach, &assoc, &datagram).await {
+                if let SocksServerError::UdpDatagramTooLarge { .. } = err {
+                    return Err(err);
+                }
+                debug!("error in handling udp request: {err}");
+            }
+        }
+        Err::<(), _>(SocksServerError::Bug("shared UDP relay channel closed"))
+    };
+    let res_fut = handle_udp_responses(&relay.socket, &outbound, &assoc);
+    let tcp_fut = wait_on_tcp(&mut inner);
+    match try_join!(req_fut, res_fut, tcp_fut) {
+        Ok(_) => warn!("unreachable"),
+        Err(SocksServerError::EOF) => debug!("EOF on controlling TCP stream, closed UDP proxy"),
+        Err(err) => warn!("while UDP proxying: {err}"),
+    }
+    Ok(inner)
+}
+
+#[cfg(test)]
+mod test {
+    use super::UdpSharedRelay;
+    use crate::new_udp_header;
+    use crate::server::{run_udp_proxy_with_options, Socks5ServerProtocol, UdpProxyOptions};
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use std::sync::Arc;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+    use tokio::net::UdpSocket;
+
+    #[tokio::test]
+    async fn associations_share_one_port() {
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let relay = Arc::new(
+            UdpSharedRelay::bind(SocketAddr::new(localhost, 0))
+                .await
+                .unwrap(),
+        );
+        let relay_addr = relay.local_addr().unwrap();
+        tokio::spawn({
+            let relay = relay.clone();
+            async move { relay.run().await }
+        });
+        let remote = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let mut controls = vec![];
+        for _ in 0..2 {
+            let (mut control, server) = duplex(64);
+            control
+                .write_all(&[5, 1, 0, 5, 3, 0, 1, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+            let (proto, _, target) = Socks5ServerProtocol::accept_no_auth(server)
+                .await
+                .unwrap()
+                .read_command()
+                .await
+                .unwrap();
+            let mut opts = UdpProxyOptions::new(localhost);
+            opts.set_shared_relay(relay.clone(), localhost);
+            tokio::spawn(async move { run_udp_proxy_with_options(proto, &target, opts).await });
+
+            let mut reply = [0u8; 12];
+            control.read_exact(&mut reply).await.unwrap();
+            assert_eq!(
+                u16::from_be_bytes([reply[10], reply[11]]),
+                relay_addr.port()
+            );
+            controls.push(control);
+        }
+        assert_eq!(relay.associations(), 2);
+
+        let mut buf = [0u8; 64];
+        for name in [&b"first"[..], b"second"] {
+            let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let mut packet = new_udp_header(remote.local_addr().unwrap()).unwrap();
+            packet.extend_from_slice(name);
+            client.send_to(&packet, relay_addr).await.unwrap();
+
+            let (len, outbound) = remote.recv_from(&mut buf).await.unwrap();
+            assert_eq!(&buf[..len], name);
+            remote.send_to(b"reply", outbound).await.unwrap();
+            let (len, from) = client.recv_from(&mut buf).await.unwrap();
+            assert_eq!(from, relay_addr);
+            assert!(buf[..len].ends_with(b"reply"));
+        }
+
+        drop(controls);
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert_eq!(relay.associations(), 0);
+    }
+
+    #[tokio::test]
+    async fn association_takes_declared_port() {
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let relay = Arc::new(
+            UdpSharedRelay::bind(SocketAddr::new(localhost, 0))
+                .await
+                .unwrap(),
+        );
+        let relay_addr = relay.local_addr().unwrap();
+        tokio::spawn({
+            let relay = relay.clone();
+            async move { relay.run().await }
+        });
+        let remote = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let other = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let (mut control, server) = duplex(64);
+        let mut request = vec![5, 1, 0, 5, 3, 0, 1, 127, 0, 0, 1];
+        request.extend_from_slice(&client.local_addr().unwrap().port().to_be_bytes());
+        control.write_all(&request).await.unwrap();
+        let (proto, _, target) = Socks5ServerProtocol::accept_no_auth(server)
+            .await
+            .unwrap()
+            .read_command()
+            .await
+            .unwrap();
+        let mut opts = UdpProxyOptions::new(localhost);
+        opts.set_shared_relay(relay.clone(), localhost);
+        tokio::spawn(async move { run_udp_proxy_with_options(proto, &target, opts).await });
+        let mut reply = [0u8; 12];
+        control.read_exact(&mut reply).await.unwrap();
+
+        let mut buf = [0u8; 64];
+        for (socket, name) in [(&other, &b"other"[..]), (&client, b"client")] {
+            let mut packet = new_udp_header(remote.local_addr().unwrap()).unwrap();
+            packet.extend_from_slice(name);
+            socket.send_to(&packet, relay_addr).await.unwrap();
+        }
+        // the datagram of the other socket of the same IP is dropped
+        let (len, _) = remote.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"client");
+    }
+}
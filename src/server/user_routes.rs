@@ -0,0 +1,193 @@
+use super::{ConnectOptions, DirectDialer, EgressPool, EgressStrategy, Route, UpstreamDialer};
+use crate::util::proxy_url::ProxyUrl;
+use crate::ConfigError;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+
+/// How the sessions of a user reach their targets, see [`UserRoutes`].
+#[derive(Debug, Clone)]
+pub enum UserRoute {
+    /// Connect directly, with the egress settings of the server.
+    Direct,
+    /// Connect directly from one of these source IPs, in turn among those of the
+    /// family of the target.
+    SourceIp(Arc<EgressPool>),
+    /// Connect through a further SOCKS5 proxy.
+    Upstream(ProxyUrl),
+    /// Refuse the connections.
+    Block,
+}
+
+/// The egress of the authenticated users: the source IPs or the upstream proxy each one
+/// always exits through.
+///
+/// Parsed from a file with one user per line, `*` being the route of the users not
+/// listed and of the unauthenticated sessions:
+///
+/// ```text
+/// # user  route
+/// alice   ip 203.0.113.5 2001:db8::5
+/// bob     upstream socks5h://proxy-b.example:1080
+/// carol   block
+/// *       direct
+/// ```
+///
+/// Share it behind an `Arc` and call [`UserRoutes::reload`] on SIGHUP (see
+/// `run_with_signals`): the sessions opened afterwards use the new routes.
+#[derive(Debug, Default)]
+pub struct UserRoutes {
+    routes: RwLock<HashMap<String, UserRoute>>,
+}
+
+impl UserRoutes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn parse(routes: &str) -> Result<Self, ConfigError> {
+        Ok(UserRoutes {
+            routes: RwLock::new(parse_routes(routes)?),
+        })
+    }
+
+    /// Replace the routes with the ones parsed from `routes`, keeping the current ones
+    /// if it is invalid.
+    pub fn reload(&self, routes: &str) -> Result<(), ConfigError> {
+        let routes = parse_routes(routes)?;
+        info!("Reloaded the routes of {} users", routes.len());
+        *self.routes.write().unwrap() = routes;
+        Ok(())
+    }
+
+    pub fn insert<S: Into<String>>(&self, user: S, route: UserRoute) -> &Self {
+        self.routes.write().unwrap().insert(user.into(), route);
+        self
+    }
+
+    /// The route of `user`, or the `*` one.
+    pub fn get(&self, user: Option<&str>) -> Option<UserRoute> {
+        let routes = self.routes.read().unwrap();
+        user.and_then(|user| routes.get(user))
+            .or_else(|| routes.get("*"))
+            .cloned()
+    }
+
+    /// The dialer route of a session of `user`, connecting directly with `opts`.
+    ///
+    /// `None` when no route applies, leaving the choice to the other routing, e.g. a
+    /// `DialerRoutes` per target.
+    pub fn route(&self, user: Option<&str>, opts: &ConnectOptions) -> Option<Route> {
+        let route = match self.get(user)? {
+            UserRoute::Direct => Route::dialer(DirectDialer::new(opts.clone())),
+            UserRoute::SourceIp(pool) => {
+                let mut opts = opts.clone();
+                opts.set_egress_pool(pool);
+                Route::dialer(DirectDialer::new(opts))
+            }
+            UserRoute::Upstream(proxy) => Route::dialer(UpstreamDialer::new(proxy)),
+            UserRoute::Block => Route::Block,
+        };
+        Some(route)
+    }
+}
+
+fn parse_routes(routes: &str) -> Result<HashMap<String, UserRoute>, ConfigError> {
+    let mut parsed = HashMap::new();
+    let mut err = ConfigError::new();
+    for (i, line) in routes.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut fields = line.split_whitespace();
+        let Some(user) = fields.next() else {
+            continue;
+        };
+        let route = match fields.next() {
+            Some("direct") => Some(UserRoute::Direct),
+            Some("block") => Some(UserRoute::Block),
+            Some("ip") => {
+                let ips: Result<Vec<IpAddr>, _> = fields.map(str::parse).collect();
+                match ips {
+                    Ok(ips) if !ips.is_empty() => Some(UserRoute::SourceIp(Arc::new(
+                        EgressPool::new(ips, EgressStrategy::RoundRobin),
+                    ))),
+                    Ok(_) => {
+                        err.check(false, format!("line {}: no IP for `{}`", i + 1, user));
+                        None
+                    }
+                    Err(e) => {
+                        err.check(false, format!("line {}: invalid IP: {}", i + 1, e));
+                        None
+                    }
+                }
+            }
+            Some("upstream") => match fields.next().map(ProxyUrl::parse) {
+                Some(Ok(proxy)) => Some(UserRoute::Upstream(proxy)),
+                Some(Err(e)) => {
+                    err.check(false, format!("line {}: invalid upstream: {}", i + 1, e));
+                    None
+                }
+                None => {
+                    err.check(false, format!("line {}: no upstream for `{}`", i + 1, user));
+                    None
+                }
+            },
+            Some(other) => {
+                err.check(false, format!("line {}: unknown route `{}`", i + 1, other));
+                None
+            }
+            None => {
+                err.check(false, format!("line {}: no route for `{}`", i + 1, user));
+                None
+            }
+        };
+        if let Some(route) = route {
+            err.check(
+                parsed.insert(user.to_owned(), route).is_none(),
+                format!("line {}: `{}` already has a route", i + 1, user),
+            );
+        }
+    }
+    err.into_result().map(|()| parsed)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{UserRoute, UserRoutes};
+    use crate::server::ConnectOptions;
+
+    #[test]
+    fn routes_per_user() {
+        let routes = UserRoutes::parse(
+            "# user route\n\
+             alice ip 203.0.113.5 2001:db8::5\n\
+             bob   upstream socks5h://proxy-b.example:1080 # exits in DE\n\
+             carol block\n",
+        )
+        .unwrap();
+        assert!(
+            matches!(routes.get(Some("alice")), Some(UserRoute::SourceIp(pool)) if pool.ips().len() == 2)
+        );
+        assert!(
+            matches!(routes.get(Some("bob")), Some(UserRoute::Upstream(proxy)) if proxy.host() == "proxy-b.example")
+        );
+        assert!(routes.get(Some("dave")).is_none());
+        assert!(routes.route(None, &ConnectOptions::new()).is_none());
+        let opts = ConnectOptions::new();
+        let route = |user| format!("{:?}", routes.route(Some(user), &opts).unwrap());
+        assert_eq!(route("carol"), "Block");
+        assert_eq!(route("alice"), "Dial");
+
+        let err = routes
+            .reload("alice ip nope\nbob upstream http://x\ncarol\ndave fly\ncarol block\ncarol direct\n")
+            .unwrap_err();
+        assert_eq!(err.issues().len(), 5, "{:?}", err.issues());
+        assert!(err.issues()[0].starts_with("line 1: invalid IP"));
+        assert_eq!(err.issues()[4], "line 6: `carol` already has a route");
+        // still the previous routes
+        assert!(matches!(routes.get(Some("carol")), Some(UserRoute::Block)));
+
+        routes.reload("* direct\n").unwrap();
+        assert!(matches!(routes.get(Some("carol")), Some(UserRoute::Direct)));
+        assert!(matches!(routes.get(None), Some(UserRoute::Direct)));
+    }
+}
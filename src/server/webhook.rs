@@ -0,0 +1,351 @@
+use crate::ConfigError;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE};
+use reqwest::{Client, Url};
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::time::{sleep, timeout, Instant};
+
+// a stuck endpoint can't hold the batches back longer than this
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An auth or session event posted by a [`WebhookNotifier`], as JSON with an `event`
+/// field naming it, e.g. `{"event":"auth_failure","client":"192.0.2.1:50000",...}`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum WebhookEvent {
+    AuthSuccess {
+        client: SocketAddr,
+        username: Option<String>,
+    },
+    AuthFailure {
+        client: SocketAddr,
+        username: Option<String>,
+        reason: String,
+    },
+    SessionOpen {
+        /// See `SessionId`.
+        session: Option<u64>,
+        client: SocketAddr,
+        username: Option<String>,
+        target: String,
+    },
+    SessionClose {
+        session: Option<u64>,
+        client: SocketAddr,
+        username: Option<String>,
+        target: String,
+        /// The bytes sent by the client and by the target.
+        bytes_sent: u64,
+        bytes_received: u64,
+        duration_ms: u64,
+        /// See `CloseReason`.
+        reason: String,
+    },
+}
+
+#[derive(Serialize)]
+struct Timestamped {
+    /// Milliseconds since the UNIX epoch.
+    timestamp: u64,
+    #[serde(flatten)]
+    event: WebhookEvent,
+}
+
+/// Where and how a [`WebhookNotifier`] posts the events.
+#[derive(Debug, Clone)]
+pub struct WebhookConfig {
+    url: Url,
+    headers: HeaderMap,
+    batch_size: usize,
+    flush_interval: Duration,
+    retries: u32,
+    backoff: Duration,
+    capacity: usize,
+}
+
+impl WebhookConfig {
+    /// Post to `url`, an `http://` or `https://` endpoint, HTTPS being verified against
+    /// the webpki roots.
+    pub fn new(url: &str) -> Result<Self, ConfigError> {
+        let mut err = ConfigError::new();
+        // the URL parser would silently drop them
+        err.check(!url.contains(['\r', '\n']), "webhook URL with a line break");
+        let parsed = Url::parse(url);
+        if let Err(parse_err) = &parsed {
+            err.check(false, format!("invalid webhook URL: {}", parse_err));
+        }
+        if let Ok(parsed) = &parsed {
+            err.check(
+                matches!(parsed.scheme(), "http" | "https"),
+                format!(
+                    "webhook URL scheme `{}` isn't http or https",
+                    parsed.scheme()
+                ),
+            );
+            err.check(parsed.has_host(), "webhook URL without host");
+        }
+        err.into_result().map(|()| WebhookConfig {
+            url: parsed.unwrap(),
+            headers: HeaderMap::new(),
+            batch_size: 100,
+            flush_interval: Duration::from_secs(1),
+            retries: 3,
+            backoff: Duration::from_millis(500),
+            capacity: 10_000,
+        })
+    }
+
+    /// Send this header with each request, e.g. `Authorization`. Fails on an invalid name,
+    /// or a value with a line break or another control character.
+    pub fn set_header(&mut self, name: &str, value: &str) -> Result<&mut Self, ConfigError> {
+        let mut err = ConfigError::new();
+        let name = HeaderName::from_bytes(name.as_bytes());
+        err.check(name.is_ok(), "invalid webhook header name");
+        let value = HeaderValue::from_str(value);
+        err.check(value.is_ok(), "invalid webhook header value");
+        err.into_result()?;
+        let mut value = value.unwrap();
+        // kept out of the debug output, e.g. a token
+        value.set_sensitive(true);
+        self.headers.append(name.unwrap(), value);
+        Ok(self)
+    }
+
+    /// Post up to `size` events per request, 100 by default.
+    pub fn set_batch_size(&mut self, size: usize) -> &mut Self {
+        self.batch_size = size.max(1);
+        self
+    }
+
+    /// Post the events waiting at most `interval` after the first, 1 second by default.
+    pub fn set_flush_interval(&mut self, interval: Duration) -> &mut Self {
+        self.flush_interval = interval;
+        self
+    }
+
+    /// Retry a failed request up to `retries` times, waiting `backoff` first and twice
+    /// as long each time, 3 times from 500 ms by default. The batch is dropped after.
+    pub fn set_retries(&mut self, retries: u32, backoff: Duration) -> &mut Self {
+        self.retries = retries;
+        self.backoff = backoff;
+        self
+    }
+
+    /// Keep up to `capacity` events waiting, dropping the next ones, 10000 by default.
+    pub fn set_capacity(&mut self, capacity: usize) -> &mut Self {
+        self.capacity = capacity.max(1);
+        self
+    }
+}
+
+/// Posts [`WebhookEvent`]s to an HTTP endpoint in the background, as JSON arrays of
+/// batched events, e.g. for billing or a SIEM.
+///
+/// Notifying never waits: when the endpoint can't keep up the events are dropped and
+/// counted. The background task ends once every clone is dropped, after posting the
+/// events left.
+#[derive(Debug, Clone)]
+pub struct WebhookNotifier {
+    tx: mpsc::Sender<Timestamped>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl WebhookNotifier {
+    /// Start posting to the endpoint of `config`, on the current runtime.
+    pub fn spawn(config: WebhookConfig) -> Self {
+        let (tx, rx) = mpsc::channel(config.capacity);
+        let dropped = Arc::new(AtomicU64::new(0));
+        tokio::spawn(post_events(config, rx, dropped.clone()));
+        WebhookNotifier { tx, dropped }
+    }
+
+    /// Queue `event`, returning `false` if it was dropped.
+    pub fn notify(&self, event: WebhookEvent) -> bool {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        match self.tx.try_send(Timestamped { timestamp, event }) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) | Err(TrySendError::Closed(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+        }
+    }
+
+    /// How many events were dropped so far, queue full or endpoint failing.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+async fn post_events(
+    config: WebhookConfig,
+    mut rx: mpsc::Receiver<Timestamped>,
+    dropped: Arc<AtomicU64>,
+) {
+    let client = match Client::builder().timeout(REQUEST_TIMEOUT).build() {
+        Ok(client) => client,
+        Err(err) => {
+            error!("webhook client error = {:?}", err);
+            return;
+        }
+    };
+    let mut batch = Vec::with_capacity(config.batch_size);
+    while let Some(event) = rx.recv().await {
+        batch.push(event);
+        let deadline = Instant::now() + config.flush_interval;
+        while batch.len() < config.batch_size {
+            match timeout(
+                deadline.saturating_duration_since(Instant::now()),
+                rx.recv(),
+            )
+            .await
+            {
+                Ok(Some(event)) => batch.push(event),
+                Ok(None) | Err(_) => break,
+            }
+        }
+        let body = match serde_json::to_vec(&batch) {
+            Ok(body) => body,
+            Err(err) => {
+                error!("webhook events serialization error = {:?}", err);
+                Vec::new()
+            }
+        };
+        if body.is_empty() || !post_with_retries(&client, &config, body).await {
+            dropped.fetch_add(batch.len() as u64, Ordering::Relaxed);
+        }
+        batch.clear();
+    }
+}
+
+async fn post_with_retries(client: &Client, config: &WebhookConfig, body: Vec<u8>) -> bool {
+    let mut backoff = config.backoff;
+    for attempt in 0..=config.retries {
+        if attempt > 0 {
+            sleep(backoff).await;
+            backoff *= 2;
+        }
+        let request = client
+            .post(config.url.clone())
+            .headers(config.headers.clone())
+            .header(CONTENT_TYPE, "application/json")
+            .body(body.clone());
+        match request.send().await {
+            Ok(response) if response.status().is_success() => return true,
+            Ok(response) => debug!("webhook answered {}", response.status()),
+            Err(err) => debug!("webhook request failed: {}", err.without_url()),
+        }
+    }
+    // without the credentials or query the URL may carry
+    warn!(
+        "webhook {}{} failed {} times, dropping events",
+        config.url.origin().ascii_serialization(),
+        config.url.path(),
+        config.retries + 1
+    );
+    false
+}
+
+#[cfg(all(test, feature = "webhook"))]
+mod test {
+    use super::{WebhookConfig, WebhookEvent, WebhookNotifier};
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Read a request and return its body.
+    async fn read_request(stream: &mut TcpStream) -> String {
+        let mut request = vec![];
+        let mut buf = [0u8; 4096];
+        loop {
+            let n = stream.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..n]);
+            let text = String::from_utf8_lossy(&request);
+            if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                // the header names are case-insensitive
+                let header = |name: &str| {
+                    head.lines().find_map(|line| {
+                        let (n, value) = line.split_once(": ")?;
+                        n.eq_ignore_ascii_case(name).then_some(value)
+                    })
+                };
+                let len: usize = header("Content-Length").unwrap().parse().unwrap();
+                if body.len() >= len {
+                    assert!(head.starts_with("POST /events HTTP/1.1\r\n"));
+                    assert_eq!(header("Authorization"), Some("Bearer t0k3n"));
+                    return body.to_owned();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn parse_url() {
+        assert!(WebhookConfig::new("http://siem.example/hook").is_ok());
+        assert!(WebhookConfig::new("https://siem.example/hook").is_ok());
+        assert!(WebhookConfig::new("http://[::1]:8080").is_ok());
+        assert!(WebhookConfig::new("ftp://siem.example/").is_err());
+        assert!(WebhookConfig::new("https://siem.example:x/").is_err());
+        let err = WebhookConfig::new("http://siem.example/\r\nX-Injected: 1").unwrap_err();
+        assert_eq!(err.issues().len(), 1);
+
+        let mut config = WebhookConfig::new("https://siem.example/hook").unwrap();
+        assert!(config.set_header("Authorization", "Bearer t0k3n").is_ok());
+        assert!(config.set_header("X-Tag", "a\r\nX-Injected: 1").is_err());
+        assert!(config.set_header("X Tag", "a").is_err());
+        assert!(!format!("{:?}", config).contains("t0k3n"));
+    }
+
+    #[tokio::test]
+    async fn batches_and_retries() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/events", listener.local_addr().unwrap());
+        let mut config = WebhookConfig::new(&url).unwrap();
+        config
+            .set_header("Authorization", "Bearer t0k3n")
+            .unwrap()
+            .set_flush_interval(Duration::from_millis(50))
+            .set_retries(1, Duration::from_millis(10));
+        let notifier = WebhookNotifier::spawn(config);
+
+        let client = "192.0.2.1:50000".parse().unwrap();
+        for username in ["alice", "bob"] {
+            assert!(notifier.notify(WebhookEvent::AuthSuccess {
+                client,
+                username: Some(username.to_owned()),
+            }));
+        }
+
+        // the first attempt fails, the retry carries the same batch
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let first = read_request(&mut stream).await;
+        stream
+            .write_all(b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n")
+            .await
+            .unwrap();
+        drop(stream);
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let retried = read_request(&mut stream).await;
+        stream
+            .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+            .await
+            .unwrap();
+        assert_eq!(first, retried);
+
+        let events: serde_json::Value = serde_json::from_str(&retried).unwrap();
+        let events = events.as_array().unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["event"], "auth_success");
+        assert_eq!(events[0]["client"], "192.0.2.1:50000");
+        assert_eq!(events[1]["username"], "bob");
+        assert!(events[0]["timestamp"].as_u64().unwrap() > 0);
+        assert_eq!(notifier.dropped(), 0);
+    }
+}
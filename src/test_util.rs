@@ -0,0 +1,237 @@
+//! Helpers to test SOCKS5 clients and servers in memory, without binding ports.
+//!
+//! [`spawn_server`] runs a server handler on one end of an in-memory stream and returns
+//! the other end to the test, which can drive it with a real [`crate::client::Socks5Stream`]
+//! or with raw bytes built by [`ClientScript`], malformed ones included. The `expect_*`
+//! functions read and check the server's replies.
+
+use crate::consts;
+use crate::util::target_addr::{read_address, TargetAddr};
+use crate::{ReplyError, Socks5Command};
+use std::future::Future;
+use tokio::io::{duplex, AsyncRead, AsyncReadExt, DuplexStream};
+
+/// The buffer size of the in-memory streams, large enough for any handshake.
+const PIPE_SIZE: usize = 64 * 1024;
+
+/// Two connected in-memory streams, the client's end and the server's end.
+pub fn socks_pair() -> (DuplexStream, DuplexStream) {
+    duplex(PIPE_SIZE)
+}
+
+/// Run `server` on the server's end of a new in-memory connection, in a task spawned on
+/// the current runtime, returning the client's end.
+pub fn spawn_server<F, Fut>(server: F) -> DuplexStream
+where
+    F: FnOnce(DuplexStream) -> Fut,
+    Fut: Future + Send + 'static,
+{
+    let (client, server_end) = socks_pair();
+    let session = server(server_end);
+    tokio::spawn(async move {
+        session.await;
+    });
+    client
+}
+
+/// The bytes a client sends, built step by step, with helpers to break them.
+///
+/// ```
+/// # use fast_socks5::test_util::ClientScript;
+/// # use fast_socks5::Socks5Command;
+/// # use fast_socks5::util::target_addr::TargetAddr;
+/// let target = TargetAddr::Domain("example.com".to_owned(), 80);
+/// let bytes = ClientScript::new()
+///     .greeting(&[0])
+///     .request(Socks5Command::TCPConnect, &target)
+///     .truncate(6)
+///     .into_bytes();
+/// assert_eq!(bytes, [5, 1, 0, 5, 1, 0]);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ClientScript {
+    bytes: Vec<u8>,
+}
+
+impl ClientScript {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The version and the auth methods offered.
+    pub fn greeting(mut self, methods: &[u8]) -> Self {
+        self.bytes
+            .extend_from_slice(&[consts::SOCKS5_VERSION, methods.len() as u8]);
+        self.bytes.extend_from_slice(methods);
+        self
+    }
+
+    /// A username/password subnegotiation.
+    pub fn password(mut self, username: &str, password: &str) -> Self {
+        self.bytes
+            .extend_from_slice(&[consts::SOCKS5_PASSWORD_AUTH_VERSION, username.len() as u8]);
+        self.bytes.extend_from_slice(username.as_bytes());
+        self.bytes.push(password.len() as u8);
+        self.bytes.extend_from_slice(password.as_bytes());
+        self
+    }
+
+    /// A request for `target`.
+    ///
+    /// # Panics
+    ///
+    /// If the domain of `target` is too long to be sent.
+    pub fn request(mut self, cmd: Socks5Command, target: &TargetAddr) -> Self {
+        self.bytes.extend_from_slice(&[
+            consts::SOCKS5_VERSION,
+            cmd.as_u8(),
+            consts::SOCKS5_RESERVED,
+        ]);
+        self.bytes
+            .extend(target.to_be_bytes().expect("domain too long"));
+        self
+    }
+
+    /// Arbitrary bytes.
+    pub fn raw(mut self, bytes: &[u8]) -> Self {
+        self.bytes.extend_from_slice(bytes);
+        self
+    }
+
+    /// Overwrite the byte at `index`, e.g. the version or the address type.
+    ///
+    /// # Panics
+    ///
+    /// If `index` is past the bytes scripted so far.
+    pub fn set_byte(mut self, index: usize, value: u8) -> Self {
+        self.bytes[index] = value;
+        self
+    }
+
+    /// Keep only the first `len` bytes, as a client disconnecting midway.
+    pub fn truncate(mut self, len: usize) -> Self {
+        self.bytes.truncate(len);
+        self
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+/// Read the server's method selection, check it chose `method`.
+///
+/// # Panics
+///
+/// If the reply can't be read or is another method.
+pub async fn expect_method<S: AsyncRead + Unpin>(stream: &mut S, method: u8) {
+    let mut reply = [0u8; 2];
+    stream
+        .read_exact(&mut reply)
+        .await
+        .expect("reading the method selection");
+    assert_eq!(reply, [consts::SOCKS5_VERSION, method], "method selection");
+}
+
+/// Read the server's reply to the username/password subnegotiation, check whether it
+/// accepted the credentials.
+///
+/// # Panics
+///
+/// If the reply can't be read or doesn't match.
+pub async fn expect_password_reply<S: AsyncRead + Unpin>(stream: &mut S, accepted: bool) {
+    let mut reply = [0u8; 2];
+    stream
+        .read_exact(&mut reply)
+        .await
+        .expect("reading the password reply");
+    assert_eq!(reply[0], consts::SOCKS5_PASSWORD_AUTH_VERSION);
+    assert_eq!(
+        reply[1] == consts::SOCKS5_REPLY_SUCCEEDED,
+        accepted,
+        "password reply {:?}",
+        reply
+    );
+}
+
+/// Read the server's reply to a request, check it's `expected` (`None` for success), and
+/// return its BND address.
+///
+/// # Panics
+///
+/// If the reply can't be read or doesn't match.
+pub async fn expect_reply<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    expected: Option<ReplyError>,
+) -> TargetAddr {
+    let mut header = [0u8; 4];
+    stream
+        .read_exact(&mut header)
+        .await
+        .expect("reading the reply");
+    assert_eq!(header[0], consts::SOCKS5_VERSION, "reply version");
+    let expected = expected.map_or(consts::SOCKS5_REPLY_SUCCEEDED, ReplyError::as_u8);
+    assert_eq!(header[1], expected, "reply code");
+    read_address(stream, header[3])
+        .await
+        .expect("reading the reply address")
+}
+
+/// Check the server closed the connection, after sending nothing more.
+///
+/// # Panics
+///
+/// If there is more data.
+pub async fn expect_closed<S: AsyncRead + Unpin>(stream: &mut S) {
+    let mut rest = vec![];
+    let _ = stream.read_to_end(&mut rest).await;
+    assert!(rest.is_empty(), "unexpected data {:?}", rest);
+}
+
+#[cfg(test)]
+mod test {
+    use super::{expect_closed, expect_method, expect_reply, spawn_server, ClientScript};
+    use crate::client::{Config, Socks5Stream};
+    use crate::server::Socks5ServerProtocol;
+    use crate::util::target_addr::TargetAddr;
+    use crate::{ReplyError, Socks5Command};
+    use tokio::io::AsyncWriteExt;
+
+    async fn server(stream: tokio::io::DuplexStream) {
+        let Ok(proto) = Socks5ServerProtocol::accept_no_auth(stream).await else {
+            return;
+        };
+        if let Ok((proto, _, _)) = proto.read_command().await {
+            let _ = proto.reply_success("192.0.2.1:80".parse().unwrap()).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn in_memory_sessions() {
+        let target = TargetAddr::Domain("example.com".to_owned(), 80);
+        let stream = spawn_server(server);
+        let mut client = Socks5Stream::use_stream(stream, None, Config::default())
+            .await
+            .unwrap();
+        let bind = client
+            .request(Socks5Command::TCPConnect, target.clone())
+            .await
+            .unwrap();
+        assert_eq!(bind.to_string(), "192.0.2.1:80");
+
+        // unknown address type
+        let mut stream = spawn_server(server);
+        let script = ClientScript::new()
+            .greeting(&[0])
+            .request(Socks5Command::TCPConnect, &target)
+            .set_byte(6, 9);
+        stream.write_all(script.bytes()).await.unwrap();
+        expect_method(&mut stream, 0).await;
+        expect_reply(&mut stream, Some(ReplyError::AddressTypeNotSupported)).await;
+        expect_closed(&mut stream).await;
+    }
+}
@@ -0,0 +1,99 @@
+//! Validation of the domains received from clients, before they reach the resolver and
+//! the logs.
+
+use std::net::Ipv6Addr;
+
+const MAX_NAME_LEN: usize = 253;
+const MAX_LABEL_LEN: usize = 63;
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum HostnameError {
+    #[error("empty hostname")]
+    Empty,
+    #[error("hostname of {0} bytes, over 253")]
+    TooLong(usize),
+    #[error("empty label")]
+    EmptyLabel,
+    #[error("label of {0} bytes, over 63")]
+    LabelTooLong(usize),
+    #[error("invalid character {0:?}")]
+    InvalidChar(char),
+}
+
+/// The canonical form of a hostname: lowercase, without the trailing dot.
+///
+/// Rejects the names the resolvers would choke on or that would garble the logs: empty
+/// labels, labels over 63 bytes, names over 253, and any character but letters, digits,
+/// `-` and `_` in the labels, e.g. NULs, whitespace or `/`. An IPv6 literal, bracketed
+/// or not, comes back as the canonical text of the IP.
+pub fn canonicalize_hostname(name: &str) -> Result<String, HostnameError> {
+    let literal = name.strip_prefix('[').and_then(|n| n.strip_suffix(']'));
+    if let Ok(ip) = literal.unwrap_or(name).parse::<Ipv6Addr>() {
+        return Ok(ip.to_string());
+    }
+    let name = name.strip_suffix('.').unwrap_or(name);
+    if name.is_empty() {
+        return Err(HostnameError::Empty);
+    }
+    if name.len() > MAX_NAME_LEN {
+        return Err(HostnameError::TooLong(name.len()));
+    }
+    for label in name.split('.') {
+        if label.is_empty() {
+            return Err(HostnameError::EmptyLabel);
+        }
+        if label.len() > MAX_LABEL_LEN {
+            return Err(HostnameError::LabelTooLong(label.len()));
+        }
+        if let Some(c) = label
+            .chars()
+            .find(|c| !(c.is_alphanumeric() || *c == '-' || *c == '_'))
+        {
+            return Err(HostnameError::InvalidChar(c));
+        }
+    }
+    Ok(name.to_lowercase())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{canonicalize_hostname, HostnameError};
+
+    #[test]
+    fn canonical_names() {
+        for (name, expected) in [
+            ("Example.COM", "example.com"),
+            ("example.com.", "example.com"),
+            ("_dmarc.example.com", "_dmarc.example.com"),
+            ("xn--bcher-kva.example", "xn--bcher-kva.example"),
+            ("Bücher.example", "bücher.example"),
+            ("10.0.0.1", "10.0.0.1"),
+            ("[::FFFF:10.0.0.1]", "::ffff:10.0.0.1"),
+            ("2001:DB8::1", "2001:db8::1"),
+        ] {
+            assert_eq!(canonicalize_hostname(name).as_deref(), Ok(expected));
+        }
+    }
+
+    #[test]
+    fn invalid_names() {
+        let long_label = format!("{}.com", "a".repeat(64));
+        let long_name = vec!["a".repeat(63); 4].join(".");
+        for (name, err) in [
+            ("", HostnameError::Empty),
+            (".", HostnameError::Empty),
+            ("example..com", HostnameError::EmptyLabel),
+            (".example.com", HostnameError::EmptyLabel),
+            ("example.com..", HostnameError::EmptyLabel),
+            (&long_label, HostnameError::LabelTooLong(64)),
+            (&long_name, HostnameError::TooLong(255)),
+            ("exa\0mple.com", HostnameError::InvalidChar('\0')),
+            ("example.com\n", HostnameError::InvalidChar('\n')),
+            ("exa mple.com", HostnameError::InvalidChar(' ')),
+            ("example.com/path", HostnameError::InvalidChar('/')),
+            ("example.com:80", HostnameError::InvalidChar(':')),
+        ] {
+            assert_eq!(canonicalize_hostname(name), Err(err), "{:?}", name);
+        }
+    }
+}
@@ -1,2 +1,9 @@
+pub mod hostname;
+pub mod proxy_url;
+pub mod secret;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod socket_options;
 pub mod stream;
 pub mod target_addr;
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) mod target_pattern;
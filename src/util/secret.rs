@@ -0,0 +1,100 @@
+use std::fmt;
+
+/// A value, such as a password, which is never shown by `Debug` or `Display`, so that it
+/// can't leak into errors, logs or traces.
+///
+/// Use [`Secret::expose`] where the value itself is needed. With the `serde` feature it
+/// (de)serializes as the plain value, so that configs round trip: serialize the fields
+/// to redact, e.g. in events or reports, with [`redacted`].
+#[derive(Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
+pub struct Secret<T>(T);
+
+impl<T> Secret<T> {
+    pub fn new(value: T) -> Self {
+        Secret(value)
+    }
+
+    pub fn expose(&self) -> &T {
+        &self.0
+    }
+
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> From<T> for Secret<T> {
+    fn from(value: T) -> Self {
+        Secret(value)
+    }
+}
+
+impl<T> fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(<redacted>)")
+    }
+}
+
+impl<T> fmt::Display for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+/// Serialize a [`Secret`] as `"<redacted>"`, for `#[serde(serialize_with = "...")]`.
+#[cfg(feature = "serde")]
+pub fn redacted<T, S: serde::Serializer>(_: &Secret<T>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str("<redacted>")
+}
+
+#[cfg(test)]
+mod test {
+    use super::Secret;
+    use crate::server::SocksServerError;
+    use crate::AuthenticationMethod;
+
+    #[test]
+    fn credentials_are_redacted() {
+        let method = AuthenticationMethod::Password {
+            username: "alice".to_owned(),
+            password: Secret::new("hunter2".to_owned()),
+        };
+        let err = SocksServerError::AuthenticationFailed {
+            username: "alice".to_owned().into(),
+            reason: "wrong password".to_owned(),
+        };
+        for shown in [
+            format!("{:?} {}", method, method),
+            format!("{:?} {}", err, err),
+        ] {
+            assert!(!shown.contains("alice"), "{}", shown);
+            assert!(!shown.contains("hunter2"), "{}", shown);
+        }
+        assert_eq!(err.to_string(), "Authentication rejected: wrong password");
+    }
+
+    #[cfg(feature = "webhook")]
+    #[test]
+    fn serialized_redacted() {
+        use crate::AuthenticationMethod;
+
+        let json = r#"{"Password":{"username":"alice","password":"hunter2"}}"#;
+        let method: AuthenticationMethod = serde_json::from_str(json).unwrap();
+        assert_eq!(serde_json::to_string(&method).unwrap(), json);
+
+        #[derive(serde::Serialize)]
+        struct Event {
+            #[serde(serialize_with = "super::redacted")]
+            password: Secret<String>,
+        }
+        let event = Event {
+            password: Secret::new("hunter2".to_owned()),
+        };
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"password":"<redacted>"}"#
+        );
+    }
+}
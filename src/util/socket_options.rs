@@ -0,0 +1,545 @@
+#[cfg(target_os = "linux")]
+use socket2::Protocol;
+use socket2::{Domain, Socket, Type};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpSocket, TcpStream};
+
+// pending TFO requests of a listener, before falling back to the regular handshake
+const FAST_OPEN_QUEUE: i32 = 256;
+
+/// Options of the TCP sockets opened by the server, for its listeners and its
+/// outbound connections, see `ConnectOptions::set_socket_options` and
+/// `Socks5Listener::bind_with_options`.
+///
+/// The options the platform doesn't support are skipped, logging why.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SocketOptions {
+    fast_open: bool,
+    multipath: bool,
+    dscp: Option<u8>,
+    bind_ip: Option<IpAddr>,
+}
+
+impl SocketOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// TCP Fast Open, sending the first data along with the SYN to the peers seen
+    /// before, which shaves a round trip off short connections. Linux only with the
+    /// `fast-open` feature, and subject to the `net.ipv4.tcp_fastopen` sysctl, see
+    /// [`fast_open_support`].
+    ///
+    /// Listeners accept it from the clients. Outbound, it's only used by
+    /// [`SocketOptions::connect_with_data`] with some data to send: the CONNECT command
+    /// doesn't know what the client sends first, if anything, before replying, and the
+    /// target may have to speak first (SSH, SMTP, FTP).
+    pub fn set_fast_open(&mut self, value: bool) -> &mut Self {
+        self.fast_open = value;
+        self
+    }
+
+    pub fn fast_open(&self) -> bool {
+        self.fast_open
+    }
+
+    /// Multipath TCP on the outbound connections, so that they can use several links
+    /// at once. Linux only, falling back to plain TCP when the kernel doesn't support
+    /// it (`net.mptcp.enabled`), and negotiated with the peer, which may not support it
+    /// either.
+    pub fn set_multipath(&mut self, value: bool) -> &mut Self {
+        self.multipath = value;
+        self
+    }
+
+    pub fn multipath(&self) -> bool {
+        self.multipath
+    }
+
+    /// Mark the packets of the outbound connections with this DSCP value (0 to 63,
+    /// higher bits ignored), for the QoS of the network, e.g. `46` for expedited
+    /// forwarding. `None` leaves the system default.
+    pub fn set_dscp(&mut self, dscp: Option<u8>) -> &mut Self {
+        self.dscp = dscp.map(|dscp| dscp & 0x3f);
+        self
+    }
+
+    pub fn dscp(&self) -> Option<u8> {
+        self.dscp
+    }
+
+    /// The source IP of the outbound connections, of the family of their targets, or
+    /// the one the system picks if `None`.
+    pub fn set_bind_ip(&mut self, ip: Option<IpAddr>) -> &mut Self {
+        self.bind_ip = ip;
+        self
+    }
+
+    pub fn bind_ip(&self) -> Option<IpAddr> {
+        self.bind_ip
+    }
+
+    /// Connect to `addr` with a socket set up with these options.
+    pub async fn connect(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        self.connect_with_data(addr, &[]).await
+    }
+
+    /// Connect to `addr` and send `data`, in the SYN with TCP Fast Open if enabled.
+    ///
+    /// Either way, it returns once the handshake is done, failing if the peer refused
+    /// the connection.
+    pub async fn connect_with_data(&self, addr: SocketAddr, data: &[u8]) -> io::Result<TcpStream> {
+        let domain = Domain::for_address(addr);
+        let socket = self.outbound_socket(domain)?;
+        if let Some(dscp) = self.dscp {
+            skip_unsupported("DSCP marking", set_dscp(&socket, domain, dscp));
+        }
+        let errors = ErrorQueue::of(&socket, domain);
+        let fast_open = self.fast_open
+            && !data.is_empty()
+            && match set_fast_open_connect(&socket) {
+                Ok(()) => true,
+                Err(err) => {
+                    debug!("TCP Fast Open not enabled: {}", err);
+                    false
+                }
+            };
+        if let Some(ip) = self.bind_ip {
+            socket.bind(&SocketAddr::new(ip, 0).into())?;
+        }
+        socket.set_nonblocking(true)?;
+        let socket = TcpSocket::from_std_stream(socket.into());
+        let refine = |err| match &errors {
+            Some(errors) => errors.refine(err),
+            None => err,
+        };
+        let mut stream = socket.connect(addr).await.map_err(refine)?;
+        let sent = if fast_open {
+            fast_open_handshake(&stream, data).await.map_err(refine)?
+        } else {
+            0
+        };
+        if let Some(errors) = errors {
+            errors.connected(domain);
+        }
+        stream.write_all(&data[sent..]).await?;
+        Ok(stream)
+    }
+
+    fn outbound_socket(&self, domain: Domain) -> io::Result<Socket> {
+        if self.multipath {
+            match multipath_socket(domain) {
+                Ok(socket) => return Ok(socket),
+                Err(err) => debug!("Multipath TCP not enabled: {}", err),
+            }
+        }
+        Socket::new(domain, Type::STREAM, None)
+    }
+
+    /// Set the options of a listening socket, before it listens.
+    pub(crate) fn apply_to_listener(&self, socket: &Socket) {
+        if self.fast_open {
+            skip_unsupported("TCP Fast Open", set_fast_open_listener(socket));
+        }
+    }
+}
+
+/// Which sides of TCP Fast Open the system allows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FastOpenSupport {
+    /// For outbound connections.
+    pub client: bool,
+    /// For listeners.
+    pub server: bool,
+}
+
+/// Read which sides of TCP Fast Open are enabled, from the `net.ipv4.tcp_fastopen`
+/// sysctl on Linux with the `fast-open` feature, none otherwise.
+pub fn fast_open_support() -> FastOpenSupport {
+    #[cfg(all(target_os = "linux", feature = "fast-open"))]
+    if let Ok(value) = std::fs::read_to_string("/proc/sys/net/ipv4/tcp_fastopen") {
+        let flags: u32 = value.trim().parse().unwrap_or(0);
+        return FastOpenSupport {
+            client: flags & 1 != 0,
+            server: flags & 2 != 0,
+        };
+    }
+    FastOpenSupport::default()
+}
+
+fn skip_unsupported(option: &str, res: io::Result<()>) {
+    if let Err(err) = res {
+        debug!("{} not enabled: {}", option, err);
+    }
+}
+
+/// DSCP is the upper 6 bits of the TOS byte (IPv4) or the traffic class (IPv6).
+fn set_dscp(socket: &Socket, domain: Domain, dscp: u8) -> io::Result<()> {
+    let tos = u32::from(dscp) << 2;
+    if domain == Domain::IPV6 {
+        set_traffic_class(socket, tos)
+    } else {
+        socket.set_tos(tos)
+    }
+}
+
+#[cfg(any(
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "openbsd"
+))]
+fn set_traffic_class(socket: &Socket, tclass: u32) -> io::Result<()> {
+    socket.set_tclass_v6(tclass)
+}
+
+#[cfg(not(any(
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "openbsd"
+)))]
+fn set_traffic_class(_socket: &Socket, _tclass: u32) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(target_os = "linux")]
+fn multipath_socket(domain: Domain) -> io::Result<Socket> {
+    Socket::new(domain, Type::STREAM, Some(Protocol::MPTCP))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn multipath_socket(_domain: Domain) -> io::Result<Socket> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(feature = "fast-open", feature = "icmp-errors")
+))]
+#[allow(unsafe_code)]
+fn set_int_option(socket: &Socket, level: i32, name: i32, value: i32) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    // SAFETY: the fd is open for the lifetime of `socket`, and `value` outlives the call
+    let res = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const i32 as *const libc::c_void,
+            std::mem::size_of::<i32>() as libc::socklen_t,
+        )
+    };
+    match res {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "fast-open"))]
+fn set_fast_open_connect(socket: &Socket) -> io::Result<()> {
+    set_int_option(socket, libc::IPPROTO_TCP, libc::TCP_FASTOPEN_CONNECT, 1)
+}
+
+/// The ICMP errors queued on a socket, to tell why its connect failed beyond the errno.
+#[cfg(all(target_os = "linux", feature = "icmp-errors"))]
+struct ErrorQueue(Socket);
+
+#[cfg(all(target_os = "linux", feature = "icmp-errors"))]
+impl ErrorQueue {
+    /// Queue the errors of `socket`, keeping a handle on it for after the connect.
+    fn of(socket: &Socket, domain: Domain) -> Option<Self> {
+        let res = match domain {
+            Domain::IPV6 => set_int_option(socket, libc::IPPROTO_IPV6, libc::IPV6_RECVERR, 1),
+            _ => set_int_option(socket, libc::IPPROTO_IP, libc::IP_RECVERR, 1),
+        };
+        match res.and_then(|_| socket.try_clone()) {
+            Ok(socket) => Some(ErrorQueue(socket)),
+            Err(err) => {
+                debug!("ICMP errors not read: {}", err);
+                None
+            }
+        }
+    }
+
+    /// Stop queuing the errors once connected: with them, the ICMP errors received later
+    /// would abort the connection instead of being retried.
+    fn connected(self, domain: Domain) {
+        let res = match domain {
+            Domain::IPV6 => set_int_option(&self.0, libc::IPPROTO_IPV6, libc::IPV6_RECVERR, 0),
+            _ => set_int_option(&self.0, libc::IPPROTO_IP, libc::IP_RECVERR, 0),
+        };
+        skip_unsupported("ICMP errors reset", res);
+    }
+
+    /// `err`, or the ICMP error queued if it tells more.
+    #[allow(unsafe_code)]
+    fn refine(&self, err: io::Error) -> io::Error {
+        use std::os::fd::AsRawFd;
+
+        let mut control = [0u8; 256];
+        // SAFETY: `msghdr` is a plain C struct, all null is an empty message
+        let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+        msg.msg_control = control.as_mut_ptr().cast();
+        msg.msg_controllen = control.len() as _;
+        // SAFETY: the fd is open for the lifetime of `self`, `msg` and `control` outlive the call
+        let res = unsafe {
+            libc::recvmsg(
+                self.0.as_raw_fd(),
+                &mut msg,
+                libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT,
+            )
+        };
+        if res < 0 {
+            return err;
+        }
+        // SAFETY: `msg` was filled by the kernel, the headers stay within `control`
+        let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+        while !cmsg.is_null() {
+            // SAFETY: a non-null header returned by CMSG_FIRSTHDR or CMSG_NXTHDR
+            let (level, kind) = unsafe { ((*cmsg).cmsg_level, (*cmsg).cmsg_type) };
+            if (level, kind) == (libc::SOL_IP, libc::IP_RECVERR)
+                || (level, kind) == (libc::SOL_IPV6, libc::IPV6_RECVERR)
+            {
+                // SAFETY: the data of these messages starts with a `sock_extended_err`
+                let ee: libc::sock_extended_err =
+                    unsafe { std::ptr::read_unaligned(libc::CMSG_DATA(cmsg).cast()) };
+                return icmp_error(ee.ee_origin, ee.ee_type, ee.ee_code).unwrap_or(err);
+            }
+            // SAFETY: as above
+            cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+        }
+        err
+    }
+}
+
+#[cfg(not(all(target_os = "linux", feature = "icmp-errors")))]
+struct ErrorQueue;
+
+#[cfg(not(all(target_os = "linux", feature = "icmp-errors")))]
+impl ErrorQueue {
+    fn of(_socket: &Socket, _domain: Domain) -> Option<Self> {
+        None
+    }
+
+    fn connected(self, _domain: Domain) {}
+
+    fn refine(&self, err: io::Error) -> io::Error {
+        err
+    }
+}
+
+/// The error of an ICMP message which the errno of `connect` doesn't tell: the targets
+/// administratively prohibited (ICMP type 3 codes 9, 10 and 13, ICMPv6 type 1 code 1),
+/// which the kernel reports as unreachable.
+#[cfg(any(test, all(target_os = "linux", feature = "icmp-errors")))]
+fn icmp_error(origin: u8, icmp_type: u8, code: u8) -> Option<io::Error> {
+    // SO_EE_ORIGIN_ICMP and SO_EE_ORIGIN_ICMP6
+    let prohibited = match origin {
+        2 => icmp_type == 3 && matches!(code, 9 | 10 | 13),
+        3 => icmp_type == 1 && code == 1,
+        _ => false,
+    };
+    prohibited.then(|| {
+        io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "administratively prohibited (ICMP type {} code {})",
+                icmp_type, code
+            ),
+        )
+    })
+}
+
+/// With `TCP_FASTOPEN_CONNECT`, `connect` returns before anything is sent: send the
+/// first bytes of `data` with the SYN and wait for the handshake, returning how many
+/// were sent.
+#[cfg(all(target_os = "linux", feature = "fast-open"))]
+async fn fast_open_handshake(stream: &TcpStream, data: &[u8]) -> io::Result<usize> {
+    use tokio::io::Interest;
+    // include/net/tcp_states.h
+    const TCP_SYN_SENT: i32 = 2;
+    const TCP_CLOSE: i32 = 7;
+
+    let sent = match stream.try_write(data) {
+        Ok(sent) => sent,
+        // no cookie for the peer yet, a plain SYN went out
+        Err(err) if err.raw_os_error() == Some(libc::EINPROGRESS) => 0,
+        Err(err) => return Err(err),
+    };
+    loop {
+        // the socket is writable until the SYN is sent, wait for the next change
+        let _ = stream.try_io(Interest::WRITABLE, || {
+            Err::<(), _>(io::ErrorKind::WouldBlock.into())
+        });
+        if let Some(err) = stream.take_error()? {
+            return Err(err);
+        }
+        match tcp_state(stream)? {
+            TCP_SYN_SENT => stream.writable().await?,
+            TCP_CLOSE => return Err(io::ErrorKind::ConnectionRefused.into()),
+            _ => return Ok(sent),
+        }
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "fast-open"))]
+#[allow(unsafe_code)]
+fn tcp_state(stream: &TcpStream) -> io::Result<i32> {
+    use std::os::fd::AsRawFd;
+
+    // SAFETY: `info` is a plain C struct, written by the kernel up to `len` bytes
+    let mut info: libc::tcp_info = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+    // SAFETY: the fd is open for the lifetime of `stream`, `info` and `len` outlive the call
+    let res = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_INFO,
+            &mut info as *mut libc::tcp_info as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    match res {
+        0 => Ok(i32::from(info.tcpi_state)),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(not(all(target_os = "linux", feature = "fast-open")))]
+async fn fast_open_handshake(_stream: &TcpStream, _data: &[u8]) -> io::Result<usize> {
+    Ok(0)
+}
+
+#[cfg(all(target_os = "linux", feature = "fast-open"))]
+fn set_fast_open_listener(socket: &Socket) -> io::Result<()> {
+    set_int_option(
+        socket,
+        libc::IPPROTO_TCP,
+        libc::TCP_FASTOPEN,
+        FAST_OPEN_QUEUE,
+    )
+}
+
+#[cfg(not(all(target_os = "linux", feature = "fast-open")))]
+fn set_fast_open_connect(_socket: &Socket) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(not(all(target_os = "linux", feature = "fast-open")))]
+fn set_fast_open_listener(_socket: &Socket) -> io::Result<()> {
+    let _ = FAST_OPEN_QUEUE;
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{fast_open_support, icmp_error, SocketOptions};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn fast_open_connect() {
+        // whether or not the system allows it, connections go through
+        let support = fast_open_support();
+        let mut options = SocketOptions::new();
+        options.set_fast_open(true);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 4];
+            socket.read_exact(&mut buf).await.unwrap();
+            buf
+        });
+        let mut stream = options.connect(addr).await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        assert_eq!(&server.await.unwrap(), b"ping", "{:?}", support);
+    }
+
+    #[test]
+    fn icmp_errors() {
+        for (origin, icmp_type, code, prohibited) in [
+            (2, 3, 13, true),
+            (2, 3, 9, true),
+            (2, 3, 10, true),
+            (2, 3, 1, false),
+            (2, 3, 3, false),
+            (3, 1, 1, true),
+            (3, 1, 0, false),
+            (1, 3, 13, false),
+        ] {
+            let err = icmp_error(origin, icmp_type, code);
+            assert_eq!(
+                err.is_some(),
+                prohibited,
+                "{} {} {}",
+                origin,
+                icmp_type,
+                code
+            );
+        }
+        let err = icmp_error(2, 3, 13).unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn fast_open_with_data() {
+        let support = fast_open_support();
+        let mut options = SocketOptions::new();
+        options.set_fast_open(true);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // twice, the second time with the cookie of the first one
+        for _ in 0..2 {
+            let mut stream = options.connect_with_data(addr, b"ping").await.unwrap();
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 4];
+            socket.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"ping", "{:?}", support);
+            socket.write_all(b"pong").await.unwrap();
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"pong");
+        }
+
+        // a refused connection fails instead of looking connected
+        drop(listener);
+        let err = options.connect_with_data(addr, b"ping").await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionRefused);
+    }
+
+    #[tokio::test]
+    async fn dscp_marking() {
+        let mut options = SocketOptions::new();
+        options.set_dscp(Some(46 | 0x80));
+        assert_eq!(options.dscp(), Some(46));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accept = tokio::spawn(async move { listener.accept().await.map(|_| ()) });
+        let stream = options.connect(addr).await.unwrap();
+        #[cfg(target_os = "linux")]
+        assert_eq!(socket2::SockRef::from(&stream).tos().unwrap(), 46 << 2);
+        drop(stream);
+        accept.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn multipath_connect() {
+        // falls back to plain TCP if the kernel has no MPTCP
+        let mut options = SocketOptions::new();
+        options.set_multipath(true);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accept = tokio::spawn(async move { listener.accept().await.map(|_| ()) });
+        let mut stream = options.connect(addr).await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        accept.await.unwrap().unwrap();
+    }
+}
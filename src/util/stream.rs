@@ -1,8 +1,16 @@
+#[cfg(not(target_arch = "wasm32"))]
+use crate::util::socket_options::SocketOptions;
 use crate::ReplyError;
 use std::io;
+#[cfg(not(target_arch = "wasm32"))]
+use std::net::SocketAddr;
+#[cfg(not(target_arch = "wasm32"))]
 use std::time::Duration;
+#[cfg(not(target_arch = "wasm32"))]
 use tokio::io::ErrorKind as IOErrorKind;
+#[cfg(not(target_arch = "wasm32"))]
 use tokio::net::{TcpStream, ToSocketAddrs};
+#[cfg(not(target_arch = "wasm32"))]
 use tokio::time::timeout;
 
 /// Easy to destructure bytes buffers by naming each fields:
@@ -58,6 +66,12 @@ pub enum ConnectError {
     ConnectionReset(#[source] io::Error),
     #[error("Not connected: {0}")]
     NotConnected(#[source] io::Error),
+    #[error("Network unreachable: {0}")]
+    NetworkUnreachable(#[source] io::Error),
+    #[error("Host unreachable: {0}")]
+    HostUnreachable(#[source] io::Error),
+    #[error("Connection not allowed: {0}")]
+    NotAllowed(#[source] io::Error),
     #[error("Other i/o error: {0}")]
     Other(#[source] io::Error),
 }
@@ -70,12 +84,17 @@ impl ConnectError {
             ConnectError::ConnectionAborted(_) | ConnectError::ConnectionReset(_) => {
                 ReplyError::ConnectionNotAllowed
             }
-            ConnectError::NotConnected(_) => ReplyError::NetworkUnreachable,
+            ConnectError::NotConnected(_) | ConnectError::NetworkUnreachable(_) => {
+                ReplyError::NetworkUnreachable
+            }
+            ConnectError::HostUnreachable(_) => ReplyError::HostUnreachable,
+            ConnectError::NotAllowed(_) => ReplyError::ConnectionNotAllowed,
             ConnectError::Other(_) => ReplyError::GeneralFailure,
         }
     }
 }
 
+#[cfg(not(target_arch = "wasm32"))]
 pub async fn tcp_connect_with_timeout<T>(
     addr: T,
     request_timeout_s: u64,
@@ -90,18 +109,90 @@ where
     }
 }
 
+#[cfg(not(target_arch = "wasm32"))]
 pub async fn tcp_connect<T>(addr: T) -> Result<TcpStream, ConnectError>
 where
     T: ToSocketAddrs,
 {
-    match TcpStream::connect(addr).await {
-        Ok(o) => Ok(o),
-        Err(e) => match e.kind() {
-            IOErrorKind::ConnectionRefused => Err(ConnectError::ConnectionRefused(e)),
-            IOErrorKind::ConnectionAborted => Err(ConnectError::ConnectionAborted(e)),
-            IOErrorKind::ConnectionReset => Err(ConnectError::ConnectionReset(e)),
-            IOErrorKind::NotConnected => Err(ConnectError::NotConnected(e)),
-            _ => Err(ConnectError::Other(e)),
-        },
+    TcpStream::connect(addr).await.map_err(connect_error)
+}
+
+/// Like [`tcp_connect_with_timeout`], with a socket set up with `options`.
+#[cfg(not(target_arch = "wasm32"))]
+pub async fn tcp_connect_with_options(
+    addr: SocketAddr,
+    options: &SocketOptions,
+    request_timeout_s: u64,
+) -> Result<TcpStream, ConnectError> {
+    let fut = options.connect(addr);
+    match timeout(Duration::from_secs(request_timeout_s), fut).await {
+        Ok(result) => result.map_err(connect_error),
+        Err(_) => Err(ConnectError::ConnectionTimeout),
+    }
+}
+
+/// Classify a failed connect.
+///
+/// The kernel turns the ICMP errors received during the handshake into the error of
+/// `connect`, e.g. on Linux a port unreachable into `ECONNREFUSED`, a host unreachable
+/// or administratively prohibited into `EHOSTUNREACH` and a network unreachable into
+/// `ENETUNREACH`, so they map to the matching replies. A local firewall rejecting the
+/// connection gives `EPERM`. With the `icmp-errors` feature, the connects of
+/// `SocketOptions` read the ICMP error itself, and turn the administratively prohibited
+/// ones into `EPERM` too.
+#[cfg(not(target_arch = "wasm32"))]
+fn connect_error(e: io::Error) -> ConnectError {
+    match e.kind() {
+        IOErrorKind::ConnectionRefused => ConnectError::ConnectionRefused(e),
+        IOErrorKind::NetworkUnreachable | IOErrorKind::NetworkDown => {
+            ConnectError::NetworkUnreachable(e)
+        }
+        IOErrorKind::HostUnreachable => ConnectError::HostUnreachable(e),
+        IOErrorKind::PermissionDenied => ConnectError::NotAllowed(e),
+        IOErrorKind::TimedOut => ConnectError::ConnectionTimeout,
+        IOErrorKind::ConnectionAborted => ConnectError::ConnectionAborted(e),
+        IOErrorKind::ConnectionReset => ConnectError::ConnectionReset(e),
+        IOErrorKind::NotConnected => ConnectError::NotConnected(e),
+        _ => ConnectError::Other(e),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::connect_error;
+    use crate::ReplyError;
+    use std::io;
+
+    #[test]
+    fn connect_error_replies() {
+        for (kind, reply) in [
+            (
+                io::ErrorKind::ConnectionRefused,
+                ReplyError::ConnectionRefused,
+            ),
+            (io::ErrorKind::HostUnreachable, ReplyError::HostUnreachable),
+            (
+                io::ErrorKind::NetworkUnreachable,
+                ReplyError::NetworkUnreachable,
+            ),
+            (
+                io::ErrorKind::PermissionDenied,
+                ReplyError::ConnectionNotAllowed,
+            ),
+            (io::ErrorKind::TimedOut, ReplyError::ConnectionTimeout),
+            (io::ErrorKind::OutOfMemory, ReplyError::GeneralFailure),
+        ] {
+            let err = connect_error(io::Error::from(kind));
+            assert_eq!(err.to_reply_error().as_u8(), reply.as_u8(), "{:?}", kind);
+        }
+        #[cfg(target_os = "linux")]
+        {
+            // EHOSTUNREACH, as after an ICMP administratively prohibited
+            let err = connect_error(io::Error::from_raw_os_error(113));
+            assert_eq!(
+                err.to_reply_error().as_u8(),
+                ReplyError::HostUnreachable.as_u8()
+            );
+        }
     }
 }
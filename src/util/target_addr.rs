@@ -1,12 +1,14 @@
 use crate::consts;
 use crate::consts::SOCKS5_ADDR_TYPE_IPV4;
 use crate::read_exact;
+use crate::util::hostname::{canonicalize_hostname, HostnameError};
 use crate::ReplyError;
 use std::fmt;
 use std::io;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 use std::vec::IntoIter;
 use tokio::io::{AsyncRead, AsyncReadExt};
+#[cfg(not(target_arch = "wasm32"))]
 use tokio::net::lookup_host;
 
 /// SOCKS5 reply code
@@ -32,6 +34,8 @@ pub enum AddrError {
     AddrConversionFailed(#[source] io::Error),
     #[error("Malformed UTF-8")]
     Utf8(#[source] std::string::FromUtf8Error),
+    #[error("Invalid domain: {0}")]
+    InvalidDomain(#[source] HostnameError),
     #[error("Unknown address type")]
     IncorrectAddressType,
 }
@@ -40,11 +44,45 @@ impl AddrError {
     pub fn to_reply_error(&self) -> ReplyError {
         match self {
             AddrError::IncorrectAddressType => ReplyError::AddressTypeNotSupported,
+            AddrError::InvalidDomain(_) => ReplyError::GeneralFailure,
             _ => ReplyError::ConnectionRefused,
         }
     }
 }
 
+/// Which addresses of a domain to connect to, and in which order, when it has both IPv4
+/// and IPv6 ones.
+///
+/// Hosts with broken IPv6 egress should prefer or only use IPv4, the system order
+/// usually putting IPv6 first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ResolutionPreference {
+    /// The order of the system resolver.
+    #[default]
+    System,
+    /// IPv4 addresses first, then IPv6 ones.
+    PreferIpv4,
+    /// IPv6 addresses first, then IPv4 ones.
+    PreferIpv6,
+    Ipv4Only,
+    Ipv6Only,
+}
+
+impl ResolutionPreference {
+    /// Filter and order resolved addresses, keeping the resolver order within a family.
+    pub fn apply<I: IntoIterator<Item = SocketAddr>>(self, addrs: I) -> Vec<SocketAddr> {
+        let mut addrs: Vec<SocketAddr> = addrs.into_iter().collect();
+        match self {
+            ResolutionPreference::System => {}
+            ResolutionPreference::PreferIpv4 => addrs.sort_by_key(|addr| addr.is_ipv6()),
+            ResolutionPreference::PreferIpv6 => addrs.sort_by_key(|addr| addr.is_ipv4()),
+            ResolutionPreference::Ipv4Only => addrs.retain(|addr| addr.is_ipv4()),
+            ResolutionPreference::Ipv6Only => addrs.retain(|addr| addr.is_ipv6()),
+        }
+        addrs
+    }
+}
+
 /// A description of a connection target.
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum TargetAddr {
@@ -58,25 +96,65 @@ pub enum TargetAddr {
 }
 
 impl TargetAddr {
+    #[cfg(not(target_arch = "wasm32"))]
     pub async fn resolve_dns(self) -> Result<TargetAddr, AddrError> {
+        self.resolve_dns_with(ResolutionPreference::System).await
+    }
+
+    /// Resolve a domain to its first address following `preference`.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn resolve_dns_with(
+        self,
+        preference: ResolutionPreference,
+    ) -> Result<TargetAddr, AddrError> {
         match self {
             TargetAddr::Ip(ip) => Ok(TargetAddr::Ip(ip)),
+            TargetAddr::Domain(_, _) => {
+                let socket_addr = self.resolve_all(preference).await?[0];
+
+                // has been converted to an ip
+                Ok(TargetAddr::Ip(socket_addr))
+            }
+        }
+    }
+
+    /// All the addresses to try in turn, ordered following `preference`. An IP is
+    /// returned as is, whatever its family.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn resolve_all(
+        &self,
+        preference: ResolutionPreference,
+    ) -> Result<Vec<SocketAddr>, AddrError> {
+        match self {
+            TargetAddr::Ip(ip) => Ok(vec![*ip]),
             TargetAddr::Domain(domain, port) => {
+                let domain = canonicalize_hostname(domain).map_err(AddrError::InvalidDomain)?;
                 debug!("Attempt to DNS resolve the domain {}...", &domain);
 
-                let socket_addr = lookup_host((&domain[..], port))
+                let addrs = lookup_host((&domain[..], *port))
                     .await
-                    .map_err(|err| AddrError::DNSResolutionFailed(err))?
-                    .next()
-                    .ok_or(AddrError::NoDNSRecords)?;
-                debug!("domain name resolved to {}", socket_addr);
-
-                // has been converted to an ip
-                Ok(TargetAddr::Ip(socket_addr))
+                    .map_err(|err| AddrError::DNSResolutionFailed(err))?;
+                let addrs = preference.apply(addrs);
+                if addrs.is_empty() {
+                    return Err(AddrError::NoDNSRecords);
+                }
+                debug!("domain name resolved to {:?}", addrs);
+                Ok(addrs)
             }
         }
     }
 
+    /// The address with its domain in canonical form, see
+    /// [`canonicalize_hostname`](crate::util::hostname::canonicalize_hostname).
+    pub fn canonicalize(self) -> Result<TargetAddr, AddrError> {
+        match self {
+            TargetAddr::Ip(ip) => Ok(TargetAddr::Ip(ip)),
+            TargetAddr::Domain(domain, port) => canonicalize_hostname(&domain)
+                .map(|domain| TargetAddr::Domain(domain, port))
+                .map_err(AddrError::InvalidDomain),
+        }
+    }
+
     pub fn is_ip(&self) -> bool {
         match self {
             TargetAddr::Ip(_) => true,
@@ -88,6 +166,13 @@ impl TargetAddr {
         !self.is_ip()
     }
 
+    pub fn port(&self) -> u16 {
+        match self {
+            TargetAddr::Ip(socket_addr) => socket_addr.port(),
+            TargetAddr::Domain(_, port) => *port,
+        }
+    }
+
     pub fn to_be_bytes(&self) -> Result<Vec<u8>, AddrError> {
         let mut buf = vec![];
         match self {
@@ -169,7 +254,9 @@ impl<'a> ToTargetAddr for (&'a str, u16) {
             return (addr, self.1).to_target_addr();
         }
 
-        if let Ok(addr) = self.0.parse::<Ipv6Addr>() {
+        // bare or bracketed, like in URLs
+        let ipv6 = self.0.strip_prefix('[').and_then(|a| a.strip_suffix(']'));
+        if let Ok(addr) = ipv6.unwrap_or(self.0).parse::<Ipv6Addr>() {
             return (addr, self.1).to_target_addr();
         }
 
@@ -230,48 +317,138 @@ pub enum Addr {
 }
 
 /// This function is used by the client & the server
+///
+/// The address and the port are read at once into a stack buffer, a domain being only
+/// copied out of it into the returned `TargetAddr`.
 pub async fn read_address<T: AsyncRead + Unpin>(
     stream: &mut T,
     atyp: u8,
 ) -> Result<TargetAddr, AddrError> {
+    // the longest domain and the port
+    let mut buf = [0u8; 255 + 2];
     let addr = match atyp {
         consts::SOCKS5_ADDR_TYPE_IPV4 => {
             debug!("Address type `IPv4`");
-            Addr::V4(read_exact!(stream, [0u8; 4]).map_err(|err| AddrError::IPv4Unreadable(err))?)
+            let buf = &mut buf[..4 + 2];
+            read_addr_port(stream, buf, AddrError::IPv4Unreadable).await?;
+            let ip = Ipv4Addr::new(buf[0], buf[1], buf[2], buf[3]);
+            TargetAddr::Ip(SocketAddr::V4(SocketAddrV4::new(ip, read_port(buf))))
         }
         consts::SOCKS5_ADDR_TYPE_IPV6 => {
             debug!("Address type `IPv6`");
-            Addr::V6(read_exact!(stream, [0u8; 16]).map_err(|err| AddrError::IPv6Unreadable(err))?)
+            let buf = &mut buf[..16 + 2];
+            read_addr_port(stream, buf, AddrError::IPv6Unreadable).await?;
+            let mut ip = [0u8; 16];
+            ip.copy_from_slice(&buf[..16]);
+            let ip = Ipv6Addr::from(ip);
+            TargetAddr::Ip(SocketAddr::V6(SocketAddrV6::new(ip, read_port(buf), 0, 0)))
         }
         consts::SOCKS5_ADDR_TYPE_DOMAIN_NAME => {
             debug!("Address type `domain`");
             let len =
                 read_exact!(stream, [0]).map_err(|err| AddrError::DomainLenUnreadable(err))?[0];
-            let domain = read_exact!(stream, vec![0u8; len as usize])
-                .map_err(|err| AddrError::DomainContentUnreadable(err))?;
+            let buf = &mut buf[..len as usize + 2];
+            read_addr_port(stream, buf, AddrError::DomainContentUnreadable).await?;
             // make sure the bytes are correct utf8 string
-            let domain = String::from_utf8(domain).map_err(|err| AddrError::Utf8(err))?;
-
-            Addr::Domain(domain)
+            let domain = match std::str::from_utf8(&buf[..len as usize]) {
+                Ok(domain) => domain,
+                // only allocate to build the error
+                Err(_) => {
+                    let err = String::from_utf8(buf[..len as usize].to_vec()).unwrap_err();
+                    return Err(AddrError::Utf8(err));
+                }
+            };
+            TargetAddr::Domain(domain.to_owned(), read_port(buf))
         }
         _ => return Err(AddrError::IncorrectAddressType),
     };
 
-    // Find port number
-    let port = read_exact!(stream, [0u8; 2]).map_err(|err| AddrError::PortNumberUnreadable(err))?;
-    // Convert (u8 * 2) into u16
-    let port = (port[0] as u16) << 8 | port[1] as u16;
-
-    // Merge ADDRESS + PORT into a TargetAddr
-    let addr: TargetAddr = match addr {
-        Addr::V4([a, b, c, d]) => (Ipv4Addr::new(a, b, c, d), port)
-            .to_target_addr()
-            .map_err(|err| AddrError::AddrConversionFailed(err))?,
-        Addr::V6(x) => (Ipv6Addr::from(x), port)
-            .to_target_addr()
-            .map_err(|err| AddrError::AddrConversionFailed(err))?,
-        Addr::Domain(domain) => TargetAddr::Domain(domain, port),
-    };
-
     Ok(addr)
 }
+
+/// Fill `buf` with an address followed by a port, telling which part was truncated.
+async fn read_addr_port<T: AsyncRead + Unpin>(
+    stream: &mut T,
+    buf: &mut [u8],
+    addr_err: fn(io::Error) -> AddrError,
+) -> Result<(), AddrError> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let err = match stream.read(&mut buf[filled..]).await {
+            Ok(0) => io::Error::from(io::ErrorKind::UnexpectedEof),
+            Ok(n) => {
+                filled += n;
+                continue;
+            }
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => err,
+        };
+        return Err(match filled < buf.len() - 2 {
+            true => addr_err(err),
+            false => AddrError::PortNumberUnreadable(err),
+        });
+    }
+    Ok(())
+}
+
+/// The big-endian port ending `buf`.
+fn read_port(buf: &[u8]) -> u16 {
+    let len = buf.len();
+    u16::from_be_bytes([buf[len - 2], buf[len - 1]])
+}
+
+#[cfg(test)]
+mod test {
+    use super::{read_address, AddrError, ResolutionPreference, TargetAddr};
+    use crate::consts;
+    use std::net::SocketAddr;
+
+    #[tokio::test]
+    async fn read_address_parts() {
+        let mut domain: &[u8] = b"\x0bexample.com\x01\xbb";
+        let addr = read_address(&mut domain, consts::SOCKS5_ADDR_TYPE_DOMAIN_NAME).await;
+        assert_eq!(
+            addr.unwrap(),
+            TargetAddr::Domain("example.com".to_owned(), 443)
+        );
+
+        // which part is missing
+        let mut truncated: &[u8] = &[10, 0, 0];
+        let err = read_address(&mut truncated, consts::SOCKS5_ADDR_TYPE_IPV4).await;
+        assert!(matches!(err, Err(AddrError::IPv4Unreadable(_))));
+        let mut truncated: &[u8] = &[10, 0, 0, 1, 0];
+        let err = read_address(&mut truncated, consts::SOCKS5_ADDR_TYPE_IPV4).await;
+        assert!(matches!(err, Err(AddrError::PortNumberUnreadable(_))));
+        let mut invalid: &[u8] = &[2, 0xff, 0xfe, 0, 80];
+        let err = read_address(&mut invalid, consts::SOCKS5_ADDR_TYPE_DOMAIN_NAME).await;
+        assert!(matches!(err, Err(AddrError::Utf8(_))));
+    }
+
+    #[tokio::test]
+    async fn resolution_preference() {
+        let addrs: Vec<SocketAddr> = ["[::1]:80", "10.0.0.1:80", "[::2]:80", "10.0.0.2:80"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+        for (preference, expected) in [
+            (ResolutionPreference::System, vec![0, 1, 2, 3]),
+            (ResolutionPreference::PreferIpv4, vec![1, 3, 0, 2]),
+            (ResolutionPreference::PreferIpv6, vec![0, 2, 1, 3]),
+            (ResolutionPreference::Ipv4Only, vec![1, 3]),
+            (ResolutionPreference::Ipv6Only, vec![0, 2]),
+        ] {
+            let expected: Vec<_> = expected.into_iter().map(|i| addrs[i]).collect();
+            assert_eq!(
+                preference.apply(addrs.clone()),
+                expected,
+                "{:?}",
+                preference
+            );
+        }
+
+        // IPs aren't filtered
+        let ip = TargetAddr::Ip(addrs[0]);
+        let resolved = ip.resolve_all(ResolutionPreference::Ipv4Only).await;
+        assert_eq!(resolved.unwrap(), [addrs[0]]);
+    }
+}
@@ -0,0 +1,82 @@
+use std::net::IpAddr;
+
+/// A pattern of target hosts: a domain, matching its subdomains too (`example.com`,
+/// `.example.com` or `*.example.com`), an IP, a CIDR block (`10.0.0.0/8`), or `*` for
+/// every host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum HostPattern {
+    Any,
+    Domain(String),
+    Network(IpAddr, u8),
+}
+
+impl HostPattern {
+    /// `None` for an empty pattern. The port of `example.com:8080` is ignored.
+    pub(crate) fn parse(pattern: &str) -> Option<Self> {
+        let pattern = pattern.trim().to_lowercase();
+        if pattern.is_empty() {
+            return None;
+        }
+        if pattern == "*" {
+            return Some(HostPattern::Any);
+        }
+        let (ip, prefix) = match pattern.split_once('/') {
+            Some((ip, prefix)) => (ip, prefix.parse().ok()),
+            None => (pattern.as_str(), None),
+        };
+        let ip = ip.trim_start_matches('[').trim_end_matches(']');
+        if let Ok(ip) = ip.parse::<IpAddr>() {
+            let max = if ip.is_ipv4() { 32 } else { 128 };
+            let prefix = prefix.unwrap_or(max).min(max);
+            return Some(HostPattern::Network(ip.to_canonical(), prefix));
+        }
+        // `.example.com` and `*.example.com` mean the same as `example.com`
+        let domain = pattern.trim_start_matches('*').trim_start_matches('.');
+        let domain = match domain.rsplit_once(':') {
+            Some((domain, port)) if port.parse::<u16>().is_ok() => domain,
+            _ => domain,
+        };
+        Some(HostPattern::Domain(domain.trim_end_matches('.').to_owned()))
+    }
+
+    /// Whether the pattern matches `host`, normalized by [`normalize_host`], which is
+    /// `ip` if parsed as one.
+    pub(crate) fn matches(&self, host: &str, ip: Option<IpAddr>) -> bool {
+        match (self, ip) {
+            (HostPattern::Any, _) => true,
+            (HostPattern::Domain(domain), None) => {
+                host == domain
+                    || (host.ends_with(domain.as_str())
+                        && host[..host.len() - domain.len()].ends_with('.'))
+            }
+            (HostPattern::Network(network, prefix), Some(ip)) => in_network(ip, *network, *prefix),
+            _ => false,
+        }
+    }
+}
+
+/// Lowercase `host` without its trailing dot, along with the IP it is, if any.
+pub(crate) fn normalize_host(host: &str) -> (String, Option<IpAddr>) {
+    let host = host.trim_end_matches('.').to_lowercase();
+    let ip = host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+        .ok()
+        .map(|ip| ip.to_canonical());
+    (host, ip)
+}
+
+fn in_network(ip: IpAddr, network: IpAddr, prefix: u8) -> bool {
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(network)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
@@ -0,0 +1,134 @@
+//! Property tests of the wire parsers: any input is rejected or parsed without panicking,
+//! and what the crate serializes parses back to the same value.
+
+use fast_socks5::server::Socks5ServerProtocol;
+use fast_socks5::util::target_addr::{read_address, AddrError, TargetAddr};
+use fast_socks5::{new_udp_header, parse_udp_request, UdpHeaderError};
+use proptest::prelude::*;
+use std::net::{IpAddr, SocketAddr, SocketAddrV6};
+use tokio_test::block_on;
+
+fn target_addr() -> impl Strategy<Value = TargetAddr> {
+    prop_oneof![
+        any::<(std::net::Ipv4Addr, u16)>().prop_map(|(ip, port)| TargetAddr::Ip((ip, port).into())),
+        // the wire format has no room for the flow info and scope id
+        any::<(std::net::Ipv6Addr, u16)>()
+            .prop_map(|(ip, port)| TargetAddr::Ip(SocketAddrV6::new(ip, port, 0, 0).into())),
+        ("\\PC{0,255}", any::<u16>()).prop_map(|(domain, port)| TargetAddr::Domain(domain, port)),
+    ]
+}
+
+/// The address of a request or UDP header, as the crate writes it.
+fn addr_bytes() -> impl Strategy<Value = Vec<u8>> {
+    target_addr().prop_filter_map("domain too long", |addr| addr.to_be_bytes().ok())
+}
+
+/// The bytes of a client greeting offering no authentication, then of a request.
+fn handshake(request: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![5, 1, 0];
+    bytes.extend_from_slice(request);
+    bytes
+}
+
+/// Run the server handshake up to the request, over `input`.
+fn read_command(input: &[u8]) -> Result<TargetAddr, String> {
+    block_on(async {
+        let stream = tokio::io::join(input, tokio::io::sink());
+        let proto = Socks5ServerProtocol::accept_no_auth(stream)
+            .await
+            .map_err(|err| err.to_string())?;
+        let (_, _, target) = proto.read_command().await.map_err(|err| err.to_string())?;
+        Ok(target)
+    })
+}
+
+proptest! {
+    #[test]
+    fn address_round_trip(addr in target_addr()) {
+        match addr.to_be_bytes() {
+            Ok(bytes) => {
+                let mut input = &bytes[1..];
+                let parsed = block_on(read_address(&mut input, bytes[0])).unwrap();
+                prop_assert_eq!(parsed, addr);
+                prop_assert!(input.is_empty());
+            }
+            Err(AddrError::DomainLenTooLong(len)) => prop_assert!(len > 255),
+            Err(err) => prop_assert!(false, "{}", err),
+        }
+    }
+
+    #[test]
+    fn address_any_bytes(atyp in any::<u8>(), bytes in prop::collection::vec(any::<u8>(), 0..300)) {
+        let mut input = &bytes[..];
+        if let Ok(addr) = block_on(read_address(&mut input, atyp)) {
+            // what was consumed is the serialized address
+            let consumed = bytes.len() - input.len();
+            let mut expected = vec![atyp];
+            expected.extend_from_slice(&bytes[..consumed]);
+            prop_assert_eq!(addr.to_be_bytes().unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn udp_header_round_trip(
+        addr in target_addr(),
+        payload in prop::collection::vec(any::<u8>(), 0..64),
+    ) {
+        match new_udp_header(addr.clone()) {
+            Ok(mut datagram) => {
+                datagram.extend_from_slice(&payload);
+                let (frag, parsed, data) = block_on(parse_udp_request(&datagram)).unwrap();
+                prop_assert_eq!(frag, 0);
+                prop_assert_eq!(parsed, addr);
+                prop_assert_eq!(data, &payload[..]);
+            }
+            Err(UdpHeaderError::AddrError(AddrError::DomainLenTooLong(_))) => {}
+            Err(err) => prop_assert!(false, "{}", err),
+        }
+    }
+
+    #[test]
+    fn udp_header_any_bytes(datagram in prop::collection::vec(any::<u8>(), 0..300)) {
+        if let Ok((_, addr, data)) = block_on(parse_udp_request(&datagram)) {
+            prop_assert_eq!(&datagram[..2], &[0, 0]);
+            let header_len = datagram.len() - data.len();
+            prop_assert_eq!(addr.to_be_bytes().unwrap(), &datagram[3..header_len]);
+        }
+    }
+
+    #[test]
+    fn request_round_trip(cmd in 1u8..=3, addr in addr_bytes()) {
+        let mut request = vec![5, cmd, 0];
+        request.extend_from_slice(&addr);
+        // the domains come back in canonical form, or are rejected
+        let canonical = block_on(read_address(&mut &addr[1..], addr[0]))
+            .unwrap()
+            .canonicalize();
+        match canonical {
+            Ok(canonical) => prop_assert_eq!(read_command(&handshake(&request)).unwrap(), canonical),
+            Err(_) => prop_assert!(read_command(&handshake(&request)).is_err()),
+        }
+    }
+
+    #[test]
+    fn request_any_bytes(request in prop::collection::vec(any::<u8>(), 0..300)) {
+        let _ = read_command(&handshake(&request));
+    }
+
+    #[test]
+    fn request_truncated(addr in addr_bytes(), cut in any::<prop::sample::Index>()) {
+        let mut request = vec![5, 1, 0];
+        request.extend_from_slice(&addr);
+        let cut = cut.index(request.len());
+        prop_assert!(read_command(&handshake(&request[..cut])).is_err());
+    }
+}
+
+#[test]
+fn ipv4_mapped_addresses_stay_ipv6() {
+    let ip: IpAddr = "::ffff:192.0.2.1".parse().unwrap();
+    let addr = TargetAddr::Ip(SocketAddr::new(ip, 80));
+    let bytes = addr.to_be_bytes().unwrap();
+    let parsed = block_on(read_address(&mut &bytes[1..], bytes[0])).unwrap();
+    assert_eq!(parsed, addr);
+}
@@ -0,0 +1,250 @@
+//! The server and client of this crate talking over real sockets on ephemeral ports.
+
+use fast_socks5::client::{Config, Socks5Datagram, Socks5Stream};
+use fast_socks5::server::{
+    run_tcp_proxy_with_options, run_udp_proxy, AuthOnceAcceptor, ConnectOptions, HandshakeLimits,
+    Socks5ServerProtocol, SocksServerError,
+};
+use fast_socks5::util::proxy_url::ProxyUrl;
+use fast_socks5::{ReplyError, Socks5Command, SocksError};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::time::timeout;
+
+#[derive(Debug, Clone, Copy)]
+enum Auth {
+    None,
+    Password,
+    Once,
+}
+
+const LOCALHOST: &str = "127.0.0.1";
+
+fn check(username: String, password: String) -> bool {
+    username == "alice" && password == "hunter2"
+}
+
+/// A server accepting clients with `auth`, returning its address.
+async fn spawn_server(auth: Auth) -> SocketAddr {
+    let listener = TcpListener::bind((LOCALHOST, 0)).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let auth_once = Arc::new(AuthOnceAcceptor::new());
+    tokio::spawn(async move {
+        loop {
+            let (socket, peer) = listener.accept().await.unwrap();
+            let auth_once = auth_once.clone();
+            tokio::spawn(async move {
+                let _ = serve(socket, peer.ip(), auth, &auth_once).await;
+            });
+        }
+    });
+    addr
+}
+
+async fn serve(
+    socket: TcpStream,
+    client_ip: IpAddr,
+    auth: Auth,
+    auth_once: &AuthOnceAcceptor,
+) -> Result<(), SocksServerError> {
+    let mut limits = HandshakeLimits::new();
+    limits.set_timeout(Duration::from_millis(200));
+    let (proto, cmd, target) = limits
+        .run(socket, |socket| async move {
+            let proto = match auth {
+                Auth::None => Socks5ServerProtocol::accept_no_auth(socket).await?,
+                Auth::Password => {
+                    Socks5ServerProtocol::accept_password_auth(socket, check)
+                        .await?
+                        .0
+                }
+                Auth::Once => auth_once.accept(socket, client_ip, check).await?.0,
+            };
+            proto.read_command().await
+        })
+        .await?;
+    match cmd {
+        Socks5Command::TCPConnect => {
+            let mut opts = ConnectOptions::new();
+            opts.set_request_timeout(2);
+            run_tcp_proxy_with_options(proto, &target, &opts).await?;
+        }
+        Socks5Command::UDPAssociate => {
+            let ip = LOCALHOST.parse().unwrap();
+            run_udp_proxy(proto, &target, Some(ip), ip, None).await?;
+        }
+        Socks5Command::TCPBind => {
+            proto.reply_error(&ReplyError::CommandNotSupported).await?;
+        }
+    }
+    Ok(())
+}
+
+/// A TCP echo server, returning its address.
+async fn spawn_echo() -> SocketAddr {
+    let listener = TcpListener::bind((LOCALHOST, 0)).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let (mut read, mut write) = stream.split();
+                let _ = tokio::io::copy(&mut read, &mut write).await;
+            });
+        }
+    });
+    addr
+}
+
+async fn assert_echo(stream: &mut Socks5Stream<TcpStream>) {
+    stream.write_all(b"ping").await.unwrap();
+    let mut buf = [0u8; 4];
+    timeout(Duration::from_secs(1), stream.read_exact(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(&buf, b"ping");
+}
+
+async fn connect(
+    proxy: SocketAddr,
+    target: SocketAddr,
+    password: Option<&str>,
+) -> Result<Socks5Stream<TcpStream>, SocksError> {
+    let (ip, port) = (target.ip().to_string(), target.port());
+    match password {
+        None => Socks5Stream::connect(proxy, ip, port, Config::default()).await,
+        Some(password) => {
+            let (username, password) = ("alice".to_owned(), password.to_owned());
+            Socks5Stream::connect_with_password(
+                proxy,
+                ip,
+                port,
+                username,
+                password,
+                Config::default(),
+            )
+            .await
+        }
+    }
+}
+
+#[tokio::test]
+async fn no_auth_connect() {
+    let proxy = spawn_server(Auth::None).await;
+    let echo = spawn_echo().await;
+    let mut stream = connect(proxy, echo, None).await.unwrap();
+    assert_eq!(stream.auth_method(), Some(0x00));
+    assert_echo(&mut stream).await;
+}
+
+#[tokio::test]
+async fn password_connect() {
+    let proxy = spawn_server(Auth::Password).await;
+    let echo = spawn_echo().await;
+    let mut stream = connect(proxy, echo, Some("hunter2")).await.unwrap();
+    assert_eq!(stream.auth_method(), Some(0x02));
+    assert_echo(&mut stream).await;
+
+    let res = connect(proxy, echo, Some("hunter3")).await;
+    assert!(matches!(res, Err(SocksError::AuthenticationRejected(_))));
+    let res = connect(proxy, echo, None).await;
+    assert!(matches!(res, Err(SocksError::AuthMethodUnacceptable(_))));
+}
+
+#[tokio::test]
+async fn proxy_url_connect() {
+    let proxy = spawn_server(Auth::Password).await;
+    let echo = spawn_echo().await;
+    let url: ProxyUrl = format!("socks5://alice:hunter2@{}", proxy).parse().unwrap();
+    let target = echo.ip().to_string();
+    let mut stream = Socks5Stream::connect_with_url(&url, target, echo.port(), Config::default())
+        .await
+        .unwrap();
+    assert_echo(&mut stream).await;
+}
+
+#[tokio::test]
+async fn auth_once_connect() {
+    let proxy = spawn_server(Auth::Once).await;
+    let echo = spawn_echo().await;
+    assert!(connect(proxy, echo, None).await.is_err());
+
+    let mut stream = connect(proxy, echo, Some("hunter2")).await.unwrap();
+    assert_echo(&mut stream).await;
+    // no password needed anymore from this IP
+    let mut stream = connect(proxy, echo, None).await.unwrap();
+    assert_eq!(stream.auth_method(), Some(0x00));
+    assert_echo(&mut stream).await;
+}
+
+#[tokio::test]
+async fn udp_associate() {
+    let proxy = spawn_server(Auth::None).await;
+    let echo = UdpSocket::bind((LOCALHOST, 0)).await.unwrap();
+    let echo_addr = echo.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = [0u8; 1500];
+        loop {
+            let (len, from) = echo.recv_from(&mut buf).await.unwrap();
+            echo.send_to(&buf[..len], from).await.unwrap();
+        }
+    });
+
+    let client = Socks5Datagram::connect_udp_bind(proxy, (LOCALHOST, 0), None, Config::default())
+        .await
+        .unwrap();
+    client.send_to(b"ping", echo_addr).await.unwrap();
+    let mut buf = [0u8; 1500];
+    let (len, from) = timeout(Duration::from_secs(1), client.recv_from(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(&buf[..len], b"ping");
+    assert_eq!(from.to_string(), echo_addr.to_string());
+}
+
+#[tokio::test]
+async fn error_replies() {
+    let proxy = spawn_server(Auth::None).await;
+    // a port nothing listens on
+    let closed = TcpListener::bind((LOCALHOST, 0)).await.unwrap();
+    let closed_addr = closed.local_addr().unwrap();
+    drop(closed);
+    let res = connect(proxy, closed_addr, None).await;
+    assert!(
+        matches!(
+            res,
+            Err(SocksError::ReplyError(ReplyError::ConnectionRefused))
+        ),
+        "{:?}",
+        res.err()
+    );
+
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    stream
+        .write_all(&[5, 1, 0, 5, 2, 0, 1, 127, 0, 0, 1, 0, 80])
+        .await
+        .unwrap();
+    let mut method = [0u8; 2];
+    stream.read_exact(&mut method).await.unwrap();
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply[1], ReplyError::CommandNotSupported.as_u8());
+}
+
+#[tokio::test]
+async fn idle_client_timeout() {
+    let proxy = spawn_server(Auth::None).await;
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    stream.write_all(&[5, 1, 0]).await.unwrap();
+    let mut buf = vec![];
+    timeout(Duration::from_secs(1), stream.read_to_end(&mut buf))
+        .await
+        .expect("the idle client wasn't closed")
+        .unwrap();
+    assert_eq!(buf, [5, 0]);
+}
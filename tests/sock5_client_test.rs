@@ -1,9 +1,11 @@
-use tokio::net::{TcpListener};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
 use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
 use std::time::Duration;
 use tokio::time::timeout;
 use tokio_test::assert_ok;
-use fast_socks5::client::{Config, Socks5Stream};
+use fast_socks5::client::{Config, Socks5Datagram, Socks5Stream};
+use fast_socks5::util::target_addr::TargetAddr;
+use fast_socks5::{Socks5Command, SocksError};
 
 #[tokio::test]
 async fn test_socks5_connection() -> io::Result<()> {
@@ -34,8 +36,16 @@ async fn test_socks5_connection() -> io::Result<()> {
         80,
         Config::default()
     ).await);
+    assert_eq!(socks_client.auth_method(), Some(0x00));
+    socks_client.get_ref().set_nodelay(true)?;
+    assert_eq!(socks_client.bind_addr().map(|addr| addr.to_string()), Some("255.0.0.1:80".to_owned()));
     socks_client.write_all(b"get").await?;
 
+    // peeking doesn't consume
+    let mut peeked = [0u8; 3];
+    let n = timeout(Duration::from_secs(1), socks_client.peek(&mut peeked)).await??;
+    assert_eq!(&peeked[..n], &b"all"[..n]);
+
     let mut resp = String::new();
     timeout(Duration::from_secs(1), async {
         socks_client.read_to_string(&mut resp).await.expect("Read response");
@@ -44,3 +54,227 @@ async fn test_socks5_connection() -> io::Result<()> {
     assert_eq!(resp, "all ok");
     Ok(())
 }
+
+#[tokio::test]
+async fn test_socks5_split() -> io::Result<()> {
+    let socks_server = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = socks_server.local_addr()?;
+
+    tokio::spawn(async move {
+        let (mut stream, _) = socks_server.accept().await.expect("Server accept failed");
+        read_handshake(&mut stream).await;
+        stream.write_all(&[0x05, 0x00]).await.expect("Write handshake response");
+        read_request(&mut stream).await;
+        stream.write_all(&[0x05, 0x00, 0x00, 0x01, 127, 0, 0, 1, 0x00, 0x50]).await.expect("Write response");
+
+        // echo
+        let (mut read, mut write) = stream.split();
+        io::copy(&mut read, &mut write).await.expect("Echo");
+    });
+
+    let socks_client = assert_ok!(Socks5Stream::connect(addr, "te.st".to_string(), 80, Config::default()).await);
+    let (mut read, mut write) = socks_client.into_split();
+    let writer = tokio::spawn(async move {
+        write.write_all(b"ping").await?;
+        write.shutdown().await
+    });
+    let mut echoed = String::new();
+    timeout(Duration::from_secs(1), read.read_to_string(&mut echoed)).await??;
+    writer.await??;
+    assert_eq!(echoed, "ping");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_socks5_cancelled_request() -> io::Result<()> {
+    let (client, mut server) = io::duplex(64);
+    server.write_all(&[0x05, 0x00]).await?;
+    let mut socks_client = assert_ok!(Socks5Stream::use_stream(client, None, Config::default()).await);
+
+    // the server never replies
+    let target = TargetAddr::Domain("te.st".to_string(), 80);
+    let res = timeout(Duration::from_millis(50), socks_client.request(Socks5Command::TCPConnect, target.clone())).await;
+    assert!(res.is_err());
+
+    // the stream is out of sync, it can't be used anymore
+    assert!(socks_client.write_all(b"get").await.is_err());
+    assert!(matches!(socks_client.request(Socks5Command::TCPConnect, target).await, Err(SocksError::Desynced)));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_socks5_skip_auth() -> io::Result<()> {
+    let socks_server = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = socks_server.local_addr()?;
+
+    tokio::spawn(async move {
+        let (mut stream, _) = socks_server.accept().await.expect("Server accept failed");
+        let mut buf = [0u8; 100];
+
+        // no method negotiation, the request comes first
+        let bytes_read = stream.read(&mut buf).await.expect("Read request");
+        assert_eq!(&buf[..bytes_read], &[0x05, 0x01, 0x00, 0x03, 0x05, b't', b'e', b'.', b's', b't', 0x00, 0x50]);
+        stream.write_all(&[0x05, 0x00, 0x00, 0x01, 127, 0, 0, 1, 0x00, 0x50]).await.expect("Write response");
+    });
+
+    let mut config = Config::default();
+    config.set_skip_auth(true);
+    let socks_client = assert_ok!(Socks5Stream::connect(addr, "te.st".to_string(), 80, config).await);
+    assert_eq!(socks_client.auth_method(), None);
+
+    let mut config = Config::default();
+    config.set_skip_auth(true);
+    let res = Socks5Stream::connect_with_password(addr, "te.st".to_string(), 80, "user".to_string(), "pass".to_string(), config).await;
+    assert!(matches!(res, Err(SocksError::InvalidConfig(_))));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_socks5_udp_with_bound_socket() -> io::Result<()> {
+    let socks_server = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = socks_server.local_addr()?;
+    let relay = UdpSocket::bind("127.0.0.1:0").await?;
+    let relay_port = relay.local_addr()?.port().to_be_bytes();
+
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = socks_server.accept().await.expect("Server accept failed");
+            tokio::spawn(async move {
+                let mut buf = [0u8; 100];
+
+                let bytes_read = stream.read(&mut buf).await.expect("Read initial handshake");
+                assert_eq!(&buf[..bytes_read], [0x05, 0x01, 0x00]);
+                stream.write_all(&[0x05, 0x00]).await.expect("Write handshake response");
+
+                let bytes_read = stream.read(&mut buf).await.expect("Read request");
+                assert_eq!(buf[1], 0x03, "UDP ASSOCIATE expected, got {:?}", &buf[..bytes_read]);
+                // reply with an unspecified relay address, as many servers do
+                stream.write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, relay_port[0], relay_port[1]]).await.expect("Write response");
+
+                // keep the association alive until the client is done
+                let _ = stream.read(&mut buf).await;
+            });
+        }
+    });
+
+    let out_sock = UdpSocket::bind("127.0.0.1:0").await?;
+    let out_addr = out_sock.local_addr()?;
+    let socks_client = assert_ok!(Socks5Datagram::connect_udp(
+        addr,
+        out_sock,
+        None,
+        Config::default()
+    ).await);
+    assert_eq!(socks_client.relay_addr(), relay.local_addr()?);
+    assert_ok!(socks_client.send_to(b"ping", ("te.st", 53)).await);
+
+    let mut buf = [0u8; 100];
+    let (len, from) = timeout(Duration::from_secs(1), relay.recv_from(&mut buf)).await??;
+    assert_eq!(from, out_addr);
+    assert!(buf[..len].ends_with(b"ping"));
+
+    // the IP of the proxy is also known from a given TcpStream
+    let backing_socket = TcpStream::connect(addr).await?;
+    let out_sock = UdpSocket::bind("127.0.0.1:0").await?;
+    let socks_client = assert_ok!(Socks5Datagram::use_socket(backing_socket, out_sock).await);
+    assert_eq!(socks_client.relay_addr(), relay.local_addr()?);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_socks5_udp_control_drop() -> io::Result<()> {
+    let socks_server = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = socks_server.local_addr()?;
+
+    tokio::spawn(async move {
+        let (mut stream, _) = socks_server.accept().await.expect("Server accept failed");
+        read_handshake(&mut stream).await;
+        stream.write_all(&[0x05, 0x00]).await.expect("Write handshake response");
+        read_request(&mut stream).await;
+        stream.write_all(&[0x05, 0x00, 0x00, 0x01, 127, 0, 0, 1, 0x00, 0x35]).await.expect("Write response");
+        // the association ends with the control connection
+    });
+
+    let mut config = Config::default();
+    config.set_tcp_keepalive(Duration::from_secs(30));
+    let mut socks_client = assert_ok!(Socks5Datagram::connect_udp_bind(
+        addr,
+        "127.0.0.1:0",
+        None,
+        config
+    ).await);
+    let (dropped_tx, dropped_rx) = tokio::sync::oneshot::channel();
+    socks_client.watch_control(move || {
+        let _ = dropped_tx.send(());
+    });
+
+    assert_ok!(timeout(Duration::from_secs(1), dropped_rx).await?);
+    assert!(!socks_client.is_control_alive());
+    assert!(socks_client.send_to(b"ping", ("te.st", 53)).await.is_err());
+    Ok(())
+}
+
+/// Read the method selection of a client, VER NMETHODS METHODS.
+async fn read_handshake(stream: &mut TcpStream) -> Vec<u8> {
+    let mut handshake = vec![0u8; 2];
+    stream.read_exact(&mut handshake).await.expect("Read initial handshake");
+    handshake.resize(2 + handshake[1] as usize, 0);
+    stream.read_exact(&mut handshake[2..]).await.expect("Read initial handshake");
+    handshake
+}
+
+/// Read a request, VER CMD RSV ATYP DST.ADDR DST.PORT.
+async fn read_request(stream: &mut TcpStream) -> Vec<u8> {
+    let mut request = vec![0u8; 5];
+    stream.read_exact(&mut request).await.expect("Read request");
+    // the first byte of the address is already read
+    let rest = match request[3] {
+        0x01 => 3 + 2,
+        0x04 => 15 + 2,
+        _ => request[4] as usize + 2,
+    };
+    request.resize(5 + rest, 0);
+    stream.read_exact(&mut request[5..]).await.expect("Read request");
+    request
+}
+
+/// Accept one connection, replying success, and send back the request it got.
+async fn capture_request(socks_server: TcpListener) -> Vec<u8> {
+    let (mut stream, _) = socks_server.accept().await.expect("Server accept failed");
+
+    read_handshake(&mut stream).await;
+    stream.write_all(&[0x05, 0x00]).await.expect("Write handshake response");
+    let request = read_request(&mut stream).await;
+    stream.write_all(&[0x05, 0x00, 0x00, 0x01, 127, 0, 0, 1, 0x00, 0x50]).await.expect("Write response");
+    request
+}
+
+#[tokio::test]
+async fn test_socks5_ip_literal_address_types() -> io::Result<()> {
+    let mut as_domain = Config::default();
+    as_domain.set_ip_literals_as_domain(true);
+    for (target, config, expected) in [
+        ("10.0.0.1", Config::default(), vec![0x01, 10, 0, 0, 1, 0x00, 0x50]),
+        ("[::1]", Config::default(), [vec![0x04], [0; 15].to_vec(), vec![1, 0x00, 0x50]].concat()),
+        ("::1", Config::default(), [vec![0x04], [0; 15].to_vec(), vec![1, 0x00, 0x50]].concat()),
+        ("10.0.0.1", as_domain.clone(), [&[0x03, 8][..], b"10.0.0.1", &[0x00, 0x50]].concat()),
+        ("te.st", as_domain, [&[0x03, 5][..], b"te.st", &[0x00, 0x50]].concat()),
+    ] {
+        let socks_server = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = socks_server.local_addr()?;
+        let request = tokio::spawn(capture_request(socks_server));
+        assert_ok!(Socks5Stream::connect(addr, target.to_string(), 80, config).await);
+        assert_eq!(request.await?[3..], expected, "{}", target);
+    }
+
+    // an IP literal given as a domain goes out as an IP
+    let socks_server = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = socks_server.local_addr()?;
+    let request = tokio::spawn(capture_request(socks_server));
+    let socket = tokio::net::TcpStream::connect(addr).await?;
+    let mut socks_client = assert_ok!(Socks5Stream::use_stream(socket, None, Config::default()).await);
+    let target = TargetAddr::Domain("10.0.0.1".to_owned(), 80);
+    assert_ok!(socks_client.request(Socks5Command::TCPConnect, target).await);
+    assert_eq!(request.await?[3..], [0x01, 10, 0, 0, 1, 0x00, 0x50]);
+    Ok(())
+}